@@ -14,11 +14,21 @@
  * limitations under the License.
  */
 
+#[cfg(feature = "dns-rewrite")]
+use relaylib::DnsRewriter;
+use relaylib::{RelayConfig, TuningProfile};
+use std::net::Ipv4Addr;
+#[cfg(feature = "dns-rewrite")]
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Duration;
+
 pub const PARAM_NONE: u8 = 0;
 pub const PARAM_SERIAL: u8 = 1;
 pub const PARAM_DNS_SERVERS: u8 = 1 << 1;
 pub const PARAM_ROUTES: u8 = 1 << 2;
 pub const PARAM_PORT: u8 = 1 << 3;
+pub const PARAM_RELAY_OPTIONS: u8 = 1 << 4;
 
 pub const DEFAULT_PORT: u16 = 31416;
 
@@ -27,6 +37,7 @@ pub struct CommandLineArguments {
     dns_servers: Option<String>,
     routes: Option<String>,
     port: u16,
+    relay_config: RelayConfig,
 }
 
 impl CommandLineArguments {
@@ -36,6 +47,8 @@ impl CommandLineArguments {
         let mut dns_servers = None;
         let mut routes = None;
         let mut port = 0;
+        let mut relay_config = RelayConfig::new();
+        let mut relay_options = Vec::new();
 
         let mut iter = args.into_iter();
         while let Some(arg) = iter.next() {
@@ -70,6 +83,18 @@ impl CommandLineArguments {
                 } else {
                     return Err(String::from("Missing -p parameter"));
                 }
+            } else if (accepted_parameters & PARAM_RELAY_OPTIONS) != 0 && arg.starts_with("--") {
+                let (arg, value) = match arg.as_str() {
+                    // shorthands taking no value
+                    "--no-tcp" => (String::from("--tcp"), Some(String::from("off"))),
+                    "--no-udp" => (String::from("--udp"), Some(String::from("off"))),
+                    _ => (arg, iter.next().map(Into::into)),
+                };
+                if relay_options.contains(&arg) {
+                    return Err(format!("{} already set", arg));
+                }
+                parse_relay_option(&mut relay_config, &arg, value)?;
+                relay_options.push(arg);
             } else if (accepted_parameters & PARAM_SERIAL) != 0 && serial.is_none() {
                 serial = Some(arg);
             } else {
@@ -84,6 +109,7 @@ impl CommandLineArguments {
             dns_servers,
             routes,
             port,
+            relay_config,
         })
     }
 
@@ -102,13 +128,347 @@ impl CommandLineArguments {
     pub fn port(&self) -> u16 {
         self.port
     }
+
+    pub fn relay_config(&self) -> &RelayConfig {
+        &self.relay_config
+    }
+}
+
+fn parse_relay_option<S: Into<String>>(
+    relay_config: &mut RelayConfig,
+    option: &str,
+    value: Option<S>,
+) -> Result<(), String> {
+    match option {
+        "--max-connection-lifetime" => {
+            let seconds = parse_value(option, value)?;
+            if seconds == 0 {
+                return Err(String::from("Invalid max connection lifetime: 0"));
+            }
+            relay_config.set_max_connection_lifetime(Some(Duration::from_secs(seconds)));
+        }
+        "--tcp-keepalive" => {
+            let seconds = parse_value(option, value)?;
+            if seconds == 0 {
+                return Err(String::from("Invalid TCP keepalive interval: 0"));
+            }
+            relay_config.set_tcp_keepalive_interval(Some(Duration::from_secs(seconds)));
+        }
+        "--tcp-write-timeout" => {
+            let seconds = parse_value(option, value)?;
+            if seconds == 0 {
+                return Err(String::from("Invalid TCP write timeout: 0"));
+            }
+            relay_config.set_tcp_write_timeout(Some(Duration::from_secs(seconds)));
+        }
+        "--tcp-defer-connect" => {
+            let seconds = parse_value(option, value)?;
+            if seconds == 0 {
+                return Err(String::from("Invalid TCP defer connect delay: 0"));
+            }
+            relay_config.set_tcp_defer_connect(Some(Duration::from_secs(seconds)));
+        }
+        "--tcp-half-close" => {
+            relay_config.set_tcp_half_close(parse_switch(option, value)?);
+        }
+        "--tcp-time-wait" => {
+            let seconds = parse_value(option, value)?;
+            let time_wait = if seconds == 0 {
+                None
+            } else {
+                Some(Duration::from_secs(seconds))
+            };
+            relay_config.set_tcp_time_wait(time_wait);
+        }
+        "--tcp-pool-ttl" => {
+            let seconds = parse_value(option, value)?;
+            if seconds == 0 {
+                return Err(String::from("Invalid TCP pool TTL: 0"));
+            }
+            relay_config.set_tcp_pool_ttl(Some(Duration::from_secs(seconds)));
+        }
+        "--accept-backlog" => {
+            let backlog = parse_value(option, value)?;
+            if backlog <= 0 {
+                return Err(format!("Invalid accept backlog: {}", backlog));
+            }
+            relay_config.set_accept_backlog(backlog);
+        }
+        "--max-accept-rate" => {
+            let rate = parse_value(option, value)?;
+            if rate == 0 {
+                return Err(String::from("Invalid max accept rate: 0"));
+            }
+            relay_config.set_max_accept_rate(Some(rate));
+        }
+        "--max-egress-rate" => {
+            let rate = parse_value(option, value)?;
+            if rate == 0 {
+                return Err(String::from("Invalid max egress rate: 0"));
+            }
+            relay_config.set_max_egress_rate(Some(rate));
+        }
+        "--profile" => {
+            let profile = match parse_value::<String, _>(option, value)?.as_str() {
+                "latency" => TuningProfile::Latency,
+                "throughput" => TuningProfile::Throughput,
+                other => return Err(format!("Invalid {} parameter: \"{}\"", option, other)),
+            };
+            relay_config.apply_profile(profile);
+        }
+        "--tcp-nodelay" => {
+            relay_config.set_tcp_nodelay(parse_switch(option, value)?);
+        }
+        "--client-nodelay" => {
+            relay_config.set_client_nodelay(parse_switch(option, value)?);
+        }
+        "--max-half-open" => {
+            let count = parse_value(option, value)?;
+            if count == 0 {
+                return Err(String::from("Invalid max half-open connections: 0"));
+            }
+            relay_config.set_max_half_open_connections(Some(count));
+        }
+        "--max-connections" => {
+            let count = parse_value(option, value)?;
+            if count == 0 {
+                return Err(String::from("Invalid max connections: 0"));
+            }
+            relay_config.set_max_connections_per_client(Some(count));
+        }
+        "--connection-watermarks" => {
+            let value: String = parse_value(option, value)?;
+            let watermarks = value
+                .split(',')
+                .map(|pct| match pct.parse() {
+                    Ok(pct @ 1..=100) => Ok(pct),
+                    _ => Err(format!("Invalid connection watermark: {}", pct)),
+                })
+                .collect::<Result<Vec<u8>, String>>()?;
+            relay_config.set_connection_watermarks(watermarks);
+        }
+        "--fd-headroom" => {
+            let count = parse_value(option, value)?;
+            let headroom = if count == 0 { None } else { Some(count) };
+            relay_config.set_fd_headroom(headroom);
+        }
+        "--max-source-connections" => {
+            let count = parse_value(option, value)?;
+            if count == 0 {
+                return Err(String::from("Invalid max source connections: 0"));
+            }
+            relay_config.set_max_connections_per_source(Some(count));
+        }
+        "--max-destination-rate" => {
+            let rate = parse_value(option, value)?;
+            if rate == 0 {
+                return Err(String::from("Invalid max destination rate: 0"));
+            }
+            relay_config.set_max_destination_connection_rate(Some(rate));
+        }
+        "--allow-broadcast" => {
+            relay_config.set_allow_broadcast(parse_switch(option, value)?);
+        }
+        "--allow-relay-loop" => {
+            relay_config.set_allow_relay_loop(parse_switch(option, value)?);
+        }
+        "--log-payload" => {
+            let length = parse_value(option, value)?;
+            if length == 0 {
+                return Err(String::from("Invalid payload log length: 0"));
+            }
+            relay_config.set_payload_dump_length(Some(length));
+        }
+        "--tcp-ports" => {
+            let value: String = parse_value(option, value)?;
+            let ports = value
+                .split(',')
+                .map(|port| match port.parse() {
+                    Ok(0) | Err(_) => Err(format!("Invalid TCP port: {}", port)),
+                    Ok(port) => Ok(port),
+                })
+                .collect::<Result<Vec<u16>, String>>()?;
+            relay_config.set_tcp_allowed_ports(Some(ports));
+        }
+        "--dscp-priority" => {
+            let value: String = parse_value(option, value)?;
+            let priorities = value
+                .split(',')
+                .map(|entry| {
+                    let mut parts = entry.splitn(2, '=');
+                    let dscp = parts.next().and_then(|dscp| dscp.parse().ok());
+                    let priority = parts.next().and_then(|priority| priority.parse().ok());
+                    match (dscp, priority) {
+                        (Some(dscp), Some(priority)) if dscp < 64 => Ok((dscp, priority)),
+                        _ => Err(format!("Invalid DSCP priority: {}", entry)),
+                    }
+                })
+                .collect::<Result<Vec<(u8, u32)>, String>>()?;
+            relay_config.set_dscp_priorities(Some(priorities));
+        }
+        #[cfg(feature = "dns-rewrite")]
+        "--dns-rewrite" => {
+            let value: String = parse_value(option, value)?;
+            let mut dns_rewriter = DnsRewriter::new();
+            for entry in value.split(',') {
+                let mut parts = entry.splitn(2, '=');
+                let name = parts.next().filter(|name| !name.is_empty());
+                let addr = parts.next().and_then(|addr| addr.parse().ok());
+                match (name, addr) {
+                    (Some(name), Some(addr)) => dns_rewriter.add(name, addr),
+                    _ => return Err(format!("Invalid DNS rewrite: {}", entry)),
+                }
+            }
+            relay_config.set_payload_rewriter(Some(Rc::new(dns_rewriter)));
+        }
+        "--tcp-reset-on-close" => {
+            let value: String = parse_value(option, value)?;
+            let networks = value
+                .split(',')
+                .map(|entry| {
+                    let mut parts = entry.splitn(2, '/');
+                    let network = parts.next().and_then(|network| network.parse().ok());
+                    let prefix_length = match parts.next() {
+                        Some(prefix_length) => prefix_length.parse().ok(),
+                        None => Some(32),
+                    };
+                    match (network, prefix_length) {
+                        (Some(network), Some(prefix_length)) if prefix_length <= 32 => {
+                            Ok((network, prefix_length))
+                        }
+                        _ => Err(format!("Invalid network: {}", entry)),
+                    }
+                })
+                .collect::<Result<Vec<(Ipv4Addr, u8)>, String>>()?;
+            relay_config.set_tcp_reset_networks(Some(networks));
+        }
+        "--reject-unreachable" => {
+            relay_config.set_reject_unreachable(parse_switch(option, value)?);
+        }
+        "--tcp" => {
+            relay_config.set_tcp_enabled(parse_switch(option, value)?);
+        }
+        "--udp" => {
+            relay_config.set_udp_enabled(parse_switch(option, value)?);
+        }
+        "--reject-source-route" => {
+            relay_config.set_reject_source_route(parse_switch(option, value)?);
+        }
+        "--reject-record-route" => {
+            relay_config.set_reject_record_route(parse_switch(option, value)?);
+        }
+        "--drop-fragments" => {
+            relay_config.set_drop_fragments(parse_switch(option, value)?);
+        }
+        "--ethernet-frames" => {
+            relay_config.set_ethernet_frames(parse_switch(option, value)?);
+        }
+        "--udp-source-ports" => {
+            let value: String = parse_value(option, value)?;
+            let mut ports = value.splitn(2, '-');
+            let first = parse_value(option, ports.next())?;
+            let last = match ports.next() {
+                Some(last) => parse_value(option, Some(last))?,
+                None => first,
+            };
+            if first == 0 || first > last {
+                return Err(format!("Invalid UDP source ports: {}", value));
+            }
+            relay_config.set_udp_source_ports(Some(first..=last));
+        }
+        "--allow-multicast" => {
+            relay_config.set_allow_multicast(parse_switch(option, value)?);
+        }
+        "--multicast-interface" => {
+            relay_config.set_multicast_interface(parse_value(option, value)?);
+        }
+        "--dns-timeout" => {
+            let seconds = parse_value(option, value)?;
+            if seconds == 0 {
+                return Err(String::from("Invalid DNS timeout: 0"));
+            }
+            relay_config.set_dns_timeout(Some(Duration::from_secs(seconds)));
+        }
+        "--udp-jitter" => {
+            relay_config.set_udp_jitter(parse_switch(option, value)?);
+        }
+        "--udp-max-payload" => {
+            let length = parse_value(option, value)?;
+            if length == 0 {
+                return Err(String::from("Invalid UDP max payload: 0"));
+            }
+            relay_config.set_udp_max_payload(Some(length));
+        }
+        "--socket-rcvbuf" => {
+            let size = parse_value(option, value)?;
+            if size == 0 {
+                return Err(String::from("Invalid socket receive buffer size: 0"));
+            }
+            relay_config.set_socket_receive_buffer(Some(size));
+        }
+        "--socket-sndbuf" => {
+            let size = parse_value(option, value)?;
+            if size == 0 {
+                return Err(String::from("Invalid socket send buffer size: 0"));
+            }
+            relay_config.set_socket_send_buffer(Some(size));
+        }
+        "--ecn-threshold" => {
+            let percent = parse_value(option, value)?;
+            if percent > 100 {
+                return Err(format!("Invalid ECN threshold: {}", percent));
+            }
+            relay_config.set_ecn_threshold(Some(percent));
+        }
+        "--mirror" => {
+            relay_config.set_mirror_address(Some(parse_value(option, value)?));
+        }
+        "--tcp-keepalive-probes" => {
+            relay_config.set_tcp_keepalive_probes(parse_value(option, value)?);
+        }
+        "--stats-file" => {
+            relay_config.set_stats_file(Some(parse_value::<String, _>(option, value)?.into()));
+        }
+        "--stats-interval" => {
+            let seconds = parse_value(option, value)?;
+            if seconds == 0 {
+                return Err(String::from("Invalid stats interval: 0"));
+            }
+            relay_config.set_stats_interval(Duration::from_secs(seconds));
+        }
+        #[cfg(feature = "statsd")]
+        "--statsd" => {
+            relay_config.set_statsd_address(Some(parse_value(option, value)?));
+        }
+        _ => return Err(format!("Unexpected argument: \"{}\"", option)),
+    }
+    Ok(())
+}
+
+fn parse_value<T: FromStr, S: Into<String>>(param: &str, value: Option<S>) -> Result<T, String> {
+    if let Some(value) = value {
+        let value = value.into();
+        value
+            .parse()
+            .map_err(|_| format!("Invalid {} parameter: \"{}\"", param, value))
+    } else {
+        Err(format!("Missing {} parameter", param))
+    }
+}
+
+fn parse_switch<S: Into<String>>(param: &str, value: Option<S>) -> Result<bool, String> {
+    match parse_value::<String, _>(param, value)?.as_str() {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        other => Err(format!("Invalid {} parameter: \"{}\"", param, other)),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    const ACCEPT_ALL: u8 = PARAM_SERIAL | PARAM_DNS_SERVERS | PARAM_ROUTES;
+    const ACCEPT_ALL: u8 = PARAM_SERIAL | PARAM_DNS_SERVERS | PARAM_ROUTES | PARAM_RELAY_OPTIONS;
 
     #[test]
     fn test_no_args() {
@@ -178,4 +538,609 @@ mod tests {
         let raw_args = vec!["-r"];
         assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
     }
+
+    #[test]
+    fn test_max_connection_lifetime_parameter() {
+        let raw_args = vec!["--max-connection-lifetime", "600"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        let max_lifetime = args.relay_config().max_connection_lifetime();
+        assert_eq!(Some(Duration::from_secs(600)), max_lifetime);
+    }
+
+    #[test]
+    fn test_no_max_connection_lifetime_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args.relay_config().max_connection_lifetime().is_none());
+    }
+
+    #[test]
+    fn test_tcp_keepalive_parameters() {
+        let raw_args = vec!["--tcp-keepalive", "30", "--tcp-keepalive-probes", "5"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        let relay_config = args.relay_config();
+        assert_eq!(
+            Some(Duration::from_secs(30)),
+            relay_config.tcp_keepalive_interval()
+        );
+        assert_eq!(5, relay_config.tcp_keepalive_probes());
+    }
+
+    #[test]
+    fn test_tcp_write_timeout_parameter() {
+        let raw_args = vec!["--tcp-write-timeout", "120"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        let write_timeout = args.relay_config().tcp_write_timeout();
+        assert_eq!(Some(Duration::from_secs(120)), write_timeout);
+
+        let raw_args = vec!["--tcp-write-timeout", "0"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_tcp_defer_connect_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args.relay_config().tcp_defer_connect().is_none());
+
+        let raw_args = vec!["--tcp-defer-connect", "3"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        let defer_connect = args.relay_config().tcp_defer_connect();
+        assert_eq!(Some(Duration::from_secs(3)), defer_connect);
+
+        let raw_args = vec!["--tcp-defer-connect", "0"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_tcp_half_close_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args.relay_config().tcp_half_close());
+
+        let raw_args = vec!["--tcp-half-close", "off"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(!args.relay_config().tcp_half_close());
+    }
+
+    #[test]
+    fn test_tcp_time_wait_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        let time_wait = args.relay_config().tcp_time_wait();
+        assert_eq!(Some(Duration::from_secs(5)), time_wait);
+
+        let raw_args = vec!["--tcp-time-wait", "30"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        let time_wait = args.relay_config().tcp_time_wait();
+        assert_eq!(Some(Duration::from_secs(30)), time_wait);
+
+        let raw_args = vec!["--tcp-time-wait", "0"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert_eq!(None, args.relay_config().tcp_time_wait());
+    }
+
+    #[test]
+    fn test_tcp_nodelay_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args.relay_config().tcp_nodelay());
+
+        let raw_args = vec!["--tcp-nodelay", "off"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(!args.relay_config().tcp_nodelay());
+
+        let raw_args = vec!["--tcp-nodelay", "maybe"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_reject_unreachable_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args.relay_config().reject_unreachable());
+
+        let raw_args = vec!["--reject-unreachable", "off"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(!args.relay_config().reject_unreachable());
+    }
+
+    #[test]
+    fn test_max_connections_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args.relay_config().max_connections_per_client().is_none());
+
+        let raw_args = vec!["--max-connections", "100"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert_eq!(Some(100), args.relay_config().max_connections_per_client());
+
+        let raw_args = vec!["--max-connections", "0"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_connection_watermarks_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args.relay_config().connection_watermarks().is_empty());
+
+        let raw_args = vec!["--connection-watermarks", "95,80"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert_eq!(&[80, 95], args.relay_config().connection_watermarks());
+
+        let raw_args = vec!["--connection-watermarks", "0"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+
+        let raw_args = vec!["--connection-watermarks", "101"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_fd_headroom_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert_eq!(Some(32), args.relay_config().fd_headroom());
+
+        let raw_args = vec!["--fd-headroom", "100"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert_eq!(Some(100), args.relay_config().fd_headroom());
+
+        let raw_args = vec!["--fd-headroom", "0"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(args.relay_config().fd_headroom().is_none());
+    }
+
+    #[test]
+    fn test_allow_broadcast_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(!args.relay_config().allow_broadcast());
+
+        let raw_args = vec!["--allow-broadcast", "on"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(args.relay_config().allow_broadcast());
+    }
+
+    #[test]
+    fn test_max_source_connections_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args.relay_config().max_connections_per_source().is_none());
+
+        let raw_args = vec!["--max-source-connections", "50"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert_eq!(Some(50), args.relay_config().max_connections_per_source());
+
+        let raw_args = vec!["--max-source-connections", "0"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_max_destination_rate_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args
+            .relay_config()
+            .max_destination_connection_rate()
+            .is_none());
+
+        let raw_args = vec!["--max-destination-rate", "20"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        let rate = args.relay_config().max_destination_connection_rate();
+        assert_eq!(Some(20), rate);
+
+        let raw_args = vec!["--max-destination-rate", "0"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_profile_parameter() {
+        let raw_args = vec!["--profile", "latency"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(args.relay_config().tcp_nodelay());
+        assert!(args.relay_config().client_nodelay());
+
+        let raw_args = vec!["--profile", "throughput"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(!args.relay_config().tcp_nodelay());
+        assert!(!args.relay_config().client_nodelay());
+
+        // individual options override the profile
+        let raw_args = vec!["--profile", "throughput", "--tcp-nodelay", "on"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(args.relay_config().tcp_nodelay());
+        assert!(!args.relay_config().client_nodelay());
+
+        let raw_args = vec!["--profile", "fast"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_client_nodelay_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(!args.relay_config().client_nodelay());
+
+        let raw_args = vec!["--client-nodelay", "on"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(args.relay_config().client_nodelay());
+    }
+
+    #[test]
+    fn test_udp_source_ports_parameter() {
+        let raw_args = vec!["--udp-source-ports", "40000-40099"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        let source_ports = args.relay_config().udp_source_ports();
+        assert_eq!(Some(&(40000..=40099)), source_ports);
+
+        let raw_args = vec!["--udp-source-ports", "40000"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        let source_ports = args.relay_config().udp_source_ports();
+        assert_eq!(Some(&(40000..=40000)), source_ports);
+
+        let raw_args = vec!["--udp-source-ports", "40099-40000"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+        let raw_args = vec!["--udp-source-ports", "0-100"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+        let raw_args = vec!["--udp-source-ports", "40000-"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_allow_multicast_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(!args.relay_config().allow_multicast());
+        assert!(args.relay_config().multicast_interface().is_unspecified());
+
+        let raw_args = vec![
+            "--allow-multicast",
+            "on",
+            "--multicast-interface",
+            "192.168.1.12",
+        ];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(args.relay_config().allow_multicast());
+        assert_eq!(
+            Ipv4Addr::new(192, 168, 1, 12),
+            args.relay_config().multicast_interface()
+        );
+
+        let raw_args = vec!["--multicast-interface", "eth0"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_ethernet_frames_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(!args.relay_config().ethernet_frames());
+
+        let raw_args = vec!["--ethernet-frames", "on"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(args.relay_config().ethernet_frames());
+    }
+
+    #[test]
+    fn test_max_half_open_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert_eq!(None, args.relay_config().max_half_open_connections());
+
+        let raw_args = vec!["--max-half-open", "32"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert_eq!(Some(32), args.relay_config().max_half_open_connections());
+
+        let raw_args = vec!["--max-half-open", "0"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_allow_relay_loop_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(!args.relay_config().allow_relay_loop());
+
+        let raw_args = vec!["--allow-relay-loop", "on"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(args.relay_config().allow_relay_loop());
+    }
+
+    #[test]
+    fn test_protocol_parameters() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args.relay_config().tcp_enabled());
+        assert!(args.relay_config().udp_enabled());
+
+        let raw_args = vec!["--udp", "off"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(args.relay_config().tcp_enabled());
+        assert!(!args.relay_config().udp_enabled());
+
+        let raw_args = vec!["--tcp", "off", "--udp", "on"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(!args.relay_config().tcp_enabled());
+        assert!(args.relay_config().udp_enabled());
+
+        let raw_args = vec!["--no-udp", "--tcp-keepalive", "30"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(args.relay_config().tcp_enabled());
+        assert!(!args.relay_config().udp_enabled());
+
+        let raw_args = vec!["--no-tcp"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(!args.relay_config().tcp_enabled());
+        assert!(args.relay_config().udp_enabled());
+
+        let raw_args = vec!["--no-tcp", "--tcp", "on"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_ip_options_parameters() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args.relay_config().reject_source_route());
+        assert!(!args.relay_config().reject_record_route());
+
+        let raw_args = vec![
+            "--reject-source-route",
+            "off",
+            "--reject-record-route",
+            "on",
+        ];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(!args.relay_config().reject_source_route());
+        assert!(args.relay_config().reject_record_route());
+    }
+
+    #[test]
+    fn test_drop_fragments_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(!args.relay_config().drop_fragments());
+
+        let raw_args = vec!["--drop-fragments", "on"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(args.relay_config().drop_fragments());
+    }
+
+    #[test]
+    fn test_accept_parameters() {
+        let raw_args = vec!["--accept-backlog", "16", "--max-accept-rate", "10"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert_eq!(16, args.relay_config().accept_backlog());
+        assert_eq!(Some(10), args.relay_config().max_accept_rate());
+    }
+
+    #[test]
+    fn test_max_egress_rate_parameter() {
+        let raw_args = vec!["--max-egress-rate", "125000"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert_eq!(Some(125_000), args.relay_config().max_egress_rate());
+
+        let raw_args = vec!["--max-egress-rate", "0"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_tcp_ports_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args.relay_config().tcp_allowed_ports().is_none());
+
+        let raw_args = vec!["--tcp-ports", "80,443,53"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        let allowed_ports = args.relay_config().tcp_allowed_ports();
+        assert_eq!(Some(&[80, 443, 53][..]), allowed_ports);
+
+        let raw_args = vec!["--tcp-ports", "80,http"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+
+        let raw_args = vec!["--tcp-ports", "0"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_dscp_priority_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args.relay_config().dscp_priorities().is_none());
+
+        let raw_args = vec!["--dscp-priority", "46=6,34=4"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        let priorities = args.relay_config().dscp_priorities();
+        assert_eq!(Some(&[(46, 6), (34, 4)][..]), priorities);
+
+        let raw_args = vec!["--dscp-priority", "64=6"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+
+        let raw_args = vec!["--dscp-priority", "46"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[cfg(feature = "dns-rewrite")]
+    #[test]
+    fn test_dns_rewrite_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args.relay_config().payload_rewriter().is_none());
+
+        let raw_args = vec![
+            "--dns-rewrite",
+            "intranet.example.com=10.0.0.42,ipv6.example=::1",
+        ];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(args.relay_config().payload_rewriter().is_some());
+
+        let raw_args = vec!["--dns-rewrite", "intranet.example.com=10.0.0"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+
+        let raw_args = vec!["--dns-rewrite", "=10.0.0.42"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_tcp_reset_on_close_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args.relay_config().tcp_reset_networks().is_none());
+
+        let raw_args = vec!["--tcp-reset-on-close", "10.1.0.0/16,192.168.1.1"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        let networks = args.relay_config().tcp_reset_networks();
+        let expected = [
+            (Ipv4Addr::new(10, 1, 0, 0), 16),
+            (Ipv4Addr::new(192, 168, 1, 1), 32),
+        ];
+        assert_eq!(Some(&expected[..]), networks);
+
+        let raw_args = vec!["--tcp-reset-on-close", "10.1.0.0/33"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+
+        let raw_args = vec!["--tcp-reset-on-close", "10.1.0/16"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_udp_jitter_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(!args.relay_config().udp_jitter());
+
+        let raw_args = vec!["--udp-jitter", "on"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert!(args.relay_config().udp_jitter());
+    }
+
+    #[test]
+    fn test_socket_buffer_parameters() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args.relay_config().socket_receive_buffer().is_none());
+        assert!(args.relay_config().socket_send_buffer().is_none());
+
+        let raw_args = vec!["--socket-rcvbuf", "262144", "--socket-sndbuf", "131072"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert_eq!(Some(262_144), args.relay_config().socket_receive_buffer());
+        assert_eq!(Some(131_072), args.relay_config().socket_send_buffer());
+
+        let raw_args = vec!["--socket-rcvbuf", "0"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_ecn_threshold_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args.relay_config().ecn_threshold().is_none());
+
+        let raw_args = vec!["--ecn-threshold", "75"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert_eq!(Some(75), args.relay_config().ecn_threshold());
+
+        let raw_args = vec!["--ecn-threshold", "101"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_udp_max_payload_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args.relay_config().udp_max_payload().is_none());
+
+        let raw_args = vec!["--udp-max-payload", "512"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert_eq!(Some(512), args.relay_config().udp_max_payload());
+
+        let raw_args = vec!["--udp-max-payload", "0"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_effective_config() {
+        let raw_args = vec!["--tcp-write-timeout", "10", "--udp-max-payload", "512"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        let dump = format!("{:?}", args.relay_config());
+        assert!(dump.contains("tcp_write_timeout: Some(10s)"));
+        assert!(dump.contains("udp_max_payload: Some(512)"));
+        // defaults are dumped too
+        assert!(dump.contains("tcp_nodelay: true"));
+    }
+
+    #[test]
+    fn test_tcp_pool_ttl_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args.relay_config().tcp_pool_ttl().is_none());
+
+        let raw_args = vec!["--tcp-pool-ttl", "5"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert_eq!(
+            Some(Duration::from_secs(5)),
+            args.relay_config().tcp_pool_ttl()
+        );
+
+        let raw_args = vec!["--tcp-pool-ttl", "0"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_dns_timeout_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert!(args.relay_config().dns_timeout().is_none());
+
+        let raw_args = vec!["--dns-timeout", "5"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert_eq!(
+            Some(Duration::from_secs(5)),
+            args.relay_config().dns_timeout()
+        );
+
+        let raw_args = vec!["--dns-timeout", "0"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_log_payload_parameter() {
+        let args = CommandLineArguments::parse(ACCEPT_ALL, Vec::<&str>::new()).unwrap();
+        assert_eq!(None, args.relay_config().payload_dump_length());
+
+        let raw_args = vec!["--log-payload", "64"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert_eq!(Some(64), args.relay_config().payload_dump_length());
+
+        let raw_args = vec!["--log-payload", "0"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_mirror_parameter() {
+        let raw_args = vec!["--mirror", "192.168.1.2:9999"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        let mirror_address = args.relay_config().mirror_address();
+        assert_eq!(Some("192.168.1.2:9999".parse().unwrap()), mirror_address);
+
+        let raw_args = vec!["--mirror", "192.168.1.2"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_stats_file_parameters() {
+        let raw_args = vec!["--stats-file", "/tmp/stats.json", "--stats-interval", "30"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        let relay_config = args.relay_config();
+        assert_eq!(Some("/tmp/stats.json".as_ref()), relay_config.stats_file());
+        assert_eq!(Duration::from_secs(30), relay_config.stats_interval());
+
+        let raw_args = vec!["--stats-interval", "0"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[cfg(feature = "statsd")]
+    #[test]
+    fn test_statsd_parameter() {
+        let raw_args = vec!["--statsd", "127.0.0.1:8125"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        let statsd_address = args.relay_config().statsd_address();
+        assert_eq!(Some("127.0.0.1:8125".parse().unwrap()), statsd_address);
+
+        let raw_args = vec!["--statsd", "localhost"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_duplicate_relay_option() {
+        let raw_args = vec!["--tcp-keepalive", "30", "--tcp-keepalive", "60"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_unknown_relay_option() {
+        let raw_args = vec!["--unknown", "42"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
+
+    #[test]
+    fn test_invalid_max_connection_lifetime_parameter() {
+        let raw_args = vec!["--max-connection-lifetime", "forever"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+        let raw_args = vec!["--max-connection-lifetime", "0"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+        let raw_args = vec!["--max-connection-lifetime"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
 }
@@ -16,10 +16,22 @@
 
 mod relay;
 pub use crate::relay::byte_buffer;
+pub use crate::relay::checksum;
+#[cfg(feature = "dns-rewrite")]
+pub use crate::relay::DnsRewriter;
+pub use crate::relay::{
+    ConnectionClassifier, ConnectionDetail, ConnectionDirection, ConnectionId, ConnectionInfo,
+    DestinationResolver, Direction, DropReason, EventReceiver, PayloadRewriter, Protocol,
+    RelayConfig, RelayEvent, RelayState, ResetScope, RouterStats, RouterStatsDelta, StatsListener,
+    Timer, TuningProfile,
+};
 
+#[cfg(feature = "packet-injection")]
+pub use crate::relay::Relay;
+#[cfg(not(feature = "packet-injection"))]
 use crate::relay::Relay;
 use std::io;
 
-pub fn relay(port: u16) -> io::Result<()> {
-    Relay::new(port).run()
+pub fn relay(port: u16, config: RelayConfig) -> io::Result<()> {
+    Relay::new(port, config).run()
 }
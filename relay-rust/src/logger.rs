@@ -16,18 +16,128 @@
 
 use chrono::prelude::Local;
 use log::*;
+use std::env;
 use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
 
-static LOGGER: SimpleLogger = SimpleLogger;
-const THRESHOLD: LevelFilter = LevelFilter::Info;
+const DEFAULT_THRESHOLD: LevelFilter = LevelFilter::Info;
+const LEVELS_ENV: &str = "GNIREHTET_LOG";
+// number of log lines buffered before they are dropped
+const QUEUE_CAPACITY: usize = 1024;
 
-pub struct SimpleLogger;
+pub struct SimpleLogger {
+    default_threshold: LevelFilter,
+    // (target, threshold), there are only few targets
+    thresholds: Vec<(String, LevelFilter)>,
+    // if set, the lines are written to stdout by a separate thread
+    queue: Option<SyncSender<Message>>,
+    dropped: Arc<AtomicU64>,
+}
+
+enum Message {
+    Line(String),
+    // acknowledged once all the previous lines are written
+    Flush(SyncSender<()>),
+}
+
+impl SimpleLogger {
+    /// Parse a comma-separated list of `target=level` (the target is the tag of the logs, like
+    /// `TcpConnection`, case-insensitive) or `level` (for the other targets).
+    fn parse(spec: &str) -> Result<Self, String> {
+        let mut logger = Self {
+            default_threshold: DEFAULT_THRESHOLD,
+            thresholds: Vec::new(),
+            queue: None,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        for item in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+        {
+            let mut parts = item.splitn(2, '=');
+            let first = parts.next().unwrap();
+            let (target, level) = match parts.next() {
+                Some(level) => (Some(first), level),
+                None => (None, first),
+            };
+            let threshold = level
+                .parse()
+                .map_err(|_| format!("Invalid log level: {}", level))?;
+            match target {
+                Some(target) => logger
+                    .thresholds
+                    .push((target.to_ascii_lowercase(), threshold)),
+                None => logger.default_threshold = threshold,
+            }
+        }
+        Ok(logger)
+    }
+
+    /// Write the lines from a separate thread, so that a slow output never blocks the relay.
+    ///
+    /// If the queue is full, lines are dropped (and the number of dropped lines is logged
+    /// afterwards). Errors are still written synchronously (to stderr), once the pending lines are
+    /// written.
+    fn start_writer(&mut self) {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let dropped = self.dropped.clone();
+        thread::spawn(move || Self::write_lines(receiver, &dropped));
+        self.queue = Some(sender);
+    }
+
+    fn write_lines(receiver: Receiver<Message>, dropped: &AtomicU64) {
+        for message in receiver {
+            match message {
+                Message::Line(line) => println!("{}", line),
+                Message::Flush(ack) => {
+                    io::stdout().flush().unwrap();
+                    let _ = ack.send(());
+                }
+            }
+            let count = dropped.swap(0, Ordering::Relaxed);
+            if count > 0 {
+                println!("({} log lines dropped)", count);
+            }
+        }
+    }
+
+    fn write(&self, line: String) {
+        match self.queue {
+            Some(ref queue) => match queue.try_send(Message::Line(line)) {
+                Ok(_) => (),
+                Err(TrySendError::Full(_)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(TrySendError::Disconnected(Message::Line(line))) => println!("{}", line),
+                Err(TrySendError::Disconnected(_)) => (),
+            },
+            None => println!("{}", line),
+        }
+    }
+
+    fn threshold(&self, target: &str) -> LevelFilter {
+        self.thresholds
+            .iter()
+            .find(|(t, _)| t.eq_ignore_ascii_case(target))
+            .map_or(self.default_threshold, |&(_, threshold)| threshold)
+    }
+
+    fn max_threshold(&self) -> LevelFilter {
+        self.thresholds
+            .iter()
+            .map(|&(_, threshold)| threshold)
+            .fold(self.default_threshold, Ord::max)
+    }
+}
 
 impl Log for SimpleLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= THRESHOLD
+        metadata.level() <= self.threshold(metadata.target())
     }
-
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
             let date = Local::now();
@@ -40,20 +150,96 @@ impl Log for SimpleLogger {
                 record.args()
             );
             if record.level() == Level::Error {
+                // errors are rare, keep them ordered with the previous lines
+                self.flush();
                 eprintln!("{}", msg);
             } else {
-                println!("{}", msg);
+                self.write(msg);
             }
         }
     }
 
     fn flush(&self) {
+        if let Some(ref queue) = self.queue {
+            // wait for the writer thread to write the pending lines
+            let (ack, done) = mpsc::sync_channel(1);
+            if queue.send(Message::Flush(ack)).is_ok() {
+                let _ = done.recv();
+            }
+        }
         io::stdout().flush().unwrap();
         io::stderr().flush().unwrap();
     }
 }
 
 pub fn init() -> Result<(), SetLoggerError> {
-    set_max_level(THRESHOLD);
-    set_logger(&LOGGER)
+    let spec = env::var(LEVELS_ENV).unwrap_or_default();
+    let mut logger = SimpleLogger::parse(&spec).unwrap_or_else(|err| {
+        eprintln!("Ignoring {}: {}", LEVELS_ENV, err);
+        SimpleLogger::parse("").unwrap()
+    });
+    logger.start_writer();
+    set_max_level(logger.max_threshold());
+    set_logger(Box::leak(Box::new(logger)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled(logger: &SimpleLogger, target: &str, level: Level) -> bool {
+        logger.enabled(&Metadata::builder().target(target).level(level).build())
+    }
+
+    #[test]
+    fn default_thresholds() {
+        let logger = SimpleLogger::parse("").unwrap();
+        assert!(enabled(&logger, "Router", Level::Info));
+        assert!(!enabled(&logger, "Router", Level::Debug));
+        assert_eq!(LevelFilter::Info, logger.max_threshold());
+    }
+
+    #[test]
+    fn per_target_thresholds() {
+        let logger = SimpleLogger::parse("warn, tcpconnection=debug,Router=error").unwrap();
+        assert!(enabled(&logger, "TcpConnection", Level::Debug));
+        assert!(!enabled(&logger, "TcpConnection", Level::Trace));
+        assert!(!enabled(&logger, "Router", Level::Warn));
+        assert!(enabled(&logger, "Router", Level::Error));
+        assert!(!enabled(&logger, "Client", Level::Info));
+        assert!(enabled(&logger, "Client", Level::Warn));
+        assert_eq!(LevelFilter::Debug, logger.max_threshold());
+    }
+
+    fn log(logger: &SimpleLogger, level: Level) {
+        let record = Record::builder()
+            .args(format_args!("message"))
+            .level(level)
+            .target("Router")
+            .build();
+        logger.log(&record);
+    }
+
+    #[test]
+    fn drop_lines_when_queue_is_full() {
+        let mut logger = SimpleLogger::parse("").unwrap();
+        // nobody consumes the queue (as if the output were blocked)
+        let (sender, receiver) = mpsc::sync_channel(2);
+        logger.queue = Some(sender);
+        for _ in 0..5 {
+            log(&logger, Level::Info);
+        }
+        assert_eq!(3, logger.dropped.load(Ordering::Relaxed));
+        assert_eq!(2, receiver.try_iter().count());
+
+        // the queue has room again
+        log(&logger, Level::Info);
+        assert_eq!(3, logger.dropped.load(Ordering::Relaxed));
+        assert_eq!(1, receiver.try_iter().count());
+    }
+
+    #[test]
+    fn invalid_level() {
+        assert!(SimpleLogger::parse("Router=loud").is_err());
+    }
 }
@@ -28,8 +28,9 @@ mod logger;
 use crate::adb_monitor::AdbMonitor;
 use crate::cli_args::CommandLineArguments;
 use crate::execution_error::{Cmd, CommandExecutionError, ProcessIoError, ProcessStatusError};
+use relaylib::RelayConfig;
 use std::env;
-use std::process::{self, exit};
+use std::process;
 use std::thread;
 use std::time::Duration;
 
@@ -68,6 +69,276 @@ const COMMANDS: &[&dyn Command] = &[
     &RelayCommand,
 ];
 
+// options of the relay server, accepted by the commands which start it
+const RELAY_OPTIONS: &[(&str, &str)] = &[
+    (
+        "--max-connection-lifetime SECONDS",
+        "Close any connection (even active) after the given duration.\n\
+         Unlimited by default.",
+    ),
+    (
+        "--tcp-keepalive SECONDS",
+        "Send keepalive probes to the device over TCP connections idle\n\
+         for the given duration. Disabled by default.",
+    ),
+    (
+        "--tcp-keepalive-probes COUNT",
+        "Reset TCP connections after COUNT unanswered keepalive probes.\n\
+         3 by default.",
+    ),
+    (
+        "--tcp-write-timeout SECONDS",
+        "Reset TCP connections which could not write any pending data\n\
+         to the network for the given duration. Disabled by default.",
+    ),
+    (
+        "--tcp-defer-connect SECONDS",
+        "Accept TCP connections immediately, and connect to the network\n\
+         only once the client sends data, or after the given duration\n\
+         (for protocols where the server talks first). Connections\n\
+         closed without sending data never reach the network.\n\
+         Disabled by default.",
+    ),
+    (
+        "--tcp-half-close on|off",
+        "Keep delivering the data from the network after the device\n\
+         closed its side of a TCP connection, until the network side\n\
+         closes too. If disabled, a FIN from the device closes both\n\
+         directions immediately. On by default.",
+    ),
+    (
+        "--tcp-time-wait SECONDS",
+        "Drop the late segments of closed TCP connections for the given\n\
+         duration, instead of opening new connections (0 to disable).\n\
+         5 seconds by default.",
+    ),
+    (
+        "--tcp-pool-ttl SECONDS",
+        "Experimental: when a TCP connection closes, open a spare\n\
+         connection to the same destination, used by the next connection\n\
+         opened within the given duration. Only suitable for servers\n\
+         keeping idle connections open. Disabled by default.",
+    ),
+    (
+        "--profile latency|throughput",
+        "Tune several options at once: \"latency\" disables Nagle's\n\
+         algorithm towards the network and the clients, \"throughput\"\n\
+         enables it on both sides and sets 1 MiB socket buffers. Options\n\
+         given afterwards override it.",
+    ),
+    (
+        "--tcp-nodelay on|off",
+        "Disable Nagle's algorithm on TCP connections to the network,\n\
+         to reduce latency. On by default.",
+    ),
+    (
+        "--client-nodelay on|off",
+        "Disable Nagle's algorithm on the connections to the clients, so\n\
+         that packets to the device are not coalesced (for real-time\n\
+         traffic). Off by default.",
+    ),
+    (
+        "--max-half-open COUNT",
+        "Reject new TCP connections by a RST while COUNT connections of\n\
+         the same device are not established yet. Unlimited by default.",
+    ),
+    (
+        "--max-connections COUNT",
+        "Limit the number of TCP and UDP connections of each device.\n\
+         Beyond, new TCP connections are rejected by a RST and new UDP\n\
+         datagrams are dropped. Unlimited by default.",
+    ),
+    (
+        "--max-source-connections COUNT",
+        "Limit the number of TCP and UDP connections from the same device\n\
+         source address, in any state. Beyond, new connections are refused\n\
+         like for --max-connections. Unlimited by default.",
+    ),
+    (
+        "--connection-watermarks PCT[,PCT2,...]",
+        "Warn when the connections of a device reach the given\n\
+         percentages of --max-connections, and when they drop back below.",
+    ),
+    (
+        "--fd-headroom COUNT",
+        "Keep COUNT file descriptors free below the process limit.\n\
+         Beyond, new TCP connections are rejected by a RST and new UDP\n\
+         datagrams are dropped. 0 disables the check. 32 by default.",
+    ),
+    (
+        "--max-destination-rate RATE",
+        "Limit the number of new connections per second from a device to\n\
+         the same destination address. Beyond, new TCP connections are\n\
+         rejected by a RST and new UDP datagrams are dropped. Unlimited by\n\
+         default.",
+    ),
+    (
+        "--allow-broadcast on|off",
+        "Relay the packets sent to the broadcast address 255.255.255.255\n\
+         to the network. Off by default (they are dropped).",
+    ),
+    (
+        "--allow-relay-loop on|off",
+        "Allow TCP connections from the device to the relay port on\n\
+         localhost (they would be accepted as new clients). Off by\n\
+         default.",
+    ),
+    (
+        "--tcp-ports PORT[,PORT2,...]",
+        "Only allow TCP connections to the given destination ports,\n\
+         reject the others by a RST. All ports are allowed by default.",
+    ),
+    (
+        "--dscp-priority DSCP=PRIORITY[,...]",
+        "Set the socket priority (SO_PRIORITY, Linux only) of the\n\
+         connections to the network according to the DSCP of the packets\n\
+         opening them, so that the host can prioritize them.",
+    ),
+    #[cfg(feature = "dns-rewrite")]
+    (
+        "--dns-rewrite NAME=ADDR[,...]",
+        "Rewrite the A (or AAAA, for an IPv6 address) answers for the\n\
+         given names in the DNS responses relayed to the device\n\
+         (split-horizon DNS).",
+    ),
+    (
+        "--socket-rcvbuf BYTES",
+        "Set the kernel receive buffer size (SO_RCVBUF) of the client and\n\
+         network sockets. The kernel may round or clamp it.",
+    ),
+    (
+        "--socket-sndbuf BYTES",
+        "Set the kernel send buffer size (SO_SNDBUF) of the client and\n\
+         network sockets. The kernel may round or clamp it.",
+    ),
+    (
+        "--ecn-threshold PERCENT",
+        "Mark the ECN-capable packets to a device with Congestion\n\
+         Experienced while the buffer to the device is filled above the\n\
+         given percentage, to slow the flows down before dropping.",
+    ),
+    (
+        "--tcp-reset-on-close NETWORK[/PREFIX][,...]",
+        "Close by a RST instead of a FIN the TCP connections to the\n\
+         given networks (0.0.0.0/0 for all) when the network side closes\n\
+         them. Some applications behave better with an abortive close.",
+    ),
+    (
+        "--reject-unreachable on|off",
+        "Reject by a RST the TCP connections which cannot be opened\n\
+         because the network is down or the destination unreachable,\n\
+         instead of letting the device retry. On by default.",
+    ),
+    (
+        "--tcp on|off",
+        "Relay TCP connections. If off, TCP packets from the device are\n\
+         dropped. On by default.",
+    ),
+    (
+        "--udp on|off",
+        "Relay UDP datagrams (including DNS). If off, UDP packets from\n\
+         the device are dropped. On by default.",
+    ),
+    ("--no-tcp", "Same as --tcp off."),
+    ("--no-udp", "Same as --udp off."),
+    (
+        "--udp-source-ports FIRST[-LAST]",
+        "Bind the UDP sockets to the network to a port in the given range.\n\
+         Chosen by the system by default.",
+    ),
+    (
+        "--allow-multicast on|off",
+        "Join the multicast groups the device sends UDP datagrams to, and\n\
+         relay the datagrams of the group back to the device (e.g. for\n\
+         mDNS or SSDP discovery). Off by default.",
+    ),
+    (
+        "--multicast-interface ADDRESS",
+        "Join the multicast groups on the interface having the local\n\
+         address ADDRESS. Chosen by the system by default.",
+    ),
+    (
+        "--dns-timeout SECONDS",
+        "Close the UDP connections to DNS servers (port 53) when a query\n\
+         is unanswered for the given duration (the client retries anyway).\n\
+         By default, only the UDP idle timeout applies.",
+    ),
+    (
+        "--udp-jitter on|off",
+        "Measure the jitter and the packet rate of each UDP connection in\n\
+         both directions, and log them on close. Off by default.",
+    ),
+    (
+        "--udp-max-payload BYTES",
+        "Drop the UDP datagrams whose payload is larger than the given\n\
+         size, in either direction. Unlimited by default.",
+    ),
+    (
+        "--reject-source-route on|off",
+        "Drop the packets carrying a loose or strict source route IP\n\
+         option. On by default.",
+    ),
+    (
+        "--reject-record-route on|off",
+        "Drop the packets carrying a record route IP option. Off by\n\
+         default.",
+    ),
+    (
+        "--drop-fragments on|off",
+        "Drop the IP fragments (the relay does not reassemble them, so\n\
+         only the first fragment of a datagram may be relayed). Off by\n\
+         default.",
+    ),
+    (
+        "--ethernet-frames on|off",
+        "Expect an Ethernet header before each packet received from\n\
+         the device (tap-mode sources). Off by default.",
+    ),
+    (
+        "--accept-backlog COUNT",
+        "Set the backlog of the socket listening for clients.\n\
+         1024 by default.",
+    ),
+    (
+        "--max-accept-rate COUNT",
+        "Accept at most COUNT clients per second, defer the others.\n\
+         Unlimited by default.",
+    ),
+    (
+        "--max-egress-rate BYTES",
+        "Send at most BYTES bytes per second to the network, for all\n\
+         the clients together. Once reached, the clients stop reading\n\
+         from their device in turn. Unlimited by default.",
+    ),
+    (
+        "--log-payload BYTES",
+        "Log the first BYTES bytes of payload in each direction of every\n\
+         TCP connection. The logs then contain user data. Disabled by\n\
+         default.",
+    ),
+    (
+        "--mirror ADDR:PORT",
+        "Send a copy of every relayed IP packet to a UDP collector.\n\
+         Disabled by default.",
+    ),
+    (
+        "--stats-file PATH",
+        "Write the relay counters as JSON to PATH on every stats\n\
+         interval, replacing the file atomically. Disabled by default.",
+    ),
+    (
+        "--stats-interval SECONDS",
+        "Set the interval between two exports of the stats.\n\
+         10 seconds by default.",
+    ),
+    #[cfg(feature = "statsd")]
+    (
+        "--statsd ADDR:PORT",
+        "Push the relay counters to a StatsD server on every stats\n\
+         interval. Disabled by default.",
+    ),
+];
+
 trait Command {
     fn command(&self) -> &'static str;
     fn accepted_parameters(&self) -> u8;
@@ -155,6 +426,7 @@ impl Command for RunCommand {
             | cli_args::PARAM_DNS_SERVERS
             | cli_args::PARAM_ROUTES
             | cli_args::PARAM_PORT
+            | cli_args::PARAM_RELAY_OPTIONS
     }
 
     fn description(&self) -> &'static str {
@@ -171,6 +443,7 @@ impl Command for RunCommand {
             args.dns_servers(),
             args.routes(),
             args.port(),
+            args.relay_config(),
         )
     }
 }
@@ -181,7 +454,10 @@ impl Command for AutorunCommand {
     }
 
     fn accepted_parameters(&self) -> u8 {
-        cli_args::PARAM_DNS_SERVERS | cli_args::PARAM_ROUTES | cli_args::PARAM_PORT
+        cli_args::PARAM_DNS_SERVERS
+            | cli_args::PARAM_ROUTES
+            | cli_args::PARAM_PORT
+            | cli_args::PARAM_RELAY_OPTIONS
     }
 
     fn description(&self) -> &'static str {
@@ -191,7 +467,12 @@ impl Command for AutorunCommand {
     }
 
     fn execute(&self, args: &CommandLineArguments) -> Result<(), CommandExecutionError> {
-        cmd_autorun(args.dns_servers(), args.routes(), args.port())
+        cmd_autorun(
+            args.dns_servers(),
+            args.routes(),
+            args.port(),
+            args.relay_config(),
+        )
     }
 }
 
@@ -328,7 +609,7 @@ impl Command for RelayCommand {
     }
 
     fn accepted_parameters(&self) -> u8 {
-        cli_args::PARAM_NONE | cli_args::PARAM_PORT
+        cli_args::PARAM_NONE | cli_args::PARAM_PORT | cli_args::PARAM_RELAY_OPTIONS
     }
 
     fn description(&self) -> &'static str {
@@ -336,7 +617,7 @@ impl Command for RelayCommand {
     }
 
     fn execute(&self, args: &CommandLineArguments) -> Result<(), CommandExecutionError> {
-        cmd_relay(args.port())?;
+        cmd_relay(args.port(), args.relay_config())?;
         Ok(())
     }
 }
@@ -362,6 +643,7 @@ fn cmd_run(
     dns_servers: Option<&str>,
     routes: Option<&str>,
     port: u16,
+    relay_config: &RelayConfig,
 ) -> Result<(), CommandExecutionError> {
     // start in parallel so that the relay server is ready when the client connects
     async_start(serial, dns_servers, routes, port);
@@ -379,13 +661,14 @@ fn cmd_run(
     })
     .expect("Error setting Ctrl-C handler");
 
-    cmd_relay(port)
+    cmd_relay(port, relay_config)
 }
 
 fn cmd_autorun(
     dns_servers: Option<&str>,
     routes: Option<&str>,
     port: u16,
+    relay_config: &RelayConfig,
 ) -> Result<(), CommandExecutionError> {
     {
         let autostart_dns_servers = dns_servers.map(String::from);
@@ -399,7 +682,7 @@ fn cmd_autorun(
         });
     }
 
-    cmd_relay(port)
+    cmd_relay(port, relay_config)
 }
 
 fn cmd_start(
@@ -479,9 +762,9 @@ fn cmd_tunnel(serial: Option<&str>, port: u16) -> Result<(), CommandExecutionErr
     )
 }
 
-fn cmd_relay(port: u16) -> Result<(), CommandExecutionError> {
+fn cmd_relay(port: u16, relay_config: &RelayConfig) -> Result<(), CommandExecutionError> {
     info!(target: TAG, "Starting relay server on port {}...", port);
-    relaylib::relay(port)?;
+    relaylib::relay(port, relay_config.clone())?;
     Ok(())
 }
 
@@ -551,7 +834,7 @@ fn must_install_client(serial: Option<&str>) -> Result<bool, CommandExecutionErr
                 // read the versionCode of the installed package
                 if let Some(index) = dumpsys.find("    versionCode=") {
                     let start = index + 16; // size of "    versionCode=\""
-                    if let Some(end) = (&dumpsys[start..]).find(' ') {
+                    if let Some(end) = dumpsys[start..].find(' ') {
                         let installed_version_code = &dumpsys[start..start + end];
                         Ok(installed_version_code != REQUIRED_APK_VERSION_CODE)
                     } else {
@@ -586,6 +869,8 @@ fn print_usage() {
         msg.push('\n');
         append_command_usage(&mut msg, command);
     }
+    msg.push('\n');
+    append_relay_options_usage(&mut msg);
     eprint!("{}", msg);
 }
 
@@ -605,6 +890,9 @@ fn append_command_usage(msg: &mut String, command: &dyn Command) {
     if (accepted_parameters & cli_args::PARAM_ROUTES) != 0 {
         msg.push_str(" [-r ROUTE[,ROUTE2,...]]");
     }
+    if (accepted_parameters & cli_args::PARAM_RELAY_OPTIONS) != 0 {
+        msg.push_str(" [relay options...]");
+    }
     msg.push('\n');
     for desc_line in command.description().split('\n') {
         msg.push_str("      ");
@@ -613,12 +901,35 @@ fn append_command_usage(msg: &mut String, command: &dyn Command) {
     }
 }
 
+fn append_relay_options_usage(msg: &mut String) {
+    msg.push_str("  relay options:\n");
+    for &(option, description) in RELAY_OPTIONS {
+        msg.push_str("      ");
+        msg.push_str(option);
+        msg.push('\n');
+        for desc_line in description.split('\n') {
+            msg.push_str("          ");
+            msg.push_str(desc_line);
+            msg.push('\n');
+        }
+    }
+}
+
 fn print_command_usage(command: &dyn Command) {
     let mut msg = String::new();
     append_command_usage(&mut msg, command);
+    if (command.accepted_parameters() & cli_args::PARAM_RELAY_OPTIONS) != 0 {
+        append_relay_options_usage(&mut msg);
+    }
     eprint!("{}", msg);
 }
 
+// logs are written asynchronously, they must be flushed before exiting
+fn exit(code: i32) -> ! {
+    log::logger().flush();
+    process::exit(code);
+}
+
 fn main() {
     logger::init().unwrap();
     let mut args = env::args();
@@ -663,4 +974,5 @@ fn main() {
     } else {
         print_usage();
     }
+    log::logger().flush();
 }
@@ -0,0 +1,222 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Internet checksum (RFC 1071) computation.
+//!
+//! Checksum computation is the most CPU-intensive task in gnirehtet, so a SIMD implementation is
+//! used when the CPU supports it, with a portable scalar fallback.
+
+/// Compute the one's complement sum of the 16-bit big-endian words of `raw`, not complemented.
+///
+/// If the length of `raw` is odd, the last byte is padded with zero. The result may be combined
+/// with other sums using `fold()`.
+pub fn sum(raw: &[u8]) -> u64 {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("sse2") {
+            // safe because the CPU supports SSE2
+            return unsafe { sse2::sum(raw) };
+        }
+    }
+    scalar::sum(raw)
+}
+
+/// Fold a sum into 16 bits.
+pub fn fold(mut sum: u64) -> u16 {
+    while (sum & !0xFFFF) != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Compute the checksum of an IPv4 header.
+///
+/// The checksum field (bytes 10 and 11) is ignored, so the result can be both written to a header
+/// being constructed and compared to the value of a received header.
+pub fn ipv4_checksum(header: &[u8]) -> u16 {
+    assert!(header.len() >= 20, "IPv4 header too short");
+    // 10 is even, so the bytes following the checksum field start with a high-order byte
+    !fold(sum(&header[..10]) + sum(&header[12..]))
+}
+
+/// Compute the checksum of a TCP or UDP segment (header and payload), including the IPv4
+/// pseudo-header (cf rfc793 section 3.1 and rfc768).
+///
+/// The segment is summed as is: its checksum field must be zero to compute the value to write,
+/// while a valid received segment (checksum field included) yields 0. For UDP, a computed value
+/// of 0 must be transmitted as `0xFFFF`.
+pub fn transport_checksum(source: u32, destination: u32, protocol: u8, segment: &[u8]) -> u16 {
+    let mut sum = u64::from(protocol);
+    sum += u64::from(source >> 16);
+    sum += u64::from(source & 0xFFFF);
+    sum += u64::from(destination >> 16);
+    sum += u64::from(destination & 0xFFFF);
+    sum += segment.len() as u64;
+    sum += self::sum(segment);
+    !fold(sum)
+}
+
+mod scalar {
+    pub fn sum(raw: &[u8]) -> u64 {
+        // sum the high-order and the low-order bytes separately
+        let mut hsum = 0u64;
+        let mut lsum = 0u64;
+        let mut words = raw.chunks_exact(2);
+        for word in &mut words {
+            hsum += u64::from(word[0]);
+            lsum += u64::from(word[1]);
+        }
+        if let [last] = *words.remainder() {
+            // if the length is odd, the last byte is considered high-order
+            hsum += u64::from(last);
+        }
+        (hsum << 8) + lsum
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod sse2 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn sum(raw: &[u8]) -> u64 {
+        // high-order bytes are at even positions
+        let high_mask = _mm_set1_epi16(0x00FF);
+        let low_mask = _mm_set1_epi16(0xFF00u16 as i16);
+        let zero = _mm_setzero_si128();
+        let mut hsums = zero;
+        let mut lsums = zero;
+        let mut blocks = raw.chunks_exact(16);
+        for block in &mut blocks {
+            let v = _mm_loadu_si128(block.as_ptr() as *const __m128i);
+            // sum the bytes of each half into its 64-bit lane
+            hsums = _mm_add_epi64(hsums, _mm_sad_epu8(_mm_and_si128(v, high_mask), zero));
+            lsums = _mm_add_epi64(lsums, _mm_sad_epu8(_mm_and_si128(v, low_mask), zero));
+        }
+        let mut lanes = [0u64; 4];
+        _mm_storeu_si128(lanes.as_mut_ptr() as *mut __m128i, hsums);
+        _mm_storeu_si128(lanes[2..].as_mut_ptr() as *mut __m128i, lsums);
+        let hsum = lanes[0] + lanes[1];
+        let lsum = lanes[2] + lanes[3];
+        // blocks have an even length, so the remainder starts with a high-order byte
+        (hsum << 8) + lsum + super::scalar::sum(blocks.remainder())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::random;
+
+    fn random_buffer(len: usize) -> Vec<u8> {
+        (0..len).map(|_| random()).collect()
+    }
+
+    #[test]
+    fn known_ipv4_checksum() {
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xb8, 0x61, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(0xb861, ipv4_checksum(&header));
+
+        // the checksum field is ignored
+        let mut header = header;
+        header[10] = 0;
+        header[11] = 0;
+        assert_eq!(0xb861, ipv4_checksum(&header));
+    }
+
+    #[test]
+    fn known_transport_checksum() {
+        // UDP 10.0.0.1:4660 -> 10.0.0.2:53, with a 1-byte payload (odd length)
+        let mut segment = [0x12, 0x34, 0x00, 0x35, 0x00, 0x09, 0x00, 0x00, 0x41];
+        let checksum = transport_checksum(0x0a00_0001, 0x0a00_0002, 17, &segment);
+        assert_eq!(0x9870, checksum);
+
+        // a segment containing its checksum is valid
+        segment[6..8].copy_from_slice(&checksum.to_be_bytes());
+        assert_eq!(
+            0,
+            transport_checksum(0x0a00_0001, 0x0a00_0002, 17, &segment)
+        );
+    }
+
+    #[test]
+    fn sum_words() {
+        assert_eq!(0, fold(scalar::sum(&[])));
+        assert_eq!(
+            0x1234 + 0x5678,
+            fold(scalar::sum(&[0x12, 0x34, 0x56, 0x78]))
+        );
+        // odd length, padded with zero
+        assert_eq!(0x1234 + 0x5600, fold(scalar::sum(&[0x12, 0x34, 0x56])));
+        // carry
+        assert_eq!(0x0003, fold(scalar::sum(&[0xFF, 0xFF, 0x00, 0x03])));
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn sse2_matches_scalar() {
+        if !is_x86_feature_detected!("sse2") {
+            return;
+        }
+        for len in 0..100 {
+            let raw = random_buffer(len);
+            assert_eq!(scalar::sum(&raw), unsafe { sse2::sum(&raw) });
+        }
+        for _ in 0..20 {
+            let len = random::<usize>() % 65536;
+            let raw = random_buffer(len);
+            // also test unaligned slices
+            let start = random::<usize>() % 16;
+            let raw = &raw[start.min(len)..];
+            assert_eq!(scalar::sum(raw), unsafe { sse2::sum(raw) });
+        }
+    }
+
+    #[test]
+    fn sum_max_length() {
+        let raw = vec![0xFF; 65536];
+        assert_eq!(0xFFFF, fold(sum(&raw)));
+        assert_eq!(scalar::sum(&raw), sum(&raw));
+    }
+
+    // run with: cargo test bench_checksum_implementations --release -- --ignored --nocapture
+    // manual benchmark
+    #[ignore]
+    #[test]
+    fn bench_checksum_implementations() {
+        use std::hint::black_box;
+        use std::time::Instant;
+        let raw = random_buffer(1500);
+
+        let start = Instant::now();
+        for _ in 0..1_000_000 {
+            black_box(scalar::sum(black_box(&raw)));
+        }
+        println!("1000000 scalar sums: {}ms", start.elapsed().as_millis());
+
+        let start = Instant::now();
+        for _ in 0..1_000_000 {
+            black_box(sum(black_box(&raw)));
+        }
+        println!("1000000 sums: {}ms", start.elapsed().as_millis());
+    }
+}
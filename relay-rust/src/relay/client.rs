@@ -20,16 +20,30 @@ use mio::{Event, PollOpt, Ready, Token};
 use std::cell::RefCell;
 use std::io::{self, Write};
 use std::mem;
-use std::net::Shutdown;
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use super::binary;
 use super::close_listener::CloseListener;
+use super::config::RelayConfig;
+use super::congestion_marker::CongestionMarker;
+use super::connection::{ConnectionDetail, ConnectionInfo};
+use super::drop_reason::DropReason;
+use super::egress_limiter::EgressLimiter;
+use super::handshake::{self, Handshake};
+use super::ipv4_header::Protocol;
 use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
 use super::ipv4_packet_buffer::Ipv4PacketBuffer;
+use super::net;
+use super::packet_mirror::PacketMirror;
+use super::packet_size_histogram::PacketSizeHistogram;
 use super::packet_source::PacketSource;
+use super::parse_error::ParseError;
+use super::payload_rewriter::Direction;
 use super::router::Router;
 use super::selector::Selector;
+use super::stats::{ResetScope, RouterStats};
 use super::stream_buffer::StreamBuffer;
 
 const TAG: &str = "Client";
@@ -37,16 +51,35 @@ const TAG: &str = "Client";
 pub struct Client {
     id: u32,
     stream: TcpStream,
-    interests: Ready,
+    interests: Interests,
     token: Token,
     client_to_network: Ipv4PacketBuffer,
     network_to_client: StreamBuffer,
     router: Router,
+    mirror: Option<Rc<PacketMirror>>,
+    // shared by all the clients
+    egress_limiter: Option<Rc<RefCell<EgressLimiter>>>,
     close_listener: Box<dyn CloseListener<Client>>,
     closed: bool,
     pending_packet_sources: Vec<Rc<RefCell<dyn PacketSource>>>,
     // number of remaining bytes of "id" to send to the client before relaying any data
     pending_id_bytes: usize,
+    // number of invalid packets received from the client
+    dropped_packets: u64,
+    // the client may start with a handshake before sending packets
+    pending_handshake: bool,
+    to_network_sizes: PacketSizeHistogram,
+    to_device_sizes: PacketSizeHistogram,
+    congestion_marker: Option<CongestionMarker>,
+    // once draining, the client is closed when its connections are closed, or at this deadline
+    drain_deadline: Option<Instant>,
+}
+
+/// Events the client stream is registered for
+struct Interests {
+    ready: Ready,
+    // reading is suspended until the egress limiter allows the next packet
+    reading: bool,
 }
 
 /// Channel for connections to send back data immediately to the client
@@ -54,7 +87,10 @@ pub struct ClientChannel<'a> {
     network_to_client: &'a mut StreamBuffer,
     stream: &'a TcpStream,
     token: Token,
-    interests: &'a mut Ready,
+    interests: &'a mut Interests,
+    mirror: Option<&'a PacketMirror>,
+    to_device_sizes: &'a mut PacketSizeHistogram,
+    congestion_marker: Option<&'a mut CongestionMarker>,
 }
 
 impl<'a> ClientChannel<'a> {
@@ -62,13 +98,19 @@ impl<'a> ClientChannel<'a> {
         network_to_client: &'a mut StreamBuffer,
         stream: &'a TcpStream,
         token: Token,
-        interests: &'a mut Ready,
+        interests: &'a mut Interests,
+        mirror: Option<&'a PacketMirror>,
+        to_device_sizes: &'a mut PacketSizeHistogram,
+        congestion_marker: Option<&'a mut CongestionMarker>,
     ) -> Self {
         Self {
             network_to_client,
             stream,
             token,
             interests,
+            mirror,
+            to_device_sizes,
+            congestion_marker,
         }
     }
 
@@ -80,7 +122,17 @@ impl<'a> ClientChannel<'a> {
         ipv4_packet: &Ipv4Packet,
     ) -> io::Result<()> {
         if ipv4_packet.length() as usize <= self.network_to_client.remaining() {
-            self.network_to_client.read_from(ipv4_packet.raw());
+            let occupancy = self.network_to_client.size();
+            let marked = self
+                .congestion_marker
+                .as_mut()
+                .and_then(|marker| marker.mark(ipv4_packet.raw(), occupancy));
+            let raw = marked.as_deref().unwrap_or_else(|| ipv4_packet.raw());
+            self.network_to_client.read_from(raw);
+            self.to_device_sizes.record(ipv4_packet.length() as usize);
+            if let Some(mirror) = self.mirror {
+                mirror.mirror(Direction::ToDevice, raw);
+            }
             self.update_interests(selector);
             Ok(())
         } else {
@@ -93,14 +145,17 @@ impl<'a> ClientChannel<'a> {
     }
 
     fn update_interests(&mut self, selector: &mut Selector) {
-        let ready = if self.network_to_client.is_empty() {
+        let mut ready = if self.interests.reading {
             Ready::readable()
         } else {
-            Ready::readable() | Ready::writable()
+            Ready::empty()
         };
-        if *self.interests != ready {
+        if !self.network_to_client.is_empty() {
+            ready |= Ready::writable();
+        }
+        if self.interests.ready != ready {
             // interests must be changed
-            *self.interests = ready;
+            self.interests.ready = ready;
             selector
                 .reregister(self.stream, self.token, ready, PollOpt::level())
                 .expect("Cannot register on poll");
@@ -113,22 +168,46 @@ impl Client {
         id: u32,
         selector: &mut Selector,
         stream: TcpStream,
+        config: Rc<RelayConfig>,
+        mirror: Option<Rc<PacketMirror>>,
+        egress_limiter: Option<Rc<RefCell<EgressLimiter>>>,
         close_listener: Box<dyn CloseListener<Client>>,
     ) -> io::Result<Rc<RefCell<Self>>> {
         // on start, we are interested only in writing (we must first send the client id)
         let interests = Ready::writable();
+        // Ethernet frames may start with any byte, so the handshake cannot be detected
+        let pending_handshake = !config.ethernet_frames();
+        let network_to_client = StreamBuffer::new(16 * MAX_PACKET_LENGTH);
+        let congestion_marker = config.ecn_threshold().map(|percent| {
+            CongestionMarker::new(network_to_client.capacity() * usize::from(percent) / 100)
+        });
         let rc = Rc::new(RefCell::new(Self {
             id,
             stream,
-            interests,
+            interests: Interests {
+                ready: interests,
+                reading: true,
+            },
             token: Token(0), // default value, will be set afterwards
-            client_to_network: Ipv4PacketBuffer::new(),
-            network_to_client: StreamBuffer::new(16 * MAX_PACKET_LENGTH),
-            router: Router::new(),
+            client_to_network: if config.ethernet_frames() {
+                Ipv4PacketBuffer::with_ethernet_frames()
+            } else {
+                Ipv4PacketBuffer::new()
+            },
+            network_to_client,
+            router: Router::new(config),
+            mirror,
+            egress_limiter,
             closed: false,
             close_listener,
             pending_packet_sources: Vec::new(),
             pending_id_bytes: 4,
+            dropped_packets: 0,
+            pending_handshake,
+            to_network_sizes: PacketSizeHistogram::new(),
+            to_device_sizes: PacketSizeHistogram::new(),
+            congestion_marker,
+            drain_deadline: None,
         }));
 
         {
@@ -155,12 +234,59 @@ impl Client {
         &mut self.router
     }
 
-    pub fn channel(&mut self) -> ClientChannel {
+    pub fn stats(&self, taken: Instant) -> RouterStats {
+        let mut stats = self.router.stats(taken);
+        stats.clients = 1;
+        stats.packets_to_network = self.to_network_sizes.total();
+        stats.packets_to_device = self.to_device_sizes.total();
+        stats.bytes_to_network = self.to_network_sizes.bytes();
+        stats.bytes_to_device = self.to_device_sizes.bytes();
+        stats
+    }
+
+    pub fn connection_infos(&self) -> Vec<ConnectionInfo> {
+        self.router.connection_infos()
+    }
+
+    pub fn connection_detail(
+        &self,
+        protocol: Protocol,
+        source: SocketAddrV4,
+        destination: SocketAddrV4,
+    ) -> Option<ConnectionDetail> {
+        self.router.connection_detail(protocol, source, destination)
+    }
+
+    /// Pause or resume a connection, and return whether it exists.
+    pub fn set_connection_paused(
+        &mut self,
+        selector: &mut Selector,
+        protocol: Protocol,
+        source: SocketAddrV4,
+        destination: SocketAddrV4,
+        paused: bool,
+    ) -> bool {
+        self.router
+            .set_paused(selector, protocol, source, destination, paused)
+    }
+
+    pub fn reset_stats(&mut self, scope: ResetScope) {
+        self.router.drops().reset();
+        if scope == ResetScope::All {
+            self.to_network_sizes = PacketSizeHistogram::new();
+            self.to_device_sizes = PacketSizeHistogram::new();
+        }
+    }
+
+    pub fn channel(&mut self) -> ClientChannel<'_> {
         ClientChannel::new(
             &mut self.network_to_client,
             &self.stream,
             self.token,
             &mut self.interests,
+            self.mirror.as_deref(),
+            &mut self.to_device_sizes,
+            self.congestion_marker.as_mut(),
         )
     }
 
@@ -172,6 +298,33 @@ impl Client {
             warn!(target: TAG, "Cannot shutdown client socket");
         }
         self.router.clear(selector);
+        if self.dropped_packets > 0 {
+            info!(
+                target: TAG,
+                "Client #{} sent {} invalid packets",
+                self.id,
+                self.dropped_packets
+            );
+        }
+        if self.to_network_sizes.total() > 0 || self.to_device_sizes.total() > 0 {
+            info!(
+                target: TAG,
+                "Client #{} packet sizes to network: [{}]; to device: [{}]",
+                self.id,
+                self.to_network_sizes,
+                self.to_device_sizes
+            );
+        }
+        if let Some(ref congestion_marker) = self.congestion_marker {
+            if congestion_marker.marked() > 0 {
+                info!(
+                    target: TAG,
+                    "Client #{} marked {} packets with Congestion Experienced",
+                    self.id,
+                    congestion_marker.marked()
+                );
+            }
+        }
         self.close_listener.on_closed(self);
     }
 
@@ -190,6 +343,12 @@ impl Client {
     fn process(&mut self, selector: &mut Selector, event: Event) -> io::Result<()> {
         if !self.closed {
             let ready = event.readiness();
+            if !ready.is_readable() && !ready.is_writable() {
+                // error or hup: the poll would report it again until the client is closed
+                error!(target: TAG, "Client #{} link broken ({:?})", self.id, ready);
+                self.close(selector);
+                return Ok(());
+            }
             if ready.is_writable() {
                 self.process_send(selector)?;
             }
@@ -258,16 +417,18 @@ impl Client {
         selector: &mut Selector,
         ipv4_packet: &Ipv4Packet,
     ) -> io::Result<()> {
-        if ipv4_packet.length() as usize <= self.network_to_client.remaining() {
-            self.network_to_client.read_from(ipv4_packet.raw());
-            self.update_interests(selector);
-            Ok(())
-        } else {
-            warn!(target: TAG, "Client buffer full");
-            Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "Client buffer full",
-            ))
+        self.channel().send_to_client(selector, ipv4_packet)
+    }
+
+    /// Route raw packets as if they were read from the device.
+    #[cfg(any(test, feature = "packet-injection"))]
+    pub fn inject_packets(&mut self, selector: &mut Selector, mut raw: &[u8]) {
+        // through the same buffer as the data read from the stream
+        while !raw.is_empty() {
+            match self.client_to_network.read_from(&mut raw) {
+                Ok(true) => self.push_to_network(selector),
+                _ => break,
+            }
         }
     }
 
@@ -297,25 +458,81 @@ impl Client {
     }
 
     fn push_to_network(&mut self, selector: &mut Selector) {
-        while self.push_one_packet_to_network(selector) {
-            self.client_to_network.next();
+        if self.pending_handshake && !self.process_handshake(selector) {
+            return;
+        }
+        loop {
+            match self.push_one_packet_to_network(selector) {
+                Ok(true) => self.client_to_network.next(),
+                Ok(false) => break,
+                Err(ref err) if !err.is_fatal() => {
+                    warn!(target: TAG, "Dropping invalid packet: {}", err);
+                    self.dropped_packets += 1;
+                    self.router.drops().record(DropReason::from(err));
+                    self.client_to_network.next();
+                }
+                Err(err) => {
+                    error!(target: TAG, "Cannot read packets from client: {}", err);
+                    self.router.drops().record(DropReason::from(&err));
+                    self.close(selector);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Return `true` if the handshake is complete and packets may be read.
+    fn process_handshake(&mut self, selector: &mut Selector) -> bool {
+        match handshake::parse(self.client_to_network.peek()) {
+            Ok(Some(Handshake::Legacy)) => {
+                debug!(target: TAG, "Client #{} sent no handshake", self.id);
+            }
+            Ok(Some(Handshake::Versioned { version, length })) => {
+                debug!(target: TAG, "Client #{} speaks version {}", self.id, version);
+                self.client_to_network.skip(length);
+            }
+            Ok(None) => return false,
+            Err(err) => {
+                error!(target: TAG, "Client #{} rejected: {}", self.id, err);
+                self.close(selector);
+                return false;
+            }
         }
+        self.pending_handshake = false;
+        true
     }
 
-    fn push_one_packet_to_network(&mut self, selector: &mut Selector) -> bool {
-        match self.client_to_network.as_ipv4_packet() {
+    fn push_one_packet_to_network(&mut self, selector: &mut Selector) -> Result<bool, ParseError> {
+        match self.client_to_network.as_ipv4_packet()? {
             Some(ref packet) => {
+                if let Some(ref egress_limiter) = self.egress_limiter {
+                    let mut egress_limiter = egress_limiter.borrow_mut();
+                    let now = Instant::now();
+                    if !egress_limiter.is_available(now) {
+                        // keep the packet until the client may send again
+                        self.interests.reading = false;
+                        return Ok(false);
+                    }
+                    egress_limiter.consume(now, packet.length() as usize);
+                }
+                self.to_network_sizes.record(packet.length() as usize);
+                if let Some(ref mirror) = self.mirror {
+                    mirror.mirror(Direction::ToNetwork, packet.raw());
+                }
                 let mut client_channel = ClientChannel::new(
                     &mut self.network_to_client,
                     &self.stream,
                     self.token,
                     &mut self.interests,
+                    self.mirror.as_deref(),
+                    &mut self.to_device_sizes,
+                    self.congestion_marker.as_mut(),
                 );
                 self.router
                     .send_to_network(selector, &mut client_channel, packet);
-                true
+                Ok(true)
             }
-            None => false,
+            None => Ok(false),
         }
     }
 
@@ -351,10 +568,494 @@ impl Client {
     }
 
     pub fn clean_expired_connections(&mut self, selector: &mut Selector) {
-        self.router.clean_expired_connections(selector);
+        let mut client_channel = ClientChannel::new(
+            &mut self.network_to_client,
+            &self.stream,
+            self.token,
+            &mut self.interests,
+            self.mirror.as_deref(),
+            &mut self.to_device_sizes,
+            self.congestion_marker.as_mut(),
+        );
+        self.router
+            .clean_expired_connections(selector, &mut client_channel);
+    }
+
+    /// Close the connections to the addresses in `network`/`prefix_length` (as addressed by the
+    /// device), and return how many have been closed.
+    pub fn close_connections_to(
+        &mut self,
+        selector: &mut Selector,
+        network: Ipv4Addr,
+        prefix_length: u8,
+    ) -> usize {
+        let mut client_channel = ClientChannel::new(
+            &mut self.network_to_client,
+            &self.stream,
+            self.token,
+            &mut self.interests,
+            self.mirror.as_deref(),
+            &mut self.to_device_sizes,
+            self.congestion_marker.as_mut(),
+        );
+        self.router
+            .close_connections(selector, &mut client_channel, |connection| {
+                let destination = *connection.id().destination().ip();
+                net::is_in_network(destination, network, prefix_length)
+            })
+    }
+
+    /// Change the maximum number of connections of this client, and close the most idle
+    /// connections exceeding it. Return how many have been closed.
+    pub fn set_max_connections(
+        &mut self,
+        selector: &mut Selector,
+        max_connections: Option<usize>,
+    ) -> usize {
+        let mut client_channel = ClientChannel::new(
+            &mut self.network_to_client,
+            &self.stream,
+            self.token,
+            &mut self.interests,
+            self.mirror.as_deref(),
+            &mut self.to_device_sizes,
+            self.congestion_marker.as_mut(),
+        );
+        self.router
+            .set_max_connections(selector, &mut client_channel, max_connections)
+    }
+
+    /// Refuse the new connections, and close the client once its connections are closed, or
+    /// after `timeout`.
+    pub fn drain(&mut self, timeout: Duration) {
+        info!(target: TAG, "Client #{} draining", self.id);
+        self.router.set_draining();
+        self.drain_deadline = Some(Instant::now() + timeout);
+    }
+
+    pub fn drain_deadline(&self) -> Option<Instant> {
+        self.drain_deadline
+    }
+
+    /// Whether the client is draining, and has no connections left or exceeded its deadline.
+    pub fn is_drained(&self, now: Instant) -> bool {
+        self.drain_deadline
+            .is_some_and(|deadline| self.router.is_empty() || now >= deadline)
+    }
+
+    /// Close a drained client (its remaining connections are closed).
+    pub fn close_drained(&mut self, selector: &mut Selector) {
+        info!(target: TAG, "Client #{} drained", self.id);
+        self.close(selector);
+    }
+
+    /// Whether reading is suspended by the egress limiter.
+    pub fn egress_deferred(&self) -> bool {
+        !self.interests.reading
+    }
+
+    /// Send the packets deferred by the egress limiter, and resume reading unless the limit is
+    /// reached again.
+    pub fn resume_egress(&mut self, selector: &mut Selector) {
+        if !self.interests.reading {
+            self.interests.reading = true;
+            self.push_to_network(selector);
+            if !self.closed {
+                self.update_interests(selector);
+            }
+        }
     }
 
     fn must_send_id(&self) -> bool {
         self.pending_id_bytes > 0
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::relay::events::RelayEvent;
+    use crate::relay::ipv4_packet::tests::{create_tcp_packet, set_destination};
+    use crate::relay::tcp_connection::tests::{connect, create_segment, tcp_flags};
+    use crate::relay::tcp_header;
+    use mio::Events;
+    use net2::TcpStreamExt;
+    use std::cmp;
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+    use std::time::{Duration, Instant};
+
+    // Client side of a channel to be used in other tests, without a whole Client
+    pub struct MockClient {
+        network_to_client: StreamBuffer,
+        stream: TcpStream,
+        // the device end of the stream, kept open
+        _device: std::net::TcpStream,
+        token: Token,
+        interests: Interests,
+        to_device_sizes: PacketSizeHistogram,
+    }
+
+    impl MockClient {
+        pub fn new(selector: &mut Selector) -> Self {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let device = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let stream = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+            let interests = Ready::readable();
+            let token = selector
+                .register(
+                    &stream,
+                    |_: &mut Selector, _| {},
+                    interests,
+                    PollOpt::level(),
+                )
+                .unwrap();
+            Self {
+                network_to_client: StreamBuffer::new(16 * MAX_PACKET_LENGTH),
+                stream,
+                _device: device,
+                token,
+                interests: Interests {
+                    ready: interests,
+                    reading: true,
+                },
+                to_device_sizes: PacketSizeHistogram::new(),
+            }
+        }
+
+        pub fn channel(&mut self) -> ClientChannel<'_> {
+            ClientChannel::new(
+                &mut self.network_to_client,
+                &self.stream,
+                self.token,
+                &mut self.interests,
+                None,
+                &mut self.to_device_sizes,
+                None,
+            )
+        }
+
+        /// Consume the bytes sent to the device so far.
+        pub fn take_sent(&mut self) -> Vec<u8> {
+            let mut sent = Vec::new();
+            while self.network_to_client.write_to(&mut sent).unwrap() > 0 {}
+            sent
+        }
+    }
+
+    // A whole client, driven from the device end of its stream
+    pub struct MockDevice {
+        client: Rc<RefCell<Client>>,
+        // None once the link is reset
+        stream: Option<std::net::TcpStream>,
+        received: Vec<u8>,
+        pending_id_bytes: usize,
+    }
+
+    impl MockDevice {
+        pub fn new(selector: &mut Selector, config: RelayConfig) -> Self {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            stream.set_nonblocking(true).unwrap();
+            let accepted = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+            let client = Client::create(
+                0,
+                selector,
+                accepted,
+                Rc::new(config),
+                None,
+                None,
+                Box::new(|_: &Client| {}),
+            )
+            .unwrap();
+            Self {
+                client,
+                stream: Some(stream),
+                received: Vec::new(),
+                pending_id_bytes: 4,
+            }
+        }
+
+        pub fn client(&self) -> &Rc<RefCell<Client>> {
+            &self.client
+        }
+
+        /// Send a raw packet to the relay.
+        pub fn send(&mut self, raw: &[u8]) {
+            let stream = self.stream.as_mut().expect("Link reset");
+            stream.write_all(raw).unwrap();
+        }
+
+        /// Reset the link to the relay, as if the device crashed.
+        pub fn reset_link(&mut self) {
+            let stream = self.stream.take().expect("Link already reset");
+            // closed by a RST on drop
+            TcpStreamExt::set_linger(&stream, Some(Duration::from_secs(0))).unwrap();
+        }
+
+        /// Run the relay until a packet is received by the device, or until `timeout`.
+        ///
+        /// Like the relay loop (only more often), the expired connections are cleaned up on every
+        /// iteration.
+        pub fn receive(&mut self, selector: &mut Selector, timeout: Duration) -> Option<Vec<u8>> {
+            let deadline = Instant::now() + timeout;
+            let mut events = Events::with_capacity(16);
+            loop {
+                self.read_available();
+                if let Some(packet) = self.take_packet() {
+                    return Some(packet);
+                }
+                let now = Instant::now();
+                if now >= deadline {
+                    return None;
+                }
+                let poll_timeout = cmp::min(deadline - now, Duration::from_millis(10));
+                selector.poll(&mut events, Some(poll_timeout)).unwrap();
+                selector.run_handlers(&events);
+                self.client.borrow_mut().clean_expired_connections(selector);
+            }
+        }
+
+        fn read_available(&mut self) {
+            let stream = match self.stream {
+                Some(ref mut stream) => stream,
+                None => return,
+            };
+            let mut buf = [0; MAX_PACKET_LENGTH];
+            loop {
+                match stream.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(r) => self.received.extend_from_slice(&buf[..r]),
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(err) => panic!("Cannot read from relay: {}", err),
+                }
+            }
+        }
+
+        fn take_packet(&mut self) -> Option<Vec<u8>> {
+            if self.pending_id_bytes > 0 {
+                // the client id is sent first
+                let len = cmp::min(self.pending_id_bytes, self.received.len());
+                self.received.drain(..len);
+                self.pending_id_bytes -= len;
+            }
+            if self.received.len() < 4 {
+                return None;
+            }
+            let length = usize::from(u16::from_be_bytes([self.received[2], self.received[3]]));
+            if self.received.len() < length {
+                return None;
+            }
+            Some(self.received.drain(..length).collect())
+        }
+    }
+
+    #[test]
+    fn close_connections_to_network() {
+        let mut selector = Selector::create().unwrap();
+        let mut device = MockDevice::new(&mut selector, RelayConfig::new());
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // the same listener, addressed directly or through the localhost forward address
+        let mut streams = Vec::new();
+        for &ip in &[Ipv4Addr::LOCALHOST, Ipv4Addr::new(10, 0, 2, 2)] {
+            let mut syn = create_tcp_packet(tcp_header::FLAG_SYN);
+            set_destination(&mut syn, SocketAddrV4::new(ip, port));
+            device.send(&syn);
+            // SYN-ACK
+            assert!(device
+                .receive(&mut selector, Duration::from_secs(1))
+                .is_some());
+            let (stream, _) = listener.accept().unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            streams.push(stream);
+        }
+
+        let closed = device.client().borrow_mut().close_connections_to(
+            &mut selector,
+            Ipv4Addr::new(127, 0, 0, 0),
+            8,
+        );
+        assert_eq!(1, closed);
+        let rst = device
+            .receive(&mut selector, Duration::from_secs(1))
+            .expect("Expected RST");
+        assert_ne!(0, tcp_flags(&rst) & tcp_header::FLAG_RST);
+        let mut buf = [0; 16];
+        assert_eq!(0, streams[0].read(&mut buf).unwrap());
+
+        let infos = device.client().borrow().connection_infos();
+        assert_eq!(1, infos.len());
+        assert_eq!(Ipv4Addr::new(10, 0, 2, 2), *infos[0].id.destination().ip());
+        device.client().borrow_mut().router().clear(&mut selector);
+    }
+
+    #[test]
+    fn close_connections_on_broken_link() {
+        let mut selector = Selector::create().unwrap();
+        let mut device = MockDevice::new(&mut selector, RelayConfig::new());
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let destination =
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+
+        let mut streams = Vec::new();
+        for &source_port in &[1000u16, 1001] {
+            let mut syn = create_tcp_packet(tcp_header::FLAG_SYN);
+            syn[20..22].copy_from_slice(&source_port.to_be_bytes());
+            set_destination(&mut syn, destination);
+            device.send(&syn);
+            // SYN-ACK
+            assert!(device
+                .receive(&mut selector, Duration::from_secs(1))
+                .is_some());
+            let (stream, _) = listener.accept().unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            streams.push(stream);
+        }
+
+        device.reset_link();
+        assert_eq!(
+            None,
+            device.receive(&mut selector, Duration::from_millis(100))
+        );
+        assert!(device.client().borrow().closed);
+        // every connection to the network is closed
+        for mut stream in streams {
+            let mut buf = [0; 16];
+            assert_eq!(0, stream.read(&mut buf).unwrap());
+        }
+    }
+
+    #[test]
+    fn drain_client() {
+        let mut selector = Selector::create().unwrap();
+        let mut device = MockDevice::new(&mut selector, RelayConfig::new());
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let (mut stream, _) = connect(&mut selector, &mut device, &listener, true);
+
+        device
+            .client()
+            .borrow_mut()
+            .drain(Duration::from_secs(3600));
+
+        // a new connection is refused
+        let mut syn = create_segment(&listener, tcp_header::FLAG_SYN, 0x111, 0);
+        syn[20..22].copy_from_slice(&1001u16.to_be_bytes());
+        device.send(&syn);
+        let rst = device
+            .receive(&mut selector, Duration::from_secs(1))
+            .expect("Expected RST");
+        assert_ne!(0, tcp_flags(&rst) & tcp_header::FLAG_RST);
+        let stats = device.client().borrow().stats(Instant::now());
+        assert_eq!(1, stats.dropped(DropReason::ClientDraining));
+
+        // the existing connection is still relayed
+        stream.write_all(b"hello").unwrap();
+        assert!(device
+            .receive(&mut selector, Duration::from_secs(1))
+            .is_some());
+
+        let now = Instant::now();
+        assert!(!device.client().borrow().is_drained(now));
+        // the deadline is reached
+        assert!(device
+            .client()
+            .borrow()
+            .is_drained(now + Duration::from_secs(3600)));
+
+        device.client().borrow_mut().close_connections_to(
+            &mut selector,
+            Ipv4Addr::new(127, 0, 0, 0),
+            8,
+        );
+        assert!(device.client().borrow().is_drained(now));
+        device.client().borrow_mut().close_drained(&mut selector);
+        assert!(device.client().borrow().closed);
+    }
+
+    #[test]
+    fn publish_connection_events() {
+        let mut selector = Selector::create().unwrap();
+        let mut config = RelayConfig::new();
+        let first = config.subscribe_events(8);
+        let second = config.subscribe_events(8);
+        let mut device = MockDevice::new(&mut selector, config);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let (_stream, _) = connect(&mut selector, &mut device, &listener, true);
+
+        let id = device.client().borrow().connection_infos().remove(0).id;
+        for receiver in &[&first, &second] {
+            assert_eq!(
+                Some(RelayEvent::ConnectionOpened(id.clone())),
+                receiver.try_recv()
+            );
+        }
+        device.client().borrow_mut().router().clear(&mut selector);
+        assert_eq!(Some(RelayEvent::ConnectionClosed(id)), first.try_recv());
+    }
+
+    #[test]
+    fn count_relayed_packets() {
+        let mut selector = Selector::create().unwrap();
+        let mut device = MockDevice::new(&mut selector, RelayConfig::new());
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let destination =
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+        let mut syn = create_tcp_packet(tcp_header::FLAG_SYN);
+        set_destination(&mut syn, destination);
+        device.send(&syn);
+        let syn_ack = device
+            .receive(&mut selector, Duration::from_secs(1))
+            .expect("No SYN-ACK");
+
+        let mut client = device.client().borrow_mut();
+        let stats = client.stats(Instant::now());
+        assert_eq!(1, stats.clients);
+        assert_eq!(1, stats.connections);
+        assert_eq!(1, stats.packets_to_network);
+        assert_eq!(syn.len() as u64, stats.bytes_to_network);
+        assert_eq!(1, stats.packets_to_device);
+        assert_eq!(syn_ack.len() as u64, stats.bytes_to_device);
+        assert_eq!(0, stats.total_dropped());
+        client.router().clear(&mut selector);
+    }
+
+    #[test]
+    fn reset_counters() {
+        let mut selector = Selector::create().unwrap();
+        let mut config = RelayConfig::new();
+        config.set_drop_fragments(true);
+        let mut device = MockDevice::new(&mut selector, config);
+        let mut fragment = create_tcp_packet(tcp_header::FLAG_SYN);
+        fragment[6] = 0x20; // More Fragments
+        let mut send_fragment = |device: &mut MockDevice| {
+            device.send(&fragment);
+            device.receive(&mut selector, Duration::from_millis(50));
+            device.client().borrow().stats(Instant::now())
+        };
+
+        let stats = send_fragment(&mut device);
+        assert_eq!(1, stats.dropped(DropReason::Fragment));
+        device.client().borrow_mut().reset_stats(ResetScope::Drops);
+        let stats = device.client().borrow().stats(Instant::now());
+        assert_eq!(0, stats.total_dropped());
+        assert_eq!(1, stats.packets_to_network);
+
+        // counted again after the reset
+        let stats = send_fragment(&mut device);
+        assert_eq!(1, stats.dropped(DropReason::Fragment));
+        assert_eq!(2, stats.packets_to_network);
+
+        device.client().borrow_mut().reset_stats(ResetScope::All);
+        let stats = device.client().borrow().stats(Instant::now());
+        assert_eq!(0, stats.total_dropped());
+        assert_eq!(0, stats.packets_to_network);
+        assert_eq!(0, stats.bytes_to_network);
+        assert_eq!(1, stats.clients);
+    }
+}
@@ -0,0 +1,784 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+use super::connection_classifier::ConnectionClassifier;
+use super::destination_resolver::DestinationResolver;
+use super::events::{EventBus, EventReceiver};
+use super::payload_rewriter::PayloadRewriter;
+use super::socket_buffers::BufferSizes;
+use super::stats::StatsListener;
+use super::timer::Timer;
+
+/// Tunable parameters of the relay server.
+///
+/// The default values reproduce the historical behavior, except that:
+///  - TCP_NODELAY is enabled;
+///  - TCP connections to unreachable destinations are rejected;
+///  - packets carrying a source route option are rejected;
+///  - closed TCP connections stay in TIME-WAIT for 5 seconds;
+///  - a FIN from the client only half-closes the connection;
+///  - new connections are refused when fewer than 32 file descriptors are left;
+///  - TCP connections to the relay port on localhost are rejected;
+///  - datagrams to the broadcast address 255.255.255.255 are dropped.
+#[derive(Clone, Debug)]
+pub struct RelayConfig {
+    max_connection_lifetime: Option<Duration>,
+    tcp_keepalive_interval: Option<Duration>,
+    tcp_keepalive_probes: u32,
+    tcp_write_timeout: Option<Duration>,
+    tcp_defer_connect: Option<Duration>,
+    tcp_half_close: bool,
+    tcp_time_wait: Option<Duration>,
+    tcp_pool_ttl: Option<Duration>,
+    tcp_nodelay: bool,
+    client_nodelay: bool,
+    udp_source_ports: Option<RangeInclusive<u16>>,
+    allow_multicast: bool,
+    multicast_interface: Ipv4Addr,
+    udp_max_payload: Option<usize>,
+    dns_timeout: Option<Duration>,
+    udp_jitter: bool,
+    ethernet_frames: bool,
+    payload_rewriter: Option<Rc<dyn PayloadRewriter>>,
+    destination_resolver: Option<Rc<dyn DestinationResolver>>,
+    connection_classifier: Option<Rc<dyn ConnectionClassifier>>,
+    accept_backlog: i32,
+    max_accept_rate: Option<u32>,
+    max_egress_rate: Option<u32>,
+    mirror_address: Option<SocketAddr>,
+    reject_source_route: bool,
+    reject_record_route: bool,
+    drop_fragments: bool,
+    tcp_enabled: bool,
+    udp_enabled: bool,
+    max_half_open_connections: Option<usize>,
+    max_connections_per_client: Option<usize>,
+    max_connections_per_source: Option<usize>,
+    connection_watermarks: Vec<u8>,
+    fd_headroom: Option<u64>,
+    max_destination_connection_rate: Option<u32>,
+    allow_relay_loop: bool,
+    allow_broadcast: bool,
+    tcp_allowed_ports: Option<Vec<u16>>,
+    reject_unreachable: bool,
+    payload_dump_length: Option<usize>,
+    dscp_priorities: Option<Vec<(u8, u32)>>,
+    tcp_reset_networks: Option<Vec<(Ipv4Addr, u8)>>,
+    socket_receive_buffer: Option<usize>,
+    socket_send_buffer: Option<usize>,
+    ecn_threshold: Option<u8>,
+    timers: Vec<(Duration, Rc<dyn Timer>)>,
+    stats_listeners: Vec<(Duration, Rc<dyn StatsListener>)>,
+    events: EventBus,
+    stats_file: Option<PathBuf>,
+    stats_interval: Duration,
+    #[cfg(feature = "statsd")]
+    statsd_address: Option<SocketAddr>,
+    // set by the relay on start
+    relay_port: u16,
+}
+
+/// Preset of the options trading latency for throughput.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TuningProfile {
+    /// Send every packet as soon as possible (Nagle's algorithm disabled towards the network and
+    /// the clients), with the system default socket buffers.
+    Latency,
+    /// Let the system coalesce small writes (Nagle's algorithm enabled on both sides), with
+    /// larger socket buffers (`THROUGHPUT_SOCKET_BUFFER`).
+    Throughput,
+}
+
+pub const DEFAULT_TCP_KEEPALIVE_PROBES: u32 = 3;
+pub const DEFAULT_ACCEPT_BACKLOG: i32 = 1024;
+pub const DEFAULT_TCP_TIME_WAIT_SECONDS: u64 = 5;
+pub const DEFAULT_FD_HEADROOM: u64 = 32;
+pub const THROUGHPUT_SOCKET_BUFFER: usize = 1024 * 1024;
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            max_connection_lifetime: None,
+            tcp_keepalive_interval: None,
+            tcp_keepalive_probes: DEFAULT_TCP_KEEPALIVE_PROBES,
+            tcp_write_timeout: None,
+            tcp_defer_connect: None,
+            tcp_half_close: true,
+            tcp_time_wait: Some(Duration::from_secs(DEFAULT_TCP_TIME_WAIT_SECONDS)),
+            tcp_pool_ttl: None,
+            tcp_nodelay: true,
+            client_nodelay: false,
+            udp_source_ports: None,
+            allow_multicast: false,
+            multicast_interface: Ipv4Addr::UNSPECIFIED,
+            udp_max_payload: None,
+            dns_timeout: None,
+            udp_jitter: false,
+            ethernet_frames: false,
+            payload_rewriter: None,
+            destination_resolver: None,
+            connection_classifier: None,
+            accept_backlog: DEFAULT_ACCEPT_BACKLOG,
+            max_accept_rate: None,
+            max_egress_rate: None,
+            mirror_address: None,
+            reject_source_route: true,
+            reject_record_route: false,
+            drop_fragments: false,
+            tcp_enabled: true,
+            udp_enabled: true,
+            max_half_open_connections: None,
+            max_connections_per_client: None,
+            max_connections_per_source: None,
+            connection_watermarks: Vec::new(),
+            fd_headroom: Some(DEFAULT_FD_HEADROOM),
+            max_destination_connection_rate: None,
+            allow_relay_loop: false,
+            allow_broadcast: false,
+            tcp_allowed_ports: None,
+            reject_unreachable: true,
+            payload_dump_length: None,
+            dscp_priorities: None,
+            tcp_reset_networks: None,
+            socket_receive_buffer: None,
+            socket_send_buffer: None,
+            ecn_threshold: None,
+            timers: Vec::new(),
+            stats_listeners: Vec::new(),
+            events: EventBus::default(),
+            stats_file: None,
+            stats_interval: Duration::from_secs(10),
+            #[cfg(feature = "statsd")]
+            statsd_address: None,
+            relay_port: 0,
+        }
+    }
+}
+
+impl RelayConfig {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set all the options tuned by `profile`.
+    ///
+    /// Each option may still be changed individually afterwards.
+    pub fn apply_profile(&mut self, profile: TuningProfile) {
+        let nodelay = profile == TuningProfile::Latency;
+        self.tcp_nodelay = nodelay;
+        self.client_nodelay = nodelay;
+        let buffer_size = match profile {
+            TuningProfile::Latency => None,
+            TuningProfile::Throughput => Some(THROUGHPUT_SOCKET_BUFFER),
+        };
+        self.socket_receive_buffer = buffer_size;
+        self.socket_send_buffer = buffer_size;
+    }
+
+    /// Maximum duration of any connection, even if it is still active (`None` for unlimited).
+    pub fn max_connection_lifetime(&self) -> Option<Duration> {
+        self.max_connection_lifetime
+    }
+
+    pub fn set_max_connection_lifetime(&mut self, max_connection_lifetime: Option<Duration>) {
+        self.max_connection_lifetime = max_connection_lifetime;
+    }
+
+    /// Idle delay after which keepalive probes are sent to the device over TCP connections (`None`
+    /// to disable keepalive).
+    pub fn tcp_keepalive_interval(&self) -> Option<Duration> {
+        self.tcp_keepalive_interval
+    }
+
+    pub fn set_tcp_keepalive_interval(&mut self, tcp_keepalive_interval: Option<Duration>) {
+        self.tcp_keepalive_interval = tcp_keepalive_interval;
+    }
+
+    /// Number of unanswered keepalive probes after which a TCP connection is reset.
+    pub fn tcp_keepalive_probes(&self) -> u32 {
+        self.tcp_keepalive_probes
+    }
+
+    pub fn set_tcp_keepalive_probes(&mut self, tcp_keepalive_probes: u32) {
+        self.tcp_keepalive_probes = tcp_keepalive_probes;
+    }
+
+    /// Delay after which a TCP connection is reset if no pending data could be written to the
+    /// network (`None` to wait forever).
+    pub fn tcp_write_timeout(&self) -> Option<Duration> {
+        self.tcp_write_timeout
+    }
+
+    pub fn set_tcp_write_timeout(&mut self, tcp_write_timeout: Option<Duration>) {
+        self.tcp_write_timeout = tcp_write_timeout;
+    }
+
+    /// Maximum delay during which the connection to the network is postponed until the client
+    /// sends data (`None` to connect on the SYN).
+    ///
+    /// The SYN of the client is acknowledged immediately, so connections closed without sending
+    /// any data never reach the network.
+    pub fn tcp_defer_connect(&self) -> Option<Duration> {
+        self.tcp_defer_connect
+    }
+
+    pub fn set_tcp_defer_connect(&mut self, tcp_defer_connect: Option<Duration>) {
+        self.tcp_defer_connect = tcp_defer_connect;
+    }
+
+    /// Whether a FIN from the client only closes its direction, the data from the network being
+    /// still delivered until the network side closes too (if disabled, the relay closes both
+    /// directions immediately).
+    pub fn tcp_half_close(&self) -> bool {
+        self.tcp_half_close
+    }
+
+    pub fn set_tcp_half_close(&mut self, tcp_half_close: bool) {
+        self.tcp_half_close = tcp_half_close;
+    }
+
+    /// Duration during which late segments of a closed TCP connection are dropped instead of
+    /// opening a new connection (`None` to disable).
+    pub fn tcp_time_wait(&self) -> Option<Duration> {
+        self.tcp_time_wait
+    }
+
+    pub fn set_tcp_time_wait(&mut self, tcp_time_wait: Option<Duration>) {
+        self.tcp_time_wait = tcp_time_wait;
+    }
+
+    /// Duration during which a spare TCP connection, opened when a connection to the same
+    /// destination closes, is kept for the next connection (`None` to disable, the default).
+    ///
+    /// Experimental: the device connects through a socket the server accepted earlier, which is
+    /// only correct for servers keeping idle connections open.
+    pub fn tcp_pool_ttl(&self) -> Option<Duration> {
+        self.tcp_pool_ttl
+    }
+
+    pub fn set_tcp_pool_ttl(&mut self, tcp_pool_ttl: Option<Duration>) {
+        self.tcp_pool_ttl = tcp_pool_ttl;
+    }
+
+    /// Whether Nagle's algorithm is disabled (TCP_NODELAY) on the sockets connected to the
+    /// network.
+    pub fn tcp_nodelay(&self) -> bool {
+        self.tcp_nodelay
+    }
+
+    pub fn set_tcp_nodelay(&mut self, tcp_nodelay: bool) {
+        self.tcp_nodelay = tcp_nodelay;
+    }
+
+    /// Whether Nagle's algorithm is disabled (TCP_NODELAY) on the sockets connected to the
+    /// clients, so that the packets to the device are not coalesced.
+    pub fn client_nodelay(&self) -> bool {
+        self.client_nodelay
+    }
+
+    pub fn set_client_nodelay(&mut self, client_nodelay: bool) {
+        self.client_nodelay = client_nodelay;
+    }
+
+    /// Range of local ports the UDP sockets connected to the network are bound to (`None` to let
+    /// the system choose).
+    ///
+    /// It does not apply to the sockets joining a multicast group, which are bound to the group
+    /// port.
+    pub fn udp_source_ports(&self) -> Option<&RangeInclusive<u16>> {
+        self.udp_source_ports.as_ref()
+    }
+
+    pub fn set_udp_source_ports(&mut self, udp_source_ports: Option<RangeInclusive<u16>>) {
+        self.udp_source_ports = udp_source_ports;
+    }
+
+    /// Whether UDP connections to a multicast group join the group on the network socket, to
+    /// relay the datagrams of the group back to the device (if disabled, they are only sent).
+    pub fn allow_multicast(&self) -> bool {
+        self.allow_multicast
+    }
+
+    pub fn set_allow_multicast(&mut self, allow_multicast: bool) {
+        self.allow_multicast = allow_multicast;
+    }
+
+    /// The address of the interface on which multicast groups are joined (unspecified to let
+    /// the system choose).
+    pub fn multicast_interface(&self) -> Ipv4Addr {
+        self.multicast_interface
+    }
+
+    pub fn set_multicast_interface(&mut self, multicast_interface: Ipv4Addr) {
+        self.multicast_interface = multicast_interface;
+    }
+
+    /// Maximum size of the UDP payloads relayed in either direction, larger datagrams being
+    /// dropped (`None` for unlimited).
+    pub fn udp_max_payload(&self) -> Option<usize> {
+        self.udp_max_payload
+    }
+
+    pub fn set_udp_max_payload(&mut self, udp_max_payload: Option<usize>) {
+        self.udp_max_payload = udp_max_payload;
+    }
+
+    /// Delay after which a UDP connection to a DNS server is closed if a query is still unanswered
+    /// (`None` to only apply the UDP idle timeout).
+    pub fn dns_timeout(&self) -> Option<Duration> {
+        self.dns_timeout
+    }
+
+    pub fn set_dns_timeout(&mut self, dns_timeout: Option<Duration>) {
+        self.dns_timeout = dns_timeout;
+    }
+
+    /// Whether the jitter and the rate of each UDP connection are measured, and logged on close.
+    pub fn udp_jitter(&self) -> bool {
+        self.udp_jitter
+    }
+
+    pub fn set_udp_jitter(&mut self, udp_jitter: bool) {
+        self.udp_jitter = udp_jitter;
+    }
+
+    /// Whether the packets received from the device are preceded by an Ethernet header.
+    pub fn ethernet_frames(&self) -> bool {
+        self.ethernet_frames
+    }
+
+    pub fn set_ethernet_frames(&mut self, ethernet_frames: bool) {
+        self.ethernet_frames = ethernet_frames;
+    }
+
+    /// Hook called on every UDP payload relayed (`None` to relay them unchanged).
+    pub fn payload_rewriter(&self) -> Option<&Rc<dyn PayloadRewriter>> {
+        self.payload_rewriter.as_ref()
+    }
+
+    pub fn set_payload_rewriter(&mut self, payload_rewriter: Option<Rc<dyn PayloadRewriter>>) {
+        self.payload_rewriter = payload_rewriter;
+    }
+
+    /// Hook choosing the address to connect to for each new connection (`None` to connect to the
+    /// destination requested by the device).
+    pub fn destination_resolver(&self) -> Option<&Rc<dyn DestinationResolver>> {
+        self.destination_resolver.as_ref()
+    }
+
+    pub fn set_destination_resolver(
+        &mut self,
+        destination_resolver: Option<Rc<dyn DestinationResolver>>,
+    ) {
+        self.destination_resolver = destination_resolver;
+    }
+
+    /// Hook labeling each new connection, the connections are counted per label (`None` to leave
+    /// connections unlabeled).
+    pub fn connection_classifier(&self) -> Option<&Rc<dyn ConnectionClassifier>> {
+        self.connection_classifier.as_ref()
+    }
+
+    pub fn set_connection_classifier(
+        &mut self,
+        connection_classifier: Option<Rc<dyn ConnectionClassifier>>,
+    ) {
+        self.connection_classifier = connection_classifier;
+    }
+
+    /// Backlog of the socket listening for clients.
+    pub fn accept_backlog(&self) -> i32 {
+        self.accept_backlog
+    }
+
+    pub fn set_accept_backlog(&mut self, accept_backlog: i32) {
+        self.accept_backlog = accept_backlog;
+    }
+
+    /// Maximum number of clients accepted per second (`None` for unlimited).
+    ///
+    /// Clients connecting beyond this rate are accepted later.
+    pub fn max_accept_rate(&self) -> Option<u32> {
+        self.max_accept_rate
+    }
+
+    pub fn set_max_accept_rate(&mut self, max_accept_rate: Option<u32>) {
+        self.max_accept_rate = max_accept_rate;
+    }
+
+    /// Maximum number of bytes per second sent to the network by all the clients together (`None`
+    /// for unlimited).
+    ///
+    /// Once the rate is reached, the clients stop reading from the device until they may send
+    /// again, in turn.
+    pub fn max_egress_rate(&self) -> Option<u32> {
+        self.max_egress_rate
+    }
+
+    pub fn set_max_egress_rate(&mut self, max_egress_rate: Option<u32>) {
+        self.max_egress_rate = max_egress_rate;
+    }
+
+    /// Address of a UDP collector receiving a copy of every relayed IP packet (`None` to disable
+    /// mirroring).
+    pub fn mirror_address(&self) -> Option<SocketAddr> {
+        self.mirror_address
+    }
+
+    pub fn set_mirror_address(&mut self, mirror_address: Option<SocketAddr>) {
+        self.mirror_address = mirror_address;
+    }
+
+    /// Whether packets carrying a loose or strict source route IP option are dropped.
+    pub fn reject_source_route(&self) -> bool {
+        self.reject_source_route
+    }
+
+    pub fn set_reject_source_route(&mut self, reject_source_route: bool) {
+        self.reject_source_route = reject_source_route;
+    }
+
+    /// Whether packets carrying a record route IP option are dropped.
+    pub fn reject_record_route(&self) -> bool {
+        self.reject_record_route
+    }
+
+    pub fn set_reject_record_route(&mut self, reject_record_route: bool) {
+        self.reject_record_route = reject_record_route;
+    }
+
+    /// Whether IP fragments are dropped (the relay does not reassemble them).
+    pub fn drop_fragments(&self) -> bool {
+        self.drop_fragments
+    }
+
+    pub fn set_drop_fragments(&mut self, drop_fragments: bool) {
+        self.drop_fragments = drop_fragments;
+    }
+
+    /// Whether TCP packets are relayed (if disabled, they are dropped).
+    pub fn tcp_enabled(&self) -> bool {
+        self.tcp_enabled
+    }
+
+    pub fn set_tcp_enabled(&mut self, tcp_enabled: bool) {
+        self.tcp_enabled = tcp_enabled;
+    }
+
+    /// Whether UDP packets (including DNS) are relayed (if disabled, they are dropped).
+    pub fn udp_enabled(&self) -> bool {
+        self.udp_enabled
+    }
+
+    pub fn set_udp_enabled(&mut self, udp_enabled: bool) {
+        self.udp_enabled = udp_enabled;
+    }
+
+    /// Maximum number of TCP connections not established yet, per client (`None` for
+    /// unlimited).
+    ///
+    /// Beyond this limit, new TCP connections are rejected by a RST.
+    pub fn max_half_open_connections(&self) -> Option<usize> {
+        self.max_half_open_connections
+    }
+
+    pub fn set_max_half_open_connections(&mut self, max_half_open_connections: Option<usize>) {
+        self.max_half_open_connections = max_half_open_connections;
+    }
+
+    /// Maximum number of TCP and UDP connections per client (`None` for unlimited).
+    ///
+    /// Beyond this limit, new TCP connections are rejected by a RST and new UDP datagrams are
+    /// dropped, without affecting the other clients.
+    pub fn max_connections_per_client(&self) -> Option<usize> {
+        self.max_connections_per_client
+    }
+
+    pub fn set_max_connections_per_client(&mut self, max_connections_per_client: Option<usize>) {
+        self.max_connections_per_client = max_connections_per_client;
+    }
+
+    /// Maximum number of TCP and UDP connections (in any state) from the same device source
+    /// address (`None` for unlimited).
+    ///
+    /// Unlike `max_connections_per_client()`, this contains a single source address in case a
+    /// client uses several.
+    pub fn max_connections_per_source(&self) -> Option<usize> {
+        self.max_connections_per_source
+    }
+
+    pub fn set_max_connections_per_source(&mut self, max_connections_per_source: Option<usize>) {
+        self.max_connections_per_source = max_connections_per_source;
+    }
+
+    /// Percentages of `max_connections_per_client()` which publish a `HighWatermark` event when
+    /// the connections of a client reach them, and a `WatermarkRecovered` event when they drop
+    /// back below (in increasing order).
+    pub fn connection_watermarks(&self) -> &[u8] {
+        &self.connection_watermarks
+    }
+
+    pub fn set_connection_watermarks(&mut self, mut connection_watermarks: Vec<u8>) {
+        connection_watermarks.sort_unstable();
+        connection_watermarks.dedup();
+        self.connection_watermarks = connection_watermarks;
+    }
+
+    /// Number of file descriptors kept free below the process limit (`None` to never check).
+    ///
+    /// Once the open descriptors reach the limit minus this headroom, new TCP connections are
+    /// rejected by a RST and new UDP datagrams are dropped, so that the relay never fails to
+    /// accept clients or to open sockets in the middle of an operation.
+    pub fn fd_headroom(&self) -> Option<u64> {
+        self.fd_headroom
+    }
+
+    pub fn set_fd_headroom(&mut self, fd_headroom: Option<u64>) {
+        self.fd_headroom = fd_headroom;
+    }
+
+    /// Maximum number of new connections per second to the same destination address, per client
+    /// (`None` for unlimited).
+    ///
+    /// Beyond this rate, new TCP connections are rejected by a RST and new UDP datagrams are
+    /// dropped.
+    pub fn max_destination_connection_rate(&self) -> Option<u32> {
+        self.max_destination_connection_rate
+    }
+
+    pub fn set_max_destination_connection_rate(
+        &mut self,
+        max_destination_connection_rate: Option<u32>,
+    ) {
+        self.max_destination_connection_rate = max_destination_connection_rate;
+    }
+
+    /// Whether TCP connections from the device to the relay port on localhost are allowed.
+    ///
+    /// Such connections would be accepted by the relay itself, as new clients.
+    pub fn allow_relay_loop(&self) -> bool {
+        self.allow_relay_loop
+    }
+
+    pub fn set_allow_relay_loop(&mut self, allow_relay_loop: bool) {
+        self.allow_relay_loop = allow_relay_loop;
+    }
+
+    /// Whether packets to the limited broadcast address (255.255.255.255) are relayed to the
+    /// network (if disabled, they are dropped).
+    pub fn allow_broadcast(&self) -> bool {
+        self.allow_broadcast
+    }
+
+    pub fn set_allow_broadcast(&mut self, allow_broadcast: bool) {
+        self.allow_broadcast = allow_broadcast;
+    }
+
+    /// The only TCP destination ports to which connections are allowed (`None` to allow all).
+    pub fn tcp_allowed_ports(&self) -> Option<&[u16]> {
+        self.tcp_allowed_ports.as_deref()
+    }
+
+    pub fn set_tcp_allowed_ports(&mut self, tcp_allowed_ports: Option<Vec<u16>>) {
+        self.tcp_allowed_ports = tcp_allowed_ports;
+    }
+
+    /// Whether a TCP connection whose destination is unreachable (network down or unreachable,
+    /// connection refused) is immediately rejected by a RST, instead of dropping the SYN.
+    pub fn reject_unreachable(&self) -> bool {
+        self.reject_unreachable
+    }
+
+    pub fn set_reject_unreachable(&mut self, reject_unreachable: bool) {
+        self.reject_unreachable = reject_unreachable;
+    }
+
+    /// Number of bytes of payload logged in each direction at the start of every TCP connection
+    /// (`None` to disable).
+    pub fn payload_dump_length(&self) -> Option<usize> {
+        self.payload_dump_length
+    }
+
+    pub fn set_payload_dump_length(&mut self, payload_dump_length: Option<usize>) {
+        self.payload_dump_length = payload_dump_length;
+    }
+
+    /// Socket priority (SO_PRIORITY, Linux only) of the connections to the network, by DSCP of
+    /// the packets opening them (`None` to keep the default priority).
+    pub fn dscp_priorities(&self) -> Option<&[(u8, u32)]> {
+        self.dscp_priorities.as_deref()
+    }
+
+    pub fn set_dscp_priorities(&mut self, dscp_priorities: Option<Vec<(u8, u32)>>) {
+        self.dscp_priorities = dscp_priorities;
+    }
+
+    /// Destination networks (address and prefix length) of the TCP connections closed by a RST
+    /// instead of a FIN when the network side closes (`None` to always close by a FIN).
+    pub fn tcp_reset_networks(&self) -> Option<&[(Ipv4Addr, u8)]> {
+        self.tcp_reset_networks.as_deref()
+    }
+
+    pub fn set_tcp_reset_networks(&mut self, tcp_reset_networks: Option<Vec<(Ipv4Addr, u8)>>) {
+        self.tcp_reset_networks = tcp_reset_networks;
+    }
+
+    /// Kernel receive buffer size (SO_RCVBUF) of the client and network sockets (`None` to keep
+    /// the system default).
+    pub fn socket_receive_buffer(&self) -> Option<usize> {
+        self.socket_receive_buffer
+    }
+
+    pub fn set_socket_receive_buffer(&mut self, socket_receive_buffer: Option<usize>) {
+        self.socket_receive_buffer = socket_receive_buffer;
+    }
+
+    /// Kernel send buffer size (SO_SNDBUF) of the client and network sockets (`None` to keep the
+    /// system default).
+    pub fn socket_send_buffer(&self) -> Option<usize> {
+        self.socket_send_buffer
+    }
+
+    pub fn set_socket_send_buffer(&mut self, socket_send_buffer: Option<usize>) {
+        self.socket_send_buffer = socket_send_buffer;
+    }
+
+    /// Occupancy (in percent) of the buffer to each device above which its ECN-capable packets
+    /// are marked with Congestion Experienced (`None` to never mark packets).
+    pub fn ecn_threshold(&self) -> Option<u8> {
+        self.ecn_threshold
+    }
+
+    pub fn set_ecn_threshold(&mut self, ecn_threshold: Option<u8>) {
+        self.ecn_threshold = ecn_threshold;
+    }
+
+    /// Hooks called periodically on the relay thread, with their interval.
+    pub fn timers(&self) -> &[(Duration, Rc<dyn Timer>)] {
+        &self.timers
+    }
+
+    pub fn add_timer(&mut self, interval: Duration, timer: Rc<dyn Timer>) {
+        assert!(
+            interval > Duration::from_secs(0),
+            "Timer interval must not be 0"
+        );
+        self.timers.push((interval, timer));
+    }
+
+    /// Hooks called periodically on the relay thread with its statistics, with their interval.
+    pub fn stats_listeners(&self) -> &[(Duration, Rc<dyn StatsListener>)] {
+        &self.stats_listeners
+    }
+
+    pub fn add_stats_listener(&mut self, interval: Duration, listener: Rc<dyn StatsListener>) {
+        assert!(
+            interval > Duration::from_secs(0),
+            "Stats interval must not be 0"
+        );
+        self.stats_listeners.push((interval, listener));
+    }
+
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Subscribe to the relay events, queued up to `capacity` for the returned receiver.
+    ///
+    /// The delivery is lossy: the relay never waits for a subscriber, once its queue is full the
+    /// oldest event is discarded (see `EventReceiver::dropped()`).
+    pub fn subscribe_events(&mut self, capacity: usize) -> EventReceiver {
+        self.events.subscribe(capacity)
+    }
+
+    /// File the stats are written to (as JSON) every `stats_interval()` (`None` to disable).
+    pub fn stats_file(&self) -> Option<&Path> {
+        self.stats_file.as_deref()
+    }
+
+    pub fn set_stats_file(&mut self, stats_file: Option<PathBuf>) {
+        self.stats_file = stats_file;
+    }
+
+    /// StatsD server the stats are pushed to every `stats_interval()` (`None` to disable).
+    #[cfg(feature = "statsd")]
+    pub fn statsd_address(&self) -> Option<SocketAddr> {
+        self.statsd_address
+    }
+
+    #[cfg(feature = "statsd")]
+    pub fn set_statsd_address(&mut self, statsd_address: Option<SocketAddr>) {
+        self.statsd_address = statsd_address;
+    }
+
+    /// Interval between two exports of the stats.
+    pub fn stats_interval(&self) -> Duration {
+        self.stats_interval
+    }
+
+    pub fn set_stats_interval(&mut self, stats_interval: Duration) {
+        assert!(
+            stats_interval > Duration::from_secs(0),
+            "Stats interval must not be 0"
+        );
+        self.stats_interval = stats_interval;
+    }
+
+    pub(crate) fn socket_buffer_sizes(&self) -> BufferSizes {
+        BufferSizes {
+            receive: self.socket_receive_buffer,
+            send: self.socket_send_buffer,
+        }
+    }
+
+    pub(crate) fn relay_port(&self) -> u16 {
+        self.relay_port
+    }
+
+    pub(crate) fn set_relay_port(&mut self, relay_port: u16) {
+        self.relay_port = relay_port;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_tuning_profile() {
+        let mut config = RelayConfig::new();
+        config.apply_profile(TuningProfile::Throughput);
+        assert!(!config.tcp_nodelay());
+        assert!(!config.client_nodelay());
+        assert_eq!(
+            Some(THROUGHPUT_SOCKET_BUFFER),
+            config.socket_receive_buffer()
+        );
+        assert_eq!(Some(THROUGHPUT_SOCKET_BUFFER), config.socket_send_buffer());
+
+        config.apply_profile(TuningProfile::Latency);
+        assert!(config.tcp_nodelay());
+        assert!(config.client_nodelay());
+        assert!(config.socket_receive_buffer().is_none());
+        assert!(config.socket_send_buffer().is_none());
+    }
+}
@@ -0,0 +1,108 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use super::ipv4_header::{Ipv4HeaderData, ECN_CE, ECN_NOT_ECT};
+
+/// Mark the ECN-capable packets to the device with Congestion Experienced (RFC 3168) while the
+/// buffer to the device is filled above a threshold, so that the flows slow down before packets
+/// are dropped.
+pub struct CongestionMarker {
+    // buffer occupancy (in bytes) from which packets are marked
+    threshold: usize,
+    marked: u64,
+}
+
+impl CongestionMarker {
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            marked: 0,
+        }
+    }
+
+    /// Return a copy of the packet `raw` marked CE, or `None` if it must be sent unchanged.
+    pub fn mark(&mut self, raw: &[u8], occupancy: usize) -> Option<Vec<u8>> {
+        if occupancy < self.threshold {
+            return None;
+        }
+        let mut data = Ipv4HeaderData::parse(raw).ok()?;
+        if data.ecn() == ECN_NOT_ECT || data.ecn() == ECN_CE {
+            return None;
+        }
+        let mut marked = raw.to_vec();
+        {
+            let mut ipv4_header = data.bind_mut(&mut marked);
+            ipv4_header.set_ecn(ECN_CE);
+            ipv4_header.update_checksum();
+        }
+        self.marked += 1;
+        Some(marked)
+    }
+
+    /// Number of packets marked CE.
+    pub fn marked(&self) -> u64 {
+        self.marked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::checksum;
+    use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+    fn create_packet(ecn: u8) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(28);
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(ecn).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(28).unwrap(); // total length 20 + 8
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(17).unwrap(); // protocol (UDP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x42424242).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x0a000002).unwrap(); // destination address
+        raw.write_u64::<BigEndian>(0).unwrap(); // UDP header
+        raw
+    }
+
+    #[test]
+    fn mark_above_threshold() {
+        let mut marker = CongestionMarker::new(1000);
+        let raw = create_packet(0x2); // ECT(0)
+        assert!(marker.mark(&raw, 999).is_none());
+
+        let marked = marker.mark(&raw, 1000).unwrap();
+        let data = Ipv4HeaderData::parse(&marked).unwrap();
+        assert_eq!(ECN_CE, data.ecn());
+        assert_eq!(
+            checksum::ipv4_checksum(&marked[..20]),
+            BigEndian::read_u16(&marked[10..12])
+        );
+        // only the header is changed
+        assert_eq!(raw[20..], marked[20..]);
+        assert_eq!(1, marker.marked());
+    }
+
+    #[test]
+    fn never_mark_non_ecn_capable() {
+        let mut marker = CongestionMarker::new(0);
+        assert!(marker.mark(&create_packet(ECN_NOT_ECT), 1000).is_none());
+        assert!(marker.mark(&create_packet(ECN_CE), 1000).is_none());
+        assert!(marker.mark(&create_packet(0x1), 1000).is_some()); // ECT(1)
+        assert_eq!(1, marker.marked());
+    }
+}
@@ -15,12 +15,18 @@
  */
 
 use std::fmt;
-use std::net::SocketAddrV4;
+use std::io;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use super::client::ClientChannel;
+use super::destination_resolver::DestinationResolver;
 use super::ipv4_header::{Ipv4HeaderData, Protocol};
 use super::ipv4_packet::Ipv4Packet;
 use super::net;
+use super::packet_times::PacketTimes;
+use super::payload_rewriter::Direction;
 use super::selector::Selector;
 use super::transport_header::TransportHeaderData;
 
@@ -36,12 +42,145 @@ pub trait Connection {
         ipv4_packet: &Ipv4Packet,
     );
     fn close(&mut self, selector: &mut Selector);
+    /// Close the connection because its client is gone, so nothing may be sent to it anymore.
+    fn close_client_gone(&mut self, selector: &mut Selector) {
+        self.close(selector);
+    }
+    /// Called periodically, before checking for expiration.
+    fn tick(&mut self, selector: &mut Selector, client_channel: &mut ClientChannel);
+    /// Close the connection, notifying the client if the protocol supports it.
+    fn abort(&mut self, selector: &mut Selector, client_channel: &mut ClientChannel);
     fn is_expired(&self) -> bool;
     fn is_closed(&self) -> bool;
+    /// Whether the connection is not established yet (TCP handshake in progress).
+    fn is_half_open(&self) -> bool;
+    /// When the connection was opened.
+    fn created(&self) -> Instant;
+    /// The category assigned by the connection classifier (or by the connection itself, from
+    /// the TLS server name), if any.
+    fn label(&self) -> Option<&str>;
+    fn set_label(&mut self, label: String);
+    /// Snapshot of the connection, for the state dumps.
+    fn info(&self) -> ConnectionInfo;
+    /// Full snapshot of the connection, to debug a single flow.
+    fn detail(&self) -> ConnectionDetail {
+        ConnectionDetail::new(self.info())
+    }
+    /// Stop relaying in both directions, or resume. While paused, the data from the device are
+    /// buffered up to the buffer capacity, and the network sockets are not read.
+    fn set_paused(&mut self, selector: &mut Selector, paused: bool);
+}
+
+/// The side which opened a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionDirection {
+    /// Opened by the device, the only direction relayed (there is no port forwarding).
+    Outbound,
+}
+
+/// Snapshot of a connection.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    pub protocol: Protocol,
+    pub ip_version: u8,
+    pub direction: ConnectionDirection,
+    /// The TCP state, or "Open" for UDP.
+    pub state: String,
+    pub created: Instant,
+    pub label: Option<String>,
+    /// The time to connect to the network, once connected (TCP only).
+    pub connected_in: Option<Duration>,
+    /// The address of the relay socket to the network, once bound.
+    pub local_address: Option<SocketAddr>,
+    pub packets_to_network: u64,
+    pub packets_to_device: u64,
+    /// The time of the last packet relayed in each direction, if any.
+    pub last_to_network: Option<Instant>,
+    pub last_to_device: Option<Instant>,
+    pub paused: bool,
+}
+
+impl ConnectionInfo {
+    pub fn new(id: &ConnectionId, state: String, created: Instant, label: Option<&str>) -> Self {
+        Self {
+            id: id.clone(),
+            protocol: id.protocol(),
+            ip_version: id.ip_version(),
+            direction: ConnectionDirection::Outbound,
+            state,
+            created,
+            label: label.map(String::from),
+            connected_in: None,
+            local_address: None,
+            packets_to_network: 0,
+            packets_to_device: 0,
+            last_to_network: None,
+            last_to_device: None,
+            paused: false,
+        }
+    }
+
+    /// The packet counters and activity times, from the packet times recorded by the connection.
+    pub fn with_packet_times(mut self, packet_times: &PacketTimes) -> Self {
+        self.packets_to_network = packet_times.count(Direction::ToNetwork);
+        self.packets_to_device = packet_times.count(Direction::ToDevice);
+        self.last_to_network = packet_times.get(Direction::ToNetwork).map(|(_, last)| last);
+        self.last_to_device = packet_times.get(Direction::ToDevice).map(|(_, last)| last);
+        self
+    }
+
+    /// The time of the last packet relayed in either direction, or the opening of the connection.
+    pub fn last_activity(&self) -> Instant {
+        [self.last_to_network, self.last_to_device]
+            .iter()
+            .flatten()
+            .fold(self.created, |last, &time| last.max(time))
+    }
+
+    /// How long the connection has been idle at `now`.
+    pub fn idle(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_activity())
+    }
+}
+
+/// Full snapshot of a connection, including its transport internals.
+#[derive(Clone, Debug)]
+pub struct ConnectionDetail {
+    pub info: ConnectionInfo,
+    /// The sequence numbers and windows (TCP only).
+    pub sequence_space: Option<String>,
+    /// The bytes received from the device, not written to the network yet (TCP only).
+    pub pending_to_network: usize,
+    /// The bytes of the out-of-order segments waiting for the missing ones (TCP only).
+    pub out_of_order: usize,
+    /// Whether reading from the network is suspended until the device may receive more data.
+    pub backpressure: bool,
+    /// The network interface of the local address, i.e. the one used to reach the destination,
+    /// if found.
+    pub interface: Option<String>,
+}
+
+impl ConnectionDetail {
+    pub fn new(info: ConnectionInfo) -> Self {
+        let interface = match info.local_address {
+            Some(SocketAddr::V4(address)) => net::interface_name(*address.ip()),
+            _ => None,
+        };
+        Self {
+            info,
+            sequence_space: None,
+            pending_to_network: 0,
+            out_of_order: 0,
+            backpressure: false,
+            interface,
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ConnectionId {
+    ip_version: u8,
     protocol: Protocol,
     source_ip: u32,
     source_port: u16,
@@ -65,6 +204,7 @@ impl ConnectionId {
             net::to_socket_addr(destination_ip, destination_port)
         );
         Self {
+            ip_version: ipv4_header_data.version(),
             protocol: ipv4_header_data.protocol(),
             source_ip,
             source_port,
@@ -74,10 +214,22 @@ impl ConnectionId {
         }
     }
 
+    pub fn ip_version(&self) -> u8 {
+        self.ip_version
+    }
+
     pub fn protocol(&self) -> Protocol {
         self.protocol
     }
 
+    pub fn source(&self) -> SocketAddrV4 {
+        net::to_socket_addr(self.source_ip, self.source_port)
+    }
+
+    pub fn destination(&self) -> SocketAddrV4 {
+        net::to_socket_addr(self.destination_ip, self.destination_port)
+    }
+
     pub fn rewritten_destination(&self) -> SocketAddrV4 {
         let ip = if self.destination_ip == LOCALHOST_FORWARD {
             LOCALHOST
@@ -86,6 +238,25 @@ impl ConnectionId {
         };
         net::to_socket_addr(ip, self.destination_port)
     }
+
+    /// The address to connect to, as chosen by the resolver (if any).
+    pub fn resolved_destination(
+        &self,
+        resolver: Option<&Rc<dyn DestinationResolver>>,
+    ) -> io::Result<SocketAddrV4> {
+        let destination = self.rewritten_destination();
+        match resolver {
+            Some(resolver) => resolver
+                .resolve(self.protocol, self.source(), destination)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "Destination refused by the resolver",
+                    )
+                }),
+            None => Ok(destination),
+        }
+    }
 }
 
 impl fmt::Display for ConnectionId {
@@ -107,30 +278,66 @@ macro_rules! cx_format {
 
 macro_rules! cx_trace {
     (target: $target:expr, $id:expr, $($arg:tt)*) => {
-        log::trace!(target: $target, "{}", cx_format!($id, $($arg)+));
+        log::trace!(target: $target, "{}", cx_format!($id, $($arg)+))
     }
 }
 
 macro_rules! cx_debug {
     (target: $target:expr, $id:expr, $($arg:tt)*) => {
-        log::debug!(target: $target, "{}", cx_format!($id, $($arg)+));
+        log::debug!(target: $target, "{}", cx_format!($id, $($arg)+))
     }
 }
 
 macro_rules! cx_info {
     (target: $target:expr, $id:expr, $($arg:tt)*) => {
-        log::info!(target: $target, "{}", cx_format!($id, $($arg)+));
+        log::info!(target: $target, "{}", cx_format!($id, $($arg)+))
     }
 }
 
 macro_rules! cx_warn {
     (target: $target:expr, $id:expr, $($arg:tt)*) => {
-        log::warn!(target: $target, "{}", cx_format!($id, $($arg)+));
+        log::warn!(target: $target, "{}", cx_format!($id, $($arg)+))
     }
 }
 
 macro_rules! cx_error {
     (target: $target:expr, $id:expr, $($arg:tt)*) => {
-        log::error!(target: $target, "{}", cx_format!($id, $($arg)+));
+        log::error!(target: $target, "{}", cx_format!($id, $($arg)+))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::ipv4_packet::tests::{create_tcp_packet, create_udp_packet};
+    use crate::relay::tcp_header;
+
+    fn create_info(raw: &mut [u8]) -> ConnectionInfo {
+        let ipv4_packet = Ipv4Packet::parse(raw).unwrap();
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap());
+        ConnectionInfo::new(&id, String::from("Open"), Instant::now(), None)
+    }
+
+    #[test]
+    fn last_activity_in_either_direction() {
+        let mut info = create_info(&mut create_udp_packet(53));
+        let created = info.created;
+        assert_eq!(created, info.last_activity());
+
+        info.last_to_device = Some(created + Duration::from_millis(20));
+        info.last_to_network = Some(created + Duration::from_millis(10));
+        assert_eq!(created + Duration::from_millis(20), info.last_activity());
+        let now = created + Duration::from_millis(50);
+        assert_eq!(Duration::from_millis(30), info.idle(now));
+    }
+
+    #[test]
+    fn describe_transport() {
+        let info = create_info(&mut create_tcp_packet(tcp_header::FLAG_SYN));
+        assert_eq!((Protocol::Tcp, 4), (info.protocol, info.ip_version));
+
+        let info = create_info(&mut create_udp_packet(53));
+        assert_eq!((Protocol::Udp, 4), (info.protocol, info.ip_version));
     }
 }
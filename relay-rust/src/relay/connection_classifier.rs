@@ -0,0 +1,36 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use std::net::SocketAddrV4;
+
+use super::ipv4_header::Protocol;
+
+/// Hook to label each new connection with a category (e.g. "dns", "https", "quic"), to break
+/// the traffic down.
+pub trait ConnectionClassifier: fmt::Debug {
+    /// Return the label of a new connection, or `None` to leave it unlabeled.
+    ///
+    /// `payload` is the transport payload of the first packet of the connection (typically empty
+    /// for TCP, since the first packet is a SYN).
+    fn classify(
+        &self,
+        protocol: Protocol,
+        device: SocketAddrV4,
+        destination: SocketAddrV4,
+        payload: &[u8],
+    ) -> Option<String>;
+}
@@ -0,0 +1,137 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use mio::net::TcpStream;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+const TAG: &str = "ConnectionPool";
+
+// a spare connection is kept for only few destinations
+const MAX_POOLED_CONNECTIONS: usize = 16;
+
+/// Spare TCP connections to recently used destinations, opened in advance to avoid the connect
+/// latency of the next connection to the same destination.
+///
+/// A pooled socket is never reused after a device connection used it: a spare one is opened
+/// instead. This only makes sense for servers accepting (and keeping) idle connections.
+pub struct ConnectionPool {
+    ttl: Option<Duration>,
+    connections: Vec<(SocketAddrV4, TcpStream, Instant)>,
+}
+
+impl ConnectionPool {
+    /// Create a pool keeping spare connections during `ttl` (`None` to disable pooling).
+    pub fn new(ttl: Option<Duration>) -> Self {
+        Self {
+            ttl,
+            connections: Vec::new(),
+        }
+    }
+
+    /// Open a spare connection to `destination`, unless there is already one.
+    pub fn warm(&mut self, destination: SocketAddrV4) {
+        if self.ttl.is_none() {
+            return;
+        }
+        self.clean();
+        if self.position(destination).is_some() || self.connections.len() >= MAX_POOLED_CONNECTIONS
+        {
+            return;
+        }
+        match TcpStream::connect(&SocketAddr::V4(destination)) {
+            Ok(stream) => {
+                debug!(target: TAG, "Spare connection opened to {}", destination);
+                self.put(destination, stream);
+            }
+            Err(err) => debug!(
+                target: TAG,
+                "Cannot open spare connection to {}: {}",
+                destination,
+                err
+            ),
+        }
+    }
+
+    fn put(&mut self, destination: SocketAddrV4, stream: TcpStream) {
+        self.connections.push((destination, stream, Instant::now()));
+    }
+
+    /// Take the spare connection to `destination`, if any.
+    pub fn take(&mut self, destination: SocketAddrV4) -> Option<TcpStream> {
+        self.clean();
+        let index = self.position(destination)?;
+        let (_, stream, _) = self.connections.swap_remove(index);
+        Some(stream)
+    }
+
+    fn position(&self, destination: SocketAddrV4) -> Option<usize> {
+        self.connections
+            .iter()
+            .position(|&(addr, _, _)| addr == destination)
+    }
+
+    /// Close the spare connections kept for too long.
+    pub fn clean(&mut self) {
+        if let Some(ttl) = self.ttl {
+            self.connections
+                .retain(|&(_, _, opened)| opened.elapsed() < ttl);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.connections.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn connect(listener: &TcpListener) -> TcpStream {
+        let addr = listener.local_addr().unwrap();
+        TcpStream::from_stream(std::net::TcpStream::connect(addr).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn reuse_within_ttl() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = "127.0.0.1:80".parse().unwrap();
+        let mut pool = ConnectionPool::new(Some(Duration::from_secs(60)));
+
+        let stream = connect(&listener);
+        let local_addr = stream.local_addr().unwrap();
+        pool.put(destination, stream);
+
+        assert!(pool.take("127.0.0.1:81".parse().unwrap()).is_none());
+        let pooled = pool.take(destination).unwrap();
+        assert_eq!(local_addr, pooled.local_addr().unwrap());
+        // a pooled connection is used only once
+        assert!(pool.take(destination).is_none());
+    }
+
+    #[test]
+    fn expire_after_ttl() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination = "127.0.0.1:80".parse().unwrap();
+        let mut pool = ConnectionPool::new(Some(Duration::from_secs(0)));
+
+        pool.put(destination, connect(&listener));
+        assert!(pool.take(destination).is_none());
+    }
+}
@@ -17,6 +17,7 @@
 use mio::net::UdpSocket;
 use std::cmp;
 use std::io;
+use std::net::SocketAddr;
 
 pub const MAX_DATAGRAM_LENGTH: usize = 1 << 16;
 
@@ -44,6 +45,70 @@ impl DatagramReceiver for UdpSocket {
     }
 }
 
+// Expose an unconnected UdpSocket (e.g. joined to a multicast group) as DatagramSender and
+// DatagramReceiver, sending to a fixed address and receiving from any
+pub struct SendToAdapter<'a> {
+    socket: &'a UdpSocket,
+    destination: SocketAddr,
+}
+
+impl<'a> SendToAdapter<'a> {
+    pub fn new(socket: &'a UdpSocket, destination: SocketAddr) -> Self {
+        Self {
+            socket,
+            destination,
+        }
+    }
+}
+
+impl<'a> DatagramSender for SendToAdapter<'a> {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send_to(buf, &self.destination)
+    }
+}
+
+impl<'a> DatagramReceiver for SendToAdapter<'a> {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // the sender is lost, the packets to the device always come from the destination
+        self.socket.recv_from(buf).map(|(len, _)| len)
+    }
+}
+
+// Expose an unconnected UdpSocket as DatagramReceiver, discarding the datagrams not coming from the
+// expected source (if any)
+pub struct RecvFromAdapter<'a> {
+    socket: &'a UdpSocket,
+    source: Option<SocketAddr>,
+    rejected: u32,
+}
+
+impl<'a> RecvFromAdapter<'a> {
+    pub fn new(socket: &'a UdpSocket, source: Option<SocketAddr>) -> Self {
+        Self {
+            socket,
+            source,
+            rejected: 0,
+        }
+    }
+
+    /// Number of datagrams discarded because of their source.
+    pub fn rejected(&self) -> u32 {
+        self.rejected
+    }
+}
+
+impl<'a> DatagramReceiver for RecvFromAdapter<'a> {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let (len, from) = self.socket.recv_from(buf)?;
+            if self.source.is_none_or(|source| source == from) {
+                return Ok(len);
+            }
+            self.rejected += 1;
+        }
+    }
+}
+
 // Convert a Read to a DatagramReceiver
 pub struct ReadAdapter<'a, R>
 where
@@ -111,7 +176,7 @@ pub mod tests {
     impl DatagramSender for MockDatagramSocket {
         fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
             let len = cmp::min(self.buf.len(), buf.len());
-            &mut self.buf[..len].copy_from_slice(&buf[..len]);
+            self.buf[..len].copy_from_slice(&buf[..len]);
             self.len = len;
             Ok(len)
         }
@@ -120,7 +185,7 @@ pub mod tests {
     impl DatagramReceiver for MockDatagramSocket {
         fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
             let len = cmp::min(self.len, buf.len());
-            &mut buf[..len].copy_from_slice(&self.buf[..len]);
+            buf[..len].copy_from_slice(&self.buf[..len]);
             Ok(len)
         }
     }
@@ -143,6 +208,29 @@ pub mod tests {
         assert_eq!([1, 2, 3, 4, 5], &buf[..5]);
     }
 
+    #[test]
+    fn recv_from_expected_source() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::from_socket(socket).unwrap();
+        let address = receiver.local_addr().unwrap();
+        let expected = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let other = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        // datagrams on the loopback are queued before send_to() returns
+        other.send_to(&[1, 2], address).unwrap();
+        expected.send_to(&[3, 4, 5], address).unwrap();
+
+        let source = expected.local_addr().unwrap();
+        let mut adapter = RecvFromAdapter::new(&receiver, Some(source));
+        let mut buf = [0u8; 10];
+        let recved = adapter.recv(&mut buf).unwrap();
+        assert_eq!(3, recved);
+        assert_eq!([3, 4, 5], &buf[..3]);
+        assert_eq!(1, adapter.rejected());
+
+        let err = adapter.recv(&mut buf).unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+    }
+
     #[test]
     fn read_adapter() {
         let mut cursor = io::Cursor::new([1, 2, 3, 4, 5]);
@@ -84,10 +84,7 @@ impl DatagramBuffer {
                 target: TAG,
                 "Cannot write the whole datagram to the buffer (only {}/{})", w, length
             );
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Cannot write the whole datagram",
-            ));
+            return Err(io::Error::other("Cannot write the whole datagram"));
         }
         Ok(())
     }
@@ -100,10 +97,7 @@ impl DatagramBuffer {
             MAX_DATAGRAM_LENGTH
         );
         if !self.has_enough_space_for(length) {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Datagram buffer is full",
-            ));
+            return Err(io::Error::other("Datagram buffer is full"));
         }
         self.write_length(length as u16);
         let target_slice = &mut self.buf[self.head..self.head + length];
@@ -0,0 +1,40 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use std::net::SocketAddrV4;
+
+use super::ipv4_header::Protocol;
+
+/// Hook to choose the address actually connected to for each new connection (e.g. for
+/// split-tunnel routing, testing or redirection).
+///
+/// The default implementation connects to the destination requested by the device.
+pub trait DestinationResolver: fmt::Debug {
+    /// Return the address to connect to, or `None` to refuse the connection.
+    ///
+    /// `device` and `destination` are the addresses of the connection as seen from the device,
+    /// except that 10.0.2.2 is already rewritten to localhost.
+    fn resolve(
+        &self,
+        protocol: Protocol,
+        device: SocketAddrV4,
+        destination: SocketAddrV4,
+    ) -> Option<SocketAddrV4> {
+        let _ = (protocol, device);
+        Some(destination)
+    }
+}
@@ -0,0 +1,262 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use std::net::{IpAddr, SocketAddrV4};
+
+use super::payload_rewriter::{Direction, PayloadRewriter};
+
+const DNS_PORT: u16 = 53;
+const HEADER_LENGTH: usize = 12;
+const FLAG_RESPONSE: u16 = 0x8000;
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+// a valid name never needs more compression pointers than it has labels
+const MAX_POINTERS: usize = 64;
+
+/// Rewrite the addresses of the A and AAAA answers for given names in the DNS responses relayed
+/// to the device (split-horizon DNS).
+///
+/// Each record keeps its length, so only the addresses are replaced (the relay recomputes the
+/// UDP length and checksum). Responses without any matching answer are relayed unchanged.
+#[derive(Debug, Default)]
+pub struct DnsRewriter {
+    // names are stored in lowercase, without trailing dot
+    table: Vec<(String, IpAddr)>,
+}
+
+impl DnsRewriter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Answer `addr` for `name` (to A queries for an IPv4 address, to AAAA queries for an IPv6
+    /// address).
+    pub fn add(&mut self, name: &str, addr: IpAddr) {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.table.push((name, addr));
+    }
+
+    fn lookup(&self, name: &str, record_type: u16) -> Option<IpAddr> {
+        self.table
+            .iter()
+            .find(|(entry, addr)| {
+                let type_matches = match addr {
+                    IpAddr::V4(_) => record_type == TYPE_A,
+                    IpAddr::V6(_) => record_type == TYPE_AAAA,
+                };
+                type_matches && entry.eq_ignore_ascii_case(name)
+            })
+            .map(|&(_, addr)| addr)
+    }
+
+    /// Return the rewritten message, or `None` if it is not a valid response or no answer
+    /// matched.
+    fn rewrite_response(&self, message: &[u8]) -> Option<Vec<u8>> {
+        if message.len() < HEADER_LENGTH || BigEndian::read_u16(&message[2..]) & FLAG_RESPONSE == 0
+        {
+            return None;
+        }
+        let question_count = BigEndian::read_u16(&message[4..]);
+        let answer_count = BigEndian::read_u16(&message[6..]);
+
+        let mut offset = HEADER_LENGTH;
+        for _ in 0..question_count {
+            let (_, next) = read_name(message, offset)?;
+            offset = next + 4; // type and class
+        }
+
+        let mut rewritten: Option<Vec<u8>> = None;
+        for _ in 0..answer_count {
+            let (name, next) = read_name(message, offset)?;
+            let fields = message.get(next..next + 10)?;
+            let record_type = BigEndian::read_u16(&fields[0..]);
+            let class = BigEndian::read_u16(&fields[2..]);
+            let data_length = BigEndian::read_u16(&fields[8..]) as usize;
+            let data_start = next + 10;
+            let data_end = data_start + data_length;
+            if data_end > message.len() {
+                return None;
+            }
+            if class == CLASS_IN {
+                if let Some(addr) = self.lookup(&name, record_type) {
+                    let octets = match addr {
+                        IpAddr::V4(addr) => addr.octets().to_vec(),
+                        IpAddr::V6(addr) => addr.octets().to_vec(),
+                    };
+                    if octets.len() == data_length {
+                        let data = rewritten.get_or_insert_with(|| message.to_vec());
+                        data[data_start..data_end].copy_from_slice(&octets);
+                    }
+                }
+            }
+            offset = data_end;
+        }
+        rewritten
+    }
+}
+
+impl PayloadRewriter for DnsRewriter {
+    fn rewrite(
+        &self,
+        direction: Direction,
+        _device: SocketAddrV4,
+        remote: SocketAddrV4,
+        payload: &[u8],
+    ) -> Option<Vec<u8>> {
+        if direction != Direction::ToDevice || remote.port() != DNS_PORT {
+            return None;
+        }
+        self.rewrite_response(payload)
+    }
+}
+
+/// Read the (possibly compressed) name at `offset`, and return it along with the offset
+/// following it.
+fn read_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    // the offset following the name where it was first read (before any pointer)
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let length = *message.get(offset)? as usize;
+        if length & 0xC0 == 0xC0 {
+            let pointer = BigEndian::read_u16(message.get(offset..offset + 2)?) & 0x3FFF;
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return None;
+            }
+            end.get_or_insert(offset + 2);
+            offset = pointer as usize;
+        } else if length == 0 {
+            return Some((name, end.unwrap_or(offset + 1)));
+        } else {
+            let label = message.get(offset + 1..offset + 1 + length)?;
+            if !name.is_empty() {
+                name.push('.');
+            }
+            name.push_str(&String::from_utf8_lossy(label));
+            offset += 1 + length;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn write_name(raw: &mut Vec<u8>, name: &str) {
+        for label in name.split('.') {
+            raw.push(label.len() as u8);
+            raw.extend_from_slice(label.as_bytes());
+        }
+        raw.push(0);
+    }
+
+    fn write_record_header(raw: &mut Vec<u8>, record_type: u16, data_length: u16) {
+        raw.write_u16::<BigEndian>(record_type).unwrap();
+        raw.write_u16::<BigEndian>(CLASS_IN).unwrap();
+        raw.write_u32::<BigEndian>(300).unwrap(); // TTL
+        raw.write_u16::<BigEndian>(data_length).unwrap();
+    }
+
+    fn create_response() -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u16::<BigEndian>(0x1234).unwrap(); // id
+        raw.write_u16::<BigEndian>(0x8180).unwrap(); // flags: standard response
+        raw.write_u16::<BigEndian>(1).unwrap(); // questions
+        raw.write_u16::<BigEndian>(3).unwrap(); // answers
+        raw.write_u16::<BigEndian>(0).unwrap(); // authority records
+        raw.write_u16::<BigEndian>(0).unwrap(); // additional records
+
+        write_name(&mut raw, "intranet.example.com");
+        raw.write_u16::<BigEndian>(TYPE_A).unwrap();
+        raw.write_u16::<BigEndian>(CLASS_IN).unwrap();
+
+        // A record for the name of the question (compressed)
+        raw.write_u16::<BigEndian>(0xC000 | HEADER_LENGTH as u16)
+            .unwrap();
+        write_record_header(&mut raw, TYPE_A, 4);
+        raw.extend_from_slice(&[93, 184, 216, 34]);
+
+        // AAAA record for the same name, without mapping
+        raw.write_u16::<BigEndian>(0xC000 | HEADER_LENGTH as u16)
+            .unwrap();
+        write_record_header(&mut raw, TYPE_AAAA, 16);
+        raw.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+
+        // A record for another name
+        write_name(&mut raw, "www.example.com");
+        write_record_header(&mut raw, TYPE_A, 4);
+        raw.extend_from_slice(&[93, 184, 216, 35]);
+        raw
+    }
+
+    fn create_rewriter() -> DnsRewriter {
+        let mut rewriter = DnsRewriter::new();
+        rewriter.add("Intranet.Example.com.", Ipv4Addr::new(10, 0, 0, 42).into());
+        rewriter
+    }
+
+    fn rewrite(rewriter: &DnsRewriter, direction: Direction, payload: &[u8]) -> Option<Vec<u8>> {
+        let device = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 40000);
+        let remote = SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), DNS_PORT);
+        rewriter.rewrite(direction, device, remote, payload)
+    }
+
+    #[test]
+    fn rewrite_configured_name() {
+        let response = create_response();
+        let rewritten = rewrite(&create_rewriter(), Direction::ToDevice, &response).unwrap();
+        assert_eq!(response.len(), rewritten.len());
+
+        let first_address = response.len() - 31 - 28 - 4;
+        assert_eq!([10, 0, 0, 42], rewritten[first_address..first_address + 4]);
+        // everything else, including the other records, is unchanged
+        assert_eq!(response[..first_address], rewritten[..first_address]);
+        assert_eq!(
+            response[first_address + 4..],
+            rewritten[first_address + 4..]
+        );
+    }
+
+    #[test]
+    fn ignore_unmatched_responses() {
+        let response = create_response();
+        let rewriter = DnsRewriter::new();
+        assert!(rewrite(&rewriter, Direction::ToDevice, &response).is_none());
+
+        // queries are never rewritten
+        let rewriter = create_rewriter();
+        assert!(rewrite(&rewriter, Direction::ToNetwork, &response).is_none());
+        let mut query = response.clone();
+        query[2] &= 0x7F;
+        assert!(rewrite(&rewriter, Direction::ToDevice, &query).is_none());
+
+        // truncated responses are relayed unchanged
+        let truncated = &response[..response.len() - 2];
+        assert!(rewrite(&rewriter, Direction::ToDevice, truncated).is_none());
+    }
+
+    #[test]
+    fn reject_pointer_loop() {
+        let raw = [0xC0, 0x00];
+        assert!(read_name(&raw, 0).is_none());
+    }
+}
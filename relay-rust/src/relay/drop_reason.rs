@@ -0,0 +1,239 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cell::Cell;
+use std::fmt;
+
+use super::parse_error::ParseError;
+
+/// Reason why a packet is not relayed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    /// The packet could not be parsed.
+    Malformed,
+    /// The IP version is not 4.
+    BadVersion,
+    /// The TCP data offset is below the minimum or beyond the packet.
+    BadTcpOffset,
+    /// The transport protocol is neither TCP nor UDP.
+    UnsupportedProtocol,
+    TcpDisabled,
+    UdpDisabled,
+    Broadcast,
+    /// The packet contains a rejected IPv4 option.
+    IpOption,
+    Fragment,
+    /// The packet targets the relay itself.
+    RelayLoop,
+    /// The TCP destination port is not in the allowed list.
+    PortNotAllowed,
+    HalfOpenLimit,
+    /// The segment belongs to a recently closed TCP connection.
+    LateSegment,
+    SourceConnLimit,
+    ClientConnLimit,
+    /// Too many connections were opened to the destination address recently.
+    DstRateLimited,
+    /// Opening a connection would leave too few file descriptors.
+    FdExhausted,
+    /// The connection to the destination could not be created.
+    ConnectFailed,
+    /// The UDP payload exceeds the configured maximum, in either direction.
+    UdpOversize,
+    /// A datagram from the network does not come from the destination of its UDP connection.
+    UdpSourceMismatch,
+    /// The client is draining, it may not open new connections.
+    ClientDraining,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 21] = [
+        DropReason::Malformed,
+        DropReason::BadVersion,
+        DropReason::BadTcpOffset,
+        DropReason::UnsupportedProtocol,
+        DropReason::TcpDisabled,
+        DropReason::UdpDisabled,
+        DropReason::Broadcast,
+        DropReason::IpOption,
+        DropReason::Fragment,
+        DropReason::RelayLoop,
+        DropReason::PortNotAllowed,
+        DropReason::HalfOpenLimit,
+        DropReason::LateSegment,
+        DropReason::SourceConnLimit,
+        DropReason::ClientConnLimit,
+        DropReason::DstRateLimited,
+        DropReason::FdExhausted,
+        DropReason::ConnectFailed,
+        DropReason::UdpOversize,
+        DropReason::UdpSourceMismatch,
+        DropReason::ClientDraining,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            DropReason::Malformed => "malformed",
+            DropReason::BadVersion => "bad version",
+            DropReason::BadTcpOffset => "bad TCP offset",
+            DropReason::UnsupportedProtocol => "unsupported protocol",
+            DropReason::TcpDisabled => "TCP disabled",
+            DropReason::UdpDisabled => "UDP disabled",
+            DropReason::Broadcast => "broadcast",
+            DropReason::IpOption => "IP option",
+            DropReason::Fragment => "fragment",
+            DropReason::RelayLoop => "relay loop",
+            DropReason::PortNotAllowed => "port not allowed",
+            DropReason::HalfOpenLimit => "half-open limit",
+            DropReason::LateSegment => "late segment",
+            DropReason::SourceConnLimit => "source connection limit",
+            DropReason::ClientConnLimit => "client connection limit",
+            DropReason::DstRateLimited => "destination rate limited",
+            DropReason::FdExhausted => "file descriptors exhausted",
+            DropReason::ConnectFailed => "connect failed",
+            DropReason::UdpOversize => "UDP oversize",
+            DropReason::UdpSourceMismatch => "UDP source mismatch",
+            DropReason::ClientDraining => "client draining",
+        }
+    }
+
+    /// The name as an identifier (e.g. "half_open_limit"), for the exported stats.
+    pub fn key(self) -> String {
+        self.name().to_ascii_lowercase().replace([' ', '-'], "_")
+    }
+}
+
+impl From<&ParseError> for DropReason {
+    fn from(err: &ParseError) -> Self {
+        match *err {
+            ParseError::InvalidVersion(_) => DropReason::BadVersion,
+            _ => DropReason::Malformed,
+        }
+    }
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Count the dropped packets by reason.
+///
+/// The counters are shared (through an `Rc`) between a router and its connections, so they may be
+/// incremented without borrowing the router mutably.
+#[derive(Default)]
+pub struct DropCounters {
+    counts: [Cell<u64>; DropReason::ALL.len()],
+}
+
+impl DropCounters {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn record(&self, reason: DropReason) {
+        let count = &self.counts[reason as usize];
+        count.set(count.get() + 1);
+    }
+
+    pub fn get(&self, reason: DropReason) -> u64 {
+        self.counts[reason as usize].get()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().map(Cell::get).sum()
+    }
+
+    pub fn reset(&self) {
+        for count in &self.counts {
+            count.set(0);
+        }
+    }
+
+    /// The count of every reason, in the order of `DropReason::ALL`.
+    pub fn snapshot(&self) -> Vec<(DropReason, u64)> {
+        DropReason::ALL
+            .iter()
+            .map(|&reason| (reason, self.get(reason)))
+            .collect()
+    }
+}
+
+impl fmt::Display for DropCounters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut first = true;
+        for &reason in &DropReason::ALL {
+            let count = self.get(reason);
+            if count > 0 {
+                if !first {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}: {}", reason, count)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_all_reasons() {
+        for (i, &reason) in DropReason::ALL.iter().enumerate() {
+            // the counters are indexed by discriminant
+            assert_eq!(i, reason as usize);
+        }
+        assert_eq!("half_open_limit", DropReason::HalfOpenLimit.key());
+        assert_eq!("udp_source_mismatch", DropReason::UdpSourceMismatch.key());
+    }
+
+    #[test]
+    fn reason_of_parse_errors() {
+        for &version in &[0u8, 6, 0xf] {
+            let err = ParseError::InvalidVersion(version);
+            assert_eq!(DropReason::BadVersion, DropReason::from(&err));
+        }
+        assert_eq!(
+            DropReason::Malformed,
+            DropReason::from(&ParseError::Truncated)
+        );
+    }
+
+    #[test]
+    fn count_by_reason() {
+        let counters = DropCounters::new();
+        assert_eq!(0, counters.total());
+        assert_eq!("", counters.to_string());
+
+        counters.record(DropReason::Fragment);
+        counters.record(DropReason::Malformed);
+        counters.record(DropReason::Fragment);
+        assert_eq!(2, counters.get(DropReason::Fragment));
+        assert_eq!(1, counters.get(DropReason::Malformed));
+        assert_eq!(0, counters.get(DropReason::IpOption));
+        assert_eq!(3, counters.total());
+        assert_eq!("malformed: 1, fragment: 2", counters.to_string());
+
+        counters.reset();
+        assert_eq!(0, counters.total());
+        counters.record(DropReason::Fragment);
+        assert_eq!(1, counters.get(DropReason::Fragment));
+    }
+}
@@ -0,0 +1,143 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::time::{Duration, Instant};
+
+// the bucket holds one second of tokens
+const BURST: Duration = Duration::from_secs(1);
+
+/// Limit the number of bytes per second, shared by all the clients.
+///
+/// This is a token bucket, tracked as the instant at which the consumed tokens are paid back. A
+/// packet is allowed as long as some tokens remain, so the last one may leave the bucket in debt.
+pub struct EgressLimiter {
+    rate: u32,
+    // the instant at which the bucket is full again, if it has been used
+    paid_until: Option<Instant>,
+    // start of the current 1-second measurement window, if any
+    window_start: Option<Instant>,
+    window_bytes: u64,
+    // bytes consumed during the previous window
+    last_window_bytes: u64,
+}
+
+impl EgressLimiter {
+    pub fn new(rate: u32) -> Self {
+        assert!(rate > 0, "Egress rate must not be 0");
+        Self {
+            rate,
+            paid_until: None,
+            window_start: None,
+            window_bytes: 0,
+            last_window_bytes: 0,
+        }
+    }
+
+    /// Indicate whether a packet is allowed at `now`, without consuming it.
+    pub fn is_available(&self, now: Instant) -> bool {
+        self.delay(now) == Duration::from_secs(0)
+    }
+
+    /// Account for `bytes` sent at `now`.
+    pub fn consume(&mut self, now: Instant, bytes: usize) {
+        let cost = Duration::from_nanos(bytes as u64 * 1_000_000_000 / u64::from(self.rate));
+        let paid_until = match self.paid_until {
+            Some(paid_until) if paid_until > now => paid_until,
+            _ => now,
+        };
+        self.paid_until = Some(paid_until + cost);
+
+        match self.window_start {
+            Some(window_start) if now < window_start + Duration::from_secs(1) => (),
+            window_start => {
+                let consecutive = window_start
+                    .is_some_and(|window_start| now < window_start + Duration::from_secs(2));
+                self.last_window_bytes = if consecutive { self.window_bytes } else { 0 };
+                self.window_start = Some(now);
+                self.window_bytes = 0;
+            }
+        }
+        self.window_bytes += bytes as u64;
+    }
+
+    /// Return the delay before a packet may be allowed.
+    pub fn delay(&self, now: Instant) -> Duration {
+        match self.paid_until {
+            Some(paid_until) => paid_until.saturating_duration_since(now + BURST),
+            None => Duration::from_secs(0),
+        }
+    }
+
+    /// Return the bytes consumed during the last complete second.
+    pub fn rate(&self, now: Instant) -> u64 {
+        match self.window_start {
+            Some(window_start) if now < window_start + Duration::from_secs(1) => {
+                self.last_window_bytes
+            }
+            Some(window_start) if now < window_start + Duration::from_secs(2) => self.window_bytes,
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_then_rate() {
+        let start = Instant::now();
+        let mut limiter = EgressLimiter::new(1000);
+        assert!(limiter.is_available(start));
+
+        // a full bucket, then a packet in debt
+        limiter.consume(start, 600);
+        assert!(limiter.is_available(start));
+        limiter.consume(start, 600);
+        assert!(!limiter.is_available(start));
+        assert_eq!(Duration::from_millis(200), limiter.delay(start));
+
+        let later = start + Duration::from_millis(200);
+        assert!(limiter.is_available(later));
+        limiter.consume(later, 100);
+        assert_eq!(Duration::from_millis(100), limiter.delay(later));
+
+        // the bucket refills while unused
+        let idle = start + Duration::from_secs(10);
+        assert_eq!(Duration::from_secs(0), limiter.delay(idle));
+        limiter.consume(idle, 1000);
+        assert!(limiter.is_available(idle));
+    }
+
+    #[test]
+    fn measure_rate() {
+        let start = Instant::now();
+        let mut limiter = EgressLimiter::new(1000);
+        assert_eq!(0, limiter.rate(start));
+
+        limiter.consume(start, 300);
+        limiter.consume(start + Duration::from_millis(500), 200);
+        // the first window is not complete yet
+        assert_eq!(0, limiter.rate(start + Duration::from_millis(900)));
+        assert_eq!(500, limiter.rate(start + Duration::from_millis(1100)));
+
+        limiter.consume(start + Duration::from_millis(1200), 100);
+        assert_eq!(500, limiter.rate(start + Duration::from_millis(1300)));
+        assert_eq!(100, limiter.rate(start + Duration::from_millis(2300)));
+        // nothing consumed during the last second
+        assert_eq!(0, limiter.rate(start + Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,194 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use super::connection::ConnectionId;
+
+/// Something which happened on the relay, published to the event subscribers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RelayEvent {
+    ClientConnected(u32),
+    ClientDisconnected(u32),
+    ConnectionOpened(ConnectionId),
+    ConnectionClosed(ConnectionId),
+    /// The connections of a client reached `pct` percent of the connection limit.
+    HighWatermark {
+        pct: u8,
+    },
+    /// The connections of a client dropped back below `pct` percent of the connection limit.
+    WatermarkRecovered {
+        pct: u8,
+    },
+}
+
+#[derive(Debug)]
+struct Queue {
+    events: VecDeque<RelayEvent>,
+    // the events discarded because the queue was full
+    dropped: u64,
+}
+
+#[derive(Debug)]
+struct Subscription {
+    queue: Mutex<Queue>,
+    available: Condvar,
+    capacity: usize,
+}
+
+/// The subscriptions to the relay events.
+///
+/// Publishing never blocks the relay: once the queue of a subscriber is full, its oldest event is
+/// discarded to make room for the new one.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscriptions: Vec<Arc<Subscription>>,
+}
+
+impl fmt::Debug for EventBus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // not the pending events
+        write!(
+            f,
+            "EventBus {{ subscriptions: {} }}",
+            self.subscriptions.len()
+        )
+    }
+}
+
+impl EventBus {
+    pub fn subscribe(&mut self, capacity: usize) -> EventReceiver {
+        assert!(capacity > 0, "Event queue capacity must not be 0");
+        let subscription = Arc::new(Subscription {
+            queue: Mutex::new(Queue {
+                events: VecDeque::with_capacity(capacity),
+                dropped: 0,
+            }),
+            available: Condvar::new(),
+            capacity,
+        });
+        self.subscriptions.push(subscription.clone());
+        EventReceiver { subscription }
+    }
+
+    pub fn publish(&self, event: RelayEvent) {
+        for subscription in &self.subscriptions {
+            if Arc::strong_count(subscription) == 1 {
+                // the receiver is dropped
+                continue;
+            }
+            let mut queue = subscription.queue.lock().unwrap();
+            if queue.events.len() == subscription.capacity {
+                queue.events.pop_front();
+                queue.dropped += 1;
+            }
+            queue.events.push_back(event.clone());
+            subscription.available.notify_one();
+        }
+    }
+}
+
+/// The receiving end of a subscription, which may be moved to another thread.
+#[derive(Debug)]
+pub struct EventReceiver {
+    subscription: Arc<Subscription>,
+}
+
+impl EventReceiver {
+    /// Take the oldest pending event, if any.
+    pub fn try_recv(&self) -> Option<RelayEvent> {
+        self.subscription.queue.lock().unwrap().events.pop_front()
+    }
+
+    /// Take the oldest pending event, waiting for one up to `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<RelayEvent> {
+        let queue = self.subscription.queue.lock().unwrap();
+        let (mut queue, _) = self
+            .subscription
+            .available
+            .wait_timeout_while(queue, timeout, |queue| queue.events.is_empty())
+            .unwrap();
+        queue.events.pop_front()
+    }
+
+    /// The number of events discarded so far because this receiver did not keep up.
+    pub fn dropped(&self) -> u64 {
+        self.subscription.queue.lock().unwrap().dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn broadcast_to_every_subscriber() {
+        let mut bus = EventBus::default();
+        let first = bus.subscribe(8);
+        let second = bus.subscribe(8);
+        bus.publish(RelayEvent::ClientConnected(1));
+        assert_eq!(Some(RelayEvent::ClientConnected(1)), first.try_recv());
+        assert_eq!(Some(RelayEvent::ClientConnected(1)), second.try_recv());
+        assert_eq!(None, first.try_recv());
+    }
+
+    #[test]
+    fn drop_oldest_events_of_slow_subscriber() {
+        let mut bus = EventBus::default();
+        let fast = bus.subscribe(2);
+        let slow = bus.subscribe(2);
+        for client_id in 0..5 {
+            bus.publish(RelayEvent::ClientConnected(client_id));
+            assert_eq!(
+                Some(RelayEvent::ClientConnected(client_id)),
+                fast.try_recv()
+            );
+        }
+        assert_eq!(0, fast.dropped());
+
+        // only the most recent events are kept
+        assert_eq!(3, slow.dropped());
+        assert_eq!(Some(RelayEvent::ClientConnected(3)), slow.try_recv());
+        assert_eq!(Some(RelayEvent::ClientConnected(4)), slow.try_recv());
+        assert_eq!(None, slow.try_recv());
+    }
+
+    #[test]
+    fn receive_from_another_thread() {
+        let mut bus = EventBus::default();
+        let receiver = bus.subscribe(1);
+        let handle = thread::spawn(move || receiver.recv_timeout(Duration::from_secs(5)));
+        bus.publish(RelayEvent::ClientDisconnected(2));
+        assert_eq!(
+            Some(RelayEvent::ClientDisconnected(2)),
+            handle.join().unwrap()
+        );
+    }
+
+    #[test]
+    fn skip_dropped_receivers() {
+        let mut bus = EventBus::default();
+        drop(bus.subscribe(1));
+        let receiver = bus.subscribe(1);
+        bus.publish(RelayEvent::ClientConnected(1));
+        assert_eq!(Some(RelayEvent::ClientConnected(1)), receiver.try_recv());
+        assert_eq!(None, receiver.recv_timeout(Duration::from_millis(10)));
+    }
+}
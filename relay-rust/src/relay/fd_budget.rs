@@ -0,0 +1,131 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::time::{Duration, Instant};
+
+/// Soft limit of open file descriptors of the process (`RLIMIT_NOFILE`), `None` if unknown or
+/// unlimited.
+#[cfg(unix)]
+pub fn limit() -> Option<u64> {
+    let mut rlimit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    let result = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit) };
+    if result != 0 || rlimit.rlim_cur == libc::RLIM_INFINITY {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)] // rlim_t is not u64 on every platform
+    Some(rlimit.rlim_cur as u64)
+}
+
+#[cfg(not(unix))]
+pub fn limit() -> Option<u64> {
+    None
+}
+
+/// Number of file descriptors currently open by the process, `None` if unknown.
+pub fn open_count() -> Option<u64> {
+    // on Linux, /dev/fd links to /proc/self/fd
+    let entries = std::fs::read_dir("/dev/fd").ok()?;
+    // the directory itself is open while it is listed
+    Some((entries.count() as u64).saturating_sub(1))
+}
+
+// listing the open descriptors is not free, so it is done at most once per interval
+const LISTING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Estimate of the number of open file descriptors, listed at most once per second.
+///
+/// Between two listings, every connection opened is assumed to take one more descriptor. Closed
+/// connections are only accounted for on the next listing, so the estimate errs on the safe side.
+#[derive(Default)]
+pub struct OpenCount {
+    // the last listed count, with its listing time
+    listed: Option<(u64, Instant)>,
+    // number of connections opened since the last listing
+    opened: u64,
+}
+
+impl OpenCount {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn get(&mut self, now: Instant) -> Option<u64> {
+        let stale = match self.listed {
+            Some((_, listed_at)) => now.duration_since(listed_at) >= LISTING_INTERVAL,
+            None => true,
+        };
+        if stale {
+            self.listed = open_count().map(|count| (count, now));
+            self.opened = 0;
+        }
+        self.listed.map(|(count, _)| count + self.opened)
+    }
+
+    /// Account for a new connection, until the next listing.
+    pub fn opened(&mut self) {
+        self.opened += 1;
+    }
+}
+
+/// Whether opening a new connection would eat into the `headroom` reserved below `limit`.
+pub fn is_exhausted(open: u64, limit: u64, headroom: u64) -> bool {
+    open.saturating_add(headroom) >= limit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserve_headroom() {
+        assert!(!is_exhausted(100, 1024, 32));
+        assert!(!is_exhausted(991, 1024, 32));
+        assert!(is_exhausted(992, 1024, 32));
+        assert!(is_exhausted(2000, 1024, 0));
+        assert!(is_exhausted(1, 1024, u64::MAX));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn estimate_open_descriptors() {
+        let start = Instant::now();
+        let mut open_count = OpenCount::new();
+        let listed = open_count.get(start).unwrap();
+
+        // the descriptors are not listed again within the interval
+        open_count.opened();
+        open_count.opened();
+        assert_eq!(listed + 2, open_count.get(start).unwrap());
+        let later = start + Duration::from_millis(500);
+        assert_eq!(listed + 2, open_count.get(later).unwrap());
+
+        // this test opens no descriptors, but the tests run concurrently
+        let next = start + LISTING_INTERVAL;
+        assert!(open_count.get(next).unwrap() >= 3);
+        assert_eq!(0, open_count.opened);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn count_open_descriptors() {
+        // at least stdin, stdout and stderr
+        assert!(open_count().unwrap() >= 3);
+        assert!(limit().unwrap_or(u64::MAX) >= 3);
+    }
+}
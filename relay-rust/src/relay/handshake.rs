@@ -0,0 +1,131 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::error;
+use std::fmt;
+
+/// Magic sent by the clients supporting the handshake, before any packet.
+///
+/// Its first byte cannot start an IPv4 packet, so that legacy clients (sending raw IP packets
+/// immediately) are still recognized.
+pub const HANDSHAKE_MAGIC: &[u8; 4] = b"\0gnt";
+/// Versions of the client link protocol supported by the relay.
+pub const SUPPORTED_VERSIONS: &[u8] = &[1];
+
+// magic + 1 byte for the version
+const HANDSHAKE_LENGTH: usize = HANDSHAKE_MAGIC.len() + 1;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Handshake {
+    /// The client sends raw IP packets without any handshake.
+    Legacy,
+    /// The client announced a supported version, in a handshake of `length` bytes.
+    Versioned { version: u8, length: usize },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HandshakeError {
+    UnknownMagic,
+    UnsupportedVersion(u8),
+}
+
+/// Parse the handshake at the start of the data received from a client.
+///
+/// Return `Ok(None)` if more data are needed to decide.
+pub fn parse(data: &[u8]) -> Result<Option<Handshake>, HandshakeError> {
+    let first = match data.first() {
+        Some(&first) => first,
+        None => return Ok(None),
+    };
+    if first >> 4 == 4 {
+        // an IPv4 packet, from a client which does not know the handshake
+        return Ok(Some(Handshake::Legacy));
+    }
+    let len = data.len().min(HANDSHAKE_MAGIC.len());
+    if data[..len] != HANDSHAKE_MAGIC[..len] {
+        return Err(HandshakeError::UnknownMagic);
+    }
+    if data.len() < HANDSHAKE_LENGTH {
+        return Ok(None);
+    }
+    let version = data[HANDSHAKE_MAGIC.len()];
+    if !SUPPORTED_VERSIONS.contains(&version) {
+        return Err(HandshakeError::UnsupportedVersion(version));
+    }
+    Ok(Some(Handshake::Versioned {
+        version,
+        length: HANDSHAKE_LENGTH,
+    }))
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HandshakeError::UnknownMagic => write!(f, "Unknown protocol magic"),
+            HandshakeError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported protocol version: {}", version)
+            }
+        }
+    }
+}
+
+impl error::Error for HandshakeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_client() {
+        // start of an IPv4 header
+        let data = [4u8 << 4 | 5, 0, 0, 32];
+        assert_eq!(Ok(Some(Handshake::Legacy)), parse(&data));
+        assert_eq!(Ok(Some(Handshake::Legacy)), parse(&data[..1]));
+    }
+
+    #[test]
+    fn versioned_client() {
+        let mut data = HANDSHAKE_MAGIC.to_vec();
+        data.push(1);
+        data.push(4u8 << 4 | 5); // first packet
+        let expected = Handshake::Versioned {
+            version: 1,
+            length: 5,
+        };
+        assert_eq!(Ok(Some(expected)), parse(&data));
+    }
+
+    #[test]
+    fn incomplete_handshake() {
+        assert_eq!(Ok(None), parse(&[]));
+        assert_eq!(Ok(None), parse(&HANDSHAKE_MAGIC[..2]));
+        assert_eq!(Ok(None), parse(HANDSHAKE_MAGIC));
+    }
+
+    #[test]
+    fn reject_unknown_magic() {
+        assert_eq!(Err(HandshakeError::UnknownMagic), parse(b"\0abc\x01"));
+        // IPv6 is not supported
+        assert_eq!(Err(HandshakeError::UnknownMagic), parse(&[6u8 << 4, 0]));
+    }
+
+    #[test]
+    fn reject_unsupported_version() {
+        let mut data = HANDSHAKE_MAGIC.to_vec();
+        data.push(42);
+        assert_eq!(Err(HandshakeError::UnsupportedVersion(42)), parse(&data));
+    }
+}
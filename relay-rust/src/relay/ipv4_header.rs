@@ -14,7 +14,11 @@
  * limitations under the License.
  */
 
+use super::checksum;
+use super::net;
+use super::parse_error::ParseError;
 use byteorder::{BigEndian, ByteOrder};
+use std::fmt;
 use std::mem;
 
 pub struct Ipv4Header<'a> {
@@ -27,16 +31,34 @@ pub struct Ipv4HeaderMut<'a> {
     data: &'a mut Ipv4HeaderData,
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct Ipv4HeaderData {
     version: u8,
     header_length: u8,
+    dscp: u8,
+    ecn: u8,
     total_length: u16,
+    flags_fragment_offset: u16,
     protocol: Protocol,
     source: u32,
     destination: u32,
 }
 
+const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1FFF;
+
+// ECN codepoints (RFC 3168)
+pub const ECN_NOT_ECT: u8 = 0;
+pub const ECN_CE: u8 = 3;
+
+// IP option types (RFC 791), including the copied flag
+pub const OPTION_RECORD_ROUTE: u8 = 7;
+pub const OPTION_LOOSE_SOURCE_ROUTE: u8 = 0x83;
+pub const OPTION_STRICT_SOURCE_ROUTE: u8 = 0x89;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Protocol {
     Tcp,
@@ -46,11 +68,30 @@ pub enum Protocol {
 
 #[allow(dead_code)]
 impl Ipv4HeaderData {
-    pub fn parse(raw: &[u8]) -> Self {
-        Self {
-            version: raw[0] >> 4,
-            header_length: (raw[0] & 0xf) << 2,
-            total_length: BigEndian::read_u16(&raw[2..4]),
+    /// Parse the IPv4 header at the start of `raw`.
+    pub fn parse(raw: &[u8]) -> Result<Self, ParseError> {
+        if raw.len() < 20 {
+            return Err(ParseError::Truncated);
+        }
+        let version = raw[0] >> 4;
+        if version != 4 {
+            return Err(ParseError::InvalidVersion(version));
+        }
+        let header_length = (raw[0] & 0xf) << 2;
+        let total_length = BigEndian::read_u16(&raw[2..4]);
+        if header_length < 20 || u16::from(header_length) > total_length {
+            return Err(ParseError::InvalidHeaderLength(header_length));
+        }
+        if header_length as usize > raw.len() {
+            return Err(ParseError::Truncated);
+        }
+        Ok(Self {
+            version,
+            header_length,
+            dscp: raw[1] >> 2,
+            ecn: raw[1] & 0x3,
+            total_length,
+            flags_fragment_offset: BigEndian::read_u16(&raw[6..8]),
             protocol: match raw[9] {
                 6 => Protocol::Tcp,
                 17 => Protocol::Udp,
@@ -58,7 +99,7 @@ impl Ipv4HeaderData {
             },
             source: BigEndian::read_u32(&raw[12..16]),
             destination: BigEndian::read_u32(&raw[16..20]),
-        }
+        })
     }
 
     pub fn bind<'c, 'a: 'c, 'b: 'c>(&'a self, raw: &'b [u8]) -> Ipv4Header<'c> {
@@ -69,14 +110,42 @@ impl Ipv4HeaderData {
         Ipv4HeaderMut::new(raw, self)
     }
 
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
     pub fn header_length(&self) -> u8 {
         self.header_length
     }
 
+    /// Differentiated Services Code Point (the 6 first bits of the ToS).
+    pub fn dscp(&self) -> u8 {
+        self.dscp
+    }
+
+    /// Explicit Congestion Notification (the 2 last bits of the ToS).
+    pub fn ecn(&self) -> u8 {
+        self.ecn
+    }
+
     pub fn total_length(&self) -> u16 {
         self.total_length
     }
 
+    pub fn more_fragments(&self) -> bool {
+        self.flags_fragment_offset & FLAG_MORE_FRAGMENTS != 0
+    }
+
+    /// Offset of the fragment in the original datagram, in units of 8 bytes.
+    pub fn fragment_offset(&self) -> u16 {
+        self.flags_fragment_offset & FRAGMENT_OFFSET_MASK
+    }
+
+    /// Whether the packet is a fragment of a larger datagram.
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments() || self.fragment_offset() != 0
+    }
+
     pub fn protocol(&self) -> Protocol {
         self.protocol
     }
@@ -90,6 +159,23 @@ impl Ipv4HeaderData {
     }
 }
 
+impl fmt::Debug for Ipv4HeaderData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Ipv4HeaderData")
+            .field("version", &self.version)
+            .field("header_length", &self.header_length)
+            .field("dscp", &self.dscp)
+            .field("ecn", &self.ecn)
+            .field("total_length", &self.total_length)
+            .field("more_fragments", &self.more_fragments())
+            .field("fragment_offset", &self.fragment_offset())
+            .field("protocol", &self.protocol)
+            .field("source", &net::to_addr(self.source))
+            .field("destination", &net::to_addr(self.destination))
+            .finish()
+    }
+}
+
 pub fn peek_version_length(raw: &[u8]) -> Option<(u8, u16)> {
     if raw.len() >= 4 {
         // version is stored in the 4 first bits
@@ -102,6 +188,31 @@ pub fn peek_version_length(raw: &[u8]) -> Option<(u8, u16)> {
     }
 }
 
+/// Iterate over the types of the IP options (the bytes following the fixed 20-byte header).
+///
+/// The iteration stops at the end-of-options marker or at the first malformed option.
+pub fn option_types(options: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let option_type = *options.get(offset)?;
+        match option_type {
+            OPTION_END => None,
+            OPTION_NOP => {
+                offset += 1;
+                Some(option_type)
+            }
+            _ => {
+                let length = *options.get(offset + 1)? as usize;
+                if length < 2 || offset + length > options.len() {
+                    return None;
+                }
+                offset += length;
+                Some(option_type)
+            }
+        }
+    })
+}
+
 // shared definition for Ipv4Header and Ipv4HeaderMut
 macro_rules! ipv4_header_common {
     ($name:ident, $raw_type:ty, $data_type:ty) => {
@@ -109,10 +220,7 @@ macro_rules! ipv4_header_common {
         #[allow(dead_code)]
         impl<'a> $name<'a> {
             pub fn new(raw: $raw_type, data: $data_type) -> Self {
-                Self {
-                    raw: raw,
-                    data: data,
-                }
+                Self { raw, data }
             }
 
             pub fn raw(&self) -> &[u8] {
@@ -127,6 +235,14 @@ macro_rules! ipv4_header_common {
                 self.data.header_length
             }
 
+            pub fn dscp(&self) -> u8 {
+                self.data.dscp
+            }
+
+            pub fn ecn(&self) -> u8 {
+                self.data.ecn
+            }
+
             pub fn total_length(&self) -> u16 {
                 self.data.total_length
             }
@@ -142,6 +258,14 @@ macro_rules! ipv4_header_common {
             pub fn destination(&self) -> u32 {
                 self.data.destination
             }
+
+            pub fn options(&self) -> &[u8] {
+                &self.raw[20..self.data.header_length as usize]
+            }
+
+            pub fn has_option(&self, option_type: u8) -> bool {
+                option_types(self.options()).any(|t| t == option_type)
+            }
         }
     };
 }
@@ -165,6 +289,11 @@ impl<'a> Ipv4HeaderMut<'a> {
         BigEndian::write_u16(&mut self.raw[2..4], total_length);
     }
 
+    pub fn set_ecn(&mut self, ecn: u8) {
+        self.data.ecn = ecn;
+        self.raw[1] = (self.raw[1] & !0x3) | (ecn & 0x3);
+    }
+
     pub fn set_source(&mut self, source: u32) {
         self.data.source = source;
         BigEndian::write_u32(&mut self.raw[12..16], source);
@@ -191,30 +320,20 @@ impl<'a> Ipv4HeaderMut<'a> {
     }
 
     pub fn update_checksum(&mut self) {
-        let j = self.data.header_length as usize / 2;
-        // skip checksum field at 10..12
-        let mut sum = (0..5)
-            .chain(6..j)
-            .map(|i| {
-                let range = 2 * i..2 * (i + 1);
-                u32::from(BigEndian::read_u16(&self.raw[range]))
-            })
-            .sum::<u32>();
-        while (sum & !0xffff) != 0 {
-            sum = (sum & 0xffff) + (sum >> 16);
-        }
-        self.set_checksum(!sum as u16);
+        let header_length = self.data.header_length as usize;
+        let checksum = checksum::ipv4_checksum(&self.raw[..header_length]);
+        self.set_checksum(checksum);
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::identity_op)]
 mod tests {
     use super::*;
     use byteorder::{BigEndian, WriteBytesExt};
 
     fn create_header() -> Vec<u8> {
-        let mut raw: Vec<u8> = Vec::new();
-        raw.reserve(20);
+        let mut raw: Vec<u8> = Vec::with_capacity(20);
         raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
         raw.write_u8(0).unwrap(); //ToS
         raw.write_u16::<BigEndian>(28).unwrap(); // total length
@@ -230,7 +349,7 @@ mod tests {
     #[test]
     fn parse_header() {
         let raw = &create_header()[..];
-        let data = Ipv4HeaderData::parse(raw);
+        let data = Ipv4HeaderData::parse(raw).unwrap();
         assert_eq!(4, data.version);
         assert_eq!(20, data.header_length);
         assert_eq!(28, data.total_length);
@@ -239,10 +358,119 @@ mod tests {
         assert_eq!(0x42424242, data.destination);
     }
 
+    #[test]
+    fn debug_header() {
+        let raw = &create_header()[..];
+        let data = Ipv4HeaderData::parse(raw).unwrap();
+        let debug = format!("{:?}", data);
+        assert!(debug.contains("protocol: Udp"));
+        assert!(debug.contains("source: 18.52.86.120"));
+        assert!(debug.contains("destination: 66.66.66.66"));
+    }
+
+    #[test]
+    fn reject_other_versions() {
+        for &version in &[0u8, 6, 0xf] {
+            let raw = &mut create_header()[..];
+            raw[0] = version << 4 | 5;
+            let result = Ipv4HeaderData::parse(raw);
+            assert_eq!(ParseError::InvalidVersion(version), result.err().unwrap());
+        }
+    }
+
+    #[test]
+    fn parse_dscp() {
+        let raw = &mut create_header()[..];
+        raw[1] = 0xb8; // Expedited Forwarding, not ECN-capable
+        let data = Ipv4HeaderData::parse(raw).unwrap();
+        assert_eq!(46, data.dscp());
+        assert_eq!(ECN_NOT_ECT, data.ecn());
+    }
+
+    #[test]
+    fn parse_fragment() {
+        let raw = &mut create_header()[..];
+        let data = Ipv4HeaderData::parse(raw).unwrap();
+        assert!(!data.is_fragment());
+
+        // Don't Fragment only
+        raw[6] = 0x40;
+        assert!(!Ipv4HeaderData::parse(raw).unwrap().is_fragment());
+
+        // first fragment
+        raw[6] = 0x20;
+        let data = Ipv4HeaderData::parse(raw).unwrap();
+        assert!(data.more_fragments());
+        assert_eq!(0, data.fragment_offset());
+        assert!(data.is_fragment());
+
+        // last fragment, at offset 185 * 8 = 1480
+        BigEndian::write_u16(&mut raw[6..8], 185);
+        let data = Ipv4HeaderData::parse(raw).unwrap();
+        assert!(!data.more_fragments());
+        assert_eq!(185, data.fragment_offset());
+        assert!(data.is_fragment());
+    }
+
+    #[test]
+    fn set_ecn() {
+        let raw = &mut create_header()[..];
+        raw[1] = 0xb8 | 0x2; // Expedited Forwarding, ECT(0)
+        let mut data = Ipv4HeaderData::parse(raw).unwrap();
+        assert_eq!(0x2, data.ecn());
+
+        data.bind_mut(raw).set_ecn(ECN_CE);
+        assert_eq!(ECN_CE, data.ecn());
+        assert_eq!(0xbb, raw[1]);
+        // the DSCP is left untouched
+        assert_eq!(46, Ipv4HeaderData::parse(raw).unwrap().dscp());
+    }
+
+    fn create_header_with_options(options: &[u8]) -> Vec<u8> {
+        let mut raw = create_header();
+        let header_length = 20 + options.len();
+        assert_eq!(0, header_length % 4);
+        raw[0] = 4u8 << 4 | (header_length / 4) as u8;
+        BigEndian::write_u16(&mut raw[2..4], header_length as u16 + 8);
+        raw.extend_from_slice(options);
+        raw
+    }
+
+    #[test]
+    fn parse_options() {
+        let raw = &create_header()[..];
+        let data = Ipv4HeaderData::parse(raw).unwrap();
+        let header = data.bind(raw);
+        assert!(header.options().is_empty());
+        assert!(!header.has_option(OPTION_LOOSE_SOURCE_ROUTE));
+
+        // NOP, LSRR with one address, end of options
+        let options = [1, 0x83, 7, 4, 10, 0, 0, 1, 0, 0, 0, 0];
+        let raw = &create_header_with_options(&options)[..];
+        let data = Ipv4HeaderData::parse(raw).unwrap();
+        let header = data.bind(raw);
+        assert_eq!(&options, header.options());
+        assert!(header.has_option(OPTION_LOOSE_SOURCE_ROUTE));
+        assert!(!header.has_option(OPTION_STRICT_SOURCE_ROUTE));
+        assert!(!header.has_option(OPTION_RECORD_ROUTE));
+    }
+
+    #[test]
+    fn parse_malformed_options() {
+        // invalid length: the option list stops before the SSRR
+        let options = [0x44, 1, 0x89, 3, 4, 0, 0, 0];
+        let types: Vec<u8> = option_types(&options).collect();
+        assert!(types.is_empty());
+
+        // option length beyond the header
+        let options = [0x89, 12, 4, 0];
+        assert_eq!(0, option_types(&options).count());
+    }
+
     #[test]
     fn edit_header() {
         let raw = &mut create_header()[..];
-        let mut header_data = Ipv4HeaderData::parse(raw);
+        let mut header_data = Ipv4HeaderData::parse(raw).unwrap();
         let mut header = header_data.bind_mut(raw);
 
         header.set_source(0x87654321);
@@ -274,7 +502,7 @@ mod tests {
     #[test]
     fn compute_checksum() {
         let raw = &mut create_header()[..];
-        let mut header_data = Ipv4HeaderData::parse(raw);
+        let mut header_data = Ipv4HeaderData::parse(raw).unwrap();
         let mut header = header_data.bind_mut(raw);
 
         // set a fake checksum value to assert that it is correctly computed
@@ -15,6 +15,7 @@
  */
 
 use super::ipv4_header::{Ipv4Header, Ipv4HeaderData, Ipv4HeaderMut};
+use super::parse_error::ParseError;
 use super::transport_header::{TransportHeader, TransportHeaderData, TransportHeaderMut};
 
 pub const MAX_PACKET_LENGTH: usize = 1 << 16;
@@ -26,17 +27,23 @@ pub struct Ipv4Packet<'a> {
 }
 
 impl<'a> Ipv4Packet<'a> {
-    pub fn parse(raw: &'a mut [u8]) -> Self {
-        let ipv4_header_data = Ipv4HeaderData::parse(raw);
+    pub fn parse(raw: &'a mut [u8]) -> Result<Self, ParseError> {
+        let ipv4_header_data = Ipv4HeaderData::parse(raw)?;
+        if ipv4_header_data.total_length() as usize > raw.len() {
+            return Err(ParseError::Truncated);
+        }
         let transport_header_data = {
-            let payload = &raw[ipv4_header_data.header_length() as usize..];
+            let start = ipv4_header_data.header_length() as usize;
+            let end = ipv4_header_data.total_length() as usize;
+            // the transport header must not exceed the IP total length
+            let payload = &raw[start..end];
             TransportHeaderData::parse(ipv4_header_data.protocol(), payload)
         };
-        Self {
+        Ok(Self {
             raw: &mut raw[..ipv4_header_data.total_length() as usize],
             ipv4_header_data,
             transport_header_data,
-        }
+        })
     }
 
     pub fn new(
@@ -61,7 +68,7 @@ impl<'a> Ipv4Packet<'a> {
         (&self.ipv4_header_data, self.transport_header_data.as_ref())
     }
 
-    pub fn headers(&self) -> (Ipv4Header, Option<TransportHeader>) {
+    pub fn headers(&self) -> (Ipv4Header<'_>, Option<TransportHeader<'_>>) {
         let transport_index = self.ipv4_header_data.header_length() as usize;
         if let Some(ref transport_header_data) = self.transport_header_data {
             let (ipv4_header_slice, transport_slice) = self.raw.split_at(transport_index);
@@ -86,14 +93,14 @@ impl<'a> Ipv4Packet<'a> {
 
     #[inline]
     #[allow(dead_code)]
-    pub fn ipv4_header(&self) -> Ipv4Header {
+    pub fn ipv4_header(&self) -> Ipv4Header<'_> {
         let slice = &self.raw[..self.ipv4_header_data.header_length() as usize];
         self.ipv4_header_data.bind(slice)
     }
 
     #[inline]
     #[allow(dead_code)]
-    pub fn ipv4_header_mut(&mut self) -> Ipv4HeaderMut {
+    pub fn ipv4_header_mut(&mut self) -> Ipv4HeaderMut<'_> {
         let slice = &mut self.raw[..self.ipv4_header_data.header_length() as usize];
         self.ipv4_header_data.bind_mut(slice)
     }
@@ -105,7 +112,7 @@ impl<'a> Ipv4Packet<'a> {
     }
 
     #[inline]
-    pub fn transport_header(&self) -> Option<TransportHeader> {
+    pub fn transport_header(&self) -> Option<TransportHeader<'_>> {
         if let Some(ref transport_header_data) = self.transport_header_data {
             let start = self.ipv4_header_data.header_length() as usize;
             let end = start + transport_header_data.header_length() as usize;
@@ -124,7 +131,7 @@ impl<'a> Ipv4Packet<'a> {
 
     #[inline]
     #[allow(dead_code)]
-    fn transport_header_mut(&mut self) -> Option<TransportHeaderMut> {
+    fn transport_header_mut(&mut self) -> Option<TransportHeaderMut<'_>> {
         if let Some(ref mut transport_header_data) = self.transport_header_data {
             let start = self.ipv4_header_data.header_length() as usize;
             let end = start + transport_header_data.header_length() as usize;
@@ -146,7 +153,7 @@ impl<'a> Ipv4Packet<'a> {
     ///  - the transport header (if any)
     ///  - the payload (if there is a transport at all)
     #[allow(dead_code)]
-    pub fn split(&self) -> (Ipv4Header, Option<(TransportHeader, &[u8])>) {
+    pub fn split(&self) -> (Ipv4Header<'_>, Option<(TransportHeader<'_>, &[u8])>) {
         let transport_index = self.ipv4_header_data.header_length() as usize;
         if let Some(ref transport_header_data) = self.transport_header_data {
             // payload_index is relative to transport
@@ -167,7 +174,12 @@ impl<'a> Ipv4Packet<'a> {
     ///  - the IP v4 header
     ///  - the transport header (if any)
     ///  - the payload (if there is a transport at all)
-    pub fn split_mut(&mut self) -> (Ipv4HeaderMut, Option<(TransportHeaderMut, &mut [u8])>) {
+    pub fn split_mut(
+        &mut self,
+    ) -> (
+        Ipv4HeaderMut<'_>,
+        Option<(TransportHeaderMut<'_>, &mut [u8])>,
+    ) {
         let transport_index = self.ipv4_header_data.header_length() as usize;
         if let Some(ref mut transport_header_data) = self.transport_header_data {
             // payload_index is relative to transport
@@ -195,16 +207,31 @@ impl<'a> Ipv4Packet<'a> {
         self.ipv4_header_data.total_length()
     }
 
-    pub fn payload(&self) -> Option<&[u8]> {
+    /// Return the index of the payload, after both the IPv4 and the transport headers (if there is
+    /// a transport at all).
+    #[inline]
+    fn payload_index(&self) -> Option<usize> {
         self.transport_header_data
             .as_ref()
             .map(|transport_header_data| {
-                let range = self.ipv4_header_data.header_length() as usize
-                    + transport_header_data.header_length() as usize..;
-                &self.raw[range]
+                self.ipv4_header_data.header_length() as usize
+                    + transport_header_data.header_length() as usize
             })
     }
 
+    pub fn payload(&self) -> Option<&[u8]> {
+        self.payload_index().map(move |index| &self.raw[index..])
+    }
+
+    /// Return the transport payload, to be modified in place.
+    ///
+    /// The checksums must be updated afterwards by calling `compute_checksums()`.
+    #[allow(dead_code)]
+    pub fn payload_mut(&mut self) -> Option<&mut [u8]> {
+        self.payload_index()
+            .map(move |index| &mut self.raw[index..])
+    }
+
     pub fn compute_checksums(&mut self) {
         let (mut ipv4_header, transport) = self.split_mut();
         ipv4_header.update_checksum();
@@ -223,14 +250,42 @@ impl<'a> Ipv4Packet<'a> {
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use super::*;
     use crate::relay::ipv4_header::Protocol;
-    use byteorder::{BigEndian, WriteBytesExt};
+    use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+    use rand::random;
+    use std::net::SocketAddrV4;
+
+    // packets to be used in other tests, from 10.0.0.2:1234 to 66.66.66.66 (0x42424242)
+
+    pub fn create_tcp_packet(flags: u16) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(40);
+
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(40).unwrap(); // total length 20 + 20
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(0).unwrap(); // TTL
+        raw.write_u8(6).unwrap(); // protocol (TCP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x0a000002).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x42424242).unwrap(); // destination address
 
-    fn create_packet() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(32);
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(5678).unwrap(); // destination port
+        raw.write_u32::<BigEndian>(0x111).unwrap(); // sequence number
+        raw.write_u32::<BigEndian>(0x222).unwrap(); // acknowledgement number
+        raw.write_u16::<BigEndian>(5 << 12 | flags).unwrap(); // data offset + flags
+        raw.write_u16::<BigEndian>(0).unwrap(); // window
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u16::<BigEndian>(0).unwrap(); // urgent pointer
+
+        raw
+    }
+
+    pub fn create_udp_packet(destination_port: u16) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(32);
 
         raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
         raw.write_u8(0).unwrap(); //ToS
@@ -239,12 +294,12 @@ mod tests {
         raw.write_u8(0).unwrap(); // TTL
         raw.write_u8(17).unwrap(); // protocol (UDP)
         raw.write_u16::<BigEndian>(0).unwrap(); // checksum
-        raw.write_u32::<BigEndian>(0x12345678).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x0a000002).unwrap(); // source address
         raw.write_u32::<BigEndian>(0x42424242).unwrap(); // destination address
 
         raw.write_u16::<BigEndian>(1234).unwrap(); // source port
-        raw.write_u16::<BigEndian>(5678).unwrap(); // destination port
-        raw.write_u16::<BigEndian>(4).unwrap(); // length
+        raw.write_u16::<BigEndian>(destination_port).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(12).unwrap(); // length
         raw.write_u16::<BigEndian>(0).unwrap(); // checksum
 
         raw.write_u32::<BigEndian>(0x11223344).unwrap(); // payload
@@ -252,21 +307,27 @@ mod tests {
         raw
     }
 
+    /// Change the destination of a packet created by `create_tcp_packet()` or
+    /// `create_udp_packet()`.
+    pub fn set_destination(raw: &mut [u8], destination: SocketAddrV4) {
+        raw[16..20].copy_from_slice(&destination.ip().octets());
+        raw[22..24].copy_from_slice(&destination.port().to_be_bytes());
+    }
+
     #[test]
     fn parse_headers() {
-        let raw = &mut create_packet()[..];
-        let ipv4_packet = Ipv4Packet::parse(raw);
+        let raw = &mut create_udp_packet(5678)[..];
+        let ipv4_packet = Ipv4Packet::parse(raw).unwrap();
 
         {
             let ipv4_header = ipv4_packet.ipv4_header();
             assert_eq!(20, ipv4_header.header_length());
             assert_eq!(32, ipv4_header.total_length());
             assert_eq!(Protocol::Udp, ipv4_header.protocol());
-            assert_eq!(0x12345678, ipv4_header.source());
+            assert_eq!(0x0a000002, ipv4_header.source());
             assert_eq!(0x42424242, ipv4_header.destination());
 
-            if let Some(&TransportHeaderData::Udp(ref udp_header)) =
-                ipv4_packet.transport_header_data()
+            if let Some(TransportHeaderData::Udp(udp_header)) = ipv4_packet.transport_header_data()
             {
                 assert_eq!(1234, udp_header.source_port());
                 assert_eq!(5678, udp_header.destination_port());
@@ -276,10 +337,134 @@ mod tests {
         }
     }
 
+    fn create_tcp_packet_with_options() -> Vec<u8> {
+        let mut raw = Vec::with_capacity(56);
+
+        raw.write_u8(4u8 << 4 | 6).unwrap(); // version_and_ihl (with 4 bytes of options)
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(56).unwrap(); // total length 24 + 28 + 4
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(0).unwrap(); // TTL
+        raw.write_u8(6).unwrap(); // protocol (TCP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x12345678).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x42424242).unwrap(); // destination address
+        raw.write_u32::<BigEndian>(0x01010100).unwrap(); // options (NOP, NOP, NOP, EOL)
+
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(5678).unwrap(); // destination port
+        raw.write_u32::<BigEndian>(0x111).unwrap(); // sequence number
+        raw.write_u32::<BigEndian>(0x222).unwrap(); // acknowledgement number
+        raw.write_u16::<BigEndian>(7 << 12).unwrap(); // data offset + flags(0)
+        raw.write_u16::<BigEndian>(0).unwrap(); // window
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u16::<BigEndian>(0).unwrap(); // urgent pointer
+        raw.write_u32::<BigEndian>(0x020405B4).unwrap(); // option MSS = 1460
+        raw.write_u32::<BigEndian>(0x01010101).unwrap(); // options (NOP)
+
+        raw.write_u32::<BigEndian>(0x11223344).unwrap(); // payload
+
+        raw
+    }
+
     #[test]
     fn payload() {
-        let raw = &mut create_packet()[..];
-        let ipv4_packet = Ipv4Packet::parse(raw);
+        let raw = &mut create_udp_packet(5678)[..];
+        let ipv4_packet = Ipv4Packet::parse(raw).unwrap();
+        assert_eq!([0x11, 0x22, 0x33, 0x44], ipv4_packet.payload().unwrap());
+    }
+
+    #[test]
+    fn payload_with_options() {
+        let raw = &mut create_tcp_packet_with_options()[..];
+        let ipv4_packet = Ipv4Packet::parse(raw).unwrap();
+        assert_eq!(24, ipv4_packet.ipv4_header().header_length());
+        assert_eq!(28, ipv4_packet.transport_header().unwrap().header_length());
         assert_eq!([0x11, 0x22, 0x33, 0x44], ipv4_packet.payload().unwrap());
     }
+
+    #[test]
+    fn edit_payload_with_options() {
+        let raw = &mut create_tcp_packet_with_options()[..];
+        {
+            let mut ipv4_packet = Ipv4Packet::parse(raw).unwrap();
+            ipv4_packet
+                .payload_mut()
+                .unwrap()
+                .copy_from_slice(&[0xAA, 0xBB, 0xCC, 0xDD]);
+            assert_eq!([0xAA, 0xBB, 0xCC, 0xDD], ipv4_packet.payload().unwrap());
+            ipv4_packet.compute_checksums();
+        }
+        // the headers must be left untouched (except checksums)
+        assert_eq!(0x020405B4, BigEndian::read_u32(&raw[44..48]));
+        assert_eq!([0xAA, 0xBB, 0xCC, 0xDD], raw[52..]);
+    }
+
+    fn access_all(ipv4_packet: &mut Ipv4Packet) {
+        let _ = ipv4_packet.headers();
+        let _ = ipv4_packet.split();
+        let _ = ipv4_packet.payload();
+        let _ = ipv4_packet.ipv4_header().total_length();
+        if let Some(transport_header) = ipv4_packet.transport_header() {
+            let _ = transport_header.source_port();
+        }
+        if let Some(TransportHeaderData::Tcp(tcp_header_data)) = ipv4_packet.transport_header_data()
+        {
+            let _ = tcp_header_data.reset_reply(0);
+        }
+        ipv4_packet.ipv4_header_mut().swap_source_and_destination();
+        if let Some(mut transport_header) = ipv4_packet.transport_header_mut() {
+            transport_header.swap_source_and_destination();
+        }
+        ipv4_packet.compute_checksums();
+    }
+
+    #[test]
+    fn parse_truncated_packets() {
+        let raw = create_udp_packet(5678);
+        for len in 0..raw.len() {
+            let truncated = &mut raw[..len].to_vec()[..];
+            assert!(Ipv4Packet::parse(truncated).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_random_packets_without_panicking() {
+        for _ in 0..10000 {
+            let len = random::<usize>() % 80;
+            let mut raw: Vec<u8> = (0..len).map(|_| random()).collect();
+            if len >= 20 && random() {
+                // make it look like an IPv4 packet to go further in the parsing, up to the
+                // transport headers
+                raw[0] = (4 << 4) | (raw[0] & 0xf);
+                if random() {
+                    raw[0] = (4 << 4) | 5;
+                    BigEndian::write_u16(&mut raw[2..4], len as u16);
+                    raw[9] = if random() { 6 } else { 17 };
+                }
+            }
+            if let Ok(mut ipv4_packet) = Ipv4Packet::parse(&mut raw[..]) {
+                access_all(&mut ipv4_packet);
+            }
+        }
+    }
+
+    #[test]
+    fn parse_invalid_lengths() {
+        let raw = &mut create_udp_packet(5678)[..];
+        raw[0] = (4 << 4) | 4; // header length 16, below minimum
+        assert_eq!(
+            Some(ParseError::InvalidHeaderLength(16)),
+            Ipv4Packet::parse(raw).err()
+        );
+
+        let raw = &mut create_udp_packet(5678)[..];
+        BigEndian::write_u16(&mut raw[2..4], 33); // exceeds the buffer
+        assert_eq!(Some(ParseError::Truncated), Ipv4Packet::parse(raw).err());
+
+        let raw = &mut create_udp_packet(5678)[..];
+        BigEndian::write_u16(&mut raw[2..4], 24); // too short for the UDP header
+        let ipv4_packet = Ipv4Packet::parse(raw).unwrap();
+        assert!(!ipv4_packet.is_valid());
+    }
 }
@@ -18,18 +18,38 @@ use super::binary;
 use super::byte_buffer::ByteBuffer;
 use super::ipv4_header;
 use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
+use super::parse_error::ParseError;
 
+use byteorder::{BigEndian, ByteOrder};
 use log::*;
 use std::io;
 
+const ETHERNET_HEADER_LENGTH: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+const IPV6_HEADER_LENGTH: usize = 40;
+
 pub struct Ipv4PacketBuffer {
     buf: ByteBuffer,
+    // length of the link-layer header preceding each IP packet
+    link_header_length: usize,
 }
 
 impl Ipv4PacketBuffer {
     pub fn new() -> Self {
         Self {
             buf: ByteBuffer::new(MAX_PACKET_LENGTH),
+            link_header_length: 0,
+        }
+    }
+
+    /// Create a buffer for IP packets preceded by an Ethernet header (as sent by tap devices).
+    ///
+    /// The Ethernet header is stripped before parsing.
+    pub fn with_ethernet_frames() -> Self {
+        Self {
+            buf: ByteBuffer::new(ETHERNET_HEADER_LENGTH + MAX_PACKET_LENGTH),
+            link_header_length: ETHERNET_HEADER_LENGTH,
         }
     }
 
@@ -37,30 +57,84 @@ impl Ipv4PacketBuffer {
         self.buf.read_from(source)
     }
 
-    fn available_packet_length(&self) -> Option<u16> {
+    /// Return the data not consumed yet.
+    pub fn peek(&self) -> &[u8] {
+        self.buf.peek()
+    }
+
+    /// Remove `length` bytes from the front of the buffer, which may not contain packets.
+    pub fn skip(&mut self, length: usize) {
+        self.buf.consume(length);
+    }
+
+    fn ethertype(&self) -> Option<u16> {
+        let data = self.buf.peek();
+        if self.link_header_length == 0 || data.len() < ETHERNET_HEADER_LENGTH {
+            None
+        } else {
+            Some(BigEndian::read_u16(&data[12..14]))
+        }
+    }
+
+    /// Return the length of the packet in front of the buffer (including its link-layer header),
+    /// if it is fully available.
+    fn available_packet_length(&self) -> Result<Option<usize>, ParseError> {
         let data = self.buf.peek();
         trace!("Parse packet: {}", binary::build_packet_string(data));
-        if let Some((version, length)) = ipv4_header::peek_version_length(data) {
-            assert!(version == 4, "Not an Ipv4 packet, version={}", version);
-            if length as usize <= data.len() {
-                // full packet available
-                Some(length)
-            } else {
-                // no full packet available
-                None
+        if data.len() < self.link_header_length {
+            // no packet
+            return Ok(None);
+        }
+        let ip_data = &data[self.link_header_length..];
+        let length = match self.ethertype() {
+            None | Some(ETHERTYPE_IPV4) => {
+                if let Some((version, length)) = ipv4_header::peek_version_length(ip_data) {
+                    if version != 4 {
+                        return Err(ParseError::InvalidVersion(version));
+                    }
+                    if length < 20 {
+                        // the packet boundaries are lost
+                        return Err(ParseError::InvalidTotalLength(length));
+                    }
+                    length as usize
+                } else {
+                    // no packet
+                    return Ok(None);
+                }
+            }
+            Some(ETHERTYPE_IPV6) => {
+                if ip_data.len() < 6 {
+                    // no packet
+                    return Ok(None);
+                }
+                // the payload length is 16 bits starting at offset 4
+                IPV6_HEADER_LENGTH + BigEndian::read_u16(&ip_data[4..6]) as usize
             }
+            Some(ethertype) => return Err(ParseError::InvalidEthertype(ethertype)),
+        };
+        let length = self.link_header_length + length;
+        if length <= data.len() {
+            // full packet available
+            Ok(Some(length))
         } else {
-            // no packet
-            None
+            // no full packet available
+            Ok(None)
         }
     }
 
-    pub fn as_ipv4_packet(&mut self) -> Option<Ipv4Packet> {
-        if self.available_packet_length().is_some() {
+    /// Parse the packet in front of the buffer, if it is fully available.
+    ///
+    /// On error, the packet may be skipped by `next()` unless the error is fatal.
+    pub fn as_ipv4_packet(&mut self) -> Result<Option<Ipv4Packet<'_>>, ParseError> {
+        if self.available_packet_length()?.is_some() {
+            if let Some(ETHERTYPE_IPV6) = self.ethertype() {
+                return Err(ParseError::UnsupportedEthertype(ETHERTYPE_IPV6));
+            }
+            let link_header_length = self.link_header_length;
             let data = self.buf.peek_mut();
-            Some(Ipv4Packet::parse(data))
+            Ipv4Packet::parse(&mut data[link_header_length..]).map(Some)
         } else {
-            None
+            Ok(None)
         }
     }
 
@@ -68,7 +142,9 @@ impl Ipv4PacketBuffer {
         // remove the packet in front of the buffer
         let length = self
             .available_packet_length()
-            .expect("next() called while there was no packet") as usize;
+            .ok()
+            .flatten()
+            .expect("next() called while there was no packet");
         self.buf.consume(length);
     }
 }
@@ -133,8 +209,7 @@ mod tests {
         assert_eq!(0x12345678, ipv4_header.source());
         assert_eq!(0x42424242, ipv4_header.destination());
 
-        if let Some(&TransportHeaderData::Udp(ref udp_header)) = ipv4_packet.transport_header_data()
-        {
+        if let Some(TransportHeaderData::Udp(udp_header)) = ipv4_packet.transport_header_data() {
             assert_eq!(1234, udp_header.source_port());
             assert_eq!(5678, udp_header.destination_port());
         } else {
@@ -150,8 +225,7 @@ mod tests {
         assert_eq!(0x11111111, ipv4_header.source());
         assert_eq!(0x22222222, ipv4_header.destination());
 
-        if let Some(&TransportHeaderData::Udp(ref udp_header)) = ipv4_packet.transport_header_data()
-        {
+        if let Some(TransportHeaderData::Udp(udp_header)) = ipv4_packet.transport_header_data() {
             assert_eq!(1111, udp_header.source_port());
             assert_eq!(2222, udp_header.destination_port());
         } else {
@@ -167,7 +241,7 @@ mod tests {
         let mut cursor = io::Cursor::new(raw);
         packet_buffer.read_from(&mut cursor).unwrap();
 
-        let packet = packet_buffer.as_ipv4_packet().unwrap();
+        let packet = packet_buffer.as_ipv4_packet().unwrap().unwrap();
         check_packet_headers(&packet);
     }
 
@@ -179,12 +253,12 @@ mod tests {
         let mut cursor = io::Cursor::new(&raw[..14]);
         packet_buffer.read_from(&mut cursor).unwrap();
 
-        assert!(packet_buffer.as_ipv4_packet().is_none());
+        assert!(packet_buffer.as_ipv4_packet().unwrap().is_none());
 
         let mut cursor = io::Cursor::new(&raw[14..]);
         packet_buffer.read_from(&mut cursor).unwrap();
 
-        let packet = packet_buffer.as_ipv4_packet().unwrap();
+        let packet = packet_buffer.as_ipv4_packet().unwrap().unwrap();
         check_packet_headers(&packet);
     }
 
@@ -204,13 +278,110 @@ mod tests {
         let mut cursor = io::Cursor::new(raw);
         packet_buffer.read_from(&mut cursor).unwrap();
 
-        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap());
+        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap().unwrap());
+        packet_buffer.next();
+        check_another_packet_headers(&packet_buffer.as_ipv4_packet().unwrap().unwrap());
+        packet_buffer.next();
+        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap().unwrap());
+        packet_buffer.next();
+
+        assert!(packet_buffer.as_ipv4_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn skip_invalid_packet() {
+        let mut raw = Vec::new();
+        write_packet_to(&mut raw);
+        raw[0] = (4 << 4) | 15; // header length 60, exceeding the total length
+        write_another_packet_to(&mut raw);
+        let mut packet_buffer = Ipv4PacketBuffer::new();
+
+        let mut cursor = io::Cursor::new(raw);
+        packet_buffer.read_from(&mut cursor).unwrap();
+
+        let err = packet_buffer.as_ipv4_packet().err().unwrap();
+        assert!(!err.is_fatal());
         packet_buffer.next();
-        check_another_packet_headers(&packet_buffer.as_ipv4_packet().unwrap());
+        check_another_packet_headers(&packet_buffer.as_ipv4_packet().unwrap().unwrap());
+    }
+
+    #[test]
+    fn fail_on_lost_packet_boundaries() {
+        let mut raw = create_packet();
+        raw[0] = 6 << 4;
+        let mut packet_buffer = Ipv4PacketBuffer::new();
+
+        let mut cursor = io::Cursor::new(raw);
+        packet_buffer.read_from(&mut cursor).unwrap();
+
+        let err = packet_buffer.as_ipv4_packet().err().unwrap();
+        assert_eq!(ParseError::InvalidVersion(6), err);
+        assert!(err.is_fatal());
+
+        // garbage
+        let mut raw = create_packet();
+        raw[0] = 0xff;
+        let mut packet_buffer = Ipv4PacketBuffer::new();
+
+        let mut cursor = io::Cursor::new(raw);
+        packet_buffer.read_from(&mut cursor).unwrap();
+
+        let err = packet_buffer.as_ipv4_packet().err().unwrap();
+        assert_eq!(ParseError::InvalidVersion(0xf), err);
+        assert!(err.is_fatal());
+    }
+
+    fn write_ethernet_header_to(raw: &mut Vec<u8>, ethertype: u16) {
+        raw.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]); // destination MAC
+        raw.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02]); // source MAC
+        raw.write_u16::<BigEndian>(ethertype).unwrap();
+    }
+
+    #[test]
+    fn parse_ethernet_frames() {
+        let mut raw = Vec::new();
+        write_ethernet_header_to(&mut raw, ETHERTYPE_IPV4);
+        write_packet_to(&mut raw);
+        // an IPv6 frame (40 bytes header + 2 bytes payload) is skipped
+        write_ethernet_header_to(&mut raw, ETHERTYPE_IPV6);
+        raw.write_u32::<BigEndian>(6 << 28).unwrap();
+        raw.write_u16::<BigEndian>(2).unwrap(); // payload length
+        raw.extend_from_slice(&[0; 36]);
+        write_ethernet_header_to(&mut raw, ETHERTYPE_IPV4);
+        write_another_packet_to(&mut raw);
+
+        let mut packet_buffer = Ipv4PacketBuffer::with_ethernet_frames();
+        let mut cursor = io::Cursor::new(&raw[..]);
+        packet_buffer.read_from(&mut cursor).unwrap();
+
+        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap().unwrap());
         packet_buffer.next();
-        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap());
+        let err = packet_buffer.as_ipv4_packet().err().unwrap();
+        assert_eq!(ParseError::UnsupportedEthertype(ETHERTYPE_IPV6), err);
+        assert!(!err.is_fatal());
         packet_buffer.next();
+        check_another_packet_headers(&packet_buffer.as_ipv4_packet().unwrap().unwrap());
+        packet_buffer.next();
+        assert!(packet_buffer.as_ipv4_packet().unwrap().is_none());
+
+        // without the Ethernet mode, the frame is rejected
+        let mut packet_buffer = Ipv4PacketBuffer::new();
+        let mut cursor = io::Cursor::new(&raw[..]);
+        packet_buffer.read_from(&mut cursor).unwrap();
+        assert!(packet_buffer.as_ipv4_packet().err().unwrap().is_fatal());
+    }
 
-        assert!(packet_buffer.as_ipv4_packet().is_none());
+    #[test]
+    fn reject_invalid_ethertype() {
+        let mut raw = Vec::new();
+        write_ethernet_header_to(&mut raw, 0x0806); // ARP
+        write_packet_to(&mut raw);
+
+        let mut packet_buffer = Ipv4PacketBuffer::with_ethernet_frames();
+        let mut cursor = io::Cursor::new(raw);
+        packet_buffer.read_from(&mut cursor).unwrap();
+        let err = packet_buffer.as_ipv4_packet().err().unwrap();
+        assert_eq!(ParseError::InvalidEthertype(0x0806), err);
+        assert!(err.is_fatal());
     }
 }
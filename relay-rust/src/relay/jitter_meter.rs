@@ -0,0 +1,124 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Measure the inter-arrival jitter (RFC 3550 section 6.4.1) and the rate of a packet flow.
+///
+/// UDP payloads carry no timestamp, so the transit time difference is estimated from the
+/// variation of the inter-arrival intervals.
+#[derive(Default)]
+pub struct JitterMeter {
+    first_arrival: Option<Instant>,
+    last_arrival: Option<Instant>,
+    last_interval: Option<Duration>,
+    // in seconds
+    jitter: f64,
+    packets: u64,
+}
+
+impl JitterMeter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn record(&mut self, now: Instant) {
+        self.packets += 1;
+        if self.first_arrival.is_none() {
+            self.first_arrival = Some(now);
+        }
+        if let Some(last_arrival) = self.last_arrival {
+            let interval = now.saturating_duration_since(last_arrival);
+            if let Some(last_interval) = self.last_interval {
+                let d = (interval.as_secs_f64() - last_interval.as_secs_f64()).abs();
+                self.jitter += (d - self.jitter) / 16.0;
+            }
+            self.last_interval = Some(interval);
+        }
+        self.last_arrival = Some(now);
+    }
+
+    pub fn packets(&self) -> u64 {
+        self.packets
+    }
+
+    pub fn jitter(&self) -> Duration {
+        Duration::from_secs_f64(self.jitter)
+    }
+
+    /// Packets per second between the first and the last arrival.
+    pub fn rate(&self) -> f64 {
+        match (self.first_arrival, self.last_arrival) {
+            (Some(first), Some(last)) if last > first => {
+                (self.packets - 1) as f64 / (last - first).as_secs_f64()
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+impl fmt::Display for JitterMeter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} packets, {:.1} packets/s, jitter {:.1} ms",
+            self.packets(),
+            self.rate(),
+            self.jitter().as_secs_f64() * 1000.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regular_flow() {
+        let start = Instant::now();
+        let mut meter = JitterMeter::new();
+        for i in 0..50 {
+            meter.record(start + Duration::from_millis(20 * i));
+        }
+        assert_eq!(50, meter.packets());
+        assert_eq!(Duration::from_secs(0), meter.jitter());
+        assert!((meter.rate() - 50.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn irregular_flow() {
+        let start = Instant::now();
+        let mut meter = JitterMeter::new();
+        let mut t = start;
+        // alternate intervals of 10 and 30 ms: each interval differs by 20 ms from the previous
+        for i in 0..200 {
+            t += Duration::from_millis(if i % 2 == 0 { 10 } else { 30 });
+            meter.record(t);
+        }
+        let jitter = meter.jitter().as_secs_f64() * 1000.0;
+        assert!(jitter > 19.0 && jitter <= 20.0, "jitter = {} ms", jitter);
+    }
+
+    #[test]
+    fn single_packet() {
+        let mut meter = JitterMeter::new();
+        meter.record(Instant::now());
+        assert_eq!(1, meter.packets());
+        assert_eq!(Duration::from_secs(0), meter.jitter());
+        assert_eq!(0.0, meter.rate());
+    }
+}
@@ -14,31 +14,75 @@
  * limitations under the License.
  */
 
+pub use self::config::{RelayConfig, TuningProfile};
+pub use self::connection::{ConnectionDetail, ConnectionDirection, ConnectionId, ConnectionInfo};
+pub use self::connection_classifier::ConnectionClassifier;
+pub use self::destination_resolver::DestinationResolver;
+#[cfg(feature = "dns-rewrite")]
+pub use self::dns_rewriter::DnsRewriter;
+pub use self::drop_reason::DropReason;
+pub use self::events::{EventReceiver, RelayEvent};
+pub use self::ipv4_header::Protocol;
+pub use self::payload_rewriter::{Direction, PayloadRewriter};
 pub use self::relay::Relay;
+pub use self::stats::{RelayState, ResetScope, RouterStats, RouterStatsDelta, StatsListener};
+pub use self::timer::Timer;
 pub mod byte_buffer;
 
 mod binary;
+pub mod checksum;
 mod client;
 mod close_listener;
+mod config;
+mod congestion_marker;
 #[macro_use]
 mod connection;
+mod connection_classifier;
+mod connection_pool;
 mod datagram;
 mod datagram_buffer;
+mod destination_resolver;
+#[cfg(feature = "dns-rewrite")]
+mod dns_rewriter;
+mod drop_reason;
+mod egress_limiter;
+mod events;
+mod fd_budget;
+mod handshake;
 #[macro_use]
 mod interrupt;
 mod ipv4_header;
 mod ipv4_packet;
 mod ipv4_packet_buffer;
+mod jitter_meter;
+mod json;
 mod net;
+mod out_of_order_queue;
+mod packet_mirror;
+mod packet_size_histogram;
 mod packet_source;
+mod packet_times;
 mod packetizer;
+mod parse_error;
+mod payload_dump;
+mod payload_rewriter;
+mod rate_limiter;
 #[allow(clippy::module_inception)] // relay.rs is in relay/
 mod relay;
 mod router;
 mod selector;
+mod socket_buffers;
+mod socket_priority;
+mod stats;
+mod stats_file;
+#[cfg(feature = "statsd")]
+mod statsd;
 mod stream_buffer;
 mod tcp_connection;
 mod tcp_header;
+mod timer;
+#[cfg(feature = "tls-sni")]
+mod tls_sni;
 mod transport_header;
 mod tunnel_server;
 mod udp_connection;
@@ -26,3 +26,71 @@ pub fn to_socket_addr(ipv4: u32, port: u16) -> SocketAddrV4 {
     let addr = to_addr(ipv4);
     SocketAddrV4::new(addr, port)
 }
+
+/// Whether `addr` belongs to the network `network/prefix_length` (CIDR notation).
+pub fn is_in_network(addr: Ipv4Addr, network: Ipv4Addr, prefix_length: u8) -> bool {
+    if prefix_length == 0 {
+        return true;
+    }
+    let mask = !0u32 << (32 - u32::from(prefix_length.min(32)));
+    u32::from(addr) & mask == u32::from(network) & mask
+}
+
+/// Name of the network interface having the local address `addr`, `None` if unknown.
+#[cfg(unix)]
+pub fn interface_name(addr: Ipv4Addr) -> Option<String> {
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return None;
+    }
+    let mut name = None;
+    let mut current = ifaddrs;
+    while !current.is_null() {
+        // the list is valid until freeifaddrs()
+        let ifaddr = unsafe { &*current };
+        current = ifaddr.ifa_next;
+        if ifaddr.ifa_addr.is_null()
+            || i32::from(unsafe { (*ifaddr.ifa_addr).sa_family }) != libc::AF_INET
+        {
+            continue;
+        }
+        let sockaddr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+        if Ipv4Addr::from(u32::from_be(sockaddr.sin_addr.s_addr)) == addr {
+            let ifa_name = unsafe { std::ffi::CStr::from_ptr(ifaddr.ifa_name) };
+            name = Some(ifa_name.to_string_lossy().into_owned());
+            break;
+        }
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    name
+}
+
+#[cfg(not(unix))]
+pub fn interface_name(_addr: Ipv4Addr) -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_network() {
+        let addr = Ipv4Addr::new(192, 168, 1, 42);
+        assert!(is_in_network(addr, Ipv4Addr::new(192, 168, 1, 0), 24));
+        assert!(is_in_network(addr, Ipv4Addr::new(192, 168, 0, 0), 16));
+        assert!(is_in_network(addr, Ipv4Addr::new(192, 168, 1, 42), 32));
+        assert!(is_in_network(addr, Ipv4Addr::new(0, 0, 0, 0), 0));
+        assert!(!is_in_network(addr, Ipv4Addr::new(192, 168, 2, 0), 24));
+        assert!(!is_in_network(addr, Ipv4Addr::new(192, 168, 1, 43), 32));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn find_loopback_interface() {
+        // "lo" on Linux, "lo0" on macOS
+        let name = interface_name(Ipv4Addr::LOCALHOST).expect("No loopback interface");
+        assert!(name.starts_with("lo"));
+        assert_eq!(None, interface_name(Ipv4Addr::new(192, 0, 2, 1)));
+    }
+}
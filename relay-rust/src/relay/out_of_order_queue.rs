@@ -0,0 +1,234 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::num::Wrapping;
+
+/// Store TCP segments received ahead of the expected sequence number, until the gap is filled.
+///
+/// Both the number of bytes and the number of segments are bounded, so that sparse segments
+/// cannot make it grow without limit.
+pub struct OutOfOrderQueue {
+    segments: Vec<Segment>,
+    size: usize,
+    capacity: usize,
+    max_segments: usize,
+    // number of segments dropped (evicted or refused) because the queue was full
+    dropped: u64,
+}
+
+struct Segment {
+    sequence_number: u32,
+    payload: Vec<u8>,
+}
+
+// position of a sequence number relative to the expected sequence number
+fn offset(sequence_number: u32, expected: u32) -> i64 {
+    i64::from((Wrapping(sequence_number) - Wrapping(expected)).0 as i32)
+}
+
+impl Segment {
+    // position of the end of the segment relative to the expected sequence number
+    fn end_offset(&self, expected: u32) -> i64 {
+        offset(self.sequence_number, expected) + self.payload.len() as i64
+    }
+}
+
+impl OutOfOrderQueue {
+    pub fn new(capacity: usize, max_segments: usize) -> Self {
+        Self {
+            segments: Vec::new(),
+            size: 0,
+            capacity,
+            max_segments,
+            dropped: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Store a segment starting after the `expected` sequence number.
+    ///
+    /// If the queue is full, the segments the most ahead of `expected` are dropped first, so that
+    /// the data which may fill the gap soonest is kept. Return `false` if the segment itself is
+    /// dropped (the client will retransmit it).
+    pub fn insert(&mut self, sequence_number: u32, payload: &[u8], expected: u32) -> bool {
+        if self
+            .segments
+            .iter()
+            .any(|segment| segment.sequence_number == sequence_number)
+        {
+            // retransmission of a segment already stored
+            return true;
+        }
+        let new_offset = offset(sequence_number, expected);
+        while self.size + payload.len() > self.capacity || self.segments.len() >= self.max_segments
+        {
+            let farthest = self
+                .segments
+                .iter()
+                .enumerate()
+                .map(|(index, segment)| (index, offset(segment.sequence_number, expected)))
+                .max_by_key(|&(_, segment_offset)| segment_offset);
+            self.dropped += 1;
+            match farthest {
+                Some((index, segment_offset)) if segment_offset > new_offset => {
+                    let segment = self.segments.swap_remove(index);
+                    self.size -= segment.payload.len();
+                }
+                _ => return false,
+            }
+        }
+        self.size += payload.len();
+        self.segments.push(Segment {
+            sequence_number,
+            payload: payload.to_vec(),
+        });
+        true
+    }
+
+    /// Remove and return the data starting at the expected sequence number, if available.
+    ///
+    /// The segments entirely before `expected` are discarded.
+    pub fn pop(&mut self, expected: u32) -> Option<Vec<u8>> {
+        let mut size = self.size;
+        self.segments.retain(|segment| {
+            let keep = segment.end_offset(expected) > 0;
+            if !keep {
+                size -= segment.payload.len();
+            }
+            keep
+        });
+        self.size = size;
+
+        let index = self.segments.iter().position(|segment| {
+            // the segment overlaps the expected sequence number
+            offset(segment.sequence_number, expected) <= 0
+        })?;
+        let segment = self.segments.swap_remove(index);
+        self.size -= segment.payload.len();
+        let skip = (Wrapping(expected) - Wrapping(segment.sequence_number)).0 as usize;
+        let mut payload = segment.payload;
+        payload.drain(..skip);
+        Some(payload)
+    }
+
+    pub fn clear(&mut self) {
+        self.segments.clear();
+        self.size = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reorder_segments() {
+        let mut queue = OutOfOrderQueue::new(1024, 64);
+        // segments 3 and 2 are received before 1
+        assert!(queue.insert(1008, &[8, 9], 1001));
+        assert!(queue.insert(1004, &[4, 5, 6, 7], 1001));
+        assert_eq!(6, queue.size());
+
+        // the expected segment 1 is received directly
+        let mut expected = 1004u32;
+        let mut received = vec![1, 2, 3];
+
+        while let Some(data) = queue.pop(expected) {
+            expected += data.len() as u32;
+            received.extend(data);
+        }
+        assert_eq!(vec![1, 2, 3, 4, 5, 6, 7, 8, 9], received);
+        assert_eq!(1010, expected);
+        assert!(queue.is_empty());
+        assert_eq!(0, queue.size());
+    }
+
+    #[test]
+    fn overlapping_segment() {
+        let mut queue = OutOfOrderQueue::new(1024, 64);
+        assert!(queue.insert(100, &[0, 1, 2, 3, 4], 99));
+        // the first 2 bytes were already received
+        assert_eq!(Some(vec![2, 3, 4]), queue.pop(102));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn discard_obsolete_segments() {
+        let mut queue = OutOfOrderQueue::new(1024, 64);
+        assert!(queue.insert(100, &[0, 1, 2], 99));
+        assert!(queue.insert(110, &[10, 11], 99));
+        assert_eq!(None, queue.pop(105));
+        // the first segment has been discarded
+        assert_eq!(2, queue.size());
+        assert_eq!(Some(vec![10, 11]), queue.pop(110));
+    }
+
+    #[test]
+    fn wrapping_sequence_numbers() {
+        let mut queue = OutOfOrderQueue::new(1024, 64);
+        assert!(queue.insert(2, &[3, 4], 0xFFFF_FFF0));
+        assert!(queue.insert(0xFFFF_FFFF, &[0, 1, 2], 0xFFFF_FFF0));
+        assert_eq!(Some(vec![0, 1, 2]), queue.pop(0xFFFF_FFFF));
+        assert_eq!(Some(vec![3, 4]), queue.pop(2));
+    }
+
+    #[test]
+    fn bounded_capacity() {
+        let mut queue = OutOfOrderQueue::new(4, 64);
+        assert!(queue.insert(10, &[0, 1, 2], 0));
+        assert!(!queue.insert(20, &[0, 1], 0));
+        assert_eq!(3, queue.size());
+        assert_eq!(1, queue.dropped());
+
+        // a segment closer to the expected sequence number evicts the farthest one
+        assert!(queue.insert(5, &[0, 1], 0));
+        assert_eq!(2, queue.size());
+        assert_eq!(2, queue.dropped());
+        assert_eq!(None, queue.pop(0));
+        assert_eq!(Some(vec![0, 1]), queue.pop(5));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn bounded_segments() {
+        let mut queue = OutOfOrderQueue::new(1024, 2);
+        // sparse single-byte segments
+        assert!(queue.insert(110, &[10], 100));
+        assert!(queue.insert(120, &[20], 100));
+        assert!(!queue.insert(130, &[30], 100));
+        assert_eq!(2, queue.size());
+        assert_eq!(1, queue.dropped());
+
+        assert!(queue.insert(105, &[5], 100));
+        assert_eq!(2, queue.size());
+        assert_eq!(2, queue.dropped());
+        assert_eq!(Some(vec![5]), queue.pop(105));
+        assert_eq!(Some(vec![10]), queue.pop(110));
+        // the farthest segment has been evicted
+        assert!(queue.is_empty());
+    }
+}
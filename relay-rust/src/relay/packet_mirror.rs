@@ -0,0 +1,112 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use log::*;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::payload_rewriter::Direction;
+
+const TAG: &str = "PacketMirror";
+
+// 1 byte for the direction, 8 bytes for the timestamp
+const MIRROR_HEADER_LENGTH: usize = 9;
+
+/// Copy the relayed IP packets to a remote collector over UDP.
+///
+/// Each datagram contains a header followed by the whole IP packet:
+///  - 1 byte: the direction (0 from the device to the network, 1 from the network to the device);
+///  - 8 bytes: the timestamp in microseconds since the Unix epoch (big-endian).
+///
+/// Mirroring is best-effort: the socket is non-blocking, and packets which cannot be sent
+/// immediately are dropped, so that it never slows down the relay.
+pub struct PacketMirror {
+    socket: UdpSocket,
+    collector: SocketAddr,
+}
+
+impl PacketMirror {
+    pub fn create(collector: SocketAddr) -> io::Result<Self> {
+        let local_addr: SocketAddr = match collector {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local_addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, collector })
+    }
+
+    pub fn mirror(&self, direction: Direction, packet: &[u8]) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_micros() as u64);
+        let datagram = Self::encode(direction, timestamp, packet);
+        if let Err(err) = self.socket.send_to(&datagram, self.collector) {
+            if err.kind() == io::ErrorKind::WouldBlock {
+                trace!(target: TAG, "Mirror socket full, dropping packet");
+            } else {
+                debug!(target: TAG, "Cannot mirror packet: {}", err);
+            }
+        }
+    }
+
+    fn encode(direction: Direction, timestamp: u64, packet: &[u8]) -> Vec<u8> {
+        let mut datagram = vec![0; MIRROR_HEADER_LENGTH + packet.len()];
+        datagram[0] = match direction {
+            Direction::ToNetwork => 0,
+            Direction::ToDevice => 1,
+        };
+        BigEndian::write_u64(&mut datagram[1..MIRROR_HEADER_LENGTH], timestamp);
+        datagram[MIRROR_HEADER_LENGTH..].copy_from_slice(packet);
+        datagram
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn encode_header() {
+        let datagram = PacketMirror::encode(Direction::ToDevice, 0x0102_0304_0506_0708, &[42, 43]);
+        assert_eq!(vec![1, 1, 2, 3, 4, 5, 6, 7, 8, 42, 43], datagram);
+
+        let datagram = PacketMirror::encode(Direction::ToNetwork, 0, &[42]);
+        assert_eq!(0, datagram[0]);
+        assert_eq!(10, datagram.len());
+    }
+
+    #[test]
+    fn mirror_to_collector() {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mirror = PacketMirror::create(collector.local_addr().unwrap()).unwrap();
+
+        mirror.mirror(Direction::ToNetwork, &[1, 2, 3, 4]);
+
+        let mut buf = [0; 64];
+        let len = collector.recv(&mut buf).unwrap();
+        assert_eq!(MIRROR_HEADER_LENGTH + 4, len);
+        assert_eq!(0, buf[0]);
+        assert_ne!(0, BigEndian::read_u64(&buf[1..9]));
+        assert_eq!([1, 2, 3, 4], buf[9..13]);
+    }
+}
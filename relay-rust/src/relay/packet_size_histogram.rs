@@ -0,0 +1,104 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+
+// upper bounds (inclusive) of the buckets, the last bucket contains the larger packets
+const BUCKET_BOUNDS: [usize; 7] = [64, 128, 256, 512, 1024, 1500, 4096];
+
+/// Count the packets by size, to help choosing the buffer sizes.
+#[derive(Default)]
+pub struct PacketSizeHistogram {
+    counts: [u64; BUCKET_BOUNDS.len() + 1],
+    bytes: u64,
+}
+
+impl PacketSizeHistogram {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn record(&mut self, length: usize) {
+        let index = BUCKET_BOUNDS
+            .iter()
+            .position(|&bound| length <= bound)
+            .unwrap_or(BUCKET_BOUNDS.len());
+        self.counts[index] += 1;
+        self.bytes += length as u64;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The sum of the sizes of the recorded packets.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    #[allow(dead_code)]
+    fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+}
+
+impl fmt::Display for PacketSizeHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut lower = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match BUCKET_BOUNDS.get(i) {
+                Some(&bound) => {
+                    write!(f, "{}-{}: {}", lower, bound, count)?;
+                    lower = bound + 1;
+                }
+                None => write!(f, "{}+: {}", lower, count)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_in_buckets() {
+        let mut histogram = PacketSizeHistogram::new();
+        histogram.record(20);
+        histogram.record(64);
+        histogram.record(65);
+        histogram.record(1500);
+        histogram.record(1501);
+        histogram.record(0x4000);
+        assert_eq!(&[2, 1, 0, 0, 0, 1, 1, 1], histogram.counts());
+        assert_eq!(6, histogram.total());
+        assert_eq!(20 + 64 + 65 + 1500 + 1501 + 0x4000, histogram.bytes());
+    }
+
+    #[test]
+    fn format_buckets() {
+        let mut histogram = PacketSizeHistogram::new();
+        histogram.record(100);
+        histogram.record(5000);
+        let expected = "0-64: 0, 65-128: 1, 129-256: 0, 257-512: 0, 513-1024: 0, 1025-1500: 0, \
+                        1501-4096: 0, 4097+: 1";
+        assert_eq!(expected, histogram.to_string());
+    }
+}
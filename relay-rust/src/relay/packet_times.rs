@@ -0,0 +1,116 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::time::Instant;
+
+use super::payload_rewriter::Direction;
+
+/// Times of the first and last packets relayed in each direction of a connection, and their
+/// number.
+#[derive(Default)]
+pub struct PacketTimes {
+    to_network: Option<(Instant, Instant)>,
+    to_device: Option<(Instant, Instant)>,
+    // (to network, to device)
+    counts: (u64, u64),
+}
+
+impl PacketTimes {
+    pub fn record(&mut self, direction: Direction, now: Instant) {
+        let (times, count) = match direction {
+            Direction::ToNetwork => (&mut self.to_network, &mut self.counts.0),
+            Direction::ToDevice => (&mut self.to_device, &mut self.counts.1),
+        };
+        *count += 1;
+        match times {
+            Some((_, ref mut last)) => *last = now,
+            None => *times = Some((now, now)),
+        }
+    }
+
+    /// The times of the first and last packets relayed in `direction`, if any.
+    pub fn get(&self, direction: Direction) -> Option<(Instant, Instant)> {
+        match direction {
+            Direction::ToNetwork => self.to_network,
+            Direction::ToDevice => self.to_device,
+        }
+    }
+
+    /// The number of packets relayed in `direction`.
+    pub fn count(&self, direction: Direction) -> u64 {
+        match direction {
+            Direction::ToNetwork => self.counts.0,
+            Direction::ToDevice => self.counts.1,
+        }
+    }
+
+    /// Describe the times relative to `origin` (typically the opening of the connection).
+    pub fn format(&self, origin: Instant) -> String {
+        let format = |times: Option<(Instant, Instant)>| match times {
+            Some((first, last)) => format!(
+                "+{}..+{} ms",
+                first.saturating_duration_since(origin).as_millis(),
+                last.saturating_duration_since(origin).as_millis()
+            ),
+            None => String::from("none"),
+        };
+        format!(
+            "to network: {}; to device: {}",
+            format(self.get(Direction::ToNetwork)),
+            format(self.get(Direction::ToDevice))
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn bracket_activity() {
+        let origin = Instant::now();
+        let at = |ms| origin + Duration::from_millis(ms);
+        let mut times = PacketTimes::default();
+        assert_eq!(None, times.get(Direction::ToNetwork));
+
+        times.record(Direction::ToNetwork, at(10));
+        times.record(Direction::ToDevice, at(25));
+        times.record(Direction::ToNetwork, at(30));
+        times.record(Direction::ToDevice, at(40));
+        times.record(Direction::ToNetwork, at(50));
+
+        assert_eq!(Some((at(10), at(50))), times.get(Direction::ToNetwork));
+        assert_eq!(Some((at(25), at(40))), times.get(Direction::ToDevice));
+        assert_eq!(3, times.count(Direction::ToNetwork));
+        assert_eq!(2, times.count(Direction::ToDevice));
+        assert_eq!(
+            "to network: +10..+50 ms; to device: +25..+40 ms",
+            times.format(origin)
+        );
+    }
+
+    #[test]
+    fn format_inactive_direction() {
+        let origin = Instant::now();
+        let mut times = PacketTimes::default();
+        times.record(Direction::ToNetwork, origin);
+        assert_eq!(
+            "to network: +0..+0 ms; to device: none",
+            times.format(origin)
+        );
+    }
+}
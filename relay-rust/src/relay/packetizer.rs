@@ -66,11 +66,11 @@ impl Packetizer {
         }
     }
 
-    pub fn packetize_empty_payload(&mut self) -> Ipv4Packet {
+    pub fn packetize_empty_payload(&mut self) -> Ipv4Packet<'_> {
         self.build(0)
     }
 
-    pub fn packetize<R: DatagramReceiver>(&mut self, source: &mut R) -> io::Result<Ipv4Packet> {
+    pub fn packetize<R: DatagramReceiver>(&mut self, source: &mut R) -> io::Result<Ipv4Packet<'_>> {
         let r = source.recv(&mut self.buffer[self.payload_index..])?;
         let ipv4_packet = self.build(r as u16);
         Ok(ipv4_packet)
@@ -85,7 +85,7 @@ impl Packetizer {
         &mut self,
         source: &mut R,
         max_chunk_size: Option<usize>,
-    ) -> io::Result<Option<Ipv4Packet>> {
+    ) -> io::Result<Option<Ipv4Packet<'_>>> {
         let mut adapter = ReadAdapter::new(source, max_chunk_size);
         let r = adapter.recv(&mut self.buffer[self.payload_index..])?;
         let option = if r > 0 {
@@ -97,17 +97,31 @@ impl Packetizer {
         Ok(option)
     }
 
-    pub fn ipv4_header_mut(&mut self) -> Ipv4HeaderMut {
+    /// Packetize a payload provided by the caller.
+    pub fn packetize_payload(&mut self, payload: &[u8]) -> io::Result<Ipv4Packet<'_>> {
+        let payload_length = payload.len();
+        if payload_length > MAX_PACKET_LENGTH - self.payload_index {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Payload too large: {} bytes", payload_length),
+            ));
+        }
+        let end = self.payload_index + payload_length;
+        self.buffer[self.payload_index..end].copy_from_slice(payload);
+        Ok(self.build(payload_length as u16))
+    }
+
+    pub fn ipv4_header_mut(&mut self) -> Ipv4HeaderMut<'_> {
         let raw = &mut self.buffer[..self.transport_index];
         self.ipv4_header_data.bind_mut(raw)
     }
 
-    pub fn transport_header_mut(&mut self) -> TransportHeaderMut {
+    pub fn transport_header_mut(&mut self) -> TransportHeaderMut<'_> {
         let raw = &mut self.buffer[self.transport_index..self.payload_index];
         self.transport_header_data.bind_mut(raw)
     }
 
-    fn build(&mut self, payload_length: u16) -> Ipv4Packet {
+    fn build(&mut self, payload_length: u16) -> Ipv4Packet<'_> {
         let total_length = self.payload_index as u16 + payload_length;
 
         self.ipv4_header_mut().set_total_length(total_length);
@@ -123,7 +137,7 @@ impl Packetizer {
         ipv4_packet
     }
 
-    pub fn inflate(&mut self, packet_length: u16) -> Ipv4Packet {
+    pub fn inflate(&mut self, packet_length: u16) -> Ipv4Packet<'_> {
         Ipv4Packet::new(
             &mut self.buffer[..packet_length as usize],
             self.ipv4_header_data.clone(),
@@ -136,7 +150,7 @@ impl Packetizer {
 mod tests {
     use super::*;
     use crate::relay::datagram::tests::MockDatagramSocket;
-    use byteorder::{BigEndian, WriteBytesExt};
+    use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
     use std::io;
 
     fn create_packet() -> Vec<u8> {
@@ -163,7 +177,7 @@ mod tests {
     #[test]
     fn merge_headers_and_payload() {
         let raw = &mut create_packet()[..];
-        let reference_packet = Ipv4Packet::parse(raw);
+        let reference_packet = Ipv4Packet::parse(raw).unwrap();
 
         let data = [0x11u8, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
         let mut mock = MockDatagramSocket::from_data(&data);
@@ -177,10 +191,53 @@ mod tests {
         assert_eq!(data, &packet.raw()[28..36]);
     }
 
+    #[test]
+    fn mirror_addresses() {
+        let raw = &mut create_packet()[..];
+        let reference_packet = Ipv4Packet::parse(raw).unwrap();
+
+        let ipv4_header = reference_packet.ipv4_header();
+        let transport_header = reference_packet.transport_header().unwrap();
+        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header);
+
+        let packet = packetizer.packetize_empty_payload();
+        let (ipv4_header_data, transport_header_data) = packet.headers_data();
+        let transport_header_data = transport_header_data.unwrap();
+        // the response comes from the original destination, to the device
+        assert_eq!(0x42424242, ipv4_header_data.source());
+        assert_eq!(0x12345678, ipv4_header_data.destination());
+        assert_eq!(5678, transport_header_data.source_port());
+        assert_eq!(1234, transport_header_data.destination_port());
+
+        // the raw headers must match
+        let raw = packet.raw();
+        assert_eq!(0x42424242, BigEndian::read_u32(&raw[12..16]));
+        assert_eq!(0x12345678, BigEndian::read_u32(&raw[16..20]));
+        assert_eq!(5678, BigEndian::read_u16(&raw[20..22]));
+        assert_eq!(1234, BigEndian::read_u16(&raw[22..24]));
+    }
+
+    #[test]
+    fn reparse_built_headers() {
+        let raw = &mut create_packet()[..];
+        let reference_packet = Ipv4Packet::parse(raw).unwrap();
+
+        let ipv4_header = reference_packet.ipv4_header();
+        let transport_header = reference_packet.transport_header().unwrap();
+        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header);
+
+        let packet = packetizer.packetize_payload(&[0x11, 0x22]).unwrap();
+        let mut rebuilt_raw = packet.raw().to_vec();
+        let reparsed = Ipv4Packet::parse(&mut rebuilt_raw).unwrap();
+        assert_eq!(packet.headers_data(), reparsed.headers_data());
+        // the response headers differ from the request headers
+        assert_ne!(reference_packet.headers_data(), reparsed.headers_data());
+    }
+
     #[test]
     fn last_packet() {
         let raw = &mut create_packet()[..];
-        let reference_packet = Ipv4Packet::parse(raw);
+        let reference_packet = Ipv4Packet::parse(raw).unwrap();
 
         let data = [0x11u8, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
         let mut mock = MockDatagramSocket::from_data(&data);
@@ -195,10 +252,39 @@ mod tests {
         assert_eq!(data, &packet.raw()[28..36]);
     }
 
+    #[test]
+    fn packetize_payload() {
+        let raw = &mut create_packet()[..];
+        let reference_packet = Ipv4Packet::parse(raw).unwrap();
+
+        let ipv4_header = reference_packet.ipv4_header();
+        let transport_header = reference_packet.transport_header().unwrap();
+        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header);
+
+        let data = [0x11u8, 0x22, 0x33, 0x44, 0x55, 0x66];
+        let packet = packetizer.packetize_payload(&data).unwrap();
+        assert_eq!(34, packet.ipv4_header_data().total_length());
+        assert_eq!(data, packet.payload().unwrap());
+
+        let raw = packet.raw();
+        assert_eq!(34, BigEndian::read_u16(&raw[2..4])); // total length
+        assert_eq!(14, BigEndian::read_u16(&raw[24..26])); // UDP length
+
+        // the IPv4 header checksum must be valid
+        let mut sum = (0..20)
+            .step_by(2)
+            .map(|i| u32::from(BigEndian::read_u16(&raw[i..i + 2])))
+            .sum::<u32>();
+        while (sum & !0xFFFF) != 0 {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+        assert_eq!(0xFFFF, sum);
+    }
+
     #[test]
     fn packetize_chunks() {
         let raw = &mut create_packet()[..];
-        let reference_packet = Ipv4Packet::parse(raw);
+        let reference_packet = Ipv4Packet::parse(raw).unwrap();
 
         let data = [0x11u8, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
         let mut cursor = io::Cursor::new(&data);
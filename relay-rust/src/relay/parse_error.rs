@@ -0,0 +1,72 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::error;
+use std::fmt;
+
+/// Error on parsing a packet received from the device.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// The buffer is shorter than the lengths declared by the headers.
+    Truncated,
+    InvalidVersion(u8),
+    InvalidHeaderLength(u8),
+    InvalidTotalLength(u16),
+    /// The link-layer frame contains neither IPv4 nor IPv6.
+    InvalidEthertype(u16),
+    /// The link-layer frame contains a protocol not supported by the relay.
+    UnsupportedEthertype(u16),
+}
+
+impl ParseError {
+    /// Indicate whether the packet boundaries are lost, so that the following packets of the
+    /// stream cannot be read.
+    pub fn is_fatal(&self) -> bool {
+        match *self {
+            ParseError::InvalidVersion(_)
+            | ParseError::InvalidTotalLength(_)
+            | ParseError::InvalidEthertype(_) => true,
+            ParseError::Truncated
+            | ParseError::InvalidHeaderLength(_)
+            | ParseError::UnsupportedEthertype(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Truncated => write!(f, "Truncated packet"),
+            ParseError::InvalidVersion(version) => {
+                write!(f, "Not an IPv4 packet, version={}", version)
+            }
+            ParseError::InvalidHeaderLength(length) => {
+                write!(f, "Invalid IPv4 header length: {}", length)
+            }
+            ParseError::InvalidTotalLength(length) => {
+                write!(f, "Invalid IPv4 total length: {}", length)
+            }
+            ParseError::InvalidEthertype(ethertype) => {
+                write!(f, "Invalid ethertype: 0x{:04x}", ethertype)
+            }
+            ParseError::UnsupportedEthertype(ethertype) => {
+                write!(f, "Unsupported ethertype: 0x{:04x}", ethertype)
+            }
+        }
+    }
+}
+
+impl error::Error for ParseError {}
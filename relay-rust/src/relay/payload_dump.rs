@@ -0,0 +1,104 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cmp::min;
+use std::fmt::Write;
+
+use super::payload_rewriter::Direction;
+
+/// Capture the first bytes of the payload of a connection in each direction (e.g. a TLS
+/// ClientHello or an HTTP request line), to identify its protocol.
+pub struct PayloadDump {
+    limit: usize,
+    to_network: usize,
+    to_device: usize,
+}
+
+impl PayloadDump {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            to_network: 0,
+            to_device: 0,
+        }
+    }
+
+    /// Return the part of `data` to dump, if the limit is not reached yet in this direction.
+    pub fn capture<'a>(&mut self, direction: Direction, data: &'a [u8]) -> Option<&'a [u8]> {
+        let captured = match direction {
+            Direction::ToNetwork => &mut self.to_network,
+            Direction::ToDevice => &mut self.to_device,
+        };
+        let length = min(self.limit - *captured, data.len());
+        if length == 0 {
+            return None;
+        }
+        *captured += length;
+        Some(&data[..length])
+    }
+}
+
+/// Format bytes as hexadecimal values followed by their printable ASCII characters.
+pub fn format(data: &[u8]) -> String {
+    let mut s = String::new();
+    for (i, &byte) in data.iter().enumerate() {
+        if i != 0 {
+            s.push(' ');
+        }
+        write!(&mut s, "{:02X}", byte).unwrap();
+    }
+    s.push_str("  |");
+    s.extend(data.iter().map(|&byte| {
+        if byte.is_ascii_graphic() || byte == b' ' {
+            byte as char
+        } else {
+            '.'
+        }
+    }));
+    s.push('|');
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_first_bytes() {
+        let mut dump = PayloadDump::new(6);
+        assert_eq!(
+            Some(&b"GET"[..]),
+            dump.capture(Direction::ToNetwork, b"GET")
+        );
+        assert_eq!(
+            Some(&b" / "[..]),
+            dump.capture(Direction::ToNetwork, b" / HTTP/1.1")
+        );
+        assert_eq!(None, dump.capture(Direction::ToNetwork, b"Host: "));
+
+        // directions are independent
+        assert_eq!(
+            Some(&b"HTTP/1"[..]),
+            dump.capture(Direction::ToDevice, b"HTTP/1.1 200")
+        );
+        assert_eq!(None, dump.capture(Direction::ToDevice, b"OK"));
+    }
+
+    #[test]
+    fn format_bytes() {
+        assert_eq!("47 45 54 0D 0A  |GET..|", format(b"GET\r\n"));
+    }
+}
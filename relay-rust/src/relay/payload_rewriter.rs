@@ -0,0 +1,45 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use std::net::SocketAddrV4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// From the device to the network.
+    ToNetwork,
+    /// From the network to the device.
+    ToDevice,
+}
+
+/// Hook to rewrite the payload of UDP datagrams before they are relayed (e.g. to insert a
+/// transparent proxy).
+///
+/// Only UDP payloads are exposed: changing the length of a TCP segment would break the sequence
+/// numbers of an established stream. The lengths and checksums of the datagrams forwarded to the
+/// device are recomputed by the relay.
+pub trait PayloadRewriter: fmt::Debug {
+    /// Return the payload to relay instead of `payload`, or `None` to relay it unchanged.
+    ///
+    /// `device` and `remote` are the addresses of the connection as seen from the device.
+    fn rewrite(
+        &self,
+        direction: Direction,
+        device: SocketAddrV4,
+        remote: SocketAddrV4,
+        payload: &[u8],
+    ) -> Option<Vec<u8>>;
+}
@@ -0,0 +1,104 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::time::{Duration, Instant};
+
+/// Limit the number of events per second.
+pub struct RateLimiter {
+    rate: u32,
+    // start of the current 1-second window, if any
+    window_start: Option<Instant>,
+    count: u32,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            window_start: None,
+            count: 0,
+        }
+    }
+
+    fn window_end(&self) -> Option<Instant> {
+        self.window_start
+            .map(|window_start| window_start + Duration::from_secs(1))
+    }
+
+    /// Indicate whether an event is allowed at `now`, without consuming it.
+    pub fn is_available(&mut self, now: Instant) -> bool {
+        match self.window_end() {
+            Some(window_end) if now < window_end => (),
+            _ => {
+                self.window_start = Some(now);
+                self.count = 0;
+            }
+        }
+        self.count < self.rate
+    }
+
+    /// Account for an event in the current window.
+    pub fn consume(&mut self) {
+        self.count += 1;
+    }
+
+    /// Return the delay before a new event may be available.
+    pub fn delay(&self, now: Instant) -> Duration {
+        match self.window_end() {
+            Some(window_end) if self.count >= self.rate => {
+                window_end.saturating_duration_since(now)
+            }
+            _ => Duration::from_secs(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept_all(limiter: &mut RateLimiter, now: Instant, pending: &mut u32) -> u32 {
+        let mut accepted = 0;
+        while *pending > 0 && limiter.is_available(now) {
+            limiter.consume();
+            *pending -= 1;
+            accepted += 1;
+        }
+        accepted
+    }
+
+    #[test]
+    fn burst_spread_over_time() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(3);
+        let mut pending = 8;
+
+        assert_eq!(3, accept_all(&mut limiter, start, &mut pending));
+        assert!(limiter.delay(start) > Duration::from_millis(900));
+
+        let half = start + Duration::from_millis(500);
+        assert_eq!(0, accept_all(&mut limiter, half, &mut pending));
+        assert_eq!(Duration::from_millis(500), limiter.delay(half));
+
+        let t1 = start + Duration::from_secs(1);
+        assert_eq!(3, accept_all(&mut limiter, t1, &mut pending));
+
+        let t2 = start + Duration::from_secs(2);
+        assert_eq!(2, accept_all(&mut limiter, t2, &mut pending));
+        assert_eq!(0, pending);
+        assert_eq!(Duration::from_secs(0), limiter.delay(t2));
+    }
+}
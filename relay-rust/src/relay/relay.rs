@@ -18,12 +18,21 @@ use chrono::Local;
 use log::*;
 use mio::Events;
 use std::cell::RefCell;
-use std::cmp::max;
+use std::cmp::{max, min};
 use std::io;
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+#[cfg(any(test, feature = "packet-injection"))]
+use super::client::Client;
+use super::config::RelayConfig;
+use super::fd_budget;
 use super::selector::Selector;
+use super::stats::{self, StatsListener};
+use super::stats_file::StatsFile;
+#[cfg(feature = "statsd")]
+use super::statsd::StatsdExporter;
+use super::timer::TimerQueue;
 use super::tunnel_server::TunnelServer;
 use super::udp_connection::IDLE_TIMEOUT_SECONDS;
 
@@ -32,17 +41,46 @@ const CLEANING_INTERVAL_SECONDS: i64 = 60;
 
 pub struct Relay {
     port: u16,
+    config: Rc<RelayConfig>,
+    #[cfg(any(test, feature = "packet-injection"))]
+    injection: RefCell<Option<Injection>>,
 }
 
 impl Relay {
-    pub fn new(port: u16) -> Self {
-        Self { port }
+    pub fn new(port: u16, mut config: RelayConfig) -> Self {
+        config.set_relay_port(port);
+        Self {
+            port,
+            config: Rc::new(config),
+            #[cfg(any(test, feature = "packet-injection"))]
+            injection: RefCell::new(None),
+        }
+    }
+
+    /// Feed a raw IPv4 packet to the relay, as if it was received from a client, and return the
+    /// packets immediately sent back to the client.
+    ///
+    /// The packet is parsed, validated and routed like any packet read from a client, by a single
+    /// client living as long as the relay. Its connections to the network are real.
+    #[cfg(any(test, feature = "packet-injection"))]
+    pub fn inject_client_packet(&self, raw: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        let mut injection = self.injection.borrow_mut();
+        if injection.is_none() {
+            *injection = Some(Injection::new(self.config.clone())?);
+        }
+        injection.as_mut().unwrap().inject(raw)
     }
 
     pub fn run(&self) -> io::Result<()> {
         let mut selector = Selector::create().unwrap();
-        let tunnel_server = TunnelServer::create(self.port, &mut selector)?;
+        let tunnel_server = TunnelServer::create(self.port, self.config.clone(), &mut selector)?;
         info!(target: TAG, "Relay server started");
+        // the effective configuration, once defaults and command line options are resolved
+        info!(target: TAG, "Configuration: {:?}", self.config);
+        if let Some(limit) = fd_budget::limit() {
+            let open = fd_budget::open_count().map_or("?".into(), |open| open.to_string());
+            info!(target: TAG, "File descriptors: {} open, limit {}", open, limit);
+        }
         self.poll_loop(&mut selector, &tunnel_server)
     }
 
@@ -52,20 +90,69 @@ impl Relay {
         tunnel_server: &Rc<RefCell<TunnelServer>>,
     ) -> io::Result<()> {
         let mut events = Events::with_capacity(1024);
-        // no connection may expire before the UDP idle timeout delay
-        let mut next_cleaning_deadline = Local::now().timestamp() + IDLE_TIMEOUT_SECONDS as i64;
+        // no connection may expire before the UDP idle timeout delay or the configured delays
+        let mut first_cleaning_delay = IDLE_TIMEOUT_SECONDS as i64;
+        let mut cleaning_interval = CLEANING_INTERVAL_SECONDS;
+        let timers = [
+            self.config.max_connection_lifetime(),
+            self.config.tcp_keepalive_interval(),
+            self.config.tcp_write_timeout(),
+            self.config.tcp_defer_connect(),
+            self.config.dns_timeout(),
+        ];
+        for delay in timers.iter().flatten() {
+            // a timer must not be late by more than its own delay
+            let delay = max(1, delay.as_secs() as i64);
+            first_cleaning_delay = min(first_cleaning_delay, delay);
+            cleaning_interval = min(cleaning_interval, delay);
+        }
+        let mut next_cleaning_deadline = Local::now().timestamp() + first_cleaning_delay;
+        let mut listeners = self.config.stats_listeners().to_vec();
+        if let Some(path) = self.config.stats_file() {
+            let stats_file: Rc<dyn StatsListener> = Rc::new(StatsFile::new(path));
+            listeners.push((self.config.stats_interval(), stats_file));
+        }
+        #[cfg(feature = "statsd")]
+        if let Some(address) = self.config.statsd_address() {
+            let exporter: Rc<dyn StatsListener> = Rc::new(StatsdExporter::create(address)?);
+            listeners.push((self.config.stats_interval(), exporter));
+        }
+        let now = Instant::now();
+        let mut timer_queue = TimerQueue::new(self.config.timers(), now);
+        let mut listener_queue = TimerQueue::new(&listeners, now);
         loop {
             retry_on_intr!({
                 let timeout_seconds = max(0, next_cleaning_deadline - Local::now().timestamp());
-                let timeout = Some(Duration::new(timeout_seconds as u64, 0));
-                selector.poll(&mut events, timeout)
+                let mut timeout = Duration::new(timeout_seconds as u64, 0);
+                if let Some(accept_delay) = tunnel_server.borrow().accept_delay() {
+                    timeout = min(timeout, accept_delay);
+                }
+                if let Some(egress_delay) = tunnel_server.borrow().egress_delay() {
+                    timeout = min(timeout, egress_delay);
+                }
+                let deadlines = [
+                    timer_queue.next_deadline(),
+                    listener_queue.next_deadline(),
+                    tunnel_server.borrow().drain_deadline(),
+                ];
+                if let Some(&deadline) = deadlines.iter().flatten().min() {
+                    timeout = min(timeout, deadline.saturating_duration_since(Instant::now()));
+                }
+                selector.poll(&mut events, Some(timeout))
             })?;
 
+            let accepted = tunnel_server.borrow_mut().accept_deferred_clients(selector);
+            let resumed = TunnelServer::resume_egress(tunnel_server, selector);
+            let now = Instant::now();
+            let fired = timer_queue.fire_expired(now)
+                + stats::fire_expired_listeners(&mut listener_queue, now, tunnel_server, selector);
+            let drained = TunnelServer::close_drained_clients(tunnel_server, selector);
+
             let now = Local::now().timestamp();
             if now >= next_cleaning_deadline {
                 tunnel_server.borrow_mut().clean_up(selector);
-                next_cleaning_deadline = now + CLEANING_INTERVAL_SECONDS;
-            } else if events.is_empty() {
+                next_cleaning_deadline = now + cleaning_interval;
+            } else if events.is_empty() && !accepted && !resumed && fired == 0 && drained == 0 {
                 debug!(
                     target: TAG,
                     "Spurious wakeup: poll() returned without any event"
@@ -77,3 +164,151 @@ impl Relay {
         }
     }
 }
+
+// A client without a transport: the packets are injected directly, the packets sent back are read
+// from the device end of its stream
+#[cfg(any(test, feature = "packet-injection"))]
+struct Injection {
+    selector: Selector,
+    client: Rc<RefCell<Client>>,
+    device: std::net::TcpStream,
+    received: Vec<u8>,
+    // number of remaining bytes of the client id, sent first
+    pending_id_bytes: usize,
+}
+
+#[cfg(any(test, feature = "packet-injection"))]
+impl Injection {
+    // wait a bit for the events triggered by an injected packet (e.g. a local connection completed)
+    const SETTLE_TIMEOUT: Duration = Duration::from_millis(10);
+
+    fn new(config: Rc<RelayConfig>) -> io::Result<Self> {
+        use mio::net::TcpStream;
+        use std::net::{Ipv4Addr, TcpListener};
+
+        let mut selector = Selector::create()?;
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let device = std::net::TcpStream::connect(listener.local_addr()?)?;
+        device.set_nonblocking(true)?;
+        let stream = TcpStream::from_stream(listener.accept()?.0)?;
+        let client = Client::create(
+            0,
+            &mut selector,
+            stream,
+            config,
+            None,
+            None,
+            Box::new(|_: &Client| {}),
+        )?;
+        Ok(Self {
+            selector,
+            client,
+            device,
+            received: Vec::new(),
+            pending_id_bytes: 4,
+        })
+    }
+
+    fn inject(&mut self, raw: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        use std::io::Read;
+
+        self.client
+            .borrow_mut()
+            .inject_packets(&mut self.selector, raw);
+        let mut events = Events::with_capacity(16);
+        loop {
+            self.selector
+                .poll(&mut events, Some(Self::SETTLE_TIMEOUT))?;
+            if events.is_empty() {
+                break;
+            }
+            self.selector.run_handlers(&events);
+        }
+
+        let mut buf = [0; 4096];
+        loop {
+            match self.device.read(&mut buf) {
+                Ok(0) => break,
+                Ok(r) => self.received.extend_from_slice(&buf[..r]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        let id_bytes = min(self.pending_id_bytes, self.received.len());
+        self.received.drain(..id_bytes);
+        self.pending_id_bytes -= id_bytes;
+
+        Self::take_packets(&mut self.received)
+    }
+
+    // split the complete packets received so far, by their IPv4 total length
+    fn take_packets(received: &mut Vec<u8>) -> io::Result<Vec<Vec<u8>>> {
+        let mut packets = Vec::new();
+        while received.len() >= 4 {
+            let length = usize::from(u16::from_be_bytes([received[2], received[3]]));
+            if length < 20 {
+                // the packet boundaries are lost
+                let message = format!("Invalid packet length: {}", length);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+            if received.len() < length {
+                break;
+            }
+            packets.push(received.drain(..length).collect());
+        }
+        Ok(packets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::ipv4_packet::tests::{create_tcp_packet, set_destination};
+    use crate::relay::tcp_connection::tests::tcp_flags;
+    use crate::relay::tcp_header;
+    use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+
+    #[test]
+    fn inject_syn() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let destination =
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+        let mut syn = create_tcp_packet(tcp_header::FLAG_SYN);
+        set_destination(&mut syn, destination);
+
+        let relay = Relay::new(0, RelayConfig::new());
+        let responses = relay.inject_client_packet(&syn).unwrap();
+        assert_eq!(1, responses.len());
+        let syn_ack = &responses[0];
+        assert_eq!(
+            tcp_header::FLAG_SYN | tcp_header::FLAG_ACK,
+            tcp_flags(syn_ack)
+        );
+        // from the destination, acknowledging the SYN
+        assert_eq!(&destination.ip().octets(), &syn_ack[12..16]);
+        assert_eq!(&destination.port().to_be_bytes(), &syn_ack[20..22]);
+        assert_eq!(
+            0x112,
+            u32::from_be_bytes([syn_ack[28], syn_ack[29], syn_ack[30], syn_ack[31]])
+        );
+
+        // the connection is kept across injections: the retransmitted SYN is ignored
+        assert!(relay.inject_client_packet(&syn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reject_invalid_response_length() {
+        let mut received = create_tcp_packet(tcp_header::FLAG_ACK);
+        // a second packet, truncated
+        received.extend_from_slice(&[0x45, 0x00]);
+        let packets = Injection::take_packets(&mut received).unwrap();
+        assert_eq!(1, packets.len());
+        assert_eq!(2, received.len());
+
+        for &length in &[0u8, 3] {
+            let mut received = vec![0x45, 0x00, 0x00, length, 0x00];
+            let err = Injection::take_packets(&mut received).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        }
+    }
+}
@@ -17,30 +17,76 @@
 use log::*;
 use std::cell::RefCell;
 use std::io;
+use std::net::SocketAddrV4;
 use std::rc::{Rc, Weak};
+use std::time::Instant;
 
 use super::binary;
 use super::client::{Client, ClientChannel};
-use super::connection::{Connection, ConnectionId};
-use super::ipv4_header::Protocol;
+use super::config::RelayConfig;
+use super::connection::{Connection, ConnectionDetail, ConnectionId, ConnectionInfo};
+use super::connection_pool::ConnectionPool;
+use super::drop_reason::{DropCounters, DropReason};
+use super::events::RelayEvent;
+use super::fd_budget;
+use super::ipv4_header::{
+    self, Protocol, OPTION_LOOSE_SOURCE_ROUTE, OPTION_RECORD_ROUTE, OPTION_STRICT_SOURCE_ROUTE,
+};
 use super::ipv4_packet::Ipv4Packet;
+use super::net;
+use super::rate_limiter::RateLimiter;
 use super::selector::Selector;
+use super::stats::RouterStats;
 use super::tcp_connection::TcpConnection;
+use super::transport_header::TransportHeaderData;
 use super::udp_connection::UdpConnection;
 
 const TAG: &str = "Router";
 
+// beyond, the least recently used destination rate limiters are evicted
+const MAX_DESTINATION_RATE_LIMITERS: usize = 64;
+
 pub struct Router {
     client: Weak<RefCell<Client>>,
     // there are typically only few connections per client, HashMap would be less efficient
     connections: Vec<Rc<RefCell<dyn Connection>>>,
+    // recently closed TCP connections, with their closing time
+    time_wait: Vec<(ConnectionId, Instant)>,
+    connection_pool: ConnectionPool,
+    // rate limiters of new connections per destination address, the most recently used last
+    destination_rate_limiters: Vec<(u32, RateLimiter)>,
+    drops: Rc<DropCounters>,
+    // number of connections per label assigned by the classifier, in order of first appearance
+    label_counts: Vec<(String, u64)>,
+    // soft limit of open file descriptors of the process, read once on creation
+    fd_limit: Option<u64>,
+    // listed lazily, only when a packet would open a connection
+    open_fds: fd_budget::OpenCount,
+    // new connections are refused, the existing ones may finish
+    draining: bool,
+    // initialized from the config, may be changed at runtime
+    max_connections: Option<usize>,
+    // the number of connection watermarks reached
+    watermark_level: usize,
+    config: Rc<RelayConfig>,
 }
 
 impl Router {
-    pub fn new() -> Self {
+    pub fn new(config: Rc<RelayConfig>) -> Self {
         Self {
             client: Weak::new(),
             connections: Vec::new(),
+            time_wait: Vec::new(),
+            connection_pool: ConnectionPool::new(config.tcp_pool_ttl()),
+            destination_rate_limiters: Vec::new(),
+            drops: Rc::new(DropCounters::new()),
+            label_counts: Vec::new(),
+            fd_limit: fd_budget::limit(),
+            open_fds: fd_budget::OpenCount::new(),
+            draining: false,
+            max_connections: config.max_connections_per_client(),
+            watermark_level: 0,
+            config,
         }
     }
 
@@ -49,6 +95,113 @@ impl Router {
         self.client = client;
     }
 
+    pub fn drops(&self) -> &Rc<DropCounters> {
+        &self.drops
+    }
+
+    /// Refuse any new connection from now on.
+    pub fn set_draining(&mut self) {
+        self.draining = true;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// Change the maximum number of connections, and close the most idle connections exceeding
+    /// it. Return how many have been closed.
+    pub fn set_max_connections(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        max_connections: Option<usize>,
+    ) -> usize {
+        self.max_connections = max_connections;
+        let max = match max_connections {
+            Some(max) if max < self.connections.len() => max,
+            _ => {
+                self.update_watermark();
+                return 0;
+            }
+        };
+        let mut by_activity: Vec<(Instant, ConnectionId)> = self
+            .connections
+            .iter()
+            .map(|connection| {
+                let info = connection.borrow().info();
+                (info.last_activity(), info.id)
+            })
+            .collect();
+        by_activity.sort_by_key(|&(last_activity, _)| last_activity);
+        let excess = by_activity.len() - max;
+        let evicted: Vec<ConnectionId> = by_activity
+            .into_iter()
+            .take(excess)
+            .map(|(_, id)| id)
+            .collect();
+        self.close_connections(selector, client_channel, |connection| {
+            evicted.contains(connection.id())
+        })
+    }
+
+    pub fn connection_infos(&self) -> Vec<ConnectionInfo> {
+        self.connections
+            .iter()
+            .map(|connection| connection.borrow().info())
+            .collect()
+    }
+
+    /// Full snapshot of the connection identified by its protocol and addresses, if any.
+    pub fn connection_detail(
+        &self,
+        protocol: Protocol,
+        source: SocketAddrV4,
+        destination: SocketAddrV4,
+    ) -> Option<ConnectionDetail> {
+        self.find_by_addresses(protocol, source, destination)
+            .map(|connection| connection.borrow().detail())
+    }
+
+    /// Pause or resume the connection identified by its protocol and addresses, and return
+    /// whether it exists.
+    pub fn set_paused(
+        &mut self,
+        selector: &mut Selector,
+        protocol: Protocol,
+        source: SocketAddrV4,
+        destination: SocketAddrV4,
+        paused: bool,
+    ) -> bool {
+        match self.find_by_addresses(protocol, source, destination) {
+            Some(connection) => {
+                connection.borrow_mut().set_paused(selector, paused);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn find_by_addresses(
+        &self,
+        protocol: Protocol,
+        source: SocketAddrV4,
+        destination: SocketAddrV4,
+    ) -> Option<&Rc<RefCell<dyn Connection>>> {
+        self.connections.iter().find(|connection| {
+            let connection = connection.borrow();
+            let id = connection.id();
+            id.protocol() == protocol && id.source() == source && id.destination() == destination
+        })
+    }
+
+    /// Snapshot of the connections and drop counters (the packet counters are left to the client).
+    pub fn stats(&self, taken: Instant) -> RouterStats {
+        let mut stats = RouterStats::new(taken);
+        stats.connections = self.connections.len();
+        stats.drops = self.drops.snapshot();
+        stats
+    }
+
     pub fn send_to_network(
         &mut self,
         selector: &mut Selector,
@@ -56,32 +209,148 @@ impl Router {
         ipv4_packet: &Ipv4Packet,
     ) {
         if ipv4_packet.is_valid() {
+            if let Some(option) = self.rejected_option(ipv4_packet) {
+                warn!(target: TAG, "Dropping packet with {} option", option);
+                self.drops.record(DropReason::IpOption);
+                return;
+            }
+            if self.is_rejected_fragment(ipv4_packet) {
+                debug!(target: TAG, "Dropping fragment");
+                self.drops.record(DropReason::Fragment);
+                return;
+            }
+            let protocol = ipv4_packet.ipv4_header_data().protocol();
+            if let Some(reason) = self.disabled_reason(protocol) {
+                debug!(target: TAG, "Dropping {:?} packet, protocol disabled", protocol);
+                self.drops.record(reason);
+                return;
+            }
+            if self.is_rejected_broadcast(ipv4_packet) {
+                debug!(target: TAG, "Dropping broadcast packet");
+                self.drops.record(DropReason::Broadcast);
+                return;
+            }
+            if self.targets_relay(ipv4_packet) {
+                let reason = "Connection to the relay itself";
+                self.drops.record(DropReason::RelayLoop);
+                Self::reject(selector, client_channel, ipv4_packet, reason);
+                return;
+            }
+            if !self.is_port_allowed(ipv4_packet) {
+                let reason = "Destination port not allowed";
+                self.drops.record(DropReason::PortNotAllowed);
+                Self::reject(selector, client_channel, ipv4_packet, reason);
+                return;
+            }
+            if self.half_open_limit_reached(ipv4_packet) {
+                let reason = "Too many half-open connections";
+                self.drops.record(DropReason::HalfOpenLimit);
+                Self::reject(selector, client_channel, ipv4_packet, reason);
+                return;
+            }
+            if self.is_late_segment(ipv4_packet) {
+                self.drops.record(DropReason::LateSegment);
+                return;
+            }
+            if self.source_limit_reached(ipv4_packet) {
+                let reason = "Too many connections from this source address";
+                self.drops.record(DropReason::SourceConnLimit);
+                if protocol == Protocol::Tcp {
+                    Self::reject(selector, client_channel, ipv4_packet, reason);
+                } else {
+                    warn!(target: TAG, "{}, dropping packet", reason);
+                }
+                return;
+            }
+            if self.connection_limit_reached(ipv4_packet) {
+                let reason = "Too many connections for this client";
+                self.drops.record(DropReason::ClientConnLimit);
+                if protocol == Protocol::Tcp {
+                    Self::reject(selector, client_channel, ipv4_packet, reason);
+                } else {
+                    warn!(target: TAG, "{}, dropping packet", reason);
+                }
+                return;
+            }
+            if self.draining && self.opens_connection(ipv4_packet) {
+                let reason = "Client draining";
+                self.drops.record(DropReason::ClientDraining);
+                if protocol == Protocol::Tcp {
+                    Self::reject(selector, client_channel, ipv4_packet, reason);
+                } else {
+                    debug!(target: TAG, "{}, dropping packet", reason);
+                }
+                return;
+            }
+            if self.fd_limit_reached(ipv4_packet) {
+                let reason = "Too many open files";
+                self.drops.record(DropReason::FdExhausted);
+                if protocol == Protocol::Tcp {
+                    Self::reject(selector, client_channel, ipv4_packet, reason);
+                } else {
+                    warn!(target: TAG, "{}, dropping packet", reason);
+                }
+                return;
+            }
+            if self.destination_rate_exceeded(ipv4_packet) {
+                let reason = "Too many connections to this destination";
+                self.drops.record(DropReason::DstRateLimited);
+                if protocol == Protocol::Tcp {
+                    Self::reject(selector, client_channel, ipv4_packet, reason);
+                } else {
+                    warn!(target: TAG, "{}, dropping packet", reason);
+                }
+                return;
+            }
             match self.connection(selector, ipv4_packet) {
                 Ok(index) => {
-                    let closed = {
+                    let (closed, new_label) = {
                         let connection_ref = &self.connections[index];
                         let mut connection = connection_ref.borrow_mut();
+                        let had_label = connection.label().is_some();
                         connection.send_to_network(selector, client_channel, ipv4_packet);
-                        if connection.is_closed() {
+                        // the connection may label itself from its data (e.g. the TLS SNI)
+                        let new_label = if had_label {
+                            None
+                        } else {
+                            connection.label().map(String::from)
+                        };
+                        let closed = if connection.is_closed() {
                             debug!(
                                 target: TAG,
                                 "Removing connection from router: {}",
                                 connection.id()
                             );
-                            true
+                            Some(connection.id().clone())
                         } else {
-                            false
-                        }
+                            None
+                        };
+                        (closed, new_label)
                     };
-                    if closed {
-                        // the connection is closed, remove it
-                        self.connections.swap_remove(index);
+                    if let Some(label) = new_label {
+                        self.count_label(label);
+                    }
+                    if let Some(id) = closed {
+                        self.remove_at(index, id);
+                    }
+                }
+                Err(err) => {
+                    self.drops.record(DropReason::ConnectFailed);
+                    if protocol == Protocol::Tcp
+                        && self.config.reject_unreachable()
+                        && Self::is_unreachable(&err)
+                    {
+                        // the device would retransmit its SYN in vain
+                        let reason = format!("Cannot connect ({})", err);
+                        Self::reject(selector, client_channel, ipv4_packet, &reason);
+                    } else {
+                        error!(target: TAG, "Cannot create route, dropping packet: {}", err);
                     }
                 }
-                Err(err) => error!(target: TAG, "Cannot create route, dropping packet: {}", err),
             }
         } else {
             warn!(target: TAG, "Dropping invalid packet");
+            self.drops.record(Self::invalid_reason(ipv4_packet));
             if log_enabled!(target: TAG, Level::Trace) {
                 trace!(
                     target: TAG,
@@ -92,6 +361,293 @@ impl Router {
         }
     }
 
+    // a packet is invalid if its transport header could not be parsed
+    fn invalid_reason(ipv4_packet: &Ipv4Packet) -> DropReason {
+        match ipv4_packet.ipv4_header_data().protocol() {
+            Protocol::Tcp => DropReason::BadTcpOffset,
+            Protocol::Udp => DropReason::Malformed,
+            Protocol::Other => DropReason::UnsupportedProtocol,
+        }
+    }
+
+    // errors which will not be solved by retrying immediately (unlike EAGAIN)
+    fn is_unreachable(err: &io::Error) -> bool {
+        matches!(
+            err.kind(),
+            io::ErrorKind::NetworkUnreachable
+                | io::ErrorKind::NetworkDown
+                | io::ErrorKind::HostUnreachable
+                | io::ErrorKind::AddrNotAvailable
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::PermissionDenied
+        )
+    }
+
+    fn disabled_reason(&self, protocol: Protocol) -> Option<DropReason> {
+        match protocol {
+            Protocol::Tcp if !self.config.tcp_enabled() => Some(DropReason::TcpDisabled),
+            Protocol::Udp if !self.config.udp_enabled() => Some(DropReason::UdpDisabled),
+            _ => None,
+        }
+    }
+
+    fn reject(
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ipv4_packet: &Ipv4Packet,
+        reason: &str,
+    ) {
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        let id = ConnectionId::from_headers(
+            ipv4_header_data,
+            transport_header_data.expect("No transport"),
+        );
+        warn!(target: TAG, "{}, rejecting {}", reason, id);
+        TcpConnection::reject(selector, client_channel, &id, ipv4_packet);
+    }
+
+    fn is_rejected_broadcast(&self, ipv4_packet: &Ipv4Packet) -> bool {
+        let destination = net::to_addr(ipv4_packet.ipv4_header_data().destination());
+        destination.is_broadcast() && !self.config.allow_broadcast()
+    }
+
+    // a TCP connection to the relay port on localhost would be accepted as a new client
+    fn targets_relay(&self, ipv4_packet: &Ipv4Packet) -> bool {
+        if self.config.allow_relay_loop() {
+            return false;
+        }
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        if ipv4_header_data.protocol() != Protocol::Tcp {
+            return false;
+        }
+        let transport_header_data = transport_header_data.expect("No transport");
+        if transport_header_data.destination_port() != self.config.relay_port() {
+            return false;
+        }
+        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data);
+        let destination = *id.rewritten_destination().ip();
+        destination.is_loopback() || destination.is_unspecified()
+    }
+
+    fn is_port_allowed(&self, ipv4_packet: &Ipv4Packet) -> bool {
+        let allowed_ports = match self.config.tcp_allowed_ports() {
+            Some(allowed_ports) => allowed_ports,
+            None => return true,
+        };
+        match ipv4_packet.transport_header_data() {
+            Some(TransportHeaderData::Tcp(tcp_header_data)) => {
+                allowed_ports.contains(&tcp_header_data.destination_port())
+            }
+            _ => true,
+        }
+    }
+
+    fn half_open_limit_reached(&self, ipv4_packet: &Ipv4Packet) -> bool {
+        let max = match self.config.max_half_open_connections() {
+            Some(max) => max,
+            None => return false,
+        };
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        if ipv4_header_data.protocol() != Protocol::Tcp {
+            return false;
+        }
+        let half_open = self
+            .connections
+            .iter()
+            .filter(|connection| connection.borrow().is_half_open())
+            .count();
+        if half_open < max {
+            return false;
+        }
+        // packets of existing connections are never rejected
+        let id = ConnectionId::from_headers(
+            ipv4_header_data,
+            transport_header_data.expect("No transport"),
+        );
+        self.find_index(&id).is_none()
+    }
+
+    fn connection_limit_reached(&self, ipv4_packet: &Ipv4Packet) -> bool {
+        let max = match self.max_connections {
+            Some(max) => max,
+            None => return false,
+        };
+        if self.connections.len() < max {
+            return false;
+        }
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        let transport_header_data = match transport_header_data {
+            Some(transport_header_data) => transport_header_data,
+            None => return false, // rejected on connection creation
+        };
+        // packets of existing connections are never rejected
+        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data);
+        self.find_index(&id).is_none()
+    }
+
+    fn fd_limit_reached(&mut self, ipv4_packet: &Ipv4Packet) -> bool {
+        let (limit, headroom) = match (self.fd_limit, self.config.fd_headroom()) {
+            (Some(limit), Some(headroom)) => (limit, headroom),
+            _ => return false,
+        };
+        if !self.opens_connection(ipv4_packet) {
+            return false;
+        }
+        match self.open_fds.get(Instant::now()) {
+            Some(open) => fd_budget::is_exhausted(open, limit, headroom),
+            None => false,
+        }
+    }
+
+    // a SYN or the first datagram of a flow, packets of existing connections are never rejected
+    fn opens_connection(&self, ipv4_packet: &Ipv4Packet) -> bool {
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        let transport_header_data = match transport_header_data {
+            Some(transport_header_data) => transport_header_data,
+            None => return false, // rejected on connection creation
+        };
+        if let TransportHeaderData::Tcp(tcp_header_data) = transport_header_data {
+            if !tcp_header_data.is_syn() {
+                return false;
+            }
+        }
+        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data);
+        self.find_index(&id).is_none()
+    }
+
+    fn source_limit_reached(&self, ipv4_packet: &Ipv4Packet) -> bool {
+        let max = match self.config.max_connections_per_source() {
+            Some(max) => max,
+            None => return false,
+        };
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        let transport_header_data = match transport_header_data {
+            Some(transport_header_data) => transport_header_data,
+            None => return false, // rejected on connection creation
+        };
+        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data);
+        let source = id.source();
+        let count = self
+            .connections
+            .iter()
+            .filter(|connection| connection.borrow().id().source().ip() == source.ip())
+            .count();
+        // packets of existing connections are never rejected
+        count >= max && self.find_index(&id).is_none()
+    }
+
+    fn destination_rate_exceeded(&mut self, ipv4_packet: &Ipv4Packet) -> bool {
+        let rate = match self.config.max_destination_connection_rate() {
+            Some(rate) => rate,
+            None => return false,
+        };
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        let transport_header_data = match transport_header_data {
+            Some(transport_header_data) => transport_header_data,
+            None => return false, // rejected on connection creation
+        };
+        if let TransportHeaderData::Tcp(tcp_header_data) = transport_header_data {
+            if !tcp_header_data.is_syn() {
+                // only a SYN opens a TCP connection
+                return false;
+            }
+        }
+        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data);
+        if self.find_index(&id).is_some() {
+            return false;
+        }
+        let destination = ipv4_header_data.destination();
+        let mut rate_limiter = match self
+            .destination_rate_limiters
+            .iter()
+            .position(|&(addr, _)| addr == destination)
+        {
+            Some(index) => self.destination_rate_limiters.remove(index).1,
+            None => RateLimiter::new(rate),
+        };
+        let available = rate_limiter.is_available(Instant::now());
+        if available {
+            rate_limiter.consume();
+        }
+        self.destination_rate_limiters
+            .push((destination, rate_limiter));
+        if self.destination_rate_limiters.len() > MAX_DESTINATION_RATE_LIMITERS {
+            self.destination_rate_limiters.remove(0);
+        }
+        !available
+    }
+
+    fn enter_time_wait(&mut self, id: ConnectionId) {
+        if id.protocol() == Protocol::Tcp && self.config.tcp_time_wait().is_some() {
+            self.time_wait.push((id, Instant::now()));
+        }
+    }
+
+    // prepare a spare connection for the next connection to the same destination
+    fn warm_connection_pool(&mut self, id: &ConnectionId) {
+        if id.protocol() == Protocol::Tcp && self.config.tcp_pool_ttl().is_some() {
+            if let Ok(destination) = id.resolved_destination(self.config.destination_resolver()) {
+                self.connection_pool.warm(destination);
+            }
+        }
+    }
+
+    fn clean_time_wait(&mut self) {
+        if let Some(duration) = self.config.tcp_time_wait() {
+            self.time_wait
+                .retain(|&(_, closed)| closed.elapsed() < duration);
+        }
+    }
+
+    // a late segment of a recently closed connection must not open a new connection
+    fn is_late_segment(&mut self, ipv4_packet: &Ipv4Packet) -> bool {
+        if self.time_wait.is_empty() {
+            return false;
+        }
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        let tcp_header_data = match transport_header_data {
+            Some(TransportHeaderData::Tcp(tcp_header_data)) => tcp_header_data,
+            _ => return false,
+        };
+        self.clean_time_wait();
+        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap());
+        let index = match self.time_wait.iter().position(|(closed, _)| closed == &id) {
+            Some(index) => index,
+            None => return false,
+        };
+        if tcp_header_data.is_syn() {
+            // the tuple is reused for a new connection
+            self.time_wait.swap_remove(index);
+            false
+        } else {
+            debug!(target: TAG, "Dropping late segment of closed connection {}", id);
+            true
+        }
+    }
+
+    fn is_rejected_fragment(&self, ipv4_packet: &Ipv4Packet) -> bool {
+        self.config.drop_fragments() && ipv4_packet.ipv4_header_data().is_fragment()
+    }
+
+    fn rejected_option(&self, ipv4_packet: &Ipv4Packet) -> Option<&'static str> {
+        let ipv4_header = ipv4_packet.ipv4_header();
+        let options = ipv4_header.options();
+        if options.is_empty() {
+            return None;
+        }
+        let rejected =
+            ipv4_header::option_types(options).find_map(|option_type| match option_type {
+                OPTION_LOOSE_SOURCE_ROUTE | OPTION_STRICT_SOURCE_ROUTE
+                    if self.config.reject_source_route() =>
+                {
+                    Some("source route")
+                }
+                OPTION_RECORD_ROUTE if self.config.reject_record_route() => Some("record route"),
+                _ => None,
+            });
+        rejected
+    }
+
     fn connection(
         &mut self,
         selector: &mut Selector,
@@ -103,10 +659,24 @@ impl Router {
         let index = match self.find_index(&id) {
             Some(index) => index,
             None => {
-                let connection =
-                    Self::create_connection(selector, id, self.client.clone(), ipv4_packet)?;
+                let connection = Self::create_connection(
+                    selector,
+                    id,
+                    self.client.clone(),
+                    &self.config,
+                    &mut self.connection_pool,
+                    &self.drops,
+                    ipv4_packet,
+                )?;
+                self.label_connection(&mut *connection.borrow_mut(), ipv4_packet);
+                self.open_fds.opened();
+                let id = connection.borrow().id().clone();
                 let index = self.connections.len();
                 self.connections.push(connection);
+                self.config
+                    .events()
+                    .publish(RelayEvent::ConnectionOpened(id));
+                self.update_watermark();
                 index
             }
         };
@@ -117,6 +687,9 @@ impl Router {
         selector: &mut Selector,
         id: ConnectionId,
         client: Weak<RefCell<Client>>,
+        config: &RelayConfig,
+        connection_pool: &mut ConnectionPool,
+        drops: &Rc<DropCounters>,
         ipv4_packet: &Ipv4Packet,
     ) -> io::Result<Rc<RefCell<dyn Connection>>> {
         let (ipv4_header, transport_header) = ipv4_packet.headers();
@@ -126,6 +699,8 @@ impl Router {
                 selector,
                 id,
                 client,
+                config,
+                connection_pool,
                 ipv4_header,
                 transport_header,
             )?),
@@ -133,16 +708,47 @@ impl Router {
                 selector,
                 id,
                 client,
+                config,
+                drops.clone(),
                 ipv4_header,
                 transport_header,
             )?),
-            p => Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Unsupported protocol: {:?}", p),
-            )),
+            p => Err(io::Error::other(format!("Unsupported protocol: {:?}", p))),
+        }
+    }
+
+    fn label_connection(&mut self, connection: &mut dyn Connection, ipv4_packet: &Ipv4Packet) {
+        let classifier = match self.config.connection_classifier() {
+            Some(classifier) => classifier,
+            None => return,
+        };
+        let id = connection.id();
+        let payload = ipv4_packet.payload().unwrap_or(&[]);
+        if let Some(label) =
+            classifier.classify(id.protocol(), id.source(), id.destination(), payload)
+        {
+            cx_debug!(target: TAG, id, "Label: {}", label);
+            connection.set_label(label.clone());
+            self.count_label(label);
+        }
+    }
+
+    fn count_label(&mut self, label: String) {
+        match self.label_counts.iter_mut().find(|(l, _)| *l == label) {
+            Some((_, count)) => *count += 1,
+            None => self.label_counts.push((label, 1)),
         }
     }
 
+    fn format_label_counts(&self) -> String {
+        let counts: Vec<String> = self
+            .label_counts
+            .iter()
+            .map(|(label, count)| format!("{}: {}", label, count))
+            .collect();
+        counts.join(", ")
+    }
+
     fn find_index(&self, id: &ConnectionId) -> Option<usize> {
         self.connections
             .iter()
@@ -163,36 +769,841 @@ impl Router {
             "Self-removing connection from router: {}",
             connection.id()
         );
+        self.remove_at(index, connection.id().clone());
+    }
+
+    // every closed connection must be removed by this method, so that its tuple enters TIME-WAIT
+    //
+    // The id is passed explicitly, the connection may be borrowed by the caller (on self-removal).
+    fn remove_at(&mut self, index: usize, id: ConnectionId) {
         self.connections.swap_remove(index);
+        self.config
+            .events()
+            .publish(RelayEvent::ConnectionClosed(id.clone()));
+        self.warm_connection_pool(&id);
+        self.enter_time_wait(id);
+        self.update_watermark();
+    }
+
+    // publish an event for every watermark of the connection limit crossed since the last call
+    fn update_watermark(&mut self) {
+        let watermarks = self.config.connection_watermarks();
+        let count = self.connections.len();
+        let level = match self.max_connections {
+            Some(max) => watermarks
+                .iter()
+                .take_while(|&&pct| count * 100 >= max * usize::from(pct))
+                .count(),
+            None => 0,
+        };
+        for &pct in &watermarks[self.watermark_level.min(level)..level] {
+            warn!(target: TAG, "Connections reached {}% of the limit", pct);
+            self.config
+                .events()
+                .publish(RelayEvent::HighWatermark { pct });
+        }
+        for &pct in watermarks[level..self.watermark_level.max(level)]
+            .iter()
+            .rev()
+        {
+            info!(target: TAG, "Connections back below {}% of the limit", pct);
+            self.config
+                .events()
+                .publish(RelayEvent::WatermarkRecovered { pct });
+        }
+        self.watermark_level = level;
     }
 
+    /// Close all the connections, once the client is gone.
     pub fn clear(&mut self, selector: &mut Selector) {
+        if !self.connections.is_empty() {
+            // logged once for all the connections
+            info!(
+                target: TAG,
+                "Client gone, closing {} connections",
+                self.connections.len()
+            );
+        }
         for connection in &mut self.connections {
-            connection.borrow_mut().close(selector);
+            let mut connection = connection.borrow_mut();
+            connection.close_client_gone(selector);
+            self.config
+                .events()
+                .publish(RelayEvent::ConnectionClosed(connection.id().clone()));
         }
         self.connections.clear();
+        self.time_wait.clear();
+        self.connection_pool.clear();
+        self.destination_rate_limiters.clear();
+        if self.drops.total() > 0 {
+            info!(target: TAG, "Packets dropped: {}", self.drops);
+        }
+        if !self.label_counts.is_empty() {
+            info!(
+                target: TAG,
+                "Connections per label: {}",
+                self.format_label_counts()
+            );
+        }
     }
 
-    pub fn clean_expired_connections(&mut self, selector: &mut Selector) {
-        // remove the last items first, otherwise i might not be less than len() on swap_remove(i)
-        for i in (0..self.connections.len()).rev() {
-            let expired = {
+    /// Abort all the connections matching `predicate`, and return how many were closed.
+    ///
+    /// The predicate is evaluated on every connection before any is aborted, so that it never
+    /// observes the effects of the bulk operation.
+    pub fn close_connections<P>(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        predicate: P,
+    ) -> usize
+    where
+        P: FnMut(&dyn Connection) -> bool,
+    {
+        let matching = self.find_connections(predicate);
+        // remove the last items first, so that swap_remove(i) never moves a matching connection
+        for &i in matching.iter().rev() {
+            let id = {
                 let mut connection = self.connections[i].borrow_mut();
-                if connection.is_expired() {
-                    debug!(
-                        target: TAG,
-                        "Removing expired connection from router: {}",
-                        connection.id()
-                    );
-                    connection.close(selector);
-                    true
-                } else {
-                    false
-                }
+                debug!(
+                    target: TAG,
+                    "Closing connection (open for {} s, label {:?}): {}",
+                    connection.created().elapsed().as_secs(),
+                    connection.label(),
+                    connection.id()
+                );
+                connection.abort(selector, client_channel);
+                connection.id().clone()
             };
-            if expired {
-                self.connections.swap_remove(i);
+            self.remove_at(i, id);
+        }
+        matching.len()
+    }
+
+    /// Snapshot of the indices of the connections matching `predicate`, in increasing order.
+    fn find_connections<P>(&self, mut predicate: P) -> Vec<usize>
+    where
+        P: FnMut(&dyn Connection) -> bool,
+    {
+        (0..self.connections.len())
+            .filter(|&i| predicate(&*self.connections[i].borrow()))
+            .collect()
+    }
+
+    pub fn clean_expired_connections(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+    ) {
+        self.clean_time_wait();
+        self.connection_pool.clean();
+        for connection in &self.connections {
+            connection.borrow_mut().tick(selector, client_channel);
+        }
+        let expired = self.close_connections(selector, client_channel, |connection| {
+            connection.is_expired()
+        });
+        if expired > 0 {
+            debug!(target: TAG, "{} expired connections removed", expired);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::client::tests::MockClient;
+    use crate::relay::connection_classifier::ConnectionClassifier;
+    use crate::relay::ipv4_packet::tests::{create_tcp_packet, create_udp_packet, set_destination};
+    use crate::relay::tcp_header;
+    use mio::Events;
+    use std::cell::Cell;
+    use std::net::{Ipv4Addr, SocketAddrV4, TcpListener, UdpSocket};
+    use std::time::Duration;
+
+    fn create_router(tcp_time_wait: Option<Duration>) -> Router {
+        let mut config = RelayConfig::new();
+        config.set_tcp_time_wait(tcp_time_wait);
+        Router::new(Rc::new(config))
+    }
+
+    #[test]
+    fn allowed_ports() {
+        let syn = &mut create_tcp_packet(tcp_header::FLAG_SYN)[..];
+        let ipv4_packet = Ipv4Packet::parse(syn).unwrap();
+        assert!(create_router(None).is_port_allowed(&ipv4_packet));
+
+        let mut config = RelayConfig::new();
+        config.set_tcp_allowed_ports(Some(vec![80, 443]));
+        let router = Router::new(Rc::new(config));
+        assert!(!router.is_port_allowed(&ipv4_packet));
+
+        let mut config = RelayConfig::new();
+        config.set_tcp_allowed_ports(Some(vec![443, 5678]));
+        let router = Router::new(Rc::new(config));
+        assert!(router.is_port_allowed(&ipv4_packet));
+    }
+
+    struct MockConnection {
+        id: ConnectionId,
+        created: Instant,
+        label: Option<String>,
+        expired: bool,
+        aborted: Rc<Cell<bool>>,
+    }
+
+    impl Connection for MockConnection {
+        fn id(&self) -> &ConnectionId {
+            &self.id
+        }
+        fn send_to_network(&mut self, _: &mut Selector, _: &mut ClientChannel, _: &Ipv4Packet) {}
+        fn close(&mut self, _: &mut Selector) {}
+        fn tick(&mut self, _: &mut Selector, _: &mut ClientChannel) {}
+        fn abort(&mut self, _: &mut Selector, _: &mut ClientChannel) {
+            self.aborted.set(true);
+        }
+        fn is_expired(&self) -> bool {
+            self.expired
+        }
+        fn is_closed(&self) -> bool {
+            false
+        }
+        fn is_half_open(&self) -> bool {
+            false
+        }
+        fn created(&self) -> Instant {
+            self.created
+        }
+        fn label(&self) -> Option<&str> {
+            self.label.as_deref()
+        }
+        fn set_label(&mut self, label: String) {
+            self.label = Some(label);
+        }
+        fn info(&self) -> ConnectionInfo {
+            ConnectionInfo::new(&self.id, String::from("Mock"), self.created, self.label())
+        }
+        fn set_paused(&mut self, _: &mut Selector, _: bool) {}
+    }
+
+    fn create_mock_connection(ipv4_packet: &Ipv4Packet) -> MockConnection {
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap());
+        MockConnection {
+            id,
+            created: Instant::now(),
+            label: None,
+            expired: false,
+            aborted: Rc::new(Cell::new(false)),
+        }
+    }
+
+    fn add_connection(router: &mut Router, source_port: u16) {
+        add_connection_created(router, source_port, Instant::now());
+    }
+
+    // return whether the connection was aborted
+    fn add_connection_created(
+        router: &mut Router,
+        source_port: u16,
+        created: Instant,
+    ) -> Rc<Cell<bool>> {
+        let raw = &mut create_tcp_packet(tcp_header::FLAG_SYN)[..];
+        raw[20..22].copy_from_slice(&source_port.to_be_bytes());
+        let ipv4_packet = Ipv4Packet::parse(raw).unwrap();
+        let mut connection = create_mock_connection(&ipv4_packet);
+        connection.created = created;
+        let aborted = connection.aborted.clone();
+        router.connections.push(Rc::new(RefCell::new(connection)));
+        aborted
+    }
+
+    #[test]
+    fn find_old_connections() {
+        let mut router = create_router(None);
+        let now = Instant::now();
+        add_connection_created(&mut router, 1000, now - Duration::from_secs(120));
+        add_connection_created(&mut router, 1001, now);
+        add_connection_created(&mut router, 1002, now - Duration::from_secs(90));
+
+        let threshold = Duration::from_secs(60);
+        let old = router
+            .find_connections(|connection| now.duration_since(connection.created()) >= threshold);
+        assert_eq!(vec![0, 2], old);
+
+        let on_port = router.find_connections(|connection| connection.id().source().port() == 1001);
+        assert_eq!(vec![1], on_port);
+    }
+
+    #[test]
+    fn close_old_connections() {
+        let mut selector = Selector::create().unwrap();
+        let mut client = MockClient::new(&mut selector);
+        let mut router = create_router(None);
+        let now = Instant::now();
+        let aborted = [
+            add_connection_created(&mut router, 1000, now - Duration::from_secs(120)),
+            add_connection_created(&mut router, 1001, now),
+            add_connection_created(&mut router, 1002, now - Duration::from_secs(90)),
+            add_connection_created(&mut router, 1003, now - Duration::from_secs(30)),
+        ];
+
+        let threshold = Duration::from_secs(60);
+        let closed = router.close_connections(&mut selector, &mut client.channel(), |connection| {
+            now.duration_since(connection.created()) >= threshold
+        });
+        assert_eq!(2, closed);
+        let aborted: Vec<bool> = aborted.iter().map(|aborted| aborted.get()).collect();
+        assert_eq!(vec![true, false, true, false], aborted);
+
+        let mut remaining: Vec<u16> = router
+            .connections
+            .iter()
+            .map(|connection| connection.borrow().id().source().port())
+            .collect();
+        remaining.sort_unstable();
+        assert_eq!(vec![1001, 1003], remaining);
+    }
+
+    #[test]
+    fn connection_limit_per_client() {
+        let mut config = RelayConfig::new();
+        config.set_max_connections_per_client(Some(2));
+        let config = Rc::new(config);
+        let mut router1 = Router::new(config.clone());
+        let router2 = Router::new(config);
+
+        let syn = &mut create_tcp_packet(tcp_header::FLAG_SYN)[..];
+        let ipv4_packet = Ipv4Packet::parse(syn).unwrap();
+
+        add_connection(&mut router1, 1000);
+        assert!(!router1.connection_limit_reached(&ipv4_packet));
+        add_connection(&mut router1, 1001);
+        assert!(router1.connection_limit_reached(&ipv4_packet));
+
+        // the other clients are not affected
+        assert!(!router2.connection_limit_reached(&ipv4_packet));
+
+        // packets of existing connections are still accepted
+        let existing = &mut create_tcp_packet(tcp_header::FLAG_ACK)[..];
+        existing[20..22].copy_from_slice(&1000u16.to_be_bytes());
+        let ipv4_packet = Ipv4Packet::parse(existing).unwrap();
+        assert!(!router1.connection_limit_reached(&ipv4_packet));
+
+        let mut selector = Selector::create().unwrap();
+        let mut client = MockClient::new(&mut selector);
+        let syn = &mut create_tcp_packet(tcp_header::FLAG_SYN)[..];
+        send(&mut router1, &mut selector, &mut client, syn);
+        assert_eq!(1, router1.drops().get(DropReason::ClientConnLimit));
+        assert_eq!(2, router1.connections.len());
+        assert!(!client.take_sent().is_empty()); // RST
+    }
+
+    #[test]
+    fn lower_connection_limit() {
+        let mut selector = Selector::create().unwrap();
+        let mut client = MockClient::new(&mut selector);
+        let mut router = create_router(None);
+        let now = Instant::now();
+        let aborted = [
+            add_connection_created(&mut router, 1000, now - Duration::from_secs(30)),
+            add_connection_created(&mut router, 1001, now),
+            add_connection_created(&mut router, 1002, now - Duration::from_secs(90)),
+        ];
+
+        // raising the limit closes nothing
+        let closed = router.set_max_connections(&mut selector, &mut client.channel(), Some(3));
+        assert_eq!(0, closed);
+
+        // the most idle connections exceeding the new limit are closed
+        let closed = router.set_max_connections(&mut selector, &mut client.channel(), Some(1));
+        assert_eq!(2, closed);
+        let aborted: Vec<bool> = aborted.iter().map(|aborted| aborted.get()).collect();
+        assert_eq!(vec![true, false, true], aborted);
+        assert_eq!(1, router.connections.len());
+
+        // and new connections are refused
+        let syn = &mut create_tcp_packet(tcp_header::FLAG_SYN)[..];
+        assert!(router.connection_limit_reached(&Ipv4Packet::parse(syn).unwrap()));
+
+        router.set_max_connections(&mut selector, &mut client.channel(), None);
+        assert!(!router.connection_limit_reached(&Ipv4Packet::parse(syn).unwrap()));
+    }
+
+    #[test]
+    fn publish_watermark_events() {
+        let mut config = RelayConfig::new();
+        config.set_max_connections_per_client(Some(10));
+        config.set_connection_watermarks(vec![80, 95]);
+        let events = config.subscribe_events(16);
+        let mut router = Router::new(Rc::new(config));
+
+        for source_port in 1000..1009 {
+            add_connection(&mut router, source_port);
+            router.update_watermark();
+        }
+        // once, not on every connection beyond 80%
+        assert_eq!(
+            Some(RelayEvent::HighWatermark { pct: 80 }),
+            events.try_recv()
+        );
+        assert_eq!(None, events.try_recv());
+
+        add_connection(&mut router, 1009);
+        router.update_watermark();
+        assert_eq!(
+            Some(RelayEvent::HighWatermark { pct: 95 }),
+            events.try_recv()
+        );
+
+        let mut selector = Selector::create().unwrap();
+        let mut client = MockClient::new(&mut selector);
+        router.close_connections(&mut selector, &mut client.channel(), |connection| {
+            connection.id().source().port() >= 1005
+        });
+        let recovered: Vec<_> = std::iter::from_fn(|| events.try_recv())
+            .filter(|event| !matches!(event, RelayEvent::ConnectionClosed(_)))
+            .collect();
+        assert_eq!(
+            vec![
+                RelayEvent::WatermarkRecovered { pct: 95 },
+                RelayEvent::WatermarkRecovered { pct: 80 }
+            ],
+            recovered
+        );
+    }
+
+    #[test]
+    fn fd_limit() {
+        let mut router = create_router(None);
+        add_connection(&mut router, 1000);
+        let syn = &mut create_tcp_packet(tcp_header::FLAG_SYN)[..];
+        let ipv4_packet = Ipv4Packet::parse(syn).unwrap();
+
+        // simulate a limit the open descriptors (at least stdin, stdout and stderr) exceed
+        router.fd_limit = Some(3);
+        assert!(router.fd_limit_reached(&ipv4_packet));
+
+        // packets of existing connections are still accepted
+        let existing = &mut create_tcp_packet(tcp_header::FLAG_ACK)[..];
+        existing[20..22].copy_from_slice(&1000u16.to_be_bytes());
+        let existing_packet = Ipv4Packet::parse(existing).unwrap();
+        assert!(!router.fd_limit_reached(&existing_packet));
+
+        // neither are segments which do not open a connection
+        let ack = &mut create_tcp_packet(tcp_header::FLAG_ACK)[..];
+        assert!(!router.fd_limit_reached(&Ipv4Packet::parse(ack).unwrap()));
+
+        let mut selector = Selector::create().unwrap();
+        let mut client = MockClient::new(&mut selector);
+        let syn = &mut create_tcp_packet(tcp_header::FLAG_SYN)[..];
+        send(&mut router, &mut selector, &mut client, syn);
+        assert_eq!(1, router.drops().get(DropReason::FdExhausted));
+        assert!(!client.take_sent().is_empty()); // RST
+
+        router.fd_limit = Some(u64::MAX);
+        assert!(!router.fd_limit_reached(&ipv4_packet));
+
+        router.fd_limit = None;
+        assert!(!router.fd_limit_reached(&ipv4_packet));
+
+        // the headroom may be disabled
+        let mut config = RelayConfig::new();
+        config.set_fd_headroom(None);
+        let mut router = Router::new(Rc::new(config));
+        router.fd_limit = Some(3);
+        assert!(!router.fd_limit_reached(&ipv4_packet));
+    }
+
+    #[test]
+    fn connection_limit_per_source() {
+        let mut config = RelayConfig::new();
+        config.set_max_connections_per_source(Some(1));
+        let mut router = Router::new(Rc::new(config));
+        add_connection(&mut router, 1000);
+
+        // same source address (10.0.0.2), another port
+        let raw = &mut create_tcp_packet(tcp_header::FLAG_SYN)[..];
+        let ipv4_packet = Ipv4Packet::parse(raw).unwrap();
+        assert!(router.source_limit_reached(&ipv4_packet));
+
+        // another source address
+        let raw = &mut create_tcp_packet(tcp_header::FLAG_SYN)[..];
+        raw[12..16].copy_from_slice(&[10, 0, 0, 3]);
+        let ipv4_packet = Ipv4Packet::parse(raw).unwrap();
+        assert!(!router.source_limit_reached(&ipv4_packet));
+
+        // the existing connection
+        let raw = &mut create_tcp_packet(tcp_header::FLAG_ACK)[..];
+        raw[20..22].copy_from_slice(&1000u16.to_be_bytes());
+        let ipv4_packet = Ipv4Packet::parse(raw).unwrap();
+        assert!(!router.source_limit_reached(&ipv4_packet));
+    }
+
+    #[test]
+    fn destination_rate_limit() {
+        let mut config = RelayConfig::new();
+        config.set_max_destination_connection_rate(Some(2));
+        let mut router = Router::new(Rc::new(config));
+
+        let connect = |router: &mut Router, destination: [u8; 4], source_port: u16| {
+            let raw = &mut create_tcp_packet(tcp_header::FLAG_SYN)[..];
+            raw[16..20].copy_from_slice(&destination);
+            raw[20..22].copy_from_slice(&source_port.to_be_bytes());
+            router.destination_rate_exceeded(&Ipv4Packet::parse(raw).unwrap())
+        };
+
+        assert!(!connect(&mut router, [1, 2, 3, 4], 1000));
+        assert!(!connect(&mut router, [1, 2, 3, 4], 1001));
+        assert!(connect(&mut router, [1, 2, 3, 4], 1002));
+        // another destination is not affected
+        assert!(!connect(&mut router, [5, 6, 7, 8], 1003));
+
+        // segments that do not open a connection are never limited
+        let raw = &mut create_tcp_packet(tcp_header::FLAG_ACK)[..];
+        raw[16..20].copy_from_slice(&[1, 2, 3, 4]);
+        assert!(!router.destination_rate_exceeded(&Ipv4Packet::parse(raw).unwrap()));
+
+        let mut selector = Selector::create().unwrap();
+        let mut client = MockClient::new(&mut selector);
+        let raw = &mut create_tcp_packet(tcp_header::FLAG_SYN)[..];
+        raw[16..20].copy_from_slice(&[1, 2, 3, 4]);
+        send(&mut router, &mut selector, &mut client, raw);
+        assert_eq!(1, router.drops().get(DropReason::DstRateLimited));
+        assert!(!client.take_sent().is_empty()); // RST
+    }
+
+    #[derive(Debug)]
+    struct QuicClassifier;
+
+    impl ConnectionClassifier for QuicClassifier {
+        fn classify(
+            &self,
+            protocol: Protocol,
+            _device: SocketAddrV4,
+            destination: SocketAddrV4,
+            payload: &[u8],
+        ) -> Option<String> {
+            assert_eq!(4, payload.len());
+            if protocol == Protocol::Udp && destination.port() == 443 {
+                Some(String::from("quic"))
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn label_connections() {
+        let mut config = RelayConfig::new();
+        config.set_connection_classifier(Some(Rc::new(QuicClassifier)));
+        let mut router = Router::new(Rc::new(config));
+
+        for &port in &[443, 53, 443] {
+            let raw = &mut create_udp_packet(port)[..];
+            let ipv4_packet = Ipv4Packet::parse(raw).unwrap();
+            let mut connection = create_mock_connection(&ipv4_packet);
+            router.label_connection(&mut connection, &ipv4_packet);
+            let expected = if port == 443 { Some("quic") } else { None };
+            assert_eq!(expected, connection.label());
+        }
+        assert_eq!("quic: 2", router.format_label_counts());
+    }
+
+    #[cfg(feature = "tls-sni")]
+    #[test]
+    fn label_by_server_name() {
+        use crate::relay::client::tests::MockDevice;
+        use crate::relay::tcp_connection::tests::{
+            connect, create_segment, read_from_peer, with_payload,
+        };
+        use crate::relay::tls_sni::tests::CLIENT_HELLO;
+
+        let mut selector = Selector::create().unwrap();
+        let mut device = MockDevice::new(&mut selector, RelayConfig::new());
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let (mut stream, sequence_number) = connect(&mut selector, &mut device, &listener, true);
+
+        let segment = create_segment(&listener, tcp_header::FLAG_ACK, 0x112, sequence_number);
+        let client_hello = with_payload(segment, &CLIENT_HELLO);
+        device.send(&client_hello);
+        let data = read_from_peer(&mut selector, &mut device, &mut stream, CLIENT_HELLO.len());
+        assert_eq!(&CLIENT_HELLO[..], &data[..]);
+
+        // retransmitted, as if the ACK was lost
+        device.send(&client_hello);
+        device.receive(&mut selector, Duration::from_millis(50));
+
+        let mut client = device.client().borrow_mut();
+        let router = client.router();
+        let label = router.connections[0].borrow().label().map(String::from);
+        assert_eq!(Some("www.example.com"), label.as_deref());
+        assert_eq!(
+            vec![(String::from("www.example.com"), 1)],
+            router.label_counts
+        );
+        router.clear(&mut selector);
+    }
+
+    #[test]
+    fn drop_fragments() {
+        let raw = &mut create_tcp_packet(tcp_header::FLAG_ACK)[..];
+        let mut config = RelayConfig::new();
+        config.set_drop_fragments(true);
+        let router = Router::new(Rc::new(config));
+        assert!(!router.is_rejected_fragment(&Ipv4Packet::parse(raw).unwrap()));
+
+        raw[6] = 0x20; // More Fragments
+        assert!(router.is_rejected_fragment(&Ipv4Packet::parse(raw).unwrap()));
+
+        // fragments are relayed by default
+        let router = create_router(None);
+        assert!(!router.is_rejected_fragment(&Ipv4Packet::parse(raw).unwrap()));
+    }
+
+    #[test]
+    fn drop_broadcast() {
+        let raw = &mut create_tcp_packet(tcp_header::FLAG_SYN)[..];
+        raw[16..20].copy_from_slice(&[255, 255, 255, 255]);
+        let ipv4_packet = Ipv4Packet::parse(raw).unwrap();
+        assert!(create_router(None).is_rejected_broadcast(&ipv4_packet));
+
+        let mut config = RelayConfig::new();
+        config.set_allow_broadcast(true);
+        let router = Router::new(Rc::new(config));
+        assert!(!router.is_rejected_broadcast(&ipv4_packet));
+
+        let raw = &mut create_tcp_packet(tcp_header::FLAG_SYN)[..];
+        let ipv4_packet = Ipv4Packet::parse(raw).unwrap();
+        assert!(!create_router(None).is_rejected_broadcast(&ipv4_packet));
+
+        let mut selector = Selector::create().unwrap();
+        let mut client = MockClient::new(&mut selector);
+        let mut router = create_router(None);
+        let raw = &mut create_udp_packet(67)[..];
+        raw[16..20].copy_from_slice(&[255, 255, 255, 255]);
+        send(&mut router, &mut selector, &mut client, raw);
+        assert_eq!(1, router.drops().get(DropReason::Broadcast));
+        assert!(router.connections.is_empty());
+    }
+
+    fn send(router: &mut Router, selector: &mut Selector, client: &mut MockClient, raw: &mut [u8]) {
+        let ipv4_packet = Ipv4Packet::parse(raw).unwrap();
+        router.send_to_network(selector, &mut client.channel(), &ipv4_packet);
+    }
+
+    #[test]
+    fn count_invalid_packets() {
+        let mut selector = Selector::create().unwrap();
+        let mut client = MockClient::new(&mut selector);
+        let mut router = create_router(None);
+
+        let raw = &mut create_tcp_packet(tcp_header::FLAG_SYN)[..];
+        raw[32] = 4 << 4; // data offset below the minimum
+        send(&mut router, &mut selector, &mut client, raw);
+
+        assert_eq!(1, router.drops().get(DropReason::BadTcpOffset));
+        assert_eq!(1, router.drops().total());
+        assert!(router.connections.is_empty());
+        assert!(client.take_sent().is_empty());
+    }
+
+    // redirect the packet to a local listener, to which connections may actually be opened
+    fn set_local_destination(raw: &mut [u8], listener: &TcpListener) {
+        let port = listener.local_addr().unwrap().port();
+        set_destination(raw, SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
+    }
+
+    #[test]
+    fn disable_udp() {
+        let mut selector = Selector::create().unwrap();
+        let mut client = MockClient::new(&mut selector);
+        let mut config = RelayConfig::new();
+        config.set_udp_enabled(false);
+        let mut router = Router::new(Rc::new(config));
+
+        let raw = &mut create_udp_packet(53)[..];
+        send(&mut router, &mut selector, &mut client, raw);
+        assert_eq!(1, router.drops().get(DropReason::UdpDisabled));
+        assert!(router.connections.is_empty());
+
+        // TCP is still relayed
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let raw = &mut create_tcp_packet(tcp_header::FLAG_SYN)[..];
+        set_local_destination(raw, &listener);
+        send(&mut router, &mut selector, &mut client, raw);
+        assert_eq!(1, router.connections.len());
+        assert_eq!(1, router.drops().total());
+    }
+
+    #[test]
+    fn reject_disallowed_port() {
+        let mut selector = Selector::create().unwrap();
+        let mut client = MockClient::new(&mut selector);
+        let mut config = RelayConfig::new();
+        config.set_tcp_allowed_ports(Some(vec![80]));
+        let mut router = Router::new(Rc::new(config));
+
+        let raw = &mut create_tcp_packet(tcp_header::FLAG_SYN)[..];
+        send(&mut router, &mut selector, &mut client, raw);
+        assert_eq!(1, router.drops().get(DropReason::PortNotAllowed));
+        assert!(router.connections.is_empty());
+
+        // the device receives a RST
+        let sent = &mut client.take_sent()[..];
+        let ipv4_packet = Ipv4Packet::parse(sent).unwrap();
+        match ipv4_packet.transport_header_data() {
+            Some(TransportHeaderData::Tcp(tcp_header_data)) => assert!(tcp_header_data.is_rst()),
+            _ => panic!("Not a TCP segment"),
+        }
+    }
+
+    #[test]
+    fn drop_oversized_datagrams() {
+        let mut selector = Selector::create().unwrap();
+        let mut client = MockClient::new(&mut selector);
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        for &(max_payload, dropped) in &[(3, true), (4, false)] {
+            let mut config = RelayConfig::new();
+            config.set_udp_max_payload(Some(max_payload));
+            let mut router = Router::new(Rc::new(config));
+
+            let raw = &mut create_udp_packet(port)[..]; // 4-byte payload
+            raw[16..20].copy_from_slice(&Ipv4Addr::LOCALHOST.octets());
+            send(&mut router, &mut selector, &mut client, raw);
+            assert_eq!(1, router.connections.len());
+            let expected = if dropped { 1 } else { 0 };
+            assert_eq!(expected, router.drops().get(DropReason::UdpOversize));
+
+            if !dropped {
+                let mut events = Events::with_capacity(16);
+                selector
+                    .poll(&mut events, Some(Duration::from_secs(1)))
+                    .unwrap();
+                selector.run_handlers(&events);
+                let mut buf = [0; 16];
+                let len = server.recv(&mut buf).unwrap();
+                assert_eq!(&[0x11, 0x22, 0x33, 0x44], &buf[..len]);
             }
+            router.clear(&mut selector);
         }
     }
+
+    #[test]
+    fn expire_after_max_lifetime() {
+        let mut selector = Selector::create().unwrap();
+        let mut client = MockClient::new(&mut selector);
+        let mut config = RelayConfig::new();
+        config.set_max_connection_lifetime(Some(Duration::from_millis(50)));
+        let mut router = Router::new(Rc::new(config));
+
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let udp = &mut create_udp_packet(server.local_addr().unwrap().port())[..];
+        udp[16..20].copy_from_slice(&Ipv4Addr::LOCALHOST.octets());
+        send(&mut router, &mut selector, &mut client, udp);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let syn = &mut create_tcp_packet(tcp_header::FLAG_SYN)[..];
+        set_local_destination(syn, &listener);
+        send(&mut router, &mut selector, &mut client, syn);
+        assert_eq!(2, router.connections.len());
+        assert!(router
+            .connections
+            .iter()
+            .all(|connection| !connection.borrow().is_expired()));
+
+        std::thread::sleep(Duration::from_millis(60));
+        // recent traffic does not extend the lifetime
+        let udp = &mut create_udp_packet(server.local_addr().unwrap().port())[..];
+        udp[16..20].copy_from_slice(&Ipv4Addr::LOCALHOST.octets());
+        send(&mut router, &mut selector, &mut client, udp);
+        assert_eq!(2, router.connections.len());
+        for connection in &router.connections {
+            // the deadlines are checked on tick
+            connection
+                .borrow_mut()
+                .tick(&mut selector, &mut client.channel());
+        }
+        assert!(router
+            .connections
+            .iter()
+            .all(|connection| connection.borrow().is_expired()));
+        router.clear(&mut selector);
+    }
+
+    #[test]
+    fn classify_connection_errors() {
+        let unreachable = io::Error::from(io::ErrorKind::NetworkUnreachable);
+        assert!(Router::is_unreachable(&unreachable));
+        let down = io::Error::from(io::ErrorKind::NetworkDown);
+        assert!(Router::is_unreachable(&down));
+        let would_block = io::Error::from(io::ErrorKind::WouldBlock);
+        assert!(!Router::is_unreachable(&would_block));
+        let interrupted = io::Error::from(io::ErrorKind::Interrupted);
+        assert!(!Router::is_unreachable(&interrupted));
+    }
+
+    fn close(router: &mut Router, raw: &mut [u8]) {
+        let ipv4_packet = Ipv4Packet::parse(raw).unwrap();
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap());
+        router.enter_time_wait(id);
+    }
+
+    fn is_late_segment(router: &mut Router, raw: &mut [u8]) -> bool {
+        router.is_late_segment(&Ipv4Packet::parse(raw).unwrap())
+    }
+
+    #[test]
+    fn absorb_late_segments() {
+        let mut router = create_router(Some(Duration::from_secs(60)));
+        let ack = &mut create_tcp_packet(tcp_header::FLAG_ACK)[..];
+        assert!(!is_late_segment(&mut router, ack));
+
+        close(&mut router, ack);
+        assert!(is_late_segment(&mut router, ack));
+        assert!(is_late_segment(&mut router, ack));
+
+        // a new SYN reuses the tuple
+        let syn = &mut create_tcp_packet(tcp_header::FLAG_SYN)[..];
+        assert!(!is_late_segment(&mut router, syn));
+        assert!(!is_late_segment(&mut router, ack));
+    }
+
+    #[test]
+    fn time_wait_expiration() {
+        let mut router = create_router(Some(Duration::from_millis(10)));
+        let ack = &mut create_tcp_packet(tcp_header::FLAG_ACK)[..];
+        close(&mut router, ack);
+        assert!(is_late_segment(&mut router, ack));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!is_late_segment(&mut router, ack));
+    }
+
+    #[test]
+    fn expired_connection_enters_time_wait() {
+        let mut selector = Selector::create().unwrap();
+        let mut client = MockClient::new(&mut selector);
+        let mut router = create_router(Some(Duration::from_secs(60)));
+        let ack = &mut create_tcp_packet(tcp_header::FLAG_ACK)[..];
+        let mut connection = create_mock_connection(&Ipv4Packet::parse(ack).unwrap());
+        connection.expired = true;
+        router.connections.push(Rc::new(RefCell::new(connection)));
+
+        router.clean_expired_connections(&mut selector, &mut client.channel());
+        assert!(router.connections.is_empty());
+        assert!(is_late_segment(&mut router, ack));
+    }
+
+    #[test]
+    fn time_wait_disabled() {
+        let mut router = create_router(None);
+        let ack = &mut create_tcp_packet(tcp_header::FLAG_ACK)[..];
+        close(&mut router, ack);
+        assert!(!is_late_segment(&mut router, ack));
+    }
 }
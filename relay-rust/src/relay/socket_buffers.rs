@@ -0,0 +1,146 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use std::io;
+
+/// Kernel buffer sizes (SO_RCVBUF and SO_SNDBUF) of a socket, `None` if not configured.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferSizes {
+    pub receive: Option<usize>,
+    pub send: Option<usize>,
+}
+
+impl BufferSizes {
+    pub fn is_empty(&self) -> bool {
+        self.receive.is_none() && self.send.is_none()
+    }
+}
+
+impl fmt::Display for BufferSizes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let format = |size: Option<usize>| size.map_or("default".into(), |s| s.to_string());
+        write!(
+            f,
+            "SO_RCVBUF={} SO_SNDBUF={}",
+            format(self.receive),
+            format(self.send)
+        )
+    }
+}
+
+/// Set the requested buffer sizes on `socket`, and return the effective ones (the kernel may
+/// round or clamp them).
+#[cfg(unix)]
+pub fn apply<S: std::os::unix::io::AsRawFd>(
+    socket: &S,
+    requested: BufferSizes,
+) -> io::Result<BufferSizes> {
+    let fd = socket.as_raw_fd();
+    let mut effective = BufferSizes::default();
+    if let Some(size) = requested.receive {
+        set_option(fd, libc::SO_RCVBUF, size)?;
+        effective.receive = Some(get_option(fd, libc::SO_RCVBUF)?);
+    }
+    if let Some(size) = requested.send {
+        set_option(fd, libc::SO_SNDBUF, size)?;
+        effective.send = Some(get_option(fd, libc::SO_SNDBUF)?);
+    }
+    Ok(effective)
+}
+
+#[cfg(not(unix))]
+pub fn apply<S>(_socket: &S, requested: BufferSizes) -> io::Result<BufferSizes> {
+    if requested.is_empty() {
+        return Ok(requested);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Socket buffer sizes are only supported on Unix",
+    ))
+}
+
+#[cfg(unix)]
+fn set_option(fd: libc::c_int, name: libc::c_int, size: usize) -> io::Result<()> {
+    let value = size as libc::c_int;
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn get_option(fd: libc::c_int, name: libc::c_int) -> io::Result<usize> {
+    let mut value: libc::c_int = 0;
+    let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_buffer_sizes() {
+        let sizes = BufferSizes {
+            receive: Some(65536),
+            send: None,
+        };
+        assert_eq!("SO_RCVBUF=65536 SO_SNDBUF=default", sizes.to_string());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn set_buffer_sizes() {
+        use std::os::unix::io::AsRawFd;
+
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let requested = BufferSizes {
+            receive: Some(65536),
+            send: Some(32768),
+        };
+        let effective = apply(&socket, requested).unwrap();
+        // Linux doubles the requested values to account for its bookkeeping overhead
+        assert!(effective.receive.unwrap() >= 65536);
+        assert!(effective.send.unwrap() >= 32768);
+        let receive = get_option(socket.as_raw_fd(), libc::SO_RCVBUF).unwrap();
+        assert_eq!(Some(receive), effective.receive);
+
+        let unchanged = apply(&socket, BufferSizes::default()).unwrap();
+        assert!(unchanged.is_empty());
+    }
+}
@@ -0,0 +1,93 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io;
+
+/// Priority mapped to `dscp` by the configured table, if any.
+pub fn priority_of(table: Option<&[(u8, u32)]>, dscp: u8) -> Option<u32> {
+    table?
+        .iter()
+        .find(|&&(class, _)| class == dscp)
+        .map(|&(_, priority)| priority)
+}
+
+/// Set the priority (SO_PRIORITY) of the packets sent on `socket`, used by the host's qdisc.
+#[cfg(target_os = "linux")]
+pub fn set_priority<S: std::os::unix::io::AsRawFd>(socket: &S, priority: u32) -> io::Result<()> {
+    let value = priority as libc::c_int;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PRIORITY,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_priority<S>(_socket: &S, _priority: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Socket priority is only supported on Linux",
+    ))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+pub fn priority<S: std::os::unix::io::AsRawFd>(socket: &S) -> io::Result<u32> {
+    let mut value: libc::c_int = 0;
+    let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PRIORITY,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_dscp_to_priority() {
+        let table = [(46, 6), (34, 4)];
+        assert_eq!(Some(6), priority_of(Some(&table), 46));
+        assert_eq!(Some(4), priority_of(Some(&table), 34));
+        assert_eq!(None, priority_of(Some(&table), 0));
+        assert_eq!(None, priority_of(None, 46));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn set_socket_priority() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        set_priority(&socket, 5).unwrap();
+        assert_eq!(5, priority(&socket).unwrap());
+    }
+}
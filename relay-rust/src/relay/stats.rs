@@ -0,0 +1,507 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use super::connection::{ConnectionDetail, ConnectionInfo};
+use super::drop_reason::DropReason;
+use super::ipv4_header::Protocol;
+use super::json;
+use super::selector::Selector;
+use super::timer::TimerQueue;
+use super::tunnel_server::TunnelServer;
+
+/// Snapshot of the counters of the relay, summed over its clients.
+///
+/// The counters include the clients already disconnected; `clients` and `connections` are gauges.
+#[derive(Clone, Debug)]
+pub struct RouterStats {
+    pub taken: Instant,
+    pub clients: usize,
+    pub connections: usize,
+    pub packets_to_network: u64,
+    pub packets_to_device: u64,
+    pub bytes_to_network: u64,
+    pub bytes_to_device: u64,
+    /// The dropped packets for every reason, in a fixed order.
+    pub drops: Vec<(DropReason, u64)>,
+}
+
+/// Counters reset by `RelayState::reset_stats()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetScope {
+    /// The drop counters.
+    Drops,
+    /// The drop counters, and the packet and byte counters.
+    All,
+}
+
+/// Difference between two snapshots.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouterStatsDelta {
+    pub interval: Duration,
+    pub clients: i64,
+    pub connections: i64,
+    pub packets_to_network: u64,
+    pub packets_to_device: u64,
+    pub bytes_to_network: u64,
+    pub bytes_to_device: u64,
+    pub drops: Vec<(DropReason, u64)>,
+}
+
+impl RouterStats {
+    pub fn new(taken: Instant) -> Self {
+        Self {
+            taken,
+            clients: 0,
+            connections: 0,
+            packets_to_network: 0,
+            packets_to_device: 0,
+            bytes_to_network: 0,
+            bytes_to_device: 0,
+            drops: DropReason::ALL.iter().map(|&reason| (reason, 0)).collect(),
+        }
+    }
+
+    pub fn dropped(&self, reason: DropReason) -> u64 {
+        self.drops[reason as usize].1
+    }
+
+    pub fn total_dropped(&self) -> u64 {
+        self.drops.iter().map(|&(_, count)| count).sum()
+    }
+
+    /// Add the counters of `other`, but not its gauges.
+    pub(crate) fn add_counters(&mut self, other: &RouterStats) {
+        self.packets_to_network += other.packets_to_network;
+        self.packets_to_device += other.packets_to_device;
+        self.bytes_to_network += other.bytes_to_network;
+        self.bytes_to_device += other.bytes_to_device;
+        for (drop, other_drop) in self.drops.iter_mut().zip(&other.drops) {
+            drop.1 += other_drop.1;
+        }
+    }
+
+    pub(crate) fn reset(&mut self, scope: ResetScope) {
+        for drop in &mut self.drops {
+            drop.1 = 0;
+        }
+        if scope == ResetScope::All {
+            self.packets_to_network = 0;
+            self.packets_to_device = 0;
+            self.bytes_to_network = 0;
+            self.bytes_to_device = 0;
+        }
+    }
+
+    pub(crate) fn add(&mut self, other: &RouterStats) {
+        self.add_counters(other);
+        self.clients += other.clients;
+        self.connections += other.connections;
+    }
+
+    /// Format the counters and gauges as a JSON object (the snapshot time is relative, so it is
+    /// omitted).
+    pub fn to_json(&self) -> String {
+        let drops = self
+            .drops
+            .iter()
+            .map(|&(reason, count)| format!("\"{}\":{}", reason.key(), count))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "{{\"clients\":{},\"connections\":{},\"packets_to_network\":{},\
+             \"packets_to_device\":{},\"bytes_to_network\":{},\"bytes_to_device\":{},\
+             \"drops\":{{{}}}}}",
+            self.clients,
+            self.connections,
+            self.packets_to_network,
+            self.packets_to_device,
+            self.bytes_to_network,
+            self.bytes_to_device,
+            drops
+        )
+    }
+
+    /// Compute the changes since the `earlier` snapshot.
+    pub fn diff(&self, earlier: &RouterStats) -> RouterStatsDelta {
+        // a counter lower than before has been reset in between, it counts from 0
+        let delta = |now: u64, before: u64| now.checked_sub(before).unwrap_or(now);
+        RouterStatsDelta {
+            interval: self.taken.saturating_duration_since(earlier.taken),
+            clients: self.clients as i64 - earlier.clients as i64,
+            connections: self.connections as i64 - earlier.connections as i64,
+            packets_to_network: delta(self.packets_to_network, earlier.packets_to_network),
+            packets_to_device: delta(self.packets_to_device, earlier.packets_to_device),
+            bytes_to_network: delta(self.bytes_to_network, earlier.bytes_to_network),
+            bytes_to_device: delta(self.bytes_to_device, earlier.bytes_to_device),
+            drops: self
+                .drops
+                .iter()
+                .zip(&earlier.drops)
+                .map(|(&(reason, now), &(_, before))| (reason, delta(now, before)))
+                .collect(),
+        }
+    }
+}
+
+impl RouterStatsDelta {
+    /// Packets relayed per second, in both directions.
+    pub fn packets_per_second(&self) -> f64 {
+        self.rate(self.packets_to_network + self.packets_to_device)
+    }
+
+    /// Bytes relayed per second, in both directions.
+    pub fn bytes_per_second(&self) -> f64 {
+        self.rate(self.bytes_to_network + self.bytes_to_device)
+    }
+
+    fn rate(&self, count: u64) -> f64 {
+        let seconds = self.interval.as_secs_f64();
+        if seconds > 0.0 {
+            count as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Access to the relay from a `StatsListener`.
+pub struct RelayState<'a> {
+    tunnel_server: &'a mut TunnelServer,
+    selector: &'a mut Selector,
+}
+
+impl<'a> RelayState<'a> {
+    pub(crate) fn new(tunnel_server: &'a mut TunnelServer, selector: &'a mut Selector) -> Self {
+        Self {
+            tunnel_server,
+            selector,
+        }
+    }
+
+    /// Take a snapshot of the counters.
+    pub fn stats(&self) -> RouterStats {
+        self.tunnel_server.stats(Instant::now())
+    }
+
+    /// Bytes sent to the network by all the clients during the last second, if the egress rate
+    /// is limited (see `RelayConfig::set_max_egress_rate()`).
+    pub fn egress_rate(&self) -> Option<u64> {
+        self.tunnel_server.egress_rate()
+    }
+
+    /// Reset the counters in `scope`, including those of the disconnected clients.
+    ///
+    /// The gauges (the number of clients and connections) are not reset. The counters are only
+    /// incremented on the relay thread, which is running this hook, so no increment is lost.
+    pub fn reset_stats(&mut self, scope: ResetScope) {
+        self.tunnel_server.reset_stats(scope);
+    }
+
+    /// Take a full snapshot of a connection of the client `client_id`, identified by its protocol
+    /// and addresses (as sent by the device).
+    pub fn connection_detail(
+        &self,
+        client_id: u32,
+        protocol: Protocol,
+        source: SocketAddrV4,
+        destination: SocketAddrV4,
+    ) -> Option<ConnectionDetail> {
+        self.tunnel_server
+            .connection_detail(client_id, protocol, source, destination)
+    }
+
+    /// Pause a connection of the client `client_id`, identified by its protocol and addresses (as
+    /// sent by the device), or resume it. Return whether the connection exists.
+    ///
+    /// While paused, a TCP connection buffers the data from the device, and once its buffer is
+    /// full, does not acknowledge the segments, so that the device retransmits them later. A UDP
+    /// connection queues the datagrams until its buffer is full, then drops them. Neither reads
+    /// from the network.
+    pub fn set_connection_paused(
+        &mut self,
+        client_id: u32,
+        protocol: Protocol,
+        source: SocketAddrV4,
+        destination: SocketAddrV4,
+        paused: bool,
+    ) -> bool {
+        self.tunnel_server.set_connection_paused(
+            self.selector,
+            client_id,
+            protocol,
+            source,
+            destination,
+            paused,
+        )
+    }
+
+    /// Close the connections of every client to the addresses in `network`/`prefix_length` (as
+    /// addressed by the device), and return how many have been closed.
+    ///
+    /// The TCP connections are reset, the UDP ones are dropped.
+    pub fn close_connections_to(&mut self, network: Ipv4Addr, prefix_length: u8) -> usize {
+        self.tunnel_server
+            .close_connections_to(self.selector, network, prefix_length)
+    }
+
+    /// Change the maximum number of connections per client, overriding the configured one.
+    ///
+    /// The change is retroactive: the most idle connections exceeding the new limit are closed
+    /// immediately, and their count is returned. The other limits and timeouts cannot be changed
+    /// at runtime.
+    pub fn set_max_connections_per_client(&mut self, max_connections: Option<usize>) -> usize {
+        self.tunnel_server
+            .set_max_connections_per_client(self.selector, max_connections)
+    }
+
+    /// Drain the client `client_id`: it refuses new connections, and is disconnected once its
+    /// connections are closed, or after `timeout`. Return whether the client exists.
+    pub fn drain_client(&mut self, client_id: u32, timeout: Duration) -> bool {
+        self.tunnel_server.drain_client(client_id, timeout)
+    }
+
+    /// Write the whole state of the relay as a JSON object: its uptime, configuration, counters
+    /// and connections.
+    ///
+    /// No packet is relayed while the state is written, so `writer` should not block (e.g. a
+    /// regular file or a buffer).
+    pub fn dump_state(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let now = Instant::now();
+        let tunnel_server = &*self.tunnel_server;
+        let clients = tunnel_server
+            .connection_infos()
+            .iter()
+            .map(|(client_id, infos)| {
+                let connections = infos
+                    .iter()
+                    .map(|info| connection_to_json(info, now))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                format!("{{\"id\":{},\"connections\":[{}]}}", client_id, connections)
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        writeln!(
+            writer,
+            "{{\"uptime_ms\":{},\"config\":{},\"stats\":{},\"clients\":[{}]}}",
+            now.saturating_duration_since(tunnel_server.created())
+                .as_millis(),
+            json::quote(&format!("{:?}", tunnel_server.config())),
+            tunnel_server.stats(now).to_json(),
+            clients
+        )
+    }
+}
+
+fn connection_to_json(info: &ConnectionInfo, now: Instant) -> String {
+    let label = match info.label {
+        Some(ref label) => json::quote(label),
+        None => String::from("null"),
+    };
+    let connected_in = info.connected_in.map_or(String::from("null"), |duration| {
+        duration.as_millis().to_string()
+    });
+    let local_address = info.local_address.map_or(String::from("null"), |address| {
+        json::quote(&address.to_string())
+    });
+    let idle = |last: Option<Instant>| {
+        last.map_or(String::from("null"), |last| {
+            now.saturating_duration_since(last).as_millis().to_string()
+        })
+    };
+    format!(
+        "{{\"protocol\":\"{:?}\",\"ip_version\":{},\"source\":\"{}\",\"destination\":\"{}\",\"local_address\":{},\
+         \"direction\":\"{:?}\",\"state\":{},\"age_ms\":{},\"connected_in_ms\":{},\"label\":{},\
+         \"packets_to_network\":{},\"packets_to_device\":{},\"idle_ms\":{},\
+         \"idle_to_network_ms\":{},\"idle_to_device_ms\":{},\"paused\":{}}}",
+        info.protocol,
+        info.ip_version,
+        info.id.source(),
+        info.id.destination(),
+        local_address,
+        info.direction,
+        json::quote(&info.state),
+        now.saturating_duration_since(info.created).as_millis(),
+        connected_in,
+        label,
+        info.packets_to_network,
+        info.packets_to_device,
+        info.idle(now).as_millis(),
+        idle(info.last_to_network),
+        idle(info.last_to_device),
+        info.paused
+    )
+}
+
+/// Hook called periodically on the relay thread, between the I/O events, to report statistics.
+pub trait StatsListener: fmt::Debug {
+    fn on_stats(&self, relay: &mut RelayState);
+}
+
+/// Fire the expired listeners of `queue`, and return how many have been fired.
+pub(crate) fn fire_expired_listeners(
+    queue: &mut TimerQueue<dyn StatsListener>,
+    now: Instant,
+    tunnel_server: &RefCell<TunnelServer>,
+    selector: &mut Selector,
+) -> usize {
+    queue.fire_expired_with(now, |listener| {
+        let mut tunnel_server = tunnel_server.borrow_mut();
+        listener.on_stats(&mut RelayState::new(&mut tunnel_server, selector));
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::relay::config::RelayConfig;
+    use crate::relay::ipv4_packet::tests::{create_tcp_packet, set_destination};
+    use crate::relay::tcp_header;
+    use mio::Events;
+    use std::net::{TcpListener, TcpStream};
+    use std::rc::Rc;
+
+    /// Create a tunnel server with a single client, which opened a TCP connection to the returned
+    /// listener.
+    ///
+    /// The device end of the client is returned, so that the client stays connected.
+    pub fn create_tunnel_server_with_connection(
+        selector: &mut Selector,
+    ) -> (Rc<RefCell<TunnelServer>>, TcpStream, TcpListener) {
+        let config = Rc::new(RelayConfig::new());
+        let tunnel_server = TunnelServer::create(0, config, selector).unwrap();
+        let port = tunnel_server.borrow().local_port();
+        let mut device = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let destination =
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+        let mut syn = create_tcp_packet(tcp_header::FLAG_SYN);
+        set_destination(&mut syn, destination);
+        device.write_all(&syn).unwrap();
+
+        // accept the client, then read its SYN
+        let mut events = Events::with_capacity(16);
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while tunnel_server.borrow().stats(Instant::now()).connections == 0 {
+            assert!(Instant::now() < deadline, "No connection opened");
+            selector
+                .poll(&mut events, Some(Duration::from_millis(10)))
+                .unwrap();
+            selector.run_handlers(&events);
+        }
+        (tunnel_server, device, listener)
+    }
+
+    #[test]
+    fn dump_relay_state() {
+        let mut selector = Selector::create().unwrap();
+        let (tunnel_server, _device, listener) =
+            create_tunnel_server_with_connection(&mut selector);
+        let mut tunnel_server = tunnel_server.borrow_mut();
+        let relay = RelayState::new(&mut tunnel_server, &mut selector);
+        let mut dump = Vec::new();
+        relay.dump_state(&mut dump).unwrap();
+        let dump = String::from_utf8(dump).unwrap();
+
+        assert!(dump.starts_with("{\"uptime_ms\":"));
+        assert!(dump.contains(",\"config\":\"RelayConfig {"));
+        assert!(dump.contains(",\"stats\":{\"clients\":1,\"connections\":1,"));
+        let port = listener.local_addr().unwrap().port();
+        let connection = format!(
+            ",\"clients\":[{{\"id\":0,\"connections\":[{{\"protocol\":\"Tcp\",\
+             \"ip_version\":4,\"source\":\"10.0.0.2:1234\",\"destination\":\"127.0.0.1:{}\",\
+             \"local_address\":\"127.0.0.1:",
+            port
+        );
+        assert!(dump.contains(&connection), "{}", dump);
+        assert!(dump.contains(",\"direction\":\"Outbound\",\"state\":\"Syn"));
+        assert!(dump.contains(",\"connected_in_ms\":"));
+        assert!(dump.contains(",\"label\":null,\"packets_to_network\":"));
+        assert!(dump.contains(",\"idle_ms\":"));
+        assert!(dump.contains(",\"idle_to_network_ms\":null,"));
+        assert!(dump.ends_with("}]}]}\n"));
+    }
+
+    #[test]
+    fn diff_snapshots() {
+        let origin = Instant::now();
+        let mut earlier = RouterStats::new(origin);
+        earlier.clients = 1;
+        earlier.connections = 3;
+        earlier.packets_to_network = 10;
+        earlier.packets_to_device = 20;
+        earlier.bytes_to_network = 1000;
+        earlier.bytes_to_device = 4000;
+        earlier.drops[DropReason::Fragment as usize].1 = 2;
+
+        let mut later = earlier.clone();
+        later.taken = origin + Duration::from_secs(2);
+        later.connections = 1;
+        later.packets_to_network = 30;
+        later.packets_to_device = 60;
+        later.bytes_to_network = 3000;
+        later.bytes_to_device = 9000;
+        later.drops[DropReason::Fragment as usize].1 = 5;
+
+        let delta = later.diff(&earlier);
+        assert_eq!(Duration::from_secs(2), delta.interval);
+        assert_eq!(0, delta.clients);
+        assert_eq!(-2, delta.connections);
+        assert_eq!(20, delta.packets_to_network);
+        assert_eq!(40, delta.packets_to_device);
+        assert_eq!(2000, delta.bytes_to_network);
+        assert_eq!(5000, delta.bytes_to_device);
+        assert_eq!(
+            (DropReason::Fragment, 3),
+            delta.drops[DropReason::Fragment as usize]
+        );
+        assert_eq!(3, delta.drops.iter().map(|&(_, count)| count).sum::<u64>());
+        assert_eq!(30.0, delta.packets_per_second());
+        assert_eq!(3500.0, delta.bytes_per_second());
+    }
+
+    #[test]
+    fn format_json() {
+        let mut stats = RouterStats::new(Instant::now());
+        stats.clients = 1;
+        stats.connections = 2;
+        stats.bytes_to_device = 300;
+        stats.drops[DropReason::Fragment as usize].1 = 4;
+        let json = stats.to_json();
+        assert!(json.starts_with(
+            "{\"clients\":1,\"connections\":2,\"packets_to_network\":0,\
+             \"packets_to_device\":0,\"bytes_to_network\":0,\"bytes_to_device\":300,\
+             \"drops\":{\"malformed\":0,"
+        ));
+        assert!(json.contains(",\"fragment\":4,"));
+        assert!(json.ends_with(",\"client_draining\":0}}"));
+    }
+
+    #[test]
+    fn diff_same_instant() {
+        let stats = RouterStats::new(Instant::now());
+        let delta = stats.diff(&stats);
+        assert_eq!(Duration::from_secs(0), delta.interval);
+        assert_eq!(0.0, delta.packets_per_second());
+        assert_eq!(0.0, delta.bytes_per_second());
+    }
+}
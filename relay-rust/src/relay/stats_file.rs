@@ -0,0 +1,101 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::stats::{RelayState, StatsListener};
+
+const TAG: &str = "StatsFile";
+
+/// Write the stats snapshots to a JSON file, replaced atomically on every snapshot.
+#[derive(Debug)]
+pub struct StatsFile {
+    path: PathBuf,
+    // written first, then renamed to path, so that readers never see a partial file
+    temp_path: PathBuf,
+}
+
+impl StatsFile {
+    pub fn new(path: &Path) -> Self {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        Self {
+            path: path.to_owned(),
+            temp_path: temp_path.into(),
+        }
+    }
+
+    fn write(&self, json: &str) -> io::Result<()> {
+        fs::write(&self.temp_path, json)?;
+        fs::rename(&self.temp_path, &self.path)
+    }
+}
+
+impl StatsListener for StatsFile {
+    fn on_stats(&self, relay: &mut RelayState) {
+        let json = relay.stats().to_json() + "\n";
+        if let Err(err) = self.write(&json) {
+            warn!(
+                target: TAG,
+                "Cannot write stats to {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::selector::Selector;
+    use crate::relay::stats::tests::create_tunnel_server_with_connection;
+    use crate::relay::stats::{self, StatsListener};
+    use crate::relay::timer::TimerQueue;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn write_on_interval() {
+        let path =
+            std::env::temp_dir().join(format!("gnirehtet-stats-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut selector = Selector::create().unwrap();
+        let (tunnel_server, _device, _listener) =
+            create_tunnel_server_with_connection(&mut selector);
+
+        let origin = Instant::now();
+        let interval = Duration::from_secs(10);
+        let stats_file: Rc<dyn StatsListener> = Rc::new(StatsFile::new(&path));
+        let mut queue = TimerQueue::new(&[(interval, stats_file)], origin);
+        let mut fire =
+            |now| stats::fire_expired_listeners(&mut queue, now, &tunnel_server, &mut selector);
+        assert_eq!(0, fire(origin + interval / 2));
+        assert!(!path.exists());
+
+        assert_eq!(1, fire(origin + interval));
+        let json = fs::read_to_string(&path).unwrap();
+        assert!(json.starts_with("{\"clients\":1,\"connections\":1,\"packets_to_network\":1,"));
+        assert!(json.ends_with("}\n"));
+        assert!(!StatsFile::new(&path).temp_path.exists());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use std::cell::RefCell;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+
+use super::stats::{RelayState, RouterStats, StatsListener};
+
+const TAG: &str = "Statsd";
+
+const PREFIX: &str = "gnirehtet";
+
+// keep the datagrams below a typical MTU, several metrics are sent per datagram
+const MAX_DATAGRAM_LENGTH: usize = 1432;
+
+/// Push the stats to a StatsD (or DogStatsD) server over UDP.
+///
+/// The numbers of clients and connections are sent as gauges, the other counters as StatsD
+/// counters, incremented by their change since the previous push.
+#[derive(Debug)]
+pub struct StatsdExporter {
+    socket: UdpSocket,
+    server: SocketAddr,
+    previous: RefCell<Option<RouterStats>>,
+}
+
+impl StatsdExporter {
+    pub fn create(server: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        // never block the relay thread, the metrics are dropped instead
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            server,
+            previous: RefCell::new(None),
+        })
+    }
+
+    fn lines(stats: &RouterStats, previous: Option<&RouterStats>) -> Vec<String> {
+        let earlier = match previous {
+            Some(previous) => previous.clone(),
+            None => RouterStats::new(stats.taken),
+        };
+        let delta = stats.diff(&earlier);
+        let mut lines = vec![
+            format!("{}.clients:{}|g", PREFIX, stats.clients),
+            format!("{}.connections:{}|g", PREFIX, stats.connections),
+            format!(
+                "{}.packets_to_network:{}|c",
+                PREFIX, delta.packets_to_network
+            ),
+            format!("{}.packets_to_device:{}|c", PREFIX, delta.packets_to_device),
+            format!("{}.bytes_to_network:{}|c", PREFIX, delta.bytes_to_network),
+            format!("{}.bytes_to_device:{}|c", PREFIX, delta.bytes_to_device),
+        ];
+        for &(reason, count) in &delta.drops {
+            if count > 0 {
+                lines.push(format!("{}.drops.{}:{}|c", PREFIX, reason.key(), count));
+            }
+        }
+        lines
+    }
+
+    fn send(&self, lines: &[String]) -> io::Result<()> {
+        let mut datagram = String::new();
+        for line in lines {
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM_LENGTH {
+                self.socket.send_to(datagram.as_bytes(), self.server)?;
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(line);
+        }
+        if !datagram.is_empty() {
+            self.socket.send_to(datagram.as_bytes(), self.server)?;
+        }
+        Ok(())
+    }
+}
+
+impl StatsListener for StatsdExporter {
+    fn on_stats(&self, relay: &mut RelayState) {
+        let stats = relay.stats();
+        let lines = Self::lines(&stats, self.previous.borrow().as_ref());
+        if let Err(err) = self.send(&lines) {
+            warn!(target: TAG, "Cannot send stats to {}: {}", self.server, err);
+        }
+        *self.previous.borrow_mut() = Some(stats);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::config::RelayConfig;
+    use crate::relay::drop_reason::DropReason;
+    use crate::relay::selector::Selector;
+    use crate::relay::stats::{self, StatsListener};
+    use crate::relay::timer::TimerQueue;
+    use crate::relay::tunnel_server::TunnelServer;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn format_metrics() {
+        let origin = Instant::now();
+        let mut previous = RouterStats::new(origin);
+        previous.packets_to_network = 10;
+        previous.drops[DropReason::Fragment as usize].1 = 1;
+        let mut stats = previous.clone();
+        stats.taken = origin + Duration::from_secs(10);
+        stats.clients = 1;
+        stats.connections = 3;
+        stats.packets_to_network = 25;
+        stats.bytes_to_device = 512;
+        stats.drops[DropReason::Fragment as usize].1 = 3;
+
+        let lines = StatsdExporter::lines(&stats, Some(&previous));
+        let expected = [
+            "gnirehtet.clients:1|g",
+            "gnirehtet.connections:3|g",
+            "gnirehtet.packets_to_network:15|c",
+            "gnirehtet.packets_to_device:0|c",
+            "gnirehtet.bytes_to_network:0|c",
+            "gnirehtet.bytes_to_device:512|c",
+            "gnirehtet.drops.fragment:2|c",
+        ];
+        assert_eq!(&expected[..], &lines[..]);
+
+        // the first push counts from the start
+        let lines = StatsdExporter::lines(&stats, None);
+        assert_eq!("gnirehtet.packets_to_network:25|c", lines[2]);
+    }
+
+    #[test]
+    fn push_on_interval() {
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        server.set_nonblocking(true).unwrap();
+        let exporter = StatsdExporter::create(server.local_addr().unwrap()).unwrap();
+
+        let mut selector = Selector::create().unwrap();
+        let config = Rc::new(RelayConfig::new());
+        let tunnel_server = TunnelServer::create(0, config, &mut selector).unwrap();
+        let exporter: Rc<dyn StatsListener> = Rc::new(exporter);
+        let origin = Instant::now();
+        let interval = Duration::from_secs(10);
+        let mut queue = TimerQueue::new(&[(interval, exporter)], origin);
+        let mut fire =
+            |now| stats::fire_expired_listeners(&mut queue, now, &tunnel_server, &mut selector);
+
+        let mut buf = [0; MAX_DATAGRAM_LENGTH];
+        fire(origin + interval / 2);
+        let err = server.recv(&mut buf).unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+
+        fire(origin + interval);
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        server.set_nonblocking(false).unwrap();
+        let len = server.recv(&mut buf).unwrap();
+        let expected = "gnirehtet.clients:0|g\n\
+                        gnirehtet.connections:0|g\n\
+                        gnirehtet.packets_to_network:0|c\n\
+                        gnirehtet.packets_to_device:0|c\n\
+                        gnirehtet.bytes_to_network:0|c\n\
+                        gnirehtet.bytes_to_device:0|c";
+        assert_eq!(expected, std::str::from_utf8(&buf[..len]).unwrap());
+    }
+}
@@ -21,19 +21,32 @@ use rand::random;
 use std::cell::RefCell;
 use std::cmp;
 use std::io;
+use std::net::{Shutdown, SocketAddr};
 use std::num::Wrapping;
 use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
 
 use super::binary;
 use super::client::{Client, ClientChannel};
-use super::connection::{Connection, ConnectionId};
+use super::config::RelayConfig;
+use super::connection::{Connection, ConnectionDetail, ConnectionId, ConnectionInfo};
+use super::connection_pool::ConnectionPool;
 use super::ipv4_header::Ipv4Header;
 use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
+use super::net;
+use super::out_of_order_queue::OutOfOrderQueue;
 use super::packet_source::PacketSource;
+use super::packet_times::PacketTimes;
 use super::packetizer::Packetizer;
+use super::payload_dump::{self, PayloadDump};
+use super::payload_rewriter::Direction;
 use super::selector::Selector;
+use super::socket_buffers::{self, BufferSizes};
+use super::socket_priority;
 use super::stream_buffer::StreamBuffer;
-use super::tcp_header::{self, TcpHeader, TcpHeaderMut};
+use super::tcp_header::{self, ResetReply, TcpHeader, TcpHeaderMut};
+#[cfg(feature = "tls-sni")]
+use super::tls_sni;
 use super::transport_header::{TransportHeader, TransportHeaderMut};
 
 const TAG: &str = "TcpConnection";
@@ -41,20 +54,147 @@ const TAG: &str = "TcpConnection";
 // same value as GnirehtetService.MTU in the client
 const MTU: u16 = 0x4000;
 // 20 bytes for IP headers, 20 bytes for TCP headers
-const MAX_PAYLOAD_LENGTH: u16 = MTU - 20 - 20 as u16;
+const MAX_PAYLOAD_LENGTH: u16 = MTU - 20 - 20;
+
+// bound the bookkeeping of sparse out-of-order segments
+const MAX_OUT_OF_ORDER_SEGMENTS: usize = 64;
 
 pub struct TcpConnection {
     self_weak: Weak<RefCell<TcpConnection>>,
     id: ConnectionId,
     client: Weak<RefCell<Client>>,
-    stream: TcpStream,
+    // None while the connection to the network is deferred
+    stream: Option<TcpStream>,
+    // the address of the stream to the network, once created
+    local_address: Option<SocketAddr>,
+    deferred_connect: Option<DeferredConnect>,
     interests: Ready,
     token: Token,
     client_to_network: StreamBuffer,
+    out_of_order: OutOfOrderQueue,
     network_to_client: Packetizer,
     packet_for_client_length: Option<u16>,
     closed: bool,
     tcb: Tcb,
+    created: Instant,
+    // time from the SYN to the completion of the connection to the network
+    connected_in: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    keepalive: Option<Keepalive>,
+    write_timeout: Option<Duration>,
+    // last time pending data were written to the network (or started to be pending)
+    last_write_progress: Instant,
+    payload_dump: Option<PayloadDump>,
+    backpressure: Backpressure,
+    // close by a RST rather than a FIN on network EOF
+    abortive_close: bool,
+    // forward a FIN from the client as a half-close, instead of closing both directions
+    half_close: bool,
+    // both directions are shut down but data remain to be read, the stream is deregistered until
+    // the client may receive them (the poll would report HUP continuously)
+    suspended_on_hup: bool,
+    packet_times: PacketTimes,
+    label: Option<String>,
+    // why the connection was closed, when it is not a normal close
+    close_reason: Option<CloseReason>,
+    // neither read from the network nor written to it, on request
+    paused: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CloseReason {
+    // the connection to the network failed while connecting
+    ConnectFailed,
+    // the connection to the network was reset once connected
+    PeerReset,
+    // the client did not answer the keepalive probes
+    ProbeFailed,
+    // the connection exceeded the max lifetime
+    MaxLifetime,
+    // the pending data could not be written to the network for too long
+    WriteTimeout,
+    // the client closed or its link broke, the connection is closed without notifying it
+    ClientGone,
+}
+
+// reading from the network is suspended while the client cannot receive more data (full window
+// or pending packet)
+#[derive(Default)]
+struct Backpressure {
+    since: Option<Instant>,
+}
+
+impl Backpressure {
+    /// Return the duration of the backpressure once it is released.
+    fn update(&mut self, active: bool) -> Option<Duration> {
+        match (active, self.since) {
+            (true, None) => {
+                self.since = Some(Instant::now());
+                None
+            }
+            (false, Some(since)) => {
+                self.since = None;
+                Some(since.elapsed())
+            }
+            _ => None,
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.since.is_some()
+    }
+}
+
+// the connection to the network is postponed until the client sends data (or the delay expires),
+// so that connections closed right after the handshake never reach the network
+struct DeferredConnect {
+    destination: SocketAddr,
+    nodelay: bool,
+    buffer_sizes: BufferSizes,
+    priority: Option<u32>,
+    since: Instant,
+    delay: Duration,
+}
+
+impl DeferredConnect {
+    fn is_due(&self, pending_data: bool, fin_received: bool, now: Instant) -> bool {
+        if pending_data {
+            return true;
+        }
+        // a client which closed without sending anything does not need the network
+        !fin_received && now.duration_since(self.since) >= self.delay
+    }
+}
+
+struct Keepalive {
+    interval: Duration,
+    max_probes: u32,
+    // last time a packet was received from the client
+    idle_since: Instant,
+    probes_sent: u32,
+    timed_out: bool,
+}
+
+impl Keepalive {
+    fn new(interval: Duration, max_probes: u32) -> Self {
+        Self {
+            interval,
+            max_probes,
+            idle_since: Instant::now(),
+            probes_sent: 0,
+            timed_out: false,
+        }
+    }
+
+    fn touch(&mut self) {
+        self.idle_since = Instant::now();
+        self.probes_sent = 0;
+    }
+
+    fn must_probe(&self) -> bool {
+        // probes are sent every interval once the connection is idle
+        self.idle_since.elapsed() >= self.interval * (self.probes_sent + 1)
+    }
 }
 
 // Transport Control Block
@@ -110,6 +250,12 @@ impl Tcb {
         }
     }
 
+    /// Whether the FIN received from the client may be processed, i.e. once the connection is
+    /// established and all the data preceding it has been written to the network.
+    fn may_process_fin(&self, pending_data: bool) -> bool {
+        self.fin_received && !pending_data && self.state.is_connected()
+    }
+
     fn remaining_client_window(&self) -> u16 {
         let wrapped_remaining = Wrapping(self.their_acknowledgement_number)
             + Wrapping(u32::from(self.client_window))
@@ -122,6 +268,30 @@ impl Tcb {
         }
     }
 
+    /// Update the client window from an acceptable segment.
+    ///
+    /// Return `true` if the window was full and it reopens, so that reading from the network must
+    /// resume.
+    fn update_client_window(&mut self, window: u16, their_acknowledgement_number: u32) -> bool {
+        let was_full = self.remaining_client_window() == 0;
+        self.client_window = window;
+        self.their_acknowledgement_number = their_acknowledgement_number;
+        was_full && self.remaining_client_window() > 0
+    }
+
+    /// Describe the sequence space (RFC 793 variables), given the client data not written to the
+    /// network yet and the space available to store more.
+    fn sequence_space(&self, buffered: usize, receive_window: usize) -> String {
+        format!(
+            "snd.una={} snd.nxt={} snd.wnd={} rcv.nxt={} rcv.wnd={}",
+            self.their_acknowledgement_number,
+            self.sequence_number,
+            self.client_window,
+            self.acknowledgement_number + Wrapping(buffered as u32),
+            receive_window
+        )
+    }
+
     fn numbers(&self) -> String {
         format!(
             "(seq={}, ack={})",
@@ -136,30 +306,51 @@ impl TcpConnection {
         selector: &mut Selector,
         id: ConnectionId,
         client: Weak<RefCell<Client>>,
+        config: &RelayConfig,
+        connection_pool: &mut ConnectionPool,
         ipv4_header: Ipv4Header,
         transport_header: TransportHeader,
     ) -> io::Result<Rc<RefCell<Self>>> {
         cx_info!(target: TAG, id, "Open");
-        let stream = Self::create_stream(&id)?;
-
-        let tcp_header = Self::tcp_header_of_transport(transport_header);
+        let destination = id.resolved_destination(config.destination_resolver())?;
+        let buffer_sizes = config.socket_buffer_sizes();
+        let priority = socket_priority::priority_of(config.dscp_priorities(), ipv4_header.dscp());
+        let (stream, deferred_connect) = match config.tcp_defer_connect() {
+            Some(delay) => {
+                cx_debug!(target: TAG, id, "Connection deferred until the client sends data");
+                let deferred_connect = DeferredConnect {
+                    destination: destination.into(),
+                    nodelay: config.tcp_nodelay(),
+                    buffer_sizes,
+                    priority,
+                    since: Instant::now(),
+                    delay,
+                };
+                (None, Some(deferred_connect))
+            }
+            None => {
+                let stream = match connection_pool.take(destination) {
+                    Some(stream) => {
+                        cx_debug!(target: TAG, id, "Using spare connection");
+                        Self::configure_stream(&stream, config.tcp_nodelay())?;
+                        stream
+                    }
+                    None => Self::create_stream(&destination.into(), config.tcp_nodelay())?,
+                };
+                Self::set_socket_options(&id, &stream, buffer_sizes, priority);
+                (Some(stream), None)
+            }
+        };
 
-        // shrink the TCP options to pass a minimal refrence header to the packetizer
-        let mut shrinked_tcp_header_raw = [0u8; 20];
-        shrinked_tcp_header_raw.copy_from_slice(&tcp_header.raw()[..20]);
-        let mut shrinked_tcp_header_data = tcp_header.data().clone();
-        {
-            let mut shrinked_tcp_header =
-                shrinked_tcp_header_data.bind_mut(&mut shrinked_tcp_header_raw);
-            shrinked_tcp_header.shrink_options();
-            assert_eq!(20, shrinked_tcp_header.header_length());
-        }
+        let destination_ip = *id.destination().ip();
+        let abortive_close = config.tcp_reset_networks().is_some_and(|networks| {
+            networks.iter().any(|&(network, prefix_length)| {
+                net::is_in_network(destination_ip, network, prefix_length)
+            })
+        });
 
-        let shrinked_transport_header = shrinked_tcp_header_data
-            .bind(&shrinked_tcp_header_raw)
-            .into();
-
-        let packetizer = Packetizer::new(&ipv4_header, &shrinked_transport_header);
+        let tcp_header = Self::tcp_header_of_transport(transport_header);
+        let packetizer = Self::create_packetizer(&ipv4_header, &tcp_header);
 
         // interests will be set on the first packet received
         // set the initial value now so that they won't need to be updated
@@ -168,14 +359,34 @@ impl TcpConnection {
             self_weak: Weak::new(),
             id,
             client,
+            local_address: stream.as_ref().and_then(|stream| stream.local_addr().ok()),
             stream,
+            deferred_connect,
             interests,
             token: Token(0), // default value, will be set afterwards
             client_to_network: StreamBuffer::new(4 * MAX_PACKET_LENGTH),
+            out_of_order: OutOfOrderQueue::new(4 * MAX_PACKET_LENGTH, MAX_OUT_OF_ORDER_SEGMENTS),
             network_to_client: packetizer,
             packet_for_client_length: None,
             closed: false,
             tcb: Tcb::new(),
+            created: Instant::now(),
+            connected_in: None,
+            max_lifetime: config.max_connection_lifetime(),
+            keepalive: config
+                .tcp_keepalive_interval()
+                .map(|interval| Keepalive::new(interval, config.tcp_keepalive_probes())),
+            write_timeout: config.tcp_write_timeout(),
+            last_write_progress: Instant::now(),
+            payload_dump: config.payload_dump_length().map(PayloadDump::new),
+            backpressure: Backpressure::default(),
+            abortive_close,
+            half_close: config.tcp_half_close(),
+            suspended_on_hup: false,
+            packet_times: PacketTimes::default(),
+            label: None,
+            close_reason: None,
+            paused: false,
         }));
 
         {
@@ -184,19 +395,144 @@ impl TcpConnection {
             // keep a shared reference to this
             self_ref.self_weak = Rc::downgrade(&rc);
 
-            let rc2 = rc.clone();
-            // must annotate selector type: https://stackoverflow.com/a/44004103/1987178
-            let handler =
-                move |selector: &mut Selector, event| rc2.borrow_mut().on_ready(selector, event);
-            let token =
-                selector.register(&self_ref.stream, handler, interests, PollOpt::level())?;
-            self_ref.token = token;
+            if self_ref.stream.is_some() {
+                self_ref.register_stream(selector)?;
+            }
         }
         Ok(rc)
     }
 
-    fn create_stream(id: &ConnectionId) -> io::Result<TcpStream> {
-        TcpStream::connect(&id.rewritten_destination().into())
+    fn register_stream(&mut self, selector: &mut Selector) -> io::Result<()> {
+        let rc = self
+            .self_weak
+            .upgrade()
+            .expect("Expected connection not found");
+        // must annotate selector type: https://stackoverflow.com/a/44004103/1987178
+        let handler =
+            move |selector: &mut Selector, event| rc.borrow_mut().on_ready(selector, event);
+        let stream = self.stream.as_ref().expect("No stream to register");
+        self.token = selector.register(stream, handler, self.interests, PollOpt::level())?;
+        Ok(())
+    }
+
+    /// Reject the first packet of a connection by a RST, without creating the connection.
+    pub fn reject(
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        id: &ConnectionId,
+        ipv4_packet: &Ipv4Packet,
+    ) {
+        let (ipv4_header, transport_header) = ipv4_packet.headers();
+        let tcp_header = Self::tcp_header_of_transport(transport_header.expect("No transport"));
+        if tcp_header.is_rst() {
+            // never reply a RST to a RST
+            return;
+        }
+        let payload_length = ipv4_packet
+            .payload()
+            .map_or(0, |payload| payload.len() as u32);
+        let reset = tcp_header.data().reset_reply(payload_length);
+
+        let mut packetizer = Self::create_packetizer(&ipv4_header, &tcp_header);
+        let mut tcb = Tcb::new();
+        tcb.sequence_number = Wrapping(reset.sequence_number);
+        tcb.acknowledgement_number = Wrapping(reset.acknowledgement_number);
+        let ipv4_packet =
+            Self::create_empty_response_packet(id, &mut packetizer, &tcb, reset.flags);
+        if let Err(err) = client_channel.send_to_client(selector, &ipv4_packet) {
+            cx_warn!(target: TAG, id, "Cannot send packet to client: {}", err);
+        }
+    }
+
+    fn create_packetizer(ipv4_header: &Ipv4Header, tcp_header: &TcpHeader) -> Packetizer {
+        // shrink the TCP options to pass a minimal refrence header to the packetizer
+        let mut shrinked_tcp_header_raw = [0u8; 20];
+        shrinked_tcp_header_raw.copy_from_slice(&tcp_header.raw()[..20]);
+        let mut shrinked_tcp_header_data = tcp_header.data().clone();
+        {
+            let mut shrinked_tcp_header =
+                shrinked_tcp_header_data.bind_mut(&mut shrinked_tcp_header_raw);
+            shrinked_tcp_header.shrink_options();
+            assert_eq!(20, shrinked_tcp_header.header_length());
+        }
+
+        let shrinked_transport_header = shrinked_tcp_header_data
+            .bind(&shrinked_tcp_header_raw)
+            .into();
+
+        Packetizer::new(ipv4_header, &shrinked_transport_header)
+    }
+
+    fn create_stream(destination: &SocketAddr, nodelay: bool) -> io::Result<TcpStream> {
+        let stream = TcpStream::connect(destination)?;
+        Self::configure_stream(&stream, nodelay)?;
+        Ok(stream)
+    }
+
+    fn configure_stream(stream: &TcpStream, nodelay: bool) -> io::Result<()> {
+        stream.set_nodelay(nodelay)
+    }
+
+    fn set_socket_options(
+        id: &ConnectionId,
+        stream: &TcpStream,
+        buffer_sizes: BufferSizes,
+        priority: Option<u32>,
+    ) {
+        if !buffer_sizes.is_empty() {
+            // mio connects on creation, so the sizes do not apply to the window scale negotiated
+            // by the SYN
+            match socket_buffers::apply(stream, buffer_sizes) {
+                Ok(effective) => cx_debug!(target: TAG, id, "Socket buffers: {}", effective),
+                Err(err) => cx_warn!(target: TAG, id, "Cannot set socket buffers: {}", err),
+            }
+        }
+        if let Some(priority) = priority {
+            // the SYN is already sent, but the priority applies to the rest of the stream
+            if let Err(err) = socket_priority::set_priority(stream, priority) {
+                cx_warn!(target: TAG, id, "Cannot set socket priority: {}", err);
+            }
+        }
+    }
+
+    /// Connect to the network once the client sent data or the deferral delay expired.
+    fn connect_if_due(&mut self, selector: &mut Selector, client_channel: &mut ClientChannel) {
+        let pending_data = !self.client_to_network.is_empty();
+        let fin_received = self.tcb.fin_received;
+        let due = self
+            .deferred_connect
+            .as_ref()
+            .is_some_and(|deferred| deferred.is_due(pending_data, fin_received, Instant::now()));
+        if !due {
+            return;
+        }
+        let deferred = self.deferred_connect.take().unwrap();
+        cx_debug!(
+            target: TAG,
+            self.id,
+            "Deferred connection after {} ms",
+            deferred.since.elapsed().as_millis()
+        );
+        let result =
+            Self::create_stream(&deferred.destination, deferred.nodelay).and_then(|stream| {
+                Self::set_socket_options(
+                    &self.id,
+                    &stream,
+                    deferred.buffer_sizes,
+                    deferred.priority,
+                );
+                self.local_address = stream.local_addr().ok();
+                self.stream = Some(stream);
+                // writable is first triggered when the stream is connected, failures are reported
+                // as errors (and reset the client)
+                self.interests = Ready::writable();
+                self.register_stream(selector)
+            });
+        if let Err(err) = result {
+            cx_error!(target: TAG, self.id, "Cannot connect: {}", err);
+            self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_RST);
+            self.close(selector);
+        }
     }
 
     fn remove_from_router(&self) {
@@ -222,9 +558,15 @@ impl TcpConnection {
             let ready = event.readiness();
             if ready.is_readable() || ready.is_writable() {
                 if ready.is_writable() {
+                    if self.connected_in.is_none() {
+                        // also after a deferred connect, the client is not in SynSent anymore
+                        self.record_connected();
+                    }
                     if self.tcb.state == TcpState::SynSent {
                         // writable is first triggered when the stream is connected
                         self.process_connect(selector);
+                    } else if self.client_to_network.is_empty() && !self.tcb.fin_received {
+                        // connected once the deferral delay expired, nothing to write yet
                     } else {
                         self.process_send(selector)?;
                     }
@@ -238,6 +580,28 @@ impl TcpConnection {
             } else {
                 cx_debug!(target: TAG, self.id, "received ready = {:?}", ready);
                 // error or hup
+                if self.tcb.state == TcpState::CloseWait && !self.has_stream_error() {
+                    // the network closed its side too, but the client window is full
+                    cx_debug!(target: TAG, self.id, "Network closed, waiting for client window");
+                    self.suspend_on_hup(selector);
+                    return Ok(());
+                }
+                self.close_reason = Self::close_reason_on_error(&self.tcb.state);
+                match self.close_reason {
+                    Some(CloseReason::ConnectFailed) => {
+                        // reject the SYN of the client
+                        let reset = ResetReply::new(self.tcb.syn_sequence_number, None, 1);
+                        self.send_reset_to_client(selector, reset);
+                    }
+                    Some(CloseReason::PeerReset) => {
+                        // possibly while no read was requested (for example before the client
+                        // acknowledged the SYN-ACK), reset it on the client side too, instead of
+                        // leaving it half-open
+                        cx_debug!(target: TAG, self.id, "Connection reset by peer");
+                        self.send_empty_packet_to_client(selector, tcp_header::FLAG_RST);
+                    }
+                    _ => (),
+                }
                 self.close(selector);
             }
             if self.closed {
@@ -250,12 +614,16 @@ impl TcpConnection {
 
     // return Err(err) with err.kind() == io::ErrorKind::WouldBlock on spurious event
     fn process_send(&mut self, selector: &mut Selector) -> io::Result<()> {
-        match self.client_to_network.write_to(&mut self.stream) {
+        let stream = self.stream.as_mut().expect("No stream to write to");
+        match self.client_to_network.write_to(stream) {
             Ok(w) => {
                 if w != 0 {
                     self.tcb.acknowledgement_number += Wrapping(w as u32);
+                    self.last_write_progress = Instant::now();
+                    self.packet_times
+                        .record(Direction::ToNetwork, self.last_write_progress);
 
-                    if self.tcb.fin_received && self.client_to_network.is_empty() {
+                    if self.tcb.may_process_fin(!self.client_to_network.is_empty()) {
                         let client_rc = self.client.upgrade().expect("Expected client not found");
                         let mut client = client_rc.borrow_mut();
                         cx_debug!(
@@ -289,6 +657,9 @@ impl TcpConnection {
                     err.kind(),
                     err
                 );
+                if err.kind() == io::ErrorKind::ConnectionReset {
+                    self.close_reason = Some(CloseReason::PeerReset);
+                }
                 self.send_empty_packet_to_client(selector, tcp_header::FLAG_RST);
                 self.close(selector);
             }
@@ -314,14 +685,27 @@ impl TcpConnection {
             &self.tcb,
             tcp_header::FLAG_ACK | tcp_header::FLAG_PSH,
         );
-        match self
-            .network_to_client
-            .packetize_read(&mut self.stream, max_payload_length)
-        {
+        match self.network_to_client.packetize_read(
+            self.stream.as_mut().expect("No stream to read from"),
+            max_payload_length,
+        ) {
             Ok(Some(ipv4_packet)) => {
                 match Self::send_to_client(&self.client, selector, &ipv4_packet) {
                     Ok(_) => {
-                        let len = ipv4_packet.payload().unwrap().len();
+                        let payload = ipv4_packet.payload().unwrap();
+                        if let Some(ref mut payload_dump) = self.payload_dump {
+                            if let Some(data) = payload_dump.capture(Direction::ToDevice, payload) {
+                                cx_info!(
+                                    target: TAG,
+                                    self.id,
+                                    "Payload to device: {}",
+                                    payload_dump::format(data)
+                                );
+                            }
+                        }
+                        self.packet_times
+                            .record(Direction::ToDevice, Instant::now());
+                        let len = payload.len();
                         cx_debug!(
                             target: TAG,
                             self.id,
@@ -356,6 +740,9 @@ impl TcpConnection {
                     err.kind(),
                     err
                 );
+                if err.kind() == io::ErrorKind::ConnectionReset {
+                    self.close_reason = Some(CloseReason::PeerReset);
+                }
                 self.send_empty_packet_to_client(selector, tcp_header::FLAG_RST);
                 self.close(selector);
             }
@@ -363,6 +750,18 @@ impl TcpConnection {
         Ok(())
     }
 
+    fn record_connected(&mut self) {
+        // the connection is created on the first packet (the SYN) from the client
+        let connected_in = self.created.elapsed();
+        cx_info!(
+            target: TAG,
+            self.id,
+            "Connected in {} ms",
+            connected_in.as_millis()
+        );
+        self.connected_in = Some(connected_in);
+    }
+
     fn process_connect(&mut self, selector: &mut Selector) {
         assert_eq!(self.tcb.state, TcpState::SynSent);
         self.tcb.state = TcpState::SynReceived;
@@ -378,7 +777,7 @@ impl TcpConnection {
     ) -> io::Result<()> {
         let client_rc = client.upgrade().expect("Expected client not found");
         let mut client = client_rc.borrow_mut();
-        client.send_to_client(selector, &ipv4_packet)
+        client.send_to_client(selector, ipv4_packet)
     }
 
     /// Borrow self.client and send empty packet to it
@@ -417,18 +816,147 @@ impl TcpConnection {
         }
     }
 
+    /// Borrow self.client and send a RST to it
+    ///
+    /// To be used if called by on_ready() (so the client is not borrowed yet).
+    fn send_reset_to_client(&mut self, selector: &mut Selector, reset: ResetReply) {
+        let client_rc = self.client.upgrade().expect("Expected client not found");
+        let mut client = client_rc.borrow_mut();
+        self.reply_reset_to_client(selector, &mut client.channel(), reset)
+    }
+
+    /// Send a RST to the client channel (that already borrows the client)
+    fn reply_reset_to_client(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        reset: ResetReply,
+    ) {
+        // the connection is closed afterwards, the TCB may be overwritten
+        self.tcb.sequence_number = Wrapping(reset.sequence_number);
+        self.tcb.acknowledgement_number = Wrapping(reset.acknowledgement_number);
+        self.reply_empty_packet_to_client(selector, client_channel, reset.flags);
+    }
+
+    fn send_keepalive_probe(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+    ) {
+        cx_debug!(target: TAG, self.id, "Sending keepalive probe {}", self.tcb.numbers());
+        Self::update_headers(&mut self.network_to_client, &self.tcb, tcp_header::FLAG_ACK);
+        {
+            // a keepalive probe is an empty segment with a sequence number already acked
+            let mut tcp_header =
+                Self::tcp_header_of_transport_mut(self.network_to_client.transport_header_mut());
+            tcp_header.set_sequence_number((self.tcb.sequence_number - Wrapping(1)).0);
+        }
+        let ipv4_packet = self.network_to_client.packetize_empty_payload();
+        if let Err(err) = client_channel.send_to_client(selector, &ipv4_packet) {
+            // the probe will be considered unanswered, which is what we expect if the client is lost
+            cx_warn!(
+                target: TAG,
+                self.id,
+                "Cannot send keepalive probe to client: {}",
+                err
+            );
+        }
+    }
+
     fn eof(&mut self, selector: &mut Selector) {
-        self.send_empty_packet_to_client(selector, tcp_header::FLAG_FIN | tcp_header::FLAG_ACK);
+        let flags = Self::eof_flags(self.abortive_close);
+        self.send_empty_packet_to_client(selector, flags);
+        if flags & tcp_header::FLAG_RST != 0 {
+            cx_debug!(target: TAG, self.id, "Abortive close");
+            self.close(selector);
+            return;
+        }
         self.tcb.fin_sequence_number = Some(self.tcb.sequence_number.0);
         self.tcb.sequence_number += Wrapping(1); // FIN counts for 1 byte
-        self.tcb.state = if self.tcb.state == TcpState::CloseWait {
-            TcpState::LastAck
+        if self.tcb.state == TcpState::CloseWait {
+            self.tcb.state = TcpState::LastAck;
+            // both directions are closed, the stream is not needed anymore (and the poll would
+            // report HUP continuously until the client acknowledges the FIN)
+            self.release_stream(selector);
         } else {
-            TcpState::FinWait1
-        };
+            self.tcb.state = TcpState::FinWait1;
+        }
         cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
     }
 
+    fn has_stream_error(&self) -> bool {
+        match self.stream {
+            Some(ref stream) => !matches!(stream.take_error(), Ok(None)),
+            None => false,
+        }
+    }
+
+    fn deregister_stream(&mut self, selector: &mut Selector) {
+        if let Some(ref stream) = self.stream {
+            if let Err(err) = selector.deregister(stream, self.token) {
+                // do not panic, this can happen in mio
+                // see <https://github.com/Genymobile/gnirehtet/issues/136>
+                cx_warn!(
+                    target: TAG,
+                    self.id,
+                    "Fail to deregister TCP stream: {:?}",
+                    err
+                );
+            }
+        }
+    }
+
+    fn suspend_on_hup(&mut self, selector: &mut Selector) {
+        self.deregister_stream(selector);
+        self.suspended_on_hup = true;
+    }
+
+    fn release_stream(&mut self, selector: &mut Selector) {
+        if !self.suspended_on_hup {
+            self.deregister_stream(selector);
+        }
+        self.suspended_on_hup = false;
+        // socket will be closed by RAII
+        self.stream = None;
+    }
+
+    /// The state reached on a FIN from the client while established (the FIN is always ACKed).
+    ///
+    /// Without half-close (or without a network stream to keep reading), the relay replies its
+    /// own FIN immediately.
+    fn state_on_client_fin(half_close: bool, has_stream: bool) -> TcpState {
+        if half_close && has_stream {
+            TcpState::CloseWait
+        } else {
+            TcpState::LastAck
+        }
+    }
+
+    /// The flags of the packet notifying the client that the network side closed.
+    fn eof_flags(abortive_close: bool) -> u16 {
+        if abortive_close {
+            tcp_header::FLAG_RST
+        } else {
+            tcp_header::FLAG_FIN | tcp_header::FLAG_ACK
+        }
+    }
+
+    /// The reason of a close on a stream error or hangup, in `state`.
+    ///
+    /// Return `None` if the close is graceful: the client already received a FIN (or never
+    /// received a SYN-ACK), so it must not be reset.
+    ///
+    /// In `CloseWait`, a hangup without stream error is not a close (it is handled before).
+    fn close_reason_on_error(state: &TcpState) -> Option<CloseReason> {
+        match *state {
+            TcpState::SynSent => Some(CloseReason::ConnectFailed),
+            TcpState::SynReceived | TcpState::Established | TcpState::CloseWait => {
+                Some(CloseReason::PeerReset)
+            }
+            _ => None,
+        }
+    }
+
     #[inline]
     fn tcp_header_of_transport(transport_header: TransportHeader) -> TcpHeader {
         if let TransportHeader::Tcp(tcp_header) = transport_header {
@@ -483,22 +1011,45 @@ impl TcpConnection {
         let expected_packet =
             (self.tcb.acknowledgement_number + Wrapping(self.client_to_network.size() as u32)).0;
         if tcp_header.sequence_number() != expected_packet {
-            // ignore packet already received or out-of-order, retransmission is already
-            // managed by both sides
-            cx_warn!(
-                target: TAG,
-                self.id,
-                "Ignoring packet {} (acking {}); expecting {}; flags={}",
+            if self.queue_out_of_order(ipv4_packet, expected_packet) {
+                return;
+            }
+            let payload_length = ipv4_packet.payload().map_or(0, |payload| payload.len());
+            if Self::is_keepalive(
                 tcp_header.sequence_number(),
-                tcp_header.acknowledgement_number(),
                 expected_packet,
-                tcp_header.flags()
-            );
+                payload_length,
+            ) {
+                cx_debug!(target: TAG, self.id, "Received keepalive probe");
+            } else {
+                // ignore packet already received or not queued, retransmission is already managed
+                // by both sides
+                cx_warn!(
+                    target: TAG,
+                    self.id,
+                    "Ignoring packet {} (acking {}); expecting {}; flags={}",
+                    tcp_header.sequence_number(),
+                    tcp_header.acknowledgement_number(),
+                    expected_packet,
+                    tcp_header.flags()
+                );
+            }
+            if self.tcb.state != TcpState::SynSent && !tcp_header.is_rst() {
+                // an unacceptable segment must be acknowledged (RFC 793 section 3.9), this
+                // notably answers the keepalive probes
+                self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_ACK);
+            }
             return;
         }
 
-        self.tcb.client_window = tcp_header.window();
-        self.tcb.their_acknowledgement_number = tcp_header.acknowledgement_number();
+        if self
+            .tcb
+            .update_client_window(tcp_header.window(), tcp_header.acknowledgement_number())
+        {
+            // the interests are updated once the packet is handled, so reading resumes
+            // immediately
+            cx_debug!(target: TAG, self.id, "Client window reopened");
+        }
 
         cx_debug!(
             target: TAG,
@@ -558,8 +1109,25 @@ impl TcpConnection {
                 self.tcb.acknowledgement_number
             );
             self.tcb.client_window = tcp_header.window();
+            cx_debug!(
+                target: TAG,
+                self.id,
+                "SYN options: mss={:?}; window_scale={:?}",
+                tcp_header.mss(),
+                tcp_header.window_scale()
+            );
             self.tcb.state = TcpState::SynSent;
             cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
+            self.buffer_syn_payload(ipv4_packet);
+            self.buffer_syn_fin(&tcp_header);
+            if self.deferred_connect.is_some() {
+                // do not wait for the network to accept the connection
+                self.tcb.state = TcpState::SynReceived;
+                cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
+                let flags = tcp_header::FLAG_SYN | tcp_header::FLAG_ACK;
+                self.reply_empty_packet_to_client(selector, client_channel, flags);
+                self.tcb.sequence_number += Wrapping(1); // SYN counts for 1 byte
+            }
         } else {
             cx_warn!(
                 target: TAG,
@@ -569,13 +1137,61 @@ impl TcpConnection {
                 tcp_header.acknowledgement_number(),
                 tcp_header.flags()
             );
-            // make a RST in the window client
-            self.tcb.sequence_number = Wrapping(tcp_header.acknowledgement_number());
-            self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_RST);
+            let payload_length = ipv4_packet
+                .payload()
+                .map_or(0, |payload| payload.len() as u32);
+            if !tcp_header.is_rst() {
+                // never reply a RST to a RST
+                let reset = tcp_header.data().reset_reply(payload_length);
+                self.reply_reset_to_client(selector, client_channel, reset);
+            }
             self.close(selector);
         }
     }
 
+    /// Whether a segment is a keepalive probe: one byte behind the expected sequence number,
+    /// carrying no data or a single garbage byte (RFC 1122 section 4.2.3.6).
+    fn is_keepalive(sequence_number: u32, expected_packet: u32, payload_length: usize) -> bool {
+        sequence_number == expected_packet.wrapping_sub(1) && payload_length <= 1
+    }
+
+    /// Buffer the data carried by a SYN, to be written once connected.
+    fn buffer_syn_payload(&mut self, ipv4_packet: &Ipv4Packet) {
+        let payload = ipv4_packet.payload().expect("No payload");
+        // a retransmitted SYN carries the same data
+        if !payload.is_empty() && self.client_to_network.is_empty() {
+            cx_debug!(
+                target: TAG,
+                self.id,
+                "Buffering {} bytes received with the SYN",
+                payload.len()
+            );
+            self.client_to_network.read_from(payload);
+            self.dump_payload_to_network(payload);
+        }
+    }
+
+    /// Remember the FIN carried by a SYN, to be processed once its data is written.
+    fn buffer_syn_fin(&mut self, tcp_header: &TcpHeader) {
+        if tcp_header.is_fin() {
+            cx_debug!(target: TAG, self.id, "Received a FIN along with the SYN");
+            self.tcb.fin_received = true;
+        }
+    }
+
+    fn dump_payload_to_network(&mut self, payload: &[u8]) {
+        if let Some(ref mut payload_dump) = self.payload_dump {
+            if let Some(data) = payload_dump.capture(Direction::ToNetwork, payload) {
+                cx_info!(
+                    target: TAG,
+                    self.id,
+                    "Payload to network: {}",
+                    payload_dump::format(data)
+                );
+            }
+        }
+    }
+
     fn handle_duplicate_syn(
         &mut self,
         selector: &mut Selector,
@@ -589,9 +1205,12 @@ impl TcpConnection {
             // first SYN
             self.tcb.syn_sequence_number = their_sequence_number;
             self.tcb.acknowledgement_number = Wrapping(their_sequence_number) + Wrapping(1);
+            self.buffer_syn_payload(ipv4_packet);
+            self.buffer_syn_fin(&tcp_header);
         } else if their_sequence_number != self.tcb.syn_sequence_number {
             // duplicate SYN with different sequence number
-            self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_RST);
+            let reset = tcp_header.data().reset_reply(0);
+            self.reply_reset_to_client(selector, client_channel, reset);
             self.close(selector);
         }
     }
@@ -604,8 +1223,12 @@ impl TcpConnection {
             self.tcb.numbers()
         );
 
+        if self.tcb.fin_received {
+            // already pending (or processed), this is a retransmission
+            return;
+        }
         self.tcb.fin_received = true;
-        if self.client_to_network.is_empty() {
+        if self.tcb.may_process_fin(!self.client_to_network.is_empty()) {
             cx_debug!(
                 target: TAG,
                 self.id,
@@ -613,23 +1236,32 @@ impl TcpConnection {
             );
             self.do_handle_fin(selector, client_channel);
         }
-        // otherwise, the FIN will be processed once client_to_network is empty
+        // otherwise, the FIN will be processed once the connection is established and
+        // client_to_network is empty
     }
 
     fn do_handle_fin(&mut self, selector: &mut Selector, client_channel: &mut ClientChannel) {
         self.tcb.acknowledgement_number += Wrapping(1); // received FIN counts for 1 byte
 
         if self.tcb.state == TcpState::Established {
-            self.reply_empty_packet_to_client(
-                selector,
-                client_channel,
-                tcp_header::FLAG_FIN | tcp_header::FLAG_ACK,
-            );
-            self.tcb.fin_sequence_number = Some(self.tcb.sequence_number.0);
-            self.tcb.sequence_number += Wrapping(1); // FIN counts for 1 byte
-                                                     // the connection will be closed by RAII, so switch immediately to LastAck
-                                                     // (bypass CloseWait)
-            self.tcb.state = TcpState::LastAck;
+            let state = Self::state_on_client_fin(self.half_close, self.stream.is_some());
+            if state == TcpState::CloseWait {
+                // the network may still send data, only forward the half-close
+                self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_ACK);
+                let stream = self.stream.as_ref().unwrap();
+                if let Err(err) = stream.shutdown(Shutdown::Write) {
+                    cx_warn!(target: TAG, self.id, "Cannot shutdown TCP stream: {}", err);
+                }
+            } else {
+                self.reply_empty_packet_to_client(
+                    selector,
+                    client_channel,
+                    tcp_header::FLAG_FIN | tcp_header::FLAG_ACK,
+                );
+                self.tcb.fin_sequence_number = Some(self.tcb.sequence_number.0);
+                self.tcb.sequence_number += Wrapping(1); // FIN counts for 1 byte
+            }
+            self.tcb.state = state;
             cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
         } else if self.tcb.state == TcpState::FinWait1 {
             self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_ACK);
@@ -666,15 +1298,22 @@ impl TcpConnection {
 
     fn handle_ack(
         &mut self,
-        _selector: &mut Selector,
-        _client_channel: &mut ClientChannel,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
         ipv4_packet: &Ipv4Packet,
     ) {
         cx_debug!(target: TAG, self.id, "handle_ack()");
         if self.tcb.state == TcpState::SynReceived {
             self.tcb.state = TcpState::Established;
             cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
-            return;
+            if self.tcb.may_process_fin(!self.client_to_network.is_empty()) {
+                // the client sent its data and FIN before the connection was established, and
+                // the data is already written
+                cx_debug!(target: TAG, self.id, "Process the pending FIN");
+                self.do_handle_fin(selector, client_channel);
+                return;
+            }
+            // the ACK of the SYN-ACK may carry data
         }
 
         if log_enabled!(target: TAG, Level::Trace) {
@@ -697,10 +1336,101 @@ impl TcpConnection {
             return;
         }
 
+        #[cfg(feature = "tls-sni")]
+        {
+            let tcp_header = Self::tcp_header_of_packet(ipv4_packet);
+            let first_segment = Wrapping(tcp_header.sequence_number())
+                == Wrapping(self.tcb.syn_sequence_number) + Wrapping(1);
+            if first_segment && self.label.is_none() {
+                if let Some(server_name) = tls_sni::parse_sni(payload) {
+                    cx_debug!(target: TAG, self.id, "TLS server name: {}", server_name);
+                    self.label = Some(server_name);
+                }
+            }
+        }
+
+        if self.client_to_network.is_empty() {
+            // the write timeout starts when data become pending
+            self.last_write_progress = Instant::now();
+        }
         self.client_to_network.read_from(payload);
+        self.dump_payload_to_network(payload);
+        self.flush_out_of_order();
         // data will be ACKed once written to the network socket
     }
 
+    /// Store a data packet received ahead of the expected sequence number.
+    ///
+    /// Return `true` if it has been queued.
+    fn queue_out_of_order(&mut self, ipv4_packet: &Ipv4Packet, expected_packet: u32) -> bool {
+        let tcp_header = Self::tcp_header_of_packet(ipv4_packet);
+        if self.tcb.state != TcpState::Established
+            || tcp_header.is_syn()
+            || tcp_header.is_fin()
+            || tcp_header.is_rst()
+        {
+            return false;
+        }
+        let sequence_number = tcp_header.sequence_number();
+        let offset = (Wrapping(sequence_number) - Wrapping(expected_packet)).0 as i32;
+        if offset <= 0 {
+            // already received
+            return false;
+        }
+        let payload = ipv4_packet.payload().expect("No payload");
+        if payload.is_empty() {
+            return false;
+        }
+        if !self
+            .out_of_order
+            .insert(sequence_number, payload, expected_packet)
+        {
+            cx_warn!(
+                target: TAG,
+                self.id,
+                "Out-of-order queue full ({} bytes), dropping packet {}",
+                self.out_of_order.size(),
+                sequence_number
+            );
+            return false;
+        }
+        cx_debug!(
+            target: TAG,
+            self.id,
+            "Queuing out-of-order packet {}; expecting {}",
+            sequence_number,
+            expected_packet
+        );
+        true
+    }
+
+    fn flush_out_of_order(&mut self) {
+        while !self.out_of_order.is_empty() {
+            let expected_packet = (self.tcb.acknowledgement_number
+                + Wrapping(self.client_to_network.size() as u32))
+            .0;
+            let data = match self.out_of_order.pop(expected_packet) {
+                Some(data) => data,
+                None => break,
+            };
+            if self.client_to_network.remaining() < data.len() {
+                // the client will retransmit the remaining data
+                cx_warn!(target: TAG, self.id, "Not enough space, dropping queued packets");
+                self.out_of_order.clear();
+                break;
+            }
+            cx_debug!(
+                target: TAG,
+                self.id,
+                "Dequeuing {} bytes at {}",
+                data.len(),
+                expected_packet
+            );
+            self.client_to_network.read_from(&data);
+            self.dump_payload_to_network(&data);
+        }
+    }
+
     fn create_empty_response_packet<'a>(
         id: &ConnectionId,
         packetizer: &'a mut Packetizer,
@@ -737,20 +1467,60 @@ impl TcpConnection {
             // waiting for connectable
             ready = Ready::writable()
         } else {
-            if self.may_read() {
+            let may_read = self.may_read();
+            if may_read && !self.paused {
                 ready |= Ready::readable()
             }
-            if self.may_write() {
+            if self.may_write() && !self.paused {
                 ready |= Ready::writable()
             }
+            self.update_backpressure(!may_read);
         }
         cx_debug!(target: TAG, self.id, "interests: {:?}", ready);
-        if self.interests != ready {
-            // interests must be changed
-            self.interests = ready;
-            selector
-                .reregister(&self.stream, self.token, ready, PollOpt::level())
-                .expect("Cannot register on poll");
+        if self.suspended_on_hup {
+            if ready.is_readable() {
+                // the client may receive the remaining data
+                self.suspended_on_hup = false;
+                self.interests = ready;
+                self.register_stream(selector)
+                    .expect("Cannot register on poll");
+            }
+        } else if let Some(ref stream) = self.stream {
+            if self.interests != ready {
+                // interests must be changed
+                self.interests = ready;
+                selector
+                    .reregister(stream, self.token, ready, PollOpt::level())
+                    .expect("Cannot register on poll");
+            }
+        }
+    }
+
+    fn sequence_space(&self) -> String {
+        self.tcb.sequence_space(
+            self.client_to_network.size(),
+            self.client_to_network.remaining(),
+        )
+    }
+
+    fn update_backpressure(&mut self, read_suspended: bool) {
+        // once closed, the network stream is not read anymore, this is not backpressure
+        let active = read_suspended && self.tcb.state.is_connected() && !self.tcb.state.is_closed();
+        let was_active = self.backpressure.is_active();
+        match self.backpressure.update(active) {
+            Some(duration) => cx_debug!(
+                target: TAG,
+                self.id,
+                "Reading from network resumed after {} ms",
+                duration.as_millis()
+            ),
+            None if active && !was_active => cx_debug!(
+                target: TAG,
+                self.id,
+                "Reading from network suspended {}",
+                self.sequence_space()
+            ),
+            None => (),
         }
     }
 
@@ -768,6 +1538,27 @@ impl TcpConnection {
     fn may_write(&self) -> bool {
         !self.client_to_network.is_empty()
     }
+
+    // set the close reason once the write timeout or the max lifetime is exceeded
+    fn check_deadlines(&mut self) {
+        if let Some(write_timeout) = self.write_timeout {
+            if !self.paused
+                && !self.client_to_network.is_empty()
+                && self.last_write_progress.elapsed() >= write_timeout
+            {
+                cx_info!(target: TAG, self.id, "Write timeout");
+                self.close_reason = Some(CloseReason::WriteTimeout);
+                return;
+            }
+        }
+        // no idle timeout, only the (optional) max lifetime
+        if let Some(max_lifetime) = self.max_lifetime {
+            if self.created.elapsed() >= max_lifetime {
+                cx_debug!(target: TAG, self.id, "Max lifetime exceeded");
+                self.close_reason = Some(CloseReason::MaxLifetime);
+            }
+        }
+    }
 }
 
 impl Connection for TcpConnection {
@@ -781,40 +1572,155 @@ impl Connection for TcpConnection {
         client_channel: &mut ClientChannel,
         ipv4_packet: &Ipv4Packet,
     ) {
+        if let Some(ref mut keepalive) = self.keepalive {
+            keepalive.touch();
+        }
         self.handle_packet(selector, client_channel, ipv4_packet);
+        if !self.closed {
+            self.connect_if_due(selector, client_channel);
+        }
         if !self.closed {
             self.update_interests(selector);
         }
     }
 
     fn close(&mut self, selector: &mut Selector) {
-        cx_info!(target: TAG, self.id, "Close");
-        self.closed = true;
-        if let Err(err) = selector.deregister(&self.stream, self.token) {
-            // do not panic, this can happen in mio
-            // see <https://github.com/Genymobile/gnirehtet/issues/136>
-            cx_warn!(
+        match self.close_reason {
+            // the client logs once for all its connections
+            Some(CloseReason::ClientGone) => cx_debug!(target: TAG, self.id, "Close (ClientGone)"),
+            Some(reason) => cx_info!(target: TAG, self.id, "Close ({:?})", reason),
+            None => cx_info!(target: TAG, self.id, "Close"),
+        }
+        cx_debug!(target: TAG, self.id, "{}", self.sequence_space());
+        cx_debug!(
+            target: TAG,
+            self.id,
+            "Packet times: {}",
+            self.packet_times.format(self.created)
+        );
+        if self.out_of_order.dropped() > 0 {
+            cx_info!(
                 target: TAG,
                 self.id,
-                "Fail to deregister TCP stream: {:?}",
-                err
+                "{} out-of-order segments dropped",
+                self.out_of_order.dropped()
             );
         }
-        // socket will be closed by RAII
+        self.closed = true;
+        self.release_stream(selector);
+    }
+
+    fn close_client_gone(&mut self, selector: &mut Selector) {
+        if self.close_reason.is_none() {
+            self.close_reason = Some(CloseReason::ClientGone);
+        }
+        self.close(selector);
+    }
+
+    fn tick(&mut self, selector: &mut Selector, client_channel: &mut ClientChannel) {
+        if !self.closed {
+            // the server may talk first, do not wait for the client forever
+            self.connect_if_due(selector, client_channel);
+        }
+        if !self.closed && self.close_reason.is_none() {
+            self.check_deadlines();
+        }
+        if self.closed || self.close_reason.is_some() || self.tcb.state != TcpState::Established {
+            // only probe connections which might be idle forever
+            return;
+        }
+        let must_probe = match self.keepalive {
+            Some(ref keepalive) => !keepalive.timed_out && keepalive.must_probe(),
+            None => false,
+        };
+        if must_probe {
+            let keepalive = self.keepalive.as_mut().unwrap();
+            if keepalive.probes_sent < keepalive.max_probes {
+                keepalive.probes_sent += 1;
+                self.send_keepalive_probe(selector, client_channel);
+            } else {
+                cx_info!(target: TAG, self.id, "Keepalive timeout");
+                keepalive.timed_out = true;
+                self.close_reason = Some(CloseReason::ProbeFailed);
+            }
+        }
+    }
+
+    fn abort(&mut self, selector: &mut Selector, client_channel: &mut ClientChannel) {
+        if self.tcb.state != TcpState::Init {
+            // the client knows this connection, reset it
+            self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_RST);
+        }
+        self.close(selector);
     }
 
     fn is_expired(&self) -> bool {
-        // no external timeout expiration
-        false
+        // the reason is set on tick()
+        matches!(
+            self.close_reason,
+            Some(CloseReason::ProbeFailed)
+                | Some(CloseReason::MaxLifetime)
+                | Some(CloseReason::WriteTimeout)
+        )
     }
 
     fn is_closed(&self) -> bool {
         self.closed
     }
+
+    fn is_half_open(&self) -> bool {
+        !self.closed && !self.tcb.state.is_connected()
+    }
+
+    fn created(&self) -> Instant {
+        self.created
+    }
+
+    fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    fn set_label(&mut self, label: String) {
+        self.label = Some(label);
+    }
+
+    fn info(&self) -> ConnectionInfo {
+        let state = format!("{:?}", self.tcb.state);
+        let mut info = ConnectionInfo::new(&self.id, state, self.created, self.label())
+            .with_packet_times(&self.packet_times);
+        info.connected_in = self.connected_in;
+        info.local_address = self.local_address;
+        info.paused = self.paused;
+        info
+    }
+
+    fn set_paused(&mut self, selector: &mut Selector, paused: bool) {
+        if self.paused == paused {
+            return;
+        }
+        cx_info!(target: TAG, self.id, "{}", if paused { "Paused" } else { "Resumed" });
+        self.paused = paused;
+        if !paused {
+            // the pending data did not wait for the network
+            self.last_write_progress = Instant::now();
+        }
+        if !self.closed {
+            self.update_interests(selector);
+        }
+    }
+
+    fn detail(&self) -> ConnectionDetail {
+        let mut detail = ConnectionDetail::new(self.info());
+        detail.sequence_space = Some(self.sequence_space());
+        detail.pending_to_network = self.client_to_network.size();
+        detail.out_of_order = self.out_of_order.size();
+        detail.backpressure = self.backpressure.is_active();
+        detail
+    }
 }
 
 impl PacketSource for TcpConnection {
-    fn get(&mut self) -> Option<Ipv4Packet> {
+    fn get(&mut self) -> Option<Ipv4Packet<'_>> {
         if let Some(len) = self.packet_for_client_length {
             Some(self.network_to_client.inflate(len))
         } else {
@@ -835,6 +1741,620 @@ impl PacketSource for TcpConnection {
         );
         self.tcb.sequence_number += Wrapping(u32::from(len));
         self.packet_for_client_length = None;
+        self.packet_times
+            .record(Direction::ToDevice, Instant::now());
         self.update_interests(selector);
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::relay::client::tests::MockDevice;
+    use crate::relay::ipv4_header::Protocol;
+    use crate::relay::ipv4_packet::tests::{create_tcp_packet, set_destination};
+    use net2::TcpStreamExt;
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+
+    // a segment from the device to a local listener, with an open window
+    pub fn create_segment(
+        listener: &TcpListener,
+        flags: u16,
+        sequence_number: u32,
+        acknowledgement_number: u32,
+    ) -> Vec<u8> {
+        let mut raw = create_tcp_packet(flags);
+        let port = listener.local_addr().unwrap().port();
+        set_destination(&mut raw, SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
+        raw[24..28].copy_from_slice(&sequence_number.to_be_bytes());
+        raw[28..32].copy_from_slice(&acknowledgement_number.to_be_bytes());
+        raw[34..36].copy_from_slice(&0xFFFFu16.to_be_bytes());
+        raw
+    }
+
+    pub fn with_payload(mut raw: Vec<u8>, payload: &[u8]) -> Vec<u8> {
+        raw.extend_from_slice(payload);
+        let total_length = raw.len() as u16;
+        raw[2..4].copy_from_slice(&total_length.to_be_bytes());
+        raw
+    }
+
+    pub fn tcp_flags(raw: &[u8]) -> u16 {
+        u16::from_be_bytes([raw[32], raw[33]]) & 0x1FF
+    }
+
+    // Open a connection from the device to the listener, acknowledging the SYN-ACK if `acked`.
+    //
+    // Return the stream accepted by the listener and the next relay sequence number.
+    pub fn connect(
+        selector: &mut Selector,
+        device: &mut MockDevice,
+        listener: &TcpListener,
+        acked: bool,
+    ) -> (std::net::TcpStream, u32) {
+        device.send(&create_segment(listener, tcp_header::FLAG_SYN, 0x111, 0));
+        let syn_ack = device
+            .receive(selector, Duration::from_secs(1))
+            .expect("Expected SYN-ACK");
+        assert_eq!(
+            tcp_header::FLAG_SYN | tcp_header::FLAG_ACK,
+            tcp_flags(&syn_ack)
+        );
+        let sequence_number =
+            u32::from_be_bytes([syn_ack[24], syn_ack[25], syn_ack[26], syn_ack[27]]) + 1;
+        if acked {
+            let ack = create_segment(listener, tcp_header::FLAG_ACK, 0x112, sequence_number);
+            device.send(&ack);
+        }
+        let (stream, _) = listener.accept().unwrap();
+        (stream, sequence_number)
+    }
+
+    // Run the relay until `length` bytes are received by the peer (or a timeout).
+    pub fn read_from_peer(
+        selector: &mut Selector,
+        device: &mut MockDevice,
+        stream: &mut std::net::TcpStream,
+        length: usize,
+    ) -> Vec<u8> {
+        stream.set_nonblocking(true).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut data = Vec::new();
+        let mut buf = [0; 64];
+        while data.len() < length && Instant::now() < deadline {
+            // the device may receive the ACKs of its data meanwhile
+            device.receive(selector, Duration::from_millis(10));
+            match stream.read(&mut buf) {
+                Ok(r) => data.extend_from_slice(&buf[..r]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                Err(err) => panic!("Cannot read from peer: {}", err),
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn detect_keepalive() {
+        assert!(TcpConnection::is_keepalive(41, 42, 0));
+        assert!(TcpConnection::is_keepalive(41, 42, 1));
+        assert!(TcpConnection::is_keepalive(0xFFFF_FFFF, 0, 0));
+        assert!(!TcpConnection::is_keepalive(41, 42, 2));
+        assert!(!TcpConnection::is_keepalive(40, 42, 0));
+        assert!(!TcpConnection::is_keepalive(43, 42, 0));
+    }
+
+    #[test]
+    fn close_on_eof() {
+        let fin = TcpConnection::eof_flags(false);
+        assert_eq!(tcp_header::FLAG_FIN | tcp_header::FLAG_ACK, fin);
+
+        // a clean EOF is still reported by a RST to an abortive destination
+        assert_eq!(tcp_header::FLAG_RST, TcpConnection::eof_flags(true));
+    }
+
+    #[test]
+    fn defer_fin_until_established() {
+        // SYN with data and FIN, received before the connection to the network completes
+        let mut tcb = Tcb::new();
+        tcb.state = TcpState::SynSent;
+        tcb.fin_received = true;
+        assert!(!tcb.may_process_fin(true));
+
+        // connected, the data is written, but the client did not ack the SYN-ACK yet
+        tcb.state = TcpState::SynReceived;
+        assert!(!tcb.may_process_fin(false));
+
+        // the FIN is processed only once established and all the data is written
+        tcb.state = TcpState::Established;
+        assert!(!tcb.may_process_fin(true));
+        assert!(tcb.may_process_fin(false));
+
+        tcb.fin_received = false;
+        assert!(!tcb.may_process_fin(false));
+    }
+
+    #[test]
+    fn half_close_on_client_fin() {
+        let state = TcpConnection::state_on_client_fin(true, true);
+        assert_eq!(TcpState::CloseWait, state);
+        // the network side may still be read
+        assert!(state.is_connected() && !state.is_closed());
+
+        // nothing to receive from the network, or half-close disabled
+        assert_eq!(
+            TcpState::LastAck,
+            TcpConnection::state_on_client_fin(true, false)
+        );
+        assert_eq!(
+            TcpState::LastAck,
+            TcpConnection::state_on_client_fin(false, true)
+        );
+    }
+
+    #[test]
+    fn connect_deferred_on_data() {
+        let since = Instant::now();
+        let deferred = DeferredConnect {
+            destination: "127.0.0.1:80".parse().unwrap(),
+            nodelay: true,
+            buffer_sizes: BufferSizes::default(),
+            priority: None,
+            since,
+            delay: Duration::from_secs(5),
+        };
+        // a handshake-only connection does not connect, nor after the delay once closed
+        assert!(!deferred.is_due(false, false, since));
+        assert!(!deferred.is_due(false, true, since + Duration::from_secs(10)));
+        // data from the client connects immediately
+        assert!(deferred.is_due(true, false, since));
+        assert!(deferred.is_due(true, true, since));
+        // the server may talk first
+        assert!(deferred.is_due(false, false, since + Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn track_backpressure() {
+        let mut backpressure = Backpressure::default();
+        assert!(!backpressure.is_active());
+        assert_eq!(None, backpressure.update(false));
+
+        // the client window is full
+        assert_eq!(None, backpressure.update(true));
+        assert!(backpressure.is_active());
+        assert_eq!(None, backpressure.update(true));
+        assert!(backpressure.is_active());
+
+        // the client drained its buffer
+        assert!(backpressure.update(false).is_some());
+        assert!(!backpressure.is_active());
+        assert_eq!(None, backpressure.update(false));
+    }
+
+    #[test]
+    fn describe_sequence_space() {
+        let mut tcb = Tcb::new();
+        tcb.sequence_number = Wrapping(1500);
+        tcb.acknowledgement_number = Wrapping(700);
+        tcb.update_client_window(1000, 1000);
+        assert_eq!(
+            "snd.una=1000 snd.nxt=1500 snd.wnd=1000 rcv.nxt=750 rcv.wnd=4046",
+            tcb.sequence_space(50, 4046)
+        );
+    }
+
+    #[test]
+    fn reopen_client_window() {
+        let mut tcb = Tcb::new();
+        tcb.sequence_number = Wrapping(1000);
+        // the window is initially closed
+        assert!(tcb.update_client_window(100, 1000));
+        assert_eq!(100, tcb.remaining_client_window());
+
+        // the relay sent a full window
+        tcb.sequence_number += Wrapping(100);
+        assert_eq!(0, tcb.remaining_client_window());
+
+        // the device acks without opening its window
+        assert!(!tcb.update_client_window(0, 1100));
+        assert_eq!(0, tcb.remaining_client_window());
+
+        // the device window reopens
+        assert!(tcb.update_client_window(50, 1100));
+        assert_eq!(50, tcb.remaining_client_window());
+
+        // already open
+        assert!(!tcb.update_client_window(80, 1100));
+    }
+
+    #[test]
+    fn configure_nodelay() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connect =
+            || TcpStream::from_stream(std::net::TcpStream::connect(addr).unwrap()).unwrap();
+
+        let stream = connect();
+        TcpConnection::configure_stream(&stream, true).unwrap();
+        assert!(stream.nodelay().unwrap());
+
+        let stream = connect();
+        TcpConnection::configure_stream(&stream, false).unwrap();
+        assert!(!stream.nodelay().unwrap());
+    }
+
+    #[test]
+    fn reset_only_connected_clients() {
+        assert_eq!(
+            Some(CloseReason::ConnectFailed),
+            TcpConnection::close_reason_on_error(&TcpState::SynSent)
+        );
+        for state in &[
+            TcpState::SynReceived,
+            TcpState::Established,
+            TcpState::CloseWait,
+        ] {
+            assert_eq!(
+                Some(CloseReason::PeerReset),
+                TcpConnection::close_reason_on_error(state)
+            );
+        }
+        // the client already received a FIN, the close is graceful
+        for state in &[
+            TcpState::FinWait1,
+            TcpState::FinWait2,
+            TcpState::Closing,
+            TcpState::LastAck,
+        ] {
+            assert_eq!(None, TcpConnection::close_reason_on_error(state));
+        }
+    }
+
+    #[test]
+    fn reset_client_on_peer_reset() {
+        // established, or still waiting for the client to acknowledge the SYN-ACK
+        for &acked in &[true, false] {
+            let mut selector = Selector::create().unwrap();
+            let mut device = MockDevice::new(&mut selector, RelayConfig::new());
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let (stream, sequence_number) = connect(&mut selector, &mut device, &listener, acked);
+
+            // accepted, then immediately reset
+            TcpStreamExt::set_linger(&stream, Some(Duration::from_secs(0))).unwrap();
+            drop(stream);
+
+            let start = Instant::now();
+            let rst = device
+                .receive(&mut selector, Duration::from_secs(1))
+                .expect("Expected RST");
+            assert!(start.elapsed() < Duration::from_millis(500));
+            assert_ne!(0, tcp_flags(&rst) & tcp_header::FLAG_RST);
+            assert_eq!(
+                sequence_number,
+                u32::from_be_bytes([rst[24], rst[25], rst[26], rst[27]])
+            );
+            assert_eq!(
+                None,
+                device.receive(&mut selector, Duration::from_millis(50))
+            );
+            device.client().borrow_mut().router().clear(&mut selector);
+        }
+    }
+
+    #[test]
+    fn write_data_received_before_connected() {
+        let mut selector = Selector::create().unwrap();
+        let mut device = MockDevice::new(&mut selector, RelayConfig::new());
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+
+        // data with the SYN, while the connection to the network is pending
+        let syn = create_segment(&listener, tcp_header::FLAG_SYN, 0x111, 0);
+        device.send(&with_payload(syn, b"hello"));
+        let syn_ack = device
+            .receive(&mut selector, Duration::from_secs(1))
+            .expect("Expected SYN-ACK");
+        assert_eq!(
+            tcp_header::FLAG_SYN | tcp_header::FLAG_ACK,
+            tcp_flags(&syn_ack)
+        );
+        let sequence_number =
+            u32::from_be_bytes([syn_ack[24], syn_ack[25], syn_ack[26], syn_ack[27]]) + 1;
+
+        // more data with the ACK of the SYN-ACK
+        let ack = create_segment(&listener, tcp_header::FLAG_ACK, 0x117, sequence_number);
+        device.send(&with_payload(ack, b" world"));
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let data = read_from_peer(&mut selector, &mut device, &mut stream, 11);
+        assert_eq!(b"hello world", &data[..]);
+        device.client().borrow_mut().router().clear(&mut selector);
+    }
+
+    #[test]
+    fn server_talks_first_after_deferral() {
+        let mut selector = Selector::create().unwrap();
+        let mut config = RelayConfig::new();
+        config.set_tcp_defer_connect(Some(Duration::from_millis(50)));
+        let mut device = MockDevice::new(&mut selector, config);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        listener.set_nonblocking(true).unwrap();
+
+        device.send(&create_segment(&listener, tcp_header::FLAG_SYN, 0x111, 0));
+        let syn_ack = device
+            .receive(&mut selector, Duration::from_secs(1))
+            .expect("Expected SYN-ACK");
+        let sequence_number =
+            u32::from_be_bytes([syn_ack[24], syn_ack[25], syn_ack[26], syn_ack[27]]) + 1;
+        let ack = create_segment(&listener, tcp_header::FLAG_ACK, 0x112, sequence_number);
+        device.send(&ack);
+
+        // the client sends nothing, the relay connects once the delay expired
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut stream = loop {
+            match listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(Instant::now() < deadline, "Expected connection");
+                    assert_eq!(
+                        None,
+                        device.receive(&mut selector, Duration::from_millis(10))
+                    );
+                }
+                Err(err) => panic!("Cannot accept: {}", err),
+            }
+        };
+
+        stream.write_all(b"hello").unwrap();
+        let packet = device
+            .receive(&mut selector, Duration::from_secs(1))
+            .expect("Expected data");
+        assert_eq!(
+            tcp_header::FLAG_ACK | tcp_header::FLAG_PSH,
+            tcp_flags(&packet)
+        );
+        assert_eq!(b"hello", &packet[40..]);
+        device.client().borrow_mut().router().clear(&mut selector);
+    }
+
+    #[test]
+    fn record_local_address() {
+        let mut selector = Selector::create().unwrap();
+        let mut device = MockDevice::new(&mut selector, RelayConfig::new());
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let (stream, _) = connect(&mut selector, &mut device, &listener, true);
+
+        // the local address of the relay is the remote address of the accepted stream
+        let info = device.client().borrow().connection_infos().remove(0);
+        assert_eq!(Some(stream.peer_addr().unwrap()), info.local_address);
+        device.client().borrow_mut().router().clear(&mut selector);
+    }
+
+    #[test]
+    fn query_connection_detail() {
+        let mut selector = Selector::create().unwrap();
+        let mut device = MockDevice::new(&mut selector, RelayConfig::new());
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let (_stream, sequence_number) = connect(&mut selector, &mut device, &listener, true);
+        // let the relay process the ACK
+        assert_eq!(
+            None,
+            device.receive(&mut selector, Duration::from_millis(20))
+        );
+        // the source of create_tcp_packet()
+        let source = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 1234);
+        let port = listener.local_addr().unwrap().port();
+        let destination = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+
+        let client = device.client().borrow();
+        let detail = client
+            .connection_detail(Protocol::Tcp, source, destination)
+            .expect("Expected connection");
+        assert_eq!("Established", detail.info.state);
+        let sequence_space = detail.sequence_space.expect("Expected sequence space");
+        assert!(sequence_space.starts_with(&format!("snd.una={} ", sequence_number)));
+        assert_eq!(0, detail.pending_to_network);
+        assert!(!detail.backpressure);
+
+        let other = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port.wrapping_add(1));
+        assert!(client
+            .connection_detail(Protocol::Tcp, source, other)
+            .is_none());
+        assert!(client
+            .connection_detail(Protocol::Udp, source, destination)
+            .is_none());
+        drop(client);
+        device.client().borrow_mut().router().clear(&mut selector);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn report_egress_interface() {
+        let mut selector = Selector::create().unwrap();
+        let mut device = MockDevice::new(&mut selector, RelayConfig::new());
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let (_stream, _) = connect(&mut selector, &mut device, &listener, true);
+
+        // the connection to the listener is bound to a loopback address
+        let source = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 1234);
+        let port = listener.local_addr().unwrap().port();
+        let destination = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+        let detail = device
+            .client()
+            .borrow()
+            .connection_detail(Protocol::Tcp, source, destination)
+            .expect("Expected connection");
+        let interface = detail.interface.expect("Expected interface");
+        // "lo" on Linux, "lo0" on macOS
+        assert!(interface.starts_with("lo"));
+        device.client().borrow_mut().router().clear(&mut selector);
+    }
+
+    #[test]
+    fn reset_on_write_timeout() {
+        let mut selector = Selector::create().unwrap();
+        let mut config = RelayConfig::new();
+        config.set_tcp_write_timeout(Some(Duration::from_millis(100)));
+        config.set_socket_send_buffer(Some(4096));
+        let mut device = MockDevice::new(&mut selector, config);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let (stream, sequence_number) = connect(&mut selector, &mut device, &listener, true);
+        // the peer never reads, its receive window gets wedged
+        stream.set_recv_buffer_size(4096).unwrap();
+
+        let payload = [0; 1000];
+        let mut next = 0x112;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let reset = loop {
+            assert!(Instant::now() < deadline, "Expected RST");
+            let flags = tcp_header::FLAG_ACK | tcp_header::FLAG_PSH;
+            let segment = create_segment(&listener, flags, next, sequence_number);
+            device.send(&with_payload(segment, &payload));
+            let packet = match device.receive(&mut selector, Duration::from_millis(5)) {
+                Some(packet) => packet,
+                None => continue,
+            };
+            if tcp_flags(&packet) & tcp_header::FLAG_RST != 0 {
+                break packet;
+            }
+            // the data are acknowledged once written to the network
+            next = u32::from_be_bytes([packet[28], packet[29], packet[30], packet[31]]);
+        };
+        assert_eq!(
+            tcp_header::FLAG_RST,
+            tcp_flags(&reset) & tcp_header::FLAG_RST
+        );
+        assert!(device.client().borrow().connection_infos().is_empty());
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let mut selector = Selector::create().unwrap();
+        let mut device = MockDevice::new(&mut selector, RelayConfig::new());
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let (mut stream, sequence_number) = connect(&mut selector, &mut device, &listener, true);
+        // let the relay process the ACK
+        assert_eq!(
+            None,
+            device.receive(&mut selector, Duration::from_millis(20))
+        );
+        let source = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 1234);
+        let port = listener.local_addr().unwrap().port();
+        let destination = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+        let set_paused = |selector: &mut Selector, device: &MockDevice, paused| {
+            device.client().borrow_mut().set_connection_paused(
+                selector,
+                Protocol::Tcp,
+                source,
+                destination,
+                paused,
+            )
+        };
+        assert!(set_paused(&mut selector, &device, true));
+
+        // nothing is relayed in either direction
+        stream.write_all(b"hello").unwrap();
+        let ack = create_segment(
+            &listener,
+            tcp_header::FLAG_ACK | tcp_header::FLAG_PSH,
+            0x112,
+            sequence_number,
+        );
+        device.send(&with_payload(ack, b"world"));
+        assert_eq!(
+            None,
+            device.receive(&mut selector, Duration::from_millis(50))
+        );
+        stream.set_nonblocking(true).unwrap();
+        let mut buf = [0; 16];
+        let err = stream.read(&mut buf).unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+        assert!(device.client().borrow().connection_infos()[0].paused);
+
+        assert!(set_paused(&mut selector, &device, false));
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let data = loop {
+            assert!(Instant::now() < deadline, "Expected data");
+            // the ACK of the data written to the network may come first
+            if let Some(packet) = device.receive(&mut selector, Duration::from_millis(10)) {
+                if packet.len() > 40 {
+                    break packet[40..].to_vec();
+                }
+            }
+        };
+        assert_eq!(b"hello", &data[..]);
+        let data = read_from_peer(&mut selector, &mut device, &mut stream, 5);
+        assert_eq!(b"world", &data[..]);
+        device.client().borrow_mut().router().clear(&mut selector);
+    }
+
+    #[test]
+    fn measure_delayed_connect() {
+        let delay = Duration::from_millis(100);
+        let mut selector = Selector::create().unwrap();
+        let mut config = RelayConfig::new();
+        config.set_tcp_defer_connect(Some(delay));
+        let mut device = MockDevice::new(&mut selector, config);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+
+        // the SYN-ACK is sent before connecting to the network
+        device.send(&create_segment(&listener, tcp_header::FLAG_SYN, 0x111, 0));
+        device
+            .receive(&mut selector, Duration::from_secs(1))
+            .expect("Expected SYN-ACK");
+        let client = Rc::clone(device.client());
+        let connected_in = || client.borrow().connection_infos()[0].connected_in;
+        assert_eq!(None, connected_in());
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while connected_in().is_none() && Instant::now() < deadline {
+            device.receive(&mut selector, Duration::from_millis(10));
+        }
+        let connected_in = connected_in().expect("Expected connection to the network");
+        assert!(connected_in >= delay);
+        assert!(connected_in < delay + Duration::from_millis(500));
+        device.client().borrow_mut().router().clear(&mut selector);
+    }
+
+    #[test]
+    fn probe_idle_client() {
+        let interval = Duration::from_millis(50);
+        let mut selector = Selector::create().unwrap();
+        let mut config = RelayConfig::new();
+        config.set_tcp_keepalive_interval(Some(interval));
+        config.set_tcp_keepalive_probes(2);
+        let mut device = MockDevice::new(&mut selector, config);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let (_stream, sequence_number) = connect(&mut selector, &mut device, &listener, true);
+        let idle_since = Instant::now();
+
+        let mut receive_probe = |device: &mut MockDevice| {
+            let probe = device
+                .receive(&mut selector, Duration::from_secs(1))
+                .expect("Expected keepalive probe");
+            assert_eq!(tcp_header::FLAG_ACK, tcp_flags(&probe));
+            // one byte behind, to trigger an ACK
+            let probe_sequence_number =
+                u32::from_be_bytes([probe[24], probe[25], probe[26], probe[27]]);
+            assert_eq!(sequence_number - 1, probe_sequence_number);
+        };
+
+        receive_probe(&mut device);
+        assert!(idle_since.elapsed() >= interval);
+
+        // the device answers, the probes count restarts
+        let ack = create_segment(&listener, tcp_header::FLAG_ACK, 0x112, sequence_number);
+        device.send(&ack);
+        let answered = Instant::now();
+        receive_probe(&mut device);
+        assert!(answered.elapsed() >= interval);
+        receive_probe(&mut device);
+
+        // no answer to the last probe, the connection expires and the device is reset
+        let rst = device
+            .receive(&mut selector, Duration::from_secs(1))
+            .expect("Expected RST");
+        assert_ne!(0, tcp_flags(&rst) & tcp_header::FLAG_RST);
+        assert!(answered.elapsed() >= interval * 3);
+        device.client().borrow_mut().router().clear(&mut selector);
+    }
+}
@@ -14,8 +14,10 @@
  * limitations under the License.
  */
 
+use super::checksum;
 use super::ipv4_header::Ipv4HeaderData;
 use byteorder::{BigEndian, ByteOrder};
+use std::fmt;
 use std::mem;
 
 pub struct TcpHeader<'a> {
@@ -28,7 +30,7 @@ pub struct TcpHeaderMut<'a> {
     data: &'a mut TcpHeaderData,
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct TcpHeaderData {
     source_port: u16,
     destination_port: u16,
@@ -45,19 +47,122 @@ pub const FLAG_RST: u16 = 1 << 2;
 pub const FLAG_PSH: u16 = 1 << 3;
 pub const FLAG_ACK: u16 = 1 << 4;
 
+pub const OPTION_MSS: u8 = 2;
+pub const OPTION_WINDOW_SCALE: u8 = 3;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+
+impl fmt::Debug for TcpHeaderData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TcpHeaderData")
+            .field("source_port", &self.source_port)
+            .field("destination_port", &self.destination_port)
+            .field("sequence_number", &self.sequence_number)
+            .field("acknowledgement_number", &self.acknowledgement_number)
+            .field("header_length", &self.header_length)
+            .field("flags", &format_args!("{:#04x}", self.flags))
+            .field("window", &self.window)
+            .finish()
+    }
+}
+
+/// Numbers and flags of a RST replying to a segment.
+///
+/// See RFC793 "Reset Generation": <https://tools.ietf.org/html/rfc793#page-36>
+#[derive(Debug, PartialEq, Eq)]
+pub struct ResetReply {
+    pub sequence_number: u32,
+    pub acknowledgement_number: u32,
+    pub flags: u16,
+}
+
+impl ResetReply {
+    /// Build the RST replying to a segment starting at `sequence_number`, acknowledging
+    /// `acknowledgement_number` (if its ACK flag is set) and occupying `segment_length` sequence
+    /// numbers (including SYN and FIN).
+    pub fn new(
+        sequence_number: u32,
+        acknowledgement_number: Option<u32>,
+        segment_length: u32,
+    ) -> Self {
+        match acknowledgement_number {
+            Some(acknowledgement_number) => Self {
+                sequence_number: acknowledgement_number,
+                acknowledgement_number: 0,
+                flags: FLAG_RST,
+            },
+            None => Self {
+                sequence_number: 0,
+                acknowledgement_number: sequence_number.wrapping_add(segment_length),
+                flags: FLAG_RST | FLAG_ACK,
+            },
+        }
+    }
+}
+
 #[allow(dead_code)]
 impl TcpHeaderData {
-    pub fn parse(raw: &[u8]) -> Self {
-        let data_offset_and_flags = BigEndian::read_u16(&raw[12..14]);
+    /// Create a TCP header without options (its length is 20 bytes).
+    ///
+    /// The raw header is written by `TcpHeaderMut::serialize()` once bound.
+    pub fn new(
+        source_port: u16,
+        destination_port: u16,
+        sequence_number: u32,
+        acknowledgement_number: u32,
+        flags: u16,
+        window: u16,
+    ) -> Self {
         Self {
+            source_port,
+            destination_port,
+            sequence_number,
+            acknowledgement_number,
+            header_length: 20,
+            flags,
+            window,
+        }
+    }
+
+    /// Parse the TCP header at the start of `raw`, which must contain the whole IP payload.
+    ///
+    /// Return `None` if the data offset is inconsistent with the available bytes.
+    pub fn parse(raw: &[u8]) -> Option<Self> {
+        if raw.len() < 20 {
+            return None;
+        }
+        let data_offset_and_flags = BigEndian::read_u16(&raw[12..14]);
+        let header_length = ((data_offset_and_flags & 0xF000) >> 10) as u8;
+        if header_length < 20 || header_length as usize > raw.len() {
+            return None;
+        }
+        Some(Self {
             source_port: BigEndian::read_u16(&raw[0..2]),
             destination_port: BigEndian::read_u16(&raw[2..4]),
             sequence_number: BigEndian::read_u32(&raw[4..8]),
             acknowledgement_number: BigEndian::read_u32(&raw[8..12]),
-            header_length: ((data_offset_and_flags & 0xF000) >> 10) as u8,
+            header_length,
             flags: data_offset_and_flags & 0x1FF,
             window: BigEndian::read_u16(&raw[14..16]),
+        })
+    }
+
+    /// Build the RST replying to this segment, carrying `payload_length` bytes of data.
+    pub fn reset_reply(&self, payload_length: u32) -> ResetReply {
+        let mut segment_length = payload_length;
+        if self.is_syn() {
+            segment_length += 1;
+        }
+        if self.is_fin() {
+            segment_length += 1;
         }
+        let acknowledgement_number = if self.is_ack() {
+            Some(self.acknowledgement_number)
+        } else {
+            None
+        };
+        ResetReply::new(self.sequence_number, acknowledgement_number, segment_length)
     }
 
     #[inline]
@@ -131,6 +236,40 @@ impl TcpHeaderData {
     }
 }
 
+/// Iterate over the TCP options (the bytes following the fixed 20-byte header), as their kind
+/// and their data (without the kind and length bytes).
+///
+/// The iteration stops at the end-of-options marker or at the first malformed option.
+pub fn options(options: &[u8]) -> impl Iterator<Item = (u8, &[u8])> + '_ {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let kind = *options.get(offset)?;
+        match kind {
+            OPTION_END => None,
+            OPTION_NOP => {
+                offset += 1;
+                Some((kind, &options[offset..offset]))
+            }
+            _ => {
+                let length = *options.get(offset + 1)? as usize;
+                if length < 2 || offset + length > options.len() {
+                    return None;
+                }
+                let data = &options[offset + 2..offset + length];
+                offset += length;
+                Some((kind, data))
+            }
+        }
+    })
+}
+
+// data of the first option of the given kind (duplicates are ignored)
+fn find_option(raw_options: &[u8], kind: u8) -> Option<&[u8]> {
+    options(raw_options)
+        .find(|&(k, _)| k == kind)
+        .map(|(_, data)| data)
+}
+
 // shared definition for UdpHeader and UdpHeaderMut
 macro_rules! tcp_header_common {
     ($name:ident, $raw_type:ty, $data_type:ty) => {
@@ -138,10 +277,7 @@ macro_rules! tcp_header_common {
         #[allow(dead_code)]
         impl<'a> $name<'a> {
             pub fn new(raw: $raw_type, data: $data_type) -> Self {
-                Self {
-                    raw: raw,
-                    data: data,
-                }
+                Self { raw, data }
             }
 
             #[inline]
@@ -213,6 +349,24 @@ macro_rules! tcp_header_common {
             pub fn is_ack(&self) -> bool {
                 self.data.is_ack()
             }
+
+            pub fn options(&self) -> &[u8] {
+                &self.raw[20..self.data.header_length as usize]
+            }
+
+            /// The Maximum Segment Size option, if present (typically on SYN only).
+            pub fn mss(&self) -> Option<u16> {
+                find_option(self.options(), OPTION_MSS)
+                    .filter(|data| data.len() == 2)
+                    .map(BigEndian::read_u16)
+            }
+
+            /// The window scale shift count option, if present (on SYN only).
+            pub fn window_scale(&self) -> Option<u8> {
+                find_option(self.options(), OPTION_WINDOW_SCALE)
+                    .filter(|data| data.len() == 1)
+                    .map(|data| data[0])
+            }
         }
     };
 }
@@ -233,6 +387,22 @@ impl<'a> TcpHeaderMut<'a> {
         self.data
     }
 
+    /// Write all the header fields to the raw buffer.
+    ///
+    /// The checksum and urgent pointer are reset to 0, options (if any) are left untouched.
+    pub fn serialize(&mut self) {
+        BigEndian::write_u16(&mut self.raw[0..2], self.data.source_port);
+        BigEndian::write_u16(&mut self.raw[2..4], self.data.destination_port);
+        BigEndian::write_u32(&mut self.raw[4..8], self.data.sequence_number);
+        BigEndian::write_u32(&mut self.raw[8..12], self.data.acknowledgement_number);
+        let data_offset = u16::from(self.data.header_length >> 2);
+        let data_offset_and_flags = data_offset << 12 | self.data.flags & 0x1FF;
+        BigEndian::write_u16(&mut self.raw[12..14], data_offset_and_flags);
+        BigEndian::write_u16(&mut self.raw[14..16], self.data.window);
+        BigEndian::write_u16(&mut self.raw[16..18], 0); // checksum
+        BigEndian::write_u16(&mut self.raw[18..20], 0); // urgent pointer
+    }
+
     #[inline]
     pub fn set_source_port(&mut self, source_port: u16) {
         self.data.source_port = source_port;
@@ -304,7 +474,7 @@ impl<'a> TcpHeaderMut<'a> {
             ipv4_header_data.total_length() - u16::from(ipv4_header_data.header_length());
 
         let header_length = self.header_length();
-        debug_assert!(header_length % 2 == 0 && header_length >= 20);
+        debug_assert!(header_length.is_multiple_of(2) && header_length >= 20);
 
         let payload_length = transport_length - u16::from(header_length);
         debug_assert_eq!(
@@ -313,55 +483,26 @@ impl<'a> TcpHeaderMut<'a> {
             "Payload length does not match"
         );
 
-        let mut sum = 6u32; // protocol: TCP = 6
-        sum += source >> 16;
-        sum += source & 0xFFFF;
-        sum += destination >> 16;
-        sum += destination & 0xFFFF;
-        sum += u32::from(transport_length);
+        let mut sum = 6u64; // protocol: TCP = 6
+        sum += u64::from(source >> 16);
+        sum += u64::from(source & 0xFFFF);
+        sum += u64::from(destination >> 16);
+        sum += u64::from(destination & 0xFFFF);
+        sum += u64::from(transport_length);
 
         // reset checksum field, so that it can be added with other bytes
         self.set_checksum(0);
 
-        // checksum computation is the most CPU-intensive task in gnirehtet
-        // prefer optimization over readability/safety
-
-        let mut hsum = 0; // high-order bytes sum
+        // the header length is even, so the payload starts with a high-order byte
+        sum += checksum::sum(&self.raw[..header_length as usize]);
+        sum += checksum::sum(payload);
 
-        unsafe {
-            let mut p = self.raw.as_ptr();
-            let end = p.offset(header_length as isize);
-            while p < end {
-                hsum += u32::from(*p);
-                sum += u32::from(*p.offset(1));
-                p = p.offset(2);
-            }
-
-            let mut p = payload.as_ptr();
-            // -1 to ignore the last if payload_length is odd
-            let end = p.offset(payload_length as isize - 1);
-            while p < end {
-                hsum += u32::from(*p);
-                sum += u32::from(*p.offset(1));
-                p = p.offset(2);
-            }
-            if payload_length % 2 != 0 {
-                // if payload length is odd, the last byte is considered high-order
-                hsum += u32::from(*payload.get_unchecked((payload_length - 1) as usize));
-            }
-        }
-
-        // add high-order bytes sum to the global sum
-        sum += hsum << 8;
-
-        while (sum & !0xFFFF) != 0 {
-            sum = (sum & 0xFFFF) + (sum >> 16);
-        }
-        self.set_checksum(!sum as u16);
+        self.set_checksum(!checksum::fold(sum));
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::identity_op)]
 mod tests {
     use super::*;
     use crate::relay::ipv4_packet::Ipv4Packet;
@@ -369,8 +510,7 @@ mod tests {
     use byteorder::{BigEndian, WriteBytesExt};
 
     fn create_packet() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(44);
+        let mut raw = Vec::with_capacity(44);
 
         raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
         raw.write_u8(0).unwrap(); //ToS
@@ -397,8 +537,7 @@ mod tests {
     }
 
     fn create_odd_packet() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(45);
+        let mut raw = Vec::with_capacity(45);
 
         raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
         raw.write_u8(0).unwrap(); //ToS
@@ -427,8 +566,7 @@ mod tests {
     }
 
     fn create_empty_packet() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(40);
+        let mut raw = Vec::with_capacity(40);
 
         raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
         raw.write_u8(0).unwrap(); //ToS
@@ -453,8 +591,7 @@ mod tests {
     }
 
     fn create_tcp_header() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(20);
+        let mut raw = Vec::with_capacity(20);
 
         raw.write_u16::<BigEndian>(0x1234).unwrap(); // source port
         raw.write_u16::<BigEndian>(0x5678).unwrap(); // destination port
@@ -468,10 +605,92 @@ mod tests {
         raw
     }
 
+    #[test]
+    fn reset_reply_to_syn() {
+        let header = TcpHeaderData::new(0x1234, 0x5678, 0x111, 0, FLAG_SYN, 0);
+        let reset = header.reset_reply(0);
+        assert_eq!(0, reset.sequence_number);
+        assert_eq!(0x112, reset.acknowledgement_number);
+        assert_eq!(FLAG_RST | FLAG_ACK, reset.flags);
+    }
+
+    #[test]
+    fn reset_reply_to_ack() {
+        let header = TcpHeaderData::new(0x1234, 0x5678, 0x111, 0x222, FLAG_ACK | FLAG_PSH, 0);
+        let reset = header.reset_reply(4);
+        assert_eq!(0x222, reset.sequence_number);
+        assert_eq!(FLAG_RST, reset.flags);
+    }
+
+    #[test]
+    fn reset_reply_to_data_without_ack() {
+        let header = TcpHeaderData::new(0x1234, 0x5678, 0xFFFF_FFFE, 0, FLAG_FIN, 0);
+        let reset = header.reset_reply(4);
+        assert_eq!(0, reset.sequence_number);
+        // 4 bytes of data + FIN, wrapping
+        assert_eq!(3, reset.acknowledgement_number);
+        assert_eq!(FLAG_RST | FLAG_ACK, reset.flags);
+    }
+
+    #[test]
+    fn reject_invalid_data_offset() {
+        let raw = &mut create_tcp_header()[..];
+
+        BigEndian::write_u16(&mut raw[12..14], 0); // data offset = 0
+        assert!(TcpHeaderData::parse(raw).is_none());
+
+        BigEndian::write_u16(&mut raw[12..14], 4 << 12); // 16 bytes, below minimum
+        assert!(TcpHeaderData::parse(raw).is_none());
+
+        BigEndian::write_u16(&mut raw[12..14], 6 << 12); // 24 bytes, exceeds the buffer
+        assert!(TcpHeaderData::parse(raw).is_none());
+
+        BigEndian::write_u16(&mut raw[12..14], 5 << 12); // 20 bytes
+        assert_eq!(20, TcpHeaderData::parse(raw).unwrap().header_length());
+    }
+
+    fn create_syn_header(options: &[u8]) -> Vec<u8> {
+        let mut raw = create_tcp_header();
+        let data_offset = (raw.len() + options.len()) as u16 / 4;
+        BigEndian::write_u16(&mut raw[12..14], data_offset << 12 | FLAG_SYN);
+        raw.extend_from_slice(options);
+        raw
+    }
+
+    #[test]
+    fn parse_mss_and_window_scale() {
+        // MSS = 1460, NOP, window scale = 7
+        let raw = &create_syn_header(&[2, 4, 0x05, 0xB4, 1, 3, 3, 7])[..];
+        let header_data = TcpHeaderData::parse(raw).unwrap();
+        let header = header_data.bind(raw);
+        assert_eq!(Some(1460), header.mss());
+        assert_eq!(Some(7), header.window_scale());
+
+        // no options
+        let raw = &create_syn_header(&[])[..];
+        let header_data = TcpHeaderData::parse(raw).unwrap();
+        let header = header_data.bind(raw);
+        assert!(header.mss().is_none());
+        assert!(header.window_scale().is_none());
+
+        // duplicate MSS, the first one wins; nothing is parsed after the end of options
+        let raw = &create_syn_header(&[2, 4, 0x02, 0x18, 2, 4, 0x05, 0xB4, 0, 3, 3, 7])[..];
+        let header_data = TcpHeaderData::parse(raw).unwrap();
+        let header = header_data.bind(raw);
+        assert_eq!(Some(536), header.mss());
+        assert!(header.window_scale().is_none());
+    }
+
+    #[test]
+    fn reject_truncated_header() {
+        let raw = &create_tcp_header()[..];
+        assert!(TcpHeaderData::parse(&raw[..19]).is_none());
+    }
+
     #[test]
     fn edit_header() {
         let raw = &mut create_tcp_header()[..];
-        let mut header_data = TcpHeaderData::parse(raw);
+        let mut header_data = TcpHeaderData::parse(raw).unwrap();
         let mut header = header_data.bind_mut(raw);
 
         header.set_source_port(1111);
@@ -514,10 +733,38 @@ mod tests {
         assert_eq!(1111, raw_destination_port);
     }
 
+    #[test]
+    fn serialize_new_header() {
+        let mut header_data = TcpHeaderData::new(
+            1111,
+            2222,
+            0x12345678,
+            0x9ABCDEF0,
+            FLAG_SYN | FLAG_ACK,
+            4242,
+        );
+        // garbage must be overwritten
+        let raw = &mut [0xFFu8; 20][..];
+        header_data.bind_mut(raw).serialize();
+
+        assert_eq!(0x5012, BigEndian::read_u16(&raw[12..14]));
+        assert_eq!(0, BigEndian::read_u16(&raw[16..18])); // checksum
+        assert_eq!(0, BigEndian::read_u16(&raw[18..20])); // urgent pointer
+
+        let parsed = TcpHeaderData::parse(raw).unwrap();
+        assert_eq!(20, parsed.header_length());
+        assert_eq!(1111, parsed.source_port());
+        assert_eq!(2222, parsed.destination_port());
+        assert_eq!(0x12345678, parsed.sequence_number());
+        assert_eq!(0x9ABCDEF0, parsed.acknowledgement_number());
+        assert_eq!(FLAG_SYN | FLAG_ACK, parsed.flags());
+        assert_eq!(4242, parsed.window());
+    }
+
     #[test]
     fn compute_checksum() {
         let raw = &mut create_packet()[..];
-        let mut ipv4_packet = Ipv4Packet::parse(raw);
+        let mut ipv4_packet = Ipv4Packet::parse(raw).unwrap();
         let (ipv4_header, mut transport) = ipv4_packet.split_mut();
         if let Some((TransportHeaderMut::Tcp(ref mut tcp_header), ref payload)) = transport {
             // set a fake checksum value to assert that it is correctly computed
@@ -559,7 +806,7 @@ mod tests {
     #[test]
     fn compute_checksum_odd() {
         let raw = &mut create_odd_packet()[..];
-        let mut ipv4_packet = Ipv4Packet::parse(raw);
+        let mut ipv4_packet = Ipv4Packet::parse(raw).unwrap();
         let (ipv4_header, mut transport) = ipv4_packet.split_mut();
         if let Some((TransportHeaderMut::Tcp(ref mut tcp_header), ref payload)) = transport {
             // set a fake checksum value to assert that it is correctly computed
@@ -601,7 +848,7 @@ mod tests {
     #[test]
     fn compute_checksum_empty_payload() {
         let raw = &mut create_empty_packet()[..];
-        let mut ipv4_packet = Ipv4Packet::parse(raw);
+        let mut ipv4_packet = Ipv4Packet::parse(raw).unwrap();
         let (ipv4_header, mut transport) = ipv4_packet.split_mut();
         if let Some((TransportHeaderMut::Tcp(ref mut tcp_header), ref payload)) = transport {
             // set a fake checksum value to assert that it is correctly computed
@@ -638,8 +885,7 @@ mod tests {
     }
 
     fn create_long_packet() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(45);
+        let mut raw = Vec::with_capacity(45);
 
         raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
         raw.write_u8(0).unwrap(); //ToS
@@ -675,7 +921,7 @@ mod tests {
     #[test]
     fn bench_checksum() {
         let raw = &mut create_long_packet()[..];
-        let mut ipv4_packet = Ipv4Packet::parse(raw);
+        let mut ipv4_packet = Ipv4Packet::parse(raw).unwrap();
         let (ipv4_header, mut transport) = ipv4_packet.split_mut();
         if let Some((TransportHeaderMut::Tcp(ref mut tcp_header), ref payload)) = transport {
             use std::time::Instant;
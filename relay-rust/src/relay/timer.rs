@@ -0,0 +1,132 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Hook called periodically on the relay thread, between the I/O events.
+pub trait Timer: fmt::Debug {
+    fn fire(&self);
+}
+
+/// The timers registered by the embedder (there are typically very few, so they are not sorted).
+///
+/// Besides `Timer`s, the queue may schedule other hooks, fired by the caller.
+pub struct TimerQueue<T: ?Sized = dyn Timer> {
+    // interval, next deadline, timer
+    timers: Vec<(Duration, Instant, Rc<T>)>,
+}
+
+impl<T: Timer + ?Sized> TimerQueue<T> {
+    /// Fire the expired timers, and return how many have been fired.
+    pub fn fire_expired(&mut self, now: Instant) -> usize {
+        self.fire_expired_with(now, |timer| timer.fire())
+    }
+}
+
+impl<T: ?Sized> TimerQueue<T> {
+    pub fn new(timers: &[(Duration, Rc<T>)], now: Instant) -> Self {
+        Self {
+            timers: timers
+                .iter()
+                .map(|(interval, timer)| (*interval, now + *interval, timer.clone()))
+                .collect(),
+        }
+    }
+
+    /// The earliest deadline, if any timer is registered.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.iter().map(|&(_, deadline, _)| deadline).min()
+    }
+
+    /// Call `fire` for every expired timer, and return how many have been fired.
+    ///
+    /// A timer late by more than its interval fires only once, and is rescheduled from `now`.
+    pub fn fire_expired_with<F>(&mut self, now: Instant, mut fire: F) -> usize
+    where
+        F: FnMut(&T),
+    {
+        let mut fired = 0;
+        for (interval, deadline, timer) in &mut self.timers {
+            if *deadline <= now {
+                fire(timer);
+                fired += 1;
+                *deadline += *interval;
+                if *deadline <= now {
+                    *deadline = now + *interval;
+                }
+            }
+        }
+        fired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[derive(Debug, Default)]
+    struct CountingTimer {
+        count: Cell<u32>,
+    }
+
+    impl Timer for CountingTimer {
+        fn fire(&self) {
+            self.count.set(self.count.get() + 1);
+        }
+    }
+
+    #[test]
+    fn fire_on_schedule() {
+        let origin = Instant::now();
+        let at = |ms| origin + Duration::from_millis(ms);
+        let timer = Rc::new(CountingTimer::default());
+        let mut queue = TimerQueue::new(&[(Duration::from_millis(50), timer.clone())], origin);
+        assert_eq!(Some(at(50)), queue.next_deadline());
+
+        assert_eq!(0, queue.fire_expired(at(49)));
+        assert_eq!(0, timer.count.get());
+
+        assert_eq!(1, queue.fire_expired(at(51)));
+        assert_eq!(1, timer.count.get());
+        // rescheduled from its deadline, not from the (late) wakeup
+        assert_eq!(Some(at(100)), queue.next_deadline());
+
+        // late by more than the interval: fired once, rescheduled from now
+        assert_eq!(1, queue.fire_expired(at(230)));
+        assert_eq!(2, timer.count.get());
+        assert_eq!(Some(at(280)), queue.next_deadline());
+    }
+
+    #[test]
+    fn earliest_deadline() {
+        let origin = Instant::now();
+        let timer: Rc<dyn Timer> = Rc::new(CountingTimer::default());
+        let timers = [
+            (Duration::from_secs(2), timer.clone()),
+            (Duration::from_secs(1), timer),
+        ];
+        let queue = TimerQueue::new(&timers, origin);
+        assert_eq!(Some(origin + Duration::from_secs(1)), queue.next_deadline());
+
+        assert_eq!(
+            None,
+            TimerQueue::<dyn Timer>::new(&[], origin).next_deadline()
+        );
+    }
+}
@@ -0,0 +1,199 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const NAME_TYPE_HOST_NAME: u8 = 0;
+const RECORD_HEADER_LENGTH: usize = 5;
+const HANDSHAKE_HEADER_LENGTH: usize = 4;
+// client_version (2) + random (32)
+const HELLO_FIXED_LENGTH: usize = 34;
+// a ClientHello fits in the first segment in practice, never inspect beyond
+const MAX_INSPECTED_LENGTH: usize = 4096;
+const MAX_HOST_NAME_LENGTH: usize = 255;
+
+/// Extract the server name (SNI) from a TLS ClientHello, if `payload` starts with one.
+///
+/// Only the first `MAX_INSPECTED_LENGTH` bytes are inspected, and a ClientHello truncated before
+/// its server name extension is ignored. The name is returned in lowercase.
+pub fn parse_sni(payload: &[u8]) -> Option<String> {
+    let data = &payload[..payload.len().min(MAX_INSPECTED_LENGTH)];
+    let record_header = data.get(..RECORD_HEADER_LENGTH)?;
+    if record_header[0] != CONTENT_TYPE_HANDSHAKE {
+        return None;
+    }
+    // the record may be longer than the inspected data, stop at whichever comes first
+    let record_length = BigEndian::read_u16(&record_header[3..]) as usize;
+    let record_end = data.len().min(RECORD_HEADER_LENGTH + record_length);
+    let hello = &data[RECORD_HEADER_LENGTH..record_end];
+
+    let handshake_header = hello.get(..HANDSHAKE_HEADER_LENGTH)?;
+    if handshake_header[0] != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+
+    let mut offset = HANDSHAKE_HEADER_LENGTH + HELLO_FIXED_LENGTH;
+    // session id
+    offset += 1 + *hello.get(offset)? as usize;
+    // cipher suites
+    offset += 2 + BigEndian::read_u16(hello.get(offset..offset + 2)?) as usize;
+    // compression methods
+    offset += 1 + *hello.get(offset)? as usize;
+
+    let extensions_length = BigEndian::read_u16(hello.get(offset..offset + 2)?) as usize;
+    offset += 2;
+    let extensions_end = hello.len().min(offset + extensions_length);
+    while offset + 4 <= extensions_end {
+        let extension_type = BigEndian::read_u16(&hello[offset..]);
+        let extension_length = BigEndian::read_u16(&hello[offset + 2..]) as usize;
+        offset += 4;
+        if extension_type == EXTENSION_SERVER_NAME {
+            let extension = hello.get(offset..offset + extension_length)?;
+            return parse_server_name_list(extension);
+        }
+        offset += extension_length;
+    }
+    None
+}
+
+fn parse_server_name_list(extension: &[u8]) -> Option<String> {
+    let list_length = BigEndian::read_u16(extension.get(..2)?) as usize;
+    let list = extension.get(2..2 + list_length)?;
+    let mut offset = 0;
+    while offset + 3 <= list.len() {
+        let name_type = list[offset];
+        let name_length = BigEndian::read_u16(&list[offset + 1..]) as usize;
+        offset += 3;
+        let name = list.get(offset..offset + name_length)?;
+        if name_type == NAME_TYPE_HOST_NAME {
+            return to_host_name(name);
+        }
+        offset += name_length;
+    }
+    None
+}
+
+fn to_host_name(name: &[u8]) -> Option<String> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_HOST_NAME_LENGTH
+        && name
+            .iter()
+            .all(|&c| c.is_ascii_alphanumeric() || c == b'-' || c == b'.' || c == b'_');
+    if !valid {
+        return None;
+    }
+    // only ASCII characters, checked above
+    Some(String::from_utf8_lossy(name).to_ascii_lowercase())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // ClientHello sent by OpenSSL to www.example.com (TLS 1.2, one cipher suite, no ticket)
+    pub const CLIENT_HELLO: [u8; 156] = [
+        0x16, 0x03, 0x01, 0x00, 0x97, 0x01, 0x00, 0x00, 0x93, 0x03, 0x03, 0x99, 0xD2, 0xB9, 0xCB,
+        0xC0, 0x27, 0xD5, 0x8F, 0xBA, 0x14, 0x6B, 0xDE, 0x41, 0x6B, 0x51, 0x50, 0xAA, 0x9A, 0x81,
+        0x33, 0x51, 0xC6, 0xAF, 0xC6, 0x02, 0xC8, 0xD6, 0x90, 0x4F, 0x58, 0x96, 0xFB, 0x00, 0x00,
+        0x04, 0xC0, 0x2F, 0x00, 0xFF, 0x01, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00, 0x14, 0x00, 0x12,
+        0x00, 0x00, 0x0F, 0x77, 0x77, 0x77, 0x2E, 0x65, 0x78, 0x61, 0x6D, 0x70, 0x6C, 0x65, 0x2E,
+        0x63, 0x6F, 0x6D, 0x00, 0x0B, 0x00, 0x04, 0x03, 0x00, 0x01, 0x02, 0x00, 0x0A, 0x00, 0x0C,
+        0x00, 0x0A, 0x00, 0x1D, 0x00, 0x17, 0x00, 0x1E, 0x00, 0x19, 0x00, 0x18, 0x00, 0x16, 0x00,
+        0x00, 0x00, 0x17, 0x00, 0x00, 0x00, 0x0D, 0x00, 0x2A, 0x00, 0x28, 0x04, 0x03, 0x05, 0x03,
+        0x06, 0x03, 0x08, 0x07, 0x08, 0x08, 0x08, 0x09, 0x08, 0x0A, 0x08, 0x0B, 0x08, 0x04, 0x08,
+        0x05, 0x08, 0x06, 0x04, 0x01, 0x05, 0x01, 0x06, 0x01, 0x03, 0x03, 0x03, 0x01, 0x03, 0x02,
+        0x04, 0x02, 0x05, 0x02, 0x06, 0x02,
+    ];
+
+    fn create_client_hello(host_name: &[u8]) -> Vec<u8> {
+        // extension type, extension length, list length and name type
+        let mut server_name = vec![0; 7];
+        server_name.extend_from_slice(&(host_name.len() as u16).to_be_bytes());
+        server_name.extend_from_slice(host_name);
+        let list_length = (server_name.len() - 6) as u16;
+        server_name[4..6].copy_from_slice(&list_length.to_be_bytes());
+        let extension_length = (server_name.len() - 4) as u16;
+        server_name[2..4].copy_from_slice(&extension_length.to_be_bytes());
+
+        let mut extensions = vec![0x00, 0x0B, 0x00, 0x02, 0x01, 0x00]; // ec_point_formats
+        extensions.extend_from_slice(&server_name);
+        extensions.extend_from_slice(&[0x00, 0x17, 0x00, 0x00]); // extended_master_secret
+
+        let mut hello = vec![0x03, 0x03]; // TLS 1.2
+        hello.extend_from_slice(&[0x42; 32]); // random
+        hello.push(32); // session id
+        hello.extend_from_slice(&[0x24; 32]);
+        hello.extend_from_slice(&[0x00, 0x04, 0x13, 0x01, 0xC0, 0x2F]); // cipher suites
+        hello.extend_from_slice(&[0x01, 0x00]); // compression methods (null)
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn extract_sni() {
+        let client_hello = create_client_hello(b"Www.Example.com");
+        assert_eq!(
+            Some(String::from("www.example.com")),
+            parse_sni(&client_hello)
+        );
+    }
+
+    #[test]
+    fn extract_sni_of_captured_client_hello() {
+        assert_eq!(
+            Some(String::from("www.example.com")),
+            parse_sni(&CLIENT_HELLO)
+        );
+    }
+
+    #[test]
+    fn ignore_malformed_client_hello() {
+        let client_hello = create_client_hello(b"www.example.com");
+        // the last extension (4 bytes) follows the server name
+        let server_name_end = client_hello.len() - 4;
+        // no truncation may panic, and the name is never partially parsed
+        for length in 0..server_name_end {
+            assert!(parse_sni(&client_hello[..length]).is_none());
+        }
+        for length in server_name_end..client_hello.len() {
+            assert!(parse_sni(&client_hello[..length]).is_some());
+        }
+
+        // not a handshake
+        let mut data = client_hello.clone();
+        data[0] = 0x17;
+        assert!(parse_sni(&data).is_none());
+
+        // invalid host name
+        assert!(parse_sni(&create_client_hello(b"www.exa mple.com")).is_none());
+        assert!(parse_sni(&create_client_hello(b"")).is_none());
+
+        // plain HTTP
+        assert!(parse_sni(b"GET / HTTP/1.1\r\nHost: www.example.com\r\n\r\n").is_none());
+    }
+}
@@ -17,14 +17,25 @@
 use log::*;
 use mio::net::TcpListener;
 use mio::{Event, PollOpt, Ready};
+use net2::TcpBuilder;
 use std::cell::RefCell;
 use std::io;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::ptr;
 use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
 
 use super::client::Client;
+use super::config::RelayConfig;
+use super::connection::{ConnectionDetail, ConnectionInfo};
+use super::egress_limiter::EgressLimiter;
+use super::events::RelayEvent;
+use super::ipv4_header::Protocol;
+use super::packet_mirror::PacketMirror;
+use super::rate_limiter::RateLimiter;
 use super::selector::Selector;
+use super::socket_buffers;
+use super::stats::{ResetScope, RouterStats};
 
 const TAG: &str = "TunnelServer";
 
@@ -33,16 +44,51 @@ pub struct TunnelServer {
     clients: Vec<Rc<RefCell<Client>>>,
     tcp_listener: TcpListener,
     next_client_id: u32,
+    config: Rc<RelayConfig>,
+    mirror: Option<Rc<PacketMirror>>,
+    accept_rate_limiter: Option<RateLimiter>,
+    // pending clients not accepted yet due to the rate limiter
+    accept_deferred: bool,
+    egress_limiter: Option<Rc<RefCell<EgressLimiter>>>,
+    // the deferred clients are resumed in turn, starting from a different client every time
+    next_egress_turn: usize,
+    // the counters of the disconnected clients
+    retired_stats: RouterStats,
+    created: Instant,
+    // changed at runtime, applied to the new clients too
+    max_connections_per_client: Option<usize>,
 }
 
 impl TunnelServer {
-    pub fn create(port: u16, selector: &mut Selector) -> io::Result<Rc<RefCell<Self>>> {
-        let tcp_listener = Self::start_socket(port)?;
+    pub fn create(
+        port: u16,
+        config: Rc<RelayConfig>,
+        selector: &mut Selector,
+    ) -> io::Result<Rc<RefCell<Self>>> {
+        let tcp_listener = Self::start_socket(port, config.accept_backlog())?;
+        let accept_rate_limiter = config.max_accept_rate().map(RateLimiter::new);
+        let egress_limiter = config
+            .max_egress_rate()
+            .map(|rate| Rc::new(RefCell::new(EgressLimiter::new(rate))));
+        let max_connections_per_client = config.max_connections_per_client();
+        let mirror = match config.mirror_address() {
+            Some(mirror_address) => Some(Rc::new(PacketMirror::create(mirror_address)?)),
+            None => None,
+        };
         let rc = Rc::new(RefCell::new(Self {
             self_weak: Weak::new(),
             clients: Vec::new(),
             tcp_listener,
             next_client_id: 0,
+            config,
+            mirror,
+            accept_rate_limiter,
+            accept_deferred: false,
+            egress_limiter,
+            next_egress_turn: 0,
+            retired_stats: RouterStats::new(Instant::now()),
+            created: Instant::now(),
+            max_connections_per_client,
         }));
 
         // keep a shared reference to this
@@ -61,25 +107,152 @@ impl TunnelServer {
         Ok(rc)
     }
 
-    fn start_socket(port: u16) -> io::Result<TcpListener> {
+    fn start_socket(port: u16, backlog: i32) -> io::Result<TcpListener> {
         let localhost = Ipv4Addr::new(127, 0, 0, 1).into();
         let addr = SocketAddr::new(localhost, port);
-        let server = TcpListener::bind(&addr)?;
+        // same as TcpListener::bind(), with a configurable backlog
+        let builder = TcpBuilder::new_v4()?;
+        if cfg!(unix) {
+            builder.reuse_address(true)?;
+        }
+        builder.bind(addr)?;
+        let server = TcpListener::from_std(builder.listen(backlog)?)?;
         Ok(server)
     }
 
     fn on_ready(&mut self, selector: &mut Selector, _: Event) {
-        match self.accept_client(selector) {
-            Ok(_) => debug!(target: TAG, "New client accepted"),
-            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
-                debug!(target: TAG, "Spurious event, ignoring");
+        self.accept_clients(selector);
+    }
+
+    fn accept_clients(&mut self, selector: &mut Selector) {
+        self.accept_deferred = false;
+        let mut accepted = 0;
+        // the listener is edge-triggered, accept all the pending clients
+        loop {
+            if let Some(ref mut accept_rate_limiter) = self.accept_rate_limiter {
+                if !accept_rate_limiter.is_available(Instant::now()) {
+                    debug!(target: TAG, "Accept rate exceeded, deferring");
+                    self.accept_deferred = true;
+                    break;
+                }
+            }
+            match self.accept_client(selector) {
+                Ok(_) => {
+                    debug!(target: TAG, "New client accepted");
+                    if let Some(ref mut accept_rate_limiter) = self.accept_rate_limiter {
+                        accept_rate_limiter.consume();
+                    }
+                    accepted += 1;
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    if accepted == 0 {
+                        debug!(target: TAG, "Spurious event, ignoring");
+                    }
+                    break;
+                }
+                Err(err) => {
+                    error!(target: TAG, "Cannot accept client: {}", err);
+                    break;
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn local_port(&self) -> u16 {
+        self.tcp_listener.local_addr().unwrap().port()
+    }
+
+    /// Return the delay before the deferred clients may be accepted, if any.
+    pub fn accept_delay(&self) -> Option<Duration> {
+        if self.accept_deferred {
+            self.accept_rate_limiter
+                .as_ref()
+                .map(|accept_rate_limiter| accept_rate_limiter.delay(Instant::now()))
+        } else {
+            None
+        }
+    }
+
+    /// Accept the clients deferred by the rate limiter, if the delay is elapsed.
+    ///
+    /// Return `true` if an attempt was made.
+    pub fn accept_deferred_clients(&mut self, selector: &mut Selector) -> bool {
+        match self.accept_delay() {
+            Some(delay) if delay == Duration::from_secs(0) => {
+                self.accept_clients(selector);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Return the delay before the clients deferred by the egress limiter may send, if any.
+    pub fn egress_delay(&self) -> Option<Duration> {
+        let egress_limiter = self.egress_limiter.as_ref()?;
+        if self
+            .clients
+            .iter()
+            .any(|client| client.borrow().egress_deferred())
+        {
+            Some(egress_limiter.borrow().delay(Instant::now()))
+        } else {
+            None
+        }
+    }
+
+    /// Bytes sent to the network by all the clients during the last second, if the egress rate
+    /// is limited.
+    pub fn egress_rate(&self) -> Option<u64> {
+        self.egress_limiter
+            .as_ref()
+            .map(|egress_limiter| egress_limiter.borrow().rate(Instant::now()))
+    }
+
+    /// Resume the clients deferred by the egress limiter, in turn, if the delay is elapsed.
+    ///
+    /// Return `true` if an attempt was made.
+    ///
+    /// Resuming a client may close it, which removes it from the tunnel server, so it must not be
+    /// borrowed meanwhile.
+    pub fn resume_egress(tunnel_server: &RefCell<TunnelServer>, selector: &mut Selector) -> bool {
+        let deferred: Vec<_> = {
+            let mut tunnel_server = tunnel_server.borrow_mut();
+            match tunnel_server.egress_delay() {
+                Some(delay) if delay == Duration::from_secs(0) => (),
+                _ => return false,
             }
-            Err(err) => error!(target: TAG, "Cannot accept client: {}", err),
+            let count = tunnel_server.clients.len();
+            let turn = tunnel_server.next_egress_turn % count;
+            tunnel_server.next_egress_turn = turn + 1;
+            let (before, after) = tunnel_server.clients.split_at(turn);
+            after
+                .iter()
+                .chain(before)
+                .filter(|client| client.borrow().egress_deferred())
+                .cloned()
+                .collect()
+        };
+        for client in &deferred {
+            client.borrow_mut().resume_egress(selector);
         }
+        true
     }
 
     fn accept_client(&mut self, selector: &mut Selector) -> io::Result<()> {
         let (stream, _) = self.tcp_listener.accept()?;
+        // the packets to the device are written as soon as possible if Nagle's algorithm is
+        // disabled
+        if let Err(err) = stream.set_nodelay(self.config.client_nodelay()) {
+            warn!(target: TAG, "Cannot configure client socket: {}", err);
+        }
+        let buffer_sizes = self.config.socket_buffer_sizes();
+        if !buffer_sizes.is_empty() {
+            match socket_buffers::apply(&stream, buffer_sizes) {
+                Ok(effective) => info!(target: TAG, "Client socket buffers: {}", effective),
+                Err(err) => warn!(target: TAG, "Cannot set client socket buffers: {}", err),
+            }
+        }
         let client_id = self.next_client_id;
         self.next_client_id += 1;
         let weak = self.self_weak.clone();
@@ -94,14 +267,33 @@ impl TunnelServer {
                 );
             }
         });
-        let client = Client::create(client_id, selector, stream, on_client_closed)?;
+        let client = Client::create(
+            client_id,
+            selector,
+            stream,
+            self.config.clone(),
+            self.mirror.clone(),
+            self.egress_limiter.clone(),
+            on_client_closed,
+        )?;
+        if self.max_connections_per_client != self.config.max_connections_per_client() {
+            // no connection to close yet
+            client
+                .borrow_mut()
+                .set_max_connections(selector, self.max_connections_per_client);
+        }
         self.clients.push(client);
         info!(target: TAG, "Client #{} connected", client_id);
+        self.config
+            .events()
+            .publish(RelayEvent::ClientConnected(client_id));
         Ok(())
     }
 
     fn remove_client(&mut self, client: &Client) {
         info!(target: TAG, "Client #{} disconnected", client.id());
+        self.retired_stats
+            .add_counters(&client.stats(Instant::now()));
         let index = self
             .clients
             .iter()
@@ -111,6 +303,176 @@ impl TunnelServer {
             })
             .expect("Trying to remove an unknown client");
         self.clients.swap_remove(index);
+        self.config
+            .events()
+            .publish(RelayEvent::ClientDisconnected(client.id()));
+    }
+
+    pub fn stats(&self, taken: Instant) -> RouterStats {
+        let mut stats = self.retired_stats.clone();
+        stats.taken = taken;
+        for client in &self.clients {
+            stats.add(&client.borrow().stats(taken));
+        }
+        stats
+    }
+
+    pub fn created(&self) -> Instant {
+        self.created
+    }
+
+    pub fn config(&self) -> &RelayConfig {
+        &self.config
+    }
+
+    /// The connections of every client, by client id.
+    pub fn connection_infos(&self) -> Vec<(u32, Vec<ConnectionInfo>)> {
+        self.clients
+            .iter()
+            .map(|client| {
+                let client = client.borrow();
+                (client.id(), client.connection_infos())
+            })
+            .collect()
+    }
+
+    /// Full snapshot of a connection of the client `client_id`, if any.
+    pub fn connection_detail(
+        &self,
+        client_id: u32,
+        protocol: Protocol,
+        source: SocketAddrV4,
+        destination: SocketAddrV4,
+    ) -> Option<ConnectionDetail> {
+        self.clients
+            .iter()
+            .map(|client| client.borrow())
+            .find(|client| client.id() == client_id)
+            .and_then(|client| client.connection_detail(protocol, source, destination))
+    }
+
+    /// Pause or resume a connection of the client `client_id`, and return whether it exists.
+    pub fn set_connection_paused(
+        &mut self,
+        selector: &mut Selector,
+        client_id: u32,
+        protocol: Protocol,
+        source: SocketAddrV4,
+        destination: SocketAddrV4,
+        paused: bool,
+    ) -> bool {
+        self.clients
+            .iter()
+            .find(|client| client.borrow().id() == client_id)
+            .is_some_and(|client| {
+                client.borrow_mut().set_connection_paused(
+                    selector,
+                    protocol,
+                    source,
+                    destination,
+                    paused,
+                )
+            })
+    }
+
+    pub fn reset_stats(&mut self, scope: ResetScope) {
+        self.retired_stats.reset(scope);
+        for client in &self.clients {
+            client.borrow_mut().reset_stats(scope);
+        }
+    }
+
+    /// Close the connections of every client to the addresses in `network`/`prefix_length`, and
+    /// return how many have been closed.
+    pub fn close_connections_to(
+        &mut self,
+        selector: &mut Selector,
+        network: Ipv4Addr,
+        prefix_length: u8,
+    ) -> usize {
+        let closed = self
+            .clients
+            .iter()
+            .map(|client| {
+                client
+                    .borrow_mut()
+                    .close_connections_to(selector, network, prefix_length)
+            })
+            .sum();
+        info!(
+            target: TAG,
+            "{} connections to {}/{} closed", closed, network, prefix_length
+        );
+        closed
+    }
+
+    /// Change the maximum number of connections per client, including the clients connected
+    /// later, and return how many connections exceeding it have been closed.
+    pub fn set_max_connections_per_client(
+        &mut self,
+        selector: &mut Selector,
+        max_connections: Option<usize>,
+    ) -> usize {
+        self.max_connections_per_client = max_connections;
+        let closed = self
+            .clients
+            .iter()
+            .map(|client| {
+                client
+                    .borrow_mut()
+                    .set_max_connections(selector, max_connections)
+            })
+            .sum();
+        info!(
+            target: TAG,
+            "Max connections per client set to {:?}, {} connections closed", max_connections, closed
+        );
+        closed
+    }
+
+    /// Drain the client `client_id`: it refuses new connections, and is closed once its
+    /// connections are closed, or after `timeout`. Return whether the client exists.
+    pub fn drain_client(&mut self, client_id: u32, timeout: Duration) -> bool {
+        match self
+            .clients
+            .iter()
+            .find(|client| client.borrow().id() == client_id)
+        {
+            Some(client) => {
+                client.borrow_mut().drain(timeout);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Return the earliest deadline of the draining clients, if any.
+    pub fn drain_deadline(&self) -> Option<Instant> {
+        self.clients
+            .iter()
+            .filter_map(|client| client.borrow().drain_deadline())
+            .min()
+    }
+
+    /// Close the drained clients, and return how many have been closed.
+    ///
+    /// Closing a client removes it from the tunnel server, so it must not be borrowed meanwhile.
+    pub fn close_drained_clients(
+        tunnel_server: &RefCell<TunnelServer>,
+        selector: &mut Selector,
+    ) -> usize {
+        let now = Instant::now();
+        let drained: Vec<_> = tunnel_server
+            .borrow()
+            .clients
+            .iter()
+            .filter(|client| client.borrow().is_drained(now))
+            .cloned()
+            .collect();
+        for client in &drained {
+            client.borrow_mut().close_drained(selector);
+        }
+        drained.len()
     }
 
     pub fn clean_up(&mut self, selector: &mut Selector) {
@@ -119,3 +481,74 @@ impl TunnelServer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::ipv4_packet::tests::{create_udp_packet, set_destination};
+    use mio::Events;
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::net::{TcpStream, UdpSocket};
+
+    #[test]
+    fn share_egress_rate() {
+        // 32-byte packets, 100 per second
+        const RATE: u32 = 3200;
+        const DURATION: Duration = Duration::from_millis(500);
+
+        let mut selector = Selector::create().unwrap();
+        let mut config = RelayConfig::new();
+        config.set_max_egress_rate(Some(RATE));
+        let tunnel_server = TunnelServer::create(0, Rc::new(config), &mut selector).unwrap();
+        let port = tunnel_server.borrow().local_port();
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let mut packet = create_udp_packet(0);
+        let destination =
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, socket.local_addr().unwrap().port());
+        set_destination(&mut packet, destination);
+
+        // two devices sending far more than allowed
+        let mut devices = Vec::new();
+        for _ in 0..2 {
+            let mut device = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+            for _ in 0..200 {
+                device.write_all(&packet).unwrap();
+            }
+            devices.push(device);
+        }
+
+        let mut received = HashMap::new();
+        let mut events = Events::with_capacity(16);
+        let start = Instant::now();
+        while start.elapsed() < DURATION {
+            let mut timeout = Duration::from_millis(10);
+            if let Some(egress_delay) = tunnel_server.borrow().egress_delay() {
+                timeout = timeout.min(egress_delay);
+            }
+            selector.poll(&mut events, Some(timeout)).unwrap();
+            selector.run_handlers(&events);
+            TunnelServer::resume_egress(&tunnel_server, &mut selector);
+
+            let mut buf = [0; 16];
+            while let Ok((_, source)) = socket.recv_from(&mut buf) {
+                *received.entry(source).or_insert(0) += packet.len() as u32;
+            }
+        }
+
+        let elapsed = start.elapsed();
+
+        assert_eq!(2, received.len(), "Both clients must make progress");
+        let total: u32 = received.values().sum();
+        // one second of burst, and the packet in debt
+        let allowed = RATE + RATE * elapsed.as_millis() as u32 / 1000 + packet.len() as u32;
+        assert!(
+            total <= allowed,
+            "{} bytes sent, {} allowed",
+            total,
+            allowed
+        );
+        assert!(tunnel_server.borrow().egress_rate().is_some());
+    }
+}
@@ -17,36 +17,99 @@
 use log::*;
 use mio::net::UdpSocket;
 use mio::{Event, PollOpt, Ready, Token};
+use net2::UdpBuilder;
+use rand::random;
 use std::cell::RefCell;
 use std::io;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::ops::RangeInclusive;
 use std::rc::{Rc, Weak};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use super::binary;
 use super::client::{Client, ClientChannel};
-use super::connection::{Connection, ConnectionId};
+use super::config::RelayConfig;
+use super::connection::{Connection, ConnectionId, ConnectionInfo};
+use super::datagram::{RecvFromAdapter, SendToAdapter};
 use super::datagram_buffer::DatagramBuffer;
+use super::drop_reason::{DropCounters, DropReason};
 use super::ipv4_header::Ipv4Header;
 use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
+use super::jitter_meter::JitterMeter;
+use super::packet_times::PacketTimes;
 use super::packetizer::Packetizer;
+use super::payload_rewriter::{Direction, PayloadRewriter};
 use super::selector::Selector;
+use super::socket_buffers;
+use super::socket_priority;
 use super::transport_header::TransportHeader;
 
 const TAG: &str = "UdpConnection";
 
 pub const IDLE_TIMEOUT_SECONDS: u64 = 2 * 60;
 
+const DNS_PORT: u16 = 53;
+
 pub struct UdpConnection {
     id: ConnectionId,
     client: Weak<RefCell<Client>>,
+    // not connected, so that the source of the datagrams can be checked and counted
     socket: UdpSocket,
+    local_address: Option<SocketAddr>,
+    // the resolved destination (or the multicast group)
+    peer: SocketAddrV4,
+    // the joined multicast group and interface
+    multicast_group: Option<(SocketAddrV4, Ipv4Addr)>,
     interests: Ready,
     token: Token,
     client_to_network: DatagramBuffer,
     network_to_client: Packetizer,
     closed: bool,
     idle_since: Instant,
+    created: Instant,
+    max_lifetime: Option<Duration>,
+    payload_rewriter: Option<Rc<dyn PayloadRewriter>>,
+    max_payload: Option<usize>,
+    oversized_datagrams: u64,
+    drops: Rc<DropCounters>,
+    dns_query: Option<DnsQuery>,
+    // (to network, to device)
+    jitter_meters: Option<(JitterMeter, JitterMeter)>,
+    packet_times: PacketTimes,
+    label: Option<String>,
+    // neither read from the network nor written to it, on request
+    paused: bool,
+}
+
+// a DNS flow is useless once a query is unanswered for too long, the client will retry anyway
+struct DnsQuery {
+    timeout: Duration,
+    // the sending time of the oldest unanswered query
+    pending_since: Option<Instant>,
+}
+
+impl DnsQuery {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            pending_since: None,
+        }
+    }
+
+    fn sent(&mut self) {
+        if self.pending_since.is_none() {
+            self.pending_since = Some(Instant::now());
+        }
+    }
+
+    fn answered(&mut self) {
+        self.pending_since = None;
+    }
+
+    fn is_timed_out(&self) -> bool {
+        self.pending_since
+            .is_some_and(|since| since.elapsed() >= self.timeout)
+    }
 }
 
 impl UdpConnection {
@@ -55,23 +118,49 @@ impl UdpConnection {
         selector: &mut Selector,
         id: ConnectionId,
         client: Weak<RefCell<Client>>,
+        config: &RelayConfig,
+        drops: Rc<DropCounters>,
         ipv4_header: Ipv4Header,
         transport_header: TransportHeader,
     ) -> io::Result<Rc<RefCell<Self>>> {
         cx_info!(target: TAG, id, "Open");
-        let socket = Self::create_socket(&id)?;
+        let (socket, peer, joined) = Self::create_socket(&id, config, ipv4_header.dscp())?;
+        let dns_query = if id.destination().port() == DNS_PORT {
+            config.dns_timeout().map(DnsQuery::new)
+        } else {
+            None
+        };
         let packetizer = Packetizer::new(&ipv4_header, &transport_header);
         let interests = Ready::readable();
+        let local_address = socket.local_addr().ok();
         let rc = Rc::new(RefCell::new(Self {
             id,
             client,
             socket,
+            local_address,
+            peer,
+            multicast_group: joined.then(|| (peer, config.multicast_interface())),
             interests,
             token: Token(0), // default value, will be set afterwards
             client_to_network: DatagramBuffer::new(4 * MAX_PACKET_LENGTH),
             network_to_client: packetizer,
             closed: false,
             idle_since: Instant::now(),
+            created: Instant::now(),
+            max_lifetime: config.max_connection_lifetime(),
+            payload_rewriter: config.payload_rewriter().cloned(),
+            max_payload: config.udp_max_payload(),
+            oversized_datagrams: 0,
+            drops,
+            dns_query,
+            jitter_meters: if config.udp_jitter() {
+                Some((JitterMeter::new(), JitterMeter::new()))
+            } else {
+                None
+            },
+            packet_times: PacketTimes::default(),
+            label: None,
+            paused: false,
         }));
 
         {
@@ -88,11 +177,90 @@ impl UdpConnection {
         Ok(rc)
     }
 
-    fn create_socket(id: &ConnectionId) -> io::Result<UdpSocket> {
-        let autobind_addr = SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0);
-        let udp_socket = UdpSocket::bind(&autobind_addr)?;
-        udp_socket.connect(id.rewritten_destination().into())?;
-        Ok(udp_socket)
+    // return the socket along with its peer, and whether it joined the peer as a multicast group
+    fn create_socket(
+        id: &ConnectionId,
+        config: &RelayConfig,
+        dscp: u8,
+    ) -> io::Result<(UdpSocket, SocketAddrV4, bool)> {
+        let destination = id.resolved_destination(config.destination_resolver())?;
+        let multicast = destination.ip().is_multicast() && config.allow_multicast();
+        let udp_socket = if multicast {
+            Self::bind_multicast_socket(id, destination)?
+        } else {
+            Self::bind_socket(config.udp_source_ports())?
+        };
+        let buffer_sizes = config.socket_buffer_sizes();
+        if !buffer_sizes.is_empty() {
+            match socket_buffers::apply(&udp_socket, buffer_sizes) {
+                Ok(effective) => cx_debug!(target: TAG, id, "Socket buffers: {}", effective),
+                Err(err) => cx_warn!(target: TAG, id, "Cannot set socket buffers: {}", err),
+            }
+        }
+        if let Some(priority) = socket_priority::priority_of(config.dscp_priorities(), dscp) {
+            if let Err(err) = socket_priority::set_priority(&udp_socket, priority) {
+                cx_warn!(target: TAG, id, "Cannot set socket priority: {}", err);
+            }
+        }
+        if multicast {
+            udp_socket.join_multicast_v4(destination.ip(), &config.multicast_interface())?;
+            cx_info!(target: TAG, id, "Join multicast group {}", destination.ip());
+            return Ok((udp_socket, destination, true));
+        }
+        if destination.ip().is_broadcast() {
+            // only reached if broadcast is allowed
+            udp_socket.set_broadcast(true)?;
+        }
+        Ok((udp_socket, destination, false))
+    }
+
+    // bound to the group address and port, to receive only the datagrams sent to the group
+    //
+    // The configured source ports do not apply: the answers to the group are sent to the group
+    // port.
+    fn bind_multicast_socket(id: &ConnectionId, group: SocketAddrV4) -> io::Result<UdpSocket> {
+        // the group port may be shared with a local service (e.g. an mDNS responder) or another
+        // device
+        let result =
+            UdpBuilder::new_v4().and_then(|builder| builder.reuse_address(true)?.bind(group));
+        match result {
+            Ok(socket) => UdpSocket::from_socket(socket),
+            Err(ref err) if err.kind() == io::ErrorKind::AddrInUse => {
+                cx_warn!(
+                    target: TAG,
+                    id,
+                    "Multicast port {} is used exclusively, only the answers to the source port \
+                     will be received",
+                    group.port()
+                );
+                UdpSocket::bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn bind_socket(source_ports: Option<&RangeInclusive<u16>>) -> io::Result<UdpSocket> {
+        let bind_addr = |port| SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), port);
+        let source_ports = match source_ports {
+            Some(source_ports) => source_ports,
+            None => return UdpSocket::bind(&bind_addr(0)),
+        };
+        let first = u32::from(*source_ports.start());
+        let count = u32::from(*source_ports.end()) + 1 - first;
+        // start at a random port, to avoid retrying the same used ports for every connection
+        let offset = random::<u32>() % count;
+        for i in 0..count {
+            let port = (first + (offset + i) % count) as u16;
+            match UdpSocket::bind(&bind_addr(port)) {
+                Ok(udp_socket) => return Ok(udp_socket),
+                Err(ref err) if err.kind() == io::ErrorKind::AddrInUse => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "No UDP source port available in the configured range",
+        ))
     }
 
     fn remove_from_router(&self) {
@@ -188,13 +356,70 @@ impl UdpConnection {
     }
 
     fn read(&mut self, selector: &mut Selector) -> io::Result<()> {
-        let ipv4_packet = self.network_to_client.packetize(&mut self.socket)?;
+        // the answers to a multicast group come from any member
+        let source = match self.multicast_group {
+            Some(_) => None,
+            None => Some(self.peer.into()),
+        };
+        let mut adapter = RecvFromAdapter::new(&self.socket, source);
+        let result = self.network_to_client.packetize(&mut adapter);
+        let rejected = adapter.rejected();
+        if rejected > 0 {
+            cx_debug!(
+                target: TAG,
+                self.id,
+                "{} datagrams not from {}, drop packets",
+                rejected,
+                self.peer
+            );
+            for _ in 0..rejected {
+                self.drops.record(DropReason::UdpSourceMismatch);
+            }
+        }
+        let mut length = result?.length();
+        if let Some(ref payload_rewriter) = self.payload_rewriter {
+            let rewritten = {
+                let ipv4_packet = self.network_to_client.inflate(length);
+                payload_rewriter.rewrite(
+                    Direction::ToDevice,
+                    self.id.source(),
+                    self.id.destination(),
+                    ipv4_packet.payload().expect("No payload"),
+                )
+            };
+            if let Some(payload) = rewritten {
+                cx_debug!(target: TAG, self.id, "Payload rewritten to client");
+                // lengths and checksums are recomputed
+                length = self.network_to_client.packetize_payload(&payload)?.length();
+            }
+        }
+        let ipv4_packet = self.network_to_client.inflate(length);
+        let payload_length = ipv4_packet.payload().expect("No payload").len();
+        if Self::is_oversized(self.max_payload, payload_length) {
+            cx_debug!(
+                target: TAG,
+                self.id,
+                "Oversized datagram ({} bytes) from network, drop packet",
+                payload_length
+            );
+            self.oversized_datagrams += 1;
+            self.drops.record(DropReason::UdpOversize);
+            return Ok(());
+        }
+        if let Some(ref mut dns_query) = self.dns_query {
+            dns_query.answered();
+        }
+        if let Some((_, ref mut to_device)) = self.jitter_meters {
+            to_device.record(Instant::now());
+        }
         let client_rc = self.client.upgrade().expect("Expected client not found");
         match client_rc
             .borrow_mut()
             .send_to_client(selector, &ipv4_packet)
         {
             Ok(_) => {
+                self.packet_times
+                    .record(Direction::ToDevice, Instant::now());
                 cx_debug!(
                     target: TAG,
                     self.id,
@@ -216,12 +441,16 @@ impl UdpConnection {
     }
 
     fn write(&mut self) -> io::Result<()> {
-        self.client_to_network.write_to(&mut self.socket)?;
+        let mut adapter = SendToAdapter::new(&self.socket, self.peer.into());
+        self.client_to_network.write_to(&mut adapter)?;
         Ok(())
     }
 
     fn update_interests(&mut self, selector: &mut Selector) {
-        let ready = if self.client_to_network.is_empty() {
+        let ready = if self.paused {
+            // the datagrams from the device are queued until the buffer is full, then dropped
+            Ready::empty()
+        } else if self.client_to_network.is_empty() {
             Ready::readable()
         } else {
             Ready::readable() | Ready::writable()
@@ -239,6 +468,10 @@ impl UdpConnection {
     fn touch(&mut self) {
         self.idle_since = Instant::now();
     }
+
+    fn is_oversized(max_payload: Option<usize>, payload_length: usize) -> bool {
+        max_payload.is_some_and(|max_payload| payload_length > max_payload)
+    }
 }
 
 impl Connection for UdpConnection {
@@ -252,11 +485,43 @@ impl Connection for UdpConnection {
         _: &mut ClientChannel,
         ipv4_packet: &Ipv4Packet,
     ) {
-        match self
-            .client_to_network
-            .read_from(ipv4_packet.payload().expect("No payload"))
-        {
+        let payload = ipv4_packet.payload().expect("No payload");
+        let rewritten = self.payload_rewriter.as_ref().and_then(|payload_rewriter| {
+            payload_rewriter.rewrite(
+                Direction::ToNetwork,
+                self.id.source(),
+                self.id.destination(),
+                payload,
+            )
+        });
+        let payload = match rewritten {
+            Some(ref rewritten) => {
+                cx_debug!(target: TAG, self.id, "Payload rewritten to network");
+                &rewritten[..]
+            }
+            None => payload,
+        };
+        if Self::is_oversized(self.max_payload, payload.len()) {
+            cx_debug!(
+                target: TAG,
+                self.id,
+                "Oversized datagram ({} bytes) to network, drop packet",
+                payload.len()
+            );
+            self.oversized_datagrams += 1;
+            self.drops.record(DropReason::UdpOversize);
+            return;
+        }
+        match self.client_to_network.read_from(payload) {
             Ok(_) => {
+                if let Some(ref mut dns_query) = self.dns_query {
+                    dns_query.sent();
+                }
+                let now = Instant::now();
+                if let Some((ref mut to_network, _)) = self.jitter_meters {
+                    to_network.record(now);
+                }
+                self.packet_times.record(Direction::ToNetwork, now);
                 self.update_interests(selector);
             }
             Err(err) => cx_warn!(
@@ -270,7 +535,35 @@ impl Connection for UdpConnection {
 
     fn close(&mut self, selector: &mut Selector) {
         cx_info!(target: TAG, self.id, "Close");
+        cx_debug!(
+            target: TAG,
+            self.id,
+            "Packet times: {}",
+            self.packet_times.format(self.created)
+        );
+        if self.oversized_datagrams > 0 {
+            cx_info!(
+                target: TAG,
+                self.id,
+                "{} oversized datagrams dropped",
+                self.oversized_datagrams
+            );
+        }
+        if let Some((ref to_network, ref to_device)) = self.jitter_meters {
+            cx_info!(
+                target: TAG,
+                self.id,
+                "To network: {}; to device: {}",
+                to_network,
+                to_device
+            );
+        }
         self.closed = true;
+        if let Some((group, interface)) = self.multicast_group {
+            if let Err(err) = self.socket.leave_multicast_v4(group.ip(), &interface) {
+                cx_warn!(target: TAG, self.id, "Cannot leave multicast group: {}", err);
+            }
+        }
         if let Err(err) = selector.deregister(&self.socket, self.token) {
             // do not panic, this can happen in mio
             // see <https://github.com/Genymobile/gnirehtet/issues/136>
@@ -284,11 +577,384 @@ impl Connection for UdpConnection {
         // socket will be closed by RAII
     }
 
+    fn tick(&mut self, _: &mut Selector, _: &mut ClientChannel) {
+        // nothing to do, UDP connections only expire
+    }
+
+    fn abort(&mut self, selector: &mut Selector, _: &mut ClientChannel) {
+        // there is nothing to notify for UDP, just drop the socket
+        self.close(selector);
+    }
+
     fn is_expired(&self) -> bool {
+        if let Some(max_lifetime) = self.max_lifetime {
+            if self.created.elapsed() >= max_lifetime {
+                cx_debug!(target: TAG, self.id, "Max lifetime exceeded");
+                return true;
+            }
+        }
+        if let Some(ref dns_query) = self.dns_query {
+            if dns_query.is_timed_out() {
+                cx_debug!(target: TAG, self.id, "DNS query timed out");
+                return true;
+            }
+        }
         self.idle_since.elapsed().as_secs() > IDLE_TIMEOUT_SECONDS
     }
 
+    fn is_half_open(&self) -> bool {
+        false
+    }
+
+    fn created(&self) -> Instant {
+        self.created
+    }
+
+    fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    fn set_label(&mut self, label: String) {
+        self.label = Some(label);
+    }
+
     fn is_closed(&self) -> bool {
         self.closed
     }
+
+    fn info(&self) -> ConnectionInfo {
+        let state = String::from(if self.closed { "Closed" } else { "Open" });
+        let mut info = ConnectionInfo::new(&self.id, state, self.created, self.label())
+            .with_packet_times(&self.packet_times);
+        info.local_address = self.local_address;
+        info.paused = self.paused;
+        info
+    }
+
+    fn set_paused(&mut self, selector: &mut Selector, paused: bool) {
+        if self.paused == paused {
+            return;
+        }
+        cx_info!(target: TAG, self.id, "{}", if paused { "Paused" } else { "Resumed" });
+        self.paused = paused;
+        if !self.closed {
+            self.update_interests(selector);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::client::tests::MockDevice;
+    use crate::relay::destination_resolver::DestinationResolver;
+    use crate::relay::ipv4_header::Protocol;
+    use crate::relay::ipv4_packet::tests::{create_udp_packet, set_destination};
+
+    #[test]
+    fn bind_in_source_port_range() {
+        // find a free port
+        let used = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        let port = used.local_addr().unwrap().port();
+
+        let result = UdpConnection::bind_socket(Some(&(port..=port)));
+        assert_eq!(io::ErrorKind::AddrInUse, result.err().unwrap().kind());
+
+        drop(used);
+        let udp_socket = UdpConnection::bind_socket(Some(&(port..=port))).unwrap();
+        assert_eq!(port, udp_socket.local_addr().unwrap().port());
+    }
+
+    #[test]
+    fn detect_oversized_payload() {
+        assert!(!UdpConnection::is_oversized(Some(512), 512));
+        assert!(UdpConnection::is_oversized(Some(512), 513));
+        assert!(!UdpConnection::is_oversized(None, 65507));
+    }
+
+    #[test]
+    fn dns_query_timeout() {
+        let mut dns_query = DnsQuery::new(Duration::from_millis(10));
+        assert!(!dns_query.is_timed_out());
+
+        dns_query.sent();
+        assert!(!dns_query.is_timed_out());
+        std::thread::sleep(Duration::from_millis(20));
+        // reaped long before the UDP idle timeout
+        assert!(dns_query.is_timed_out());
+
+        dns_query.answered();
+        assert!(!dns_query.is_timed_out());
+    }
+
+    #[test]
+    fn dns_query_answered_in_time() {
+        let mut dns_query = DnsQuery::new(Duration::from_secs(60));
+        dns_query.sent();
+        dns_query.answered();
+        dns_query.sent();
+        assert!(!dns_query.is_timed_out());
+    }
+
+    #[test]
+    fn bind_any_source_port() {
+        let udp_socket = UdpConnection::bind_socket(None).unwrap();
+        assert_ne!(0, udp_socket.local_addr().unwrap().port());
+    }
+
+    #[derive(Debug)]
+    struct Redirect {
+        from: SocketAddrV4,
+        to: SocketAddrV4,
+    }
+
+    impl DestinationResolver for Redirect {
+        fn resolve(
+            &self,
+            protocol: Protocol,
+            _device: SocketAddrV4,
+            destination: SocketAddrV4,
+        ) -> Option<SocketAddrV4> {
+            assert_eq!(Protocol::Udp, protocol);
+            if destination == self.from {
+                Some(self.to)
+            } else {
+                // refuse any other destination
+                None
+            }
+        }
+    }
+
+    fn create_udp_packet_to(destination: SocketAddrV4) -> Vec<u8> {
+        let mut raw = create_udp_packet(destination.port());
+        set_destination(&mut raw, destination);
+        raw
+    }
+
+    fn create_id(destination: SocketAddrV4) -> ConnectionId {
+        let raw = &mut create_udp_packet_to(destination)[..];
+        let ipv4_packet = Ipv4Packet::parse(raw).unwrap();
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap())
+    }
+
+    #[test]
+    fn resolve_destination() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let to = match server.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            _ => panic!("Expected IPv4 address"),
+        };
+        let from = "1.2.3.4:53".parse().unwrap();
+        let mut config = RelayConfig::new();
+        config.set_destination_resolver(Some(Rc::new(Redirect { from, to })));
+
+        let (udp_socket, peer, _) =
+            UdpConnection::create_socket(&create_id(from), &config, 0).unwrap();
+        assert_eq!(to, peer);
+        udp_socket.send_to(b"hello", &peer.into()).unwrap();
+        let mut buf = [0u8; 16];
+        let (len, source) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(b"hello", &buf[..len]);
+        assert_eq!(udp_socket.local_addr().unwrap().port(), source.port());
+
+        let result =
+            UdpConnection::create_socket(&create_id("1.2.3.5:53".parse().unwrap()), &config, 0);
+        assert_eq!(
+            io::ErrorKind::PermissionDenied,
+            result.err().unwrap().kind()
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn set_priority_from_dscp() {
+        let destination = "127.0.0.1:53".parse().unwrap();
+        let mut config = RelayConfig::new();
+        config.set_dscp_priorities(Some(vec![(46, 6), (10, 2)]));
+
+        let (udp_socket, _, _) =
+            UdpConnection::create_socket(&create_id(destination), &config, 46).unwrap();
+        assert_eq!(6, socket_priority::priority(&udp_socket).unwrap());
+
+        // unmapped DSCP, default priority
+        let (udp_socket, _, _) =
+            UdpConnection::create_socket(&create_id(destination), &config, 0).unwrap();
+        assert_eq!(0, socket_priority::priority(&udp_socket).unwrap());
+    }
+
+    // whether the datagrams sent to a group are looped back to the local members
+    fn multicast_loopback_available(group: Ipv4Addr) -> bool {
+        let member = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        if member
+            .join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)
+            .is_err()
+        {
+            return false;
+        }
+        let port = member.local_addr().unwrap().port();
+        let sender = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        if sender.send_to(b"ping", (group, port)).is_err() {
+            return false;
+        }
+        member
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let mut buf = [0; 16];
+        member.recv(&mut buf).is_ok()
+    }
+
+    // run with: cargo test relay_joined_multicast_group -- --ignored
+    #[ignore = "requires a host looping multicast datagrams back to the local members"]
+    #[test]
+    fn relay_joined_multicast_group() {
+        let group_ip = Ipv4Addr::new(239, 255, 42, 99);
+        assert!(
+            multicast_loopback_available(group_ip),
+            "Multicast loopback unavailable"
+        );
+        // find a free port, the group port is bound if available
+        let used = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        let port = used.local_addr().unwrap().port();
+        drop(used);
+        let group = SocketAddrV4::new(group_ip, port);
+        let raw = &mut create_udp_packet_to(group)[..];
+        let ipv4_packet = Ipv4Packet::parse(raw).unwrap();
+
+        // not joined by default
+        let config = RelayConfig::new();
+        let (_, _, joined) = UdpConnection::create_socket(&create_id(group), &config, 0).unwrap();
+        assert!(!joined);
+
+        let mut config = RelayConfig::new();
+        config.set_allow_multicast(true);
+        let (udp_socket, peer, joined) =
+            UdpConnection::create_socket(&create_id(group), &config, 0).unwrap();
+        assert_eq!((group, true), (peer, joined));
+
+        // not sent to the group
+        let member = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        member.send_to(b"stray", ("127.0.0.1", port)).unwrap();
+        // from another member of the group
+        member.send_to(b"hello", group).unwrap();
+
+        let ipv4_header = ipv4_packet.ipv4_header();
+        let transport_header = ipv4_packet.transport_header().unwrap();
+        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header);
+        let mut adapter = RecvFromAdapter::new(&udp_socket, None);
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let packet = loop {
+            match packetizer.packetize(&mut adapter) {
+                Ok(packet) => break packet,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(Instant::now() < deadline, "No datagram from the group");
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(err) => panic!("Cannot receive: {}", err),
+            }
+        };
+        // relayed to the device, as if it came from the group
+        let (ipv4_header_data, transport_header_data) = packet.headers_data();
+        assert_eq!(0x0a000002, ipv4_header_data.destination());
+        assert_eq!(u32::from(*group.ip()), ipv4_header_data.source());
+        assert_eq!(1234, transport_header_data.unwrap().destination_port());
+        assert_eq!(b"hello", packet.payload().unwrap());
+
+        udp_socket
+            .leave_multicast_v4(group.ip(), &Ipv4Addr::new(0, 0, 0, 0))
+            .unwrap();
+    }
+
+    #[test]
+    fn record_local_address() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = match server.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            _ => panic!("Expected IPv4 address"),
+        };
+        let mut selector = Selector::create().unwrap();
+        let mut device = MockDevice::new(&mut selector, RelayConfig::new());
+        device.send(&create_udp_packet_to(destination));
+        device.receive(&mut selector, Duration::from_millis(100));
+
+        server
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let mut buf = [0u8; 16];
+        let (_, relay) = server.recv_from(&mut buf).unwrap();
+
+        // bound to any address, on the port seen by the server
+        let info = device.client().borrow().connection_infos().remove(0);
+        let local_address = info.local_address.expect("Expected local address");
+        assert!(local_address.ip().is_unspecified());
+        assert_eq!(relay.port(), local_address.port());
+        device.client().borrow_mut().router().clear(&mut selector);
+    }
+
+    #[test]
+    fn reset_idle_time_on_traffic() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = match server.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            _ => panic!("Expected IPv4 address"),
+        };
+        let mut selector = Selector::create().unwrap();
+        let mut device = MockDevice::new(&mut selector, RelayConfig::new());
+        let idle = |device: &MockDevice| {
+            let info = device.client().borrow().connection_infos().remove(0);
+            info.idle(Instant::now())
+        };
+
+        device.send(&create_udp_packet_to(destination));
+        device.receive(&mut selector, Duration::from_millis(20));
+        let before = idle(&device);
+        std::thread::sleep(Duration::from_millis(50));
+        let after = idle(&device);
+        assert!(after >= before + Duration::from_millis(50));
+
+        device.send(&create_udp_packet_to(destination));
+        device.receive(&mut selector, Duration::from_millis(20));
+        assert!(idle(&device) < after);
+        device.client().borrow_mut().router().clear(&mut selector);
+    }
+
+    #[test]
+    fn drop_datagrams_from_other_sources() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = match server.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            _ => panic!("Expected IPv4 address"),
+        };
+        let mut selector = Selector::create().unwrap();
+        let mut device = MockDevice::new(&mut selector, RelayConfig::new());
+        device.send(&create_udp_packet_to(destination));
+        // let the relay forward the datagram
+        device.receive(&mut selector, Duration::from_millis(100));
+
+        server
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let mut buf = [0u8; 16];
+        let (len, relay) = server.recv_from(&mut buf).unwrap();
+        assert_eq!([0x11, 0x22, 0x33, 0x44], &buf[..len]);
+
+        // the relay socket is not connected, anyone can send to it
+        let spoofer = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        spoofer.send_to(b"spoofed", relay).unwrap();
+        server.send_to(b"answer", relay).unwrap();
+
+        let packet = device
+            .receive(&mut selector, Duration::from_secs(2))
+            .expect("No answer from the server");
+        assert_eq!(b"answer", &packet[28..]);
+        assert_eq!(
+            None,
+            device.receive(&mut selector, Duration::from_millis(50))
+        );
+
+        let mut client = device.client().borrow_mut();
+        let router = client.router();
+        assert_eq!(1, router.drops().get(DropReason::UdpSourceMismatch));
+        router.clear(&mut selector);
+    }
 }
@@ -30,7 +30,7 @@ pub struct UdpHeaderMut<'a> {
     data: &'a mut UdpHeaderData,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct UdpHeaderData {
     source_port: u16,
     destination_port: u16,
@@ -38,11 +38,27 @@ pub struct UdpHeaderData {
 
 #[allow(dead_code)]
 impl UdpHeaderData {
-    pub fn parse(raw: &[u8]) -> Self {
+    /// Create a UDP header.
+    ///
+    /// The raw header is written by `UdpHeaderMut::serialize()` once bound.
+    pub fn new(source_port: u16, destination_port: u16) -> Self {
         Self {
+            source_port,
+            destination_port,
+        }
+    }
+
+    /// Parse the UDP header at the start of `raw`.
+    ///
+    /// Return `None` if `raw` is too short to contain a header.
+    pub fn parse(raw: &[u8]) -> Option<Self> {
+        if raw.len() < UDP_HEADER_LENGTH as usize {
+            return None;
+        }
+        Some(Self {
             source_port: BigEndian::read_u16(&raw[0..2]),
             destination_port: BigEndian::read_u16(&raw[2..4]),
-        }
+        })
     }
 
     #[inline]
@@ -73,10 +89,7 @@ macro_rules! udp_header_common {
         #[allow(dead_code)]
         impl<'a> $name<'a> {
             pub fn new(raw: $raw_type, data: $data_type) -> Self {
-                Self {
-                    raw: raw,
-                    data: data,
-                }
+                Self { raw, data }
             }
 
             #[inline]
@@ -118,6 +131,17 @@ impl<'a> UdpHeaderMut<'a> {
         self.data
     }
 
+    /// Write the ports to the raw buffer.
+    ///
+    /// The length is set for an empty payload and the checksum is reset to 0, they must be
+    /// updated once the payload is known.
+    pub fn serialize(&mut self) {
+        BigEndian::write_u16(&mut self.raw[0..2], self.data.source_port);
+        BigEndian::write_u16(&mut self.raw[2..4], self.data.destination_port);
+        self.set_payload_length(0);
+        self.set_checksum(0);
+    }
+
     #[inline]
     pub fn set_source_port(&mut self, source_port: u16) {
         self.data.source_port = source_port;
@@ -161,8 +185,7 @@ mod tests {
     use byteorder::{BigEndian, WriteBytesExt};
 
     fn create_header() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(8);
+        let mut raw = Vec::with_capacity(8);
         raw.write_u16::<BigEndian>(1234).unwrap(); // source port
         raw.write_u16::<BigEndian>(5678).unwrap(); // destination port
         raw.write_u16::<BigEndian>(42).unwrap(); // length
@@ -173,7 +196,7 @@ mod tests {
     #[test]
     fn parse_header() {
         let raw = &create_header()[..];
-        let data = UdpHeaderData::parse(raw);
+        let data = UdpHeaderData::parse(raw).unwrap();
         assert_eq!(1234, data.source_port());
         assert_eq!(5678, data.destination_port());
     }
@@ -181,7 +204,7 @@ mod tests {
     #[test]
     fn edit_header() {
         let raw = &mut create_header()[..];
-        let mut header_data = UdpHeaderData::parse(raw);
+        let mut header_data = UdpHeaderData::parse(raw).unwrap();
         let mut header = header_data.bind_mut(raw);
 
         header.set_source_port(1111);
@@ -212,4 +235,19 @@ mod tests {
         assert_eq!(2222, raw_source_port);
         assert_eq!(1111, raw_destination_port);
     }
+
+    #[test]
+    fn serialize_new_header() {
+        let mut header_data = UdpHeaderData::new(1111, 2222);
+        // garbage must be overwritten
+        let raw = &mut [0xFFu8; 8][..];
+        header_data.bind_mut(raw).serialize();
+
+        assert_eq!(8, BigEndian::read_u16(&raw[4..6])); // length
+        assert_eq!(0, BigEndian::read_u16(&raw[6..8])); // checksum
+
+        let parsed = UdpHeaderData::parse(raw).unwrap();
+        assert_eq!(1111, parsed.source_port());
+        assert_eq!(2222, parsed.destination_port());
+    }
 }